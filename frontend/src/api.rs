@@ -1,11 +1,52 @@
-use crate::models::{DiscoveredDevice, Machine, NetworkInterface, UpdateMachinePayload};
+use crate::models::{
+    CurrentUser, GroupOutcome, HistoryEvent, Machine, NetworkInterface, ScanResult, ServerEvent,
+    ServerEventKind, UpdateMachinePayload,
+};
 
-use leptos::leptos_dom::logging::console_log;
 
-use gloo_net::http::Request;
-use web_sys::window;
+use gloo_net::http::{Request, RequestBuilder, Response};
+use leptos::leptos_dom::logging::console_log;
+use std::cell::RefCell;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::closure::Closure;
+use web_sys::{EventSource, EventSourceInit, MessageEvent, RequestCredentials, window};
 const DEFAULT_API_PORT: u16 = 3000;
 
+thread_local! {
+    // CSRF token of the current session, sent back on every request that changes something
+    static CSRF_TOKEN: RefCell<Option<String>> = const { RefCell::new(None) };
+    // Open `/api/events` stream, kept so it isn't subscribed twice
+    static EVENT_SOURCE: RefCell<Option<EventSource>> = const { RefCell::new(None) };
+}
+
+/// Send the session cookie (also to the API port when served by trunk) and the CSRF token.
+fn authed(builder: RequestBuilder) -> RequestBuilder {
+    let builder = builder.credentials(RequestCredentials::Include);
+    match CSRF_TOKEN.with(|token| token.borrow().clone()) {
+        Some(token) => builder.header("X-CSRF-Token", &token),
+        None => builder,
+    }
+}
+
+/// Send an API request, going to the login page when the session is missing or expired.
+async fn send(request: Request) -> Result<Response, String> {
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if response.status() == 401 {
+        redirect_to_login();
+        return Err("Not logged in".to_string());
+    }
+    Ok(response)
+}
+
+fn redirect_to_login() {
+    if let Some(window) = window() {
+        let location = window.location();
+        if location.pathname().map(|path| path != "/login").unwrap_or(true) {
+            let _ = location.set_href("/login");
+        }
+    }
+}
+
 // Function to get the API base URL dynamically from the current window location
 fn get_api_base() -> String {
     if let Some(window) = window() {
@@ -28,24 +69,68 @@ fn get_api_base() -> String {
     }
 }
 
-pub async fn create_machine(machine: Machine) -> Result<(), String> {
+fn build(builder: RequestBuilder) -> Result<Request, String> {
+    authed(builder).build().map_err(|e| e.to_string())
+}
+
+/// The logged in user; also picks up the session's CSRF token after a page reload.
+pub async fn current_user() -> Result<CurrentUser, String> {
     let api_base = get_api_base();
-    Request::post(&format!("{}/machines", api_base))
-        .json(&machine)
+    let user: CurrentUser = send(build(Request::get(&format!("{}/auth/me", api_base)))?)
+        .await?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    CSRF_TOKEN.with(|token| *token.borrow_mut() = user.csrf_token.clone());
+    Ok(user)
+}
+
+pub async fn login(username: &str, password: &str) -> Result<CurrentUser, String> {
+    let api_base = get_api_base();
+    let payload = serde_json::json!({ "username": username, "password": password });
+    // Not through `send`: a 401 here means wrong credentials, not an expired session
+    let response = authed(Request::post(&format!("{}/auth/login", api_base)))
+        .json(&payload)
         .map_err(|e| e.to_string())?
         .send()
         .await
         .map_err(|e| e.to_string())?;
+    let is_success = response.ok();
+    let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+    if !is_success {
+        return Err(body
+            .get("error")
+            .and_then(|value| value.as_str())
+            .unwrap_or("Login failed")
+            .to_string());
+    }
+    let user: CurrentUser = serde_json::from_value(body).map_err(|e| e.to_string())?;
+    CSRF_TOKEN.with(|token| *token.borrow_mut() = user.csrf_token.clone());
+    Ok(user)
+}
+
+pub async fn logout() -> Result<(), String> {
+    let api_base = get_api_base();
+    send(build(Request::post(&format!("{}/auth/logout", api_base)))?).await?;
+    CSRF_TOKEN.with(|token| *token.borrow_mut() = None);
+    redirect_to_login();
+    Ok(())
+}
+
+pub async fn create_machine(machine: Machine) -> Result<(), String> {
+    let api_base = get_api_base();
+    let request = authed(Request::post(&format!("{}/machines", api_base)))
+        .json(&machine)
+        .map_err(|e| e.to_string())?;
+    send(request).await?;
 
     Ok(())
 }
 
 pub async fn get_details_machine(mac: &str) -> Result<Machine, String> {
     let api_base = get_api_base();
-    Request::get(&format!("{}/machines/{}", api_base, mac))
-        .send()
-        .await
-        .map_err(|e| e.to_string())?
+    send(build(Request::get(&format!("{}/machines/{}", api_base, mac)))?)
+        .await?
         .json()
         .await
         .map_err(|e| e.to_string())
@@ -53,12 +138,10 @@ pub async fn get_details_machine(mac: &str) -> Result<Machine, String> {
 
 pub async fn update_machine(mac: &str, payload: &UpdateMachinePayload) -> Result<(), String> {
     let api_base = get_api_base();
-    Request::put(&format!("{}/machines/{}", api_base, mac))
+    let request = authed(Request::put(&format!("{}/machines/{}", api_base, mac)))
         .json(payload)
-        .map_err(|e| e.to_string())?
-        .send()
-        .await
         .map_err(|e| e.to_string())?;
+    send(request).await?;
 
     Ok(())
 }
@@ -66,21 +149,17 @@ pub async fn update_machine(mac: &str, payload: &UpdateMachinePayload) -> Result
 pub async fn delete_machine(mac: &str) -> Result<(), String> {
     let api_base = get_api_base();
     let payload = serde_json::json!({ "mac": mac });
-    Request::delete(&format!("{}/machines/delete", api_base))
+    let request = authed(Request::delete(&format!("{}/machines/delete", api_base)))
         .json(&payload)
-        .map_err(|e| e.to_string())?
-        .send()
-        .await
         .map_err(|e| e.to_string())?;
+    send(request).await?;
     Ok(())
 }
 
 pub async fn fetch_machines() -> Result<Vec<Machine>, String> {
     let api_base = get_api_base();
-    Request::get(&format!("{}/machines", api_base))
-        .send()
-        .await
-        .map_err(|e| e.to_string())?
+    send(build(Request::get(&format!("{}/machines", api_base)))?)
+        .await?
         .json()
         .await
         .map_err(|e| e.to_string())
@@ -88,26 +167,22 @@ pub async fn fetch_machines() -> Result<Vec<Machine>, String> {
 
 pub async fn fetch_interfaces() -> Result<Vec<NetworkInterface>, String> {
     let api_base = get_api_base();
-    Request::get(&format!("{}/interfaces", api_base))
-        .send()
-        .await
-        .map_err(|e| e.to_string())?
+    send(build(Request::get(&format!("{}/interfaces", api_base)))?)
+        .await?
         .json()
         .await
         .map_err(|e| e.to_string())
 }
 
-pub async fn fetch_scan_network(device: String) -> Result<Vec<DiscoveredDevice>, String> {
+pub async fn fetch_scan_network(device: String) -> Result<ScanResult, String> {
     let api_base = get_api_base();
     let url = if device.is_empty() {
         format!("{}/scan", api_base)
     } else {
         format!("{}/scan?interface={}", api_base, device)
     };
-    Request::get(&url)
-        .send()
-        .await
-        .map_err(|e| e.to_string())?
+    send(build(Request::get(&url))?)
+        .await?
         .json()
         .await
         .map_err(|e| e.to_string())
@@ -115,10 +190,8 @@ pub async fn fetch_scan_network(device: String) -> Result<Vec<DiscoveredDevice>,
 
 pub async fn turn_off_machine(mac: &str) -> Result<String, String> {
     let api_base = get_api_base();
-    let response = Request::post(&format!("{}/machines/{}/remote-turn-off", api_base, mac))
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+    let request = build(Request::post(&format!("{}/machines/{}/remote-turn-off", api_base, mac)))?;
+    let response = send(request).await?;
 
     let is_success = response.ok();
     let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
@@ -137,10 +210,8 @@ pub async fn turn_off_machine(mac: &str) -> Result<String, String> {
 
 pub async fn wake_machine(mac: &str) -> Result<String, String> {
     let api_base = get_api_base();
-    let response = Request::post(&format!("{}/machines/{}/wake", api_base, mac))
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+    let request = build(Request::post(&format!("{}/machines/{}/wake", api_base, mac)))?;
+    let response = send(request).await?;
 
     let is_success = response.ok();
     let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
@@ -157,23 +228,136 @@ pub async fn wake_machine(mac: &str) -> Result<String, String> {
     }
 }
 
-pub async fn is_machine_online(mac: &str) -> bool {
+pub async fn approve_machine(mac: &str) -> Result<(), String> {
     let api_base = get_api_base();
-    let response = Request::get(&format!("{}/machines/{}/is-on", api_base, mac))
-        .send()
-        .await
-        .map_err(|e| e.to_string());
-
-    if response.is_err() {
-        console_log(&format!(
-            "Error checking if machine is online: {}",
-            response.err().unwrap()
-        ));
-        return false;
+    let request = build(Request::post(&format!("{}/machines/{}/approve", api_base, mac)))?;
+    let response = send(request).await?;
+
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(format!("Server responded with status {}", response.status()))
     }
-    let response = response.unwrap();
-    match response.status() {
-        200 => true,
-        _ => false,
+}
+
+/// Run `action` (`wake` or `turn-off`) on every machine of `group`.
+pub async fn group_action(group: &str, action: &str) -> Result<Vec<GroupOutcome>, String> {
+    let api_base = get_api_base();
+    let request = build(Request::post(&format!("{}/groups/{}/{}", api_base, group, action)))?;
+    let response = send(request).await?;
+    let is_success = response.ok();
+    let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+    if !is_success {
+        return Err(body["error"].as_str().map(str::to_string).unwrap_or_else(|| body.to_string()));
+    }
+    serde_json::from_value(body["results"].clone()).map_err(|e| e.to_string())
+}
+
+/// Keep the inactivity monitor from shutting `mac` down for `minutes`, returning the Unix
+/// time the override ends.
+pub async fn keep_awake(mac: &str, minutes: u32) -> Result<Option<i64>, String> {
+    let api_base = get_api_base();
+    let request = authed(Request::post(&format!("{}/machines/{}/keep-awake", api_base, mac)))
+        .json(&serde_json::json!({ "minutes": minutes }))
+        .map_err(|e| e.to_string())?;
+    let response = send(request).await?;
+    let is_success = response.ok();
+    let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+    if is_success {
+        Ok(body["keep_awake_until"].as_i64())
+    } else {
+        Err(body["error"].as_str().map(str::to_string).unwrap_or_else(|| body.to_string()))
     }
 }
+
+/// End a keep-on override early.
+pub async fn cancel_keep_awake(mac: &str) -> Result<(), String> {
+    let api_base = get_api_base();
+    let request = build(Request::delete(&format!("{}/machines/{}/keep-awake", api_base, mac)))?;
+    let response = send(request).await?;
+
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(format!("Server responded with status {}", response.status()))
+    }
+}
+
+/// Move a machine to the address neighbor discovery proposed for it.
+pub async fn accept_ip_proposal(mac: &str) -> Result<(), String> {
+    let api_base = get_api_base();
+    let request = build(Request::post(&format!("{}/machines/{}/ip-proposal", api_base, mac)))?;
+    let response = send(request).await?;
+
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(format!("Server responded with status {}", response.status()))
+    }
+}
+
+/// Keep a machine's address and forget the proposed one.
+pub async fn dismiss_ip_proposal(mac: &str) -> Result<(), String> {
+    let api_base = get_api_base();
+    let request = build(Request::delete(&format!("{}/machines/{}/ip-proposal", api_base, mac)))?;
+    let response = send(request).await?;
+
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(format!("Server responded with status {}", response.status()))
+    }
+}
+
+/// Recorded events about `mac`, newest first.
+pub async fn fetch_event_history(mac: &str) -> Result<Vec<HistoryEvent>, String> {
+    let api_base = get_api_base();
+    send(build(Request::get(&format!("{}/events/history?mac={}", api_base, mac)))?)
+        .await?
+        .json()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Follow `/api/events`, calling `on_event` for every event pushed by the server. The
+/// browser reconnects by itself when the connection drops.
+pub fn subscribe_events(on_event: impl Fn(ServerEvent) + 'static) -> Result<(), String> {
+    if EVENT_SOURCE.with(|source| source.borrow().is_some()) {
+        return Ok(());
+    }
+    let init = EventSourceInit::new();
+    init.set_with_credentials(true);
+    let source = EventSource::new_with_event_source_init_dict(
+        &format!("{}/events", get_api_base()),
+        &init,
+    )
+    .map_err(|e| format!("{:?}", e))?;
+
+    let on_event = std::rc::Rc::new(on_event);
+    let handler = on_event.clone();
+    let on_message = Closure::<dyn Fn(MessageEvent)>::new(move |message: MessageEvent| {
+        let Some(data) = message.data().as_string() else {
+            return;
+        };
+        match serde_json::from_str::<ServerEvent>(&data) {
+            Ok(event) => handler(event),
+            Err(e) => console_log(&format!("Ignoring unknown server event {}: {}", data, e)),
+        }
+    });
+    source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    on_message.forget();
+
+    let on_lagged = Closure::<dyn Fn(MessageEvent)>::new(move |_: MessageEvent| {
+        on_event(ServerEvent {
+            mac: None,
+            kind: ServerEventKind::Lagged,
+        })
+    });
+    source
+        .add_event_listener_with_callback("lagged", on_lagged.as_ref().unchecked_ref())
+        .map_err(|e| format!("{:?}", e))?;
+    on_lagged.forget();
+
+    EVENT_SOURCE.with(|slot| *slot.borrow_mut() = Some(source));
+    Ok(())
+}
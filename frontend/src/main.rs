@@ -20,11 +20,14 @@ use validator::Validate;
 use web_sys::{SubmitEvent, console};
 
 use crate::api::{
-    create_machine, delete_machine, fetch_interfaces, fetch_machines, fetch_scan_network,
-    get_details_machine, is_machine_online, turn_off_machine, wake_machine,
+    accept_ip_proposal, approve_machine, cancel_keep_awake, create_machine, current_user,
+    delete_machine, dismiss_ip_proposal, fetch_event_history, fetch_interfaces, fetch_machines,
+    fetch_scan_network, get_details_machine, group_action, keep_awake, login, logout,
+    subscribe_events, turn_off_machine, wake_machine,
 };
 use crate::models::{
-    DiscoveredDevice, Machine, NetworkInterface, PortForward,
+    local_time, CurrentUser, DiscoveredDevice, GroupOutcome, HistoryEvent, Machine,
+    NetworkInterface, PortForward, PowerOff, PowerOn, PowerState, ServerEvent, ServerEventKind,
     UpdateMachinePayload,
 };
 
@@ -56,13 +59,43 @@ pub fn ErrorDisplay(
         }}
     }
 }
+/// The logged in user, loaded once by `App`
+fn use_current_user() -> ReadSignal<CurrentUser> {
+    expect_context::<ReadSignal<CurrentUser>>()
+}
+
+/// The latest event pushed by the server on `/api/events`
+fn use_server_events() -> ReadSignal<Option<ServerEvent>> {
+    expect_context::<ReadSignal<Option<ServerEvent>>>()
+}
+
+/// Apply a pushed power state change to a list of machines.
+fn apply_state_change(machines: &mut [Machine], event: &ServerEvent) -> bool {
+    let ServerEventKind::StateChanged { to } = event.kind else {
+        return false;
+    };
+    match machines
+        .iter_mut()
+        .find(|machine| Some(&machine.mac) == event.mac.as_ref())
+    {
+        Some(machine) => {
+            machine.state = to;
+            true
+        }
+        None => false,
+    }
+}
+
 // Components
 #[component]
 fn MachineDetailPage() -> impl IntoView {
     let params = use_params_map();
-    let mac = move || params.read().get("mac").unwrap_or_default();
+    // A machine ID, or a MAC for links from before machines had IDs
+    let id = move || params.read().get("id").unwrap_or_default();
+    let user = use_current_user();
     let (loading, set_loading) = signal(false);
     let (machine_details, set_machine_details) = signal::<Machine>(Machine {
+        id: String::new(),
         name: "".to_string(),
         mac: "".to_string(),
         ip: "".to_string(),
@@ -71,17 +104,63 @@ fn MachineDetailPage() -> impl IntoView {
         can_be_turned_off: false,
         inactivity_period: 60,
         port_forwards: vec![],
+        pending_approval: false,
+        wol_armed: None,
+        power_off: PowerOff::Agent,
+        power_on: PowerOn::MagicPacket,
+        state: PowerState::Unknown,
+        schedules: vec![],
+        keep_awake_until: None,
+        depends_on: vec![],
+        tags: vec![],
+        interfaces: vec![],
+        hostname: None,
+        ip_proposal: None,
     });
 
     // Load initial machine details
     Effect::new(move || {
         leptos::task::spawn_local(async move {
-            if let Ok(cats) = get_details_machine(&mac()).await {
+            if let Ok(cats) = get_details_machine(&id()).await {
                 set_machine_details.set(cats);
             }
         });
     });
 
+    let (history, set_history) = signal::<Vec<HistoryEvent>>(vec![]);
+    let load_history = move || {
+        leptos::task::spawn_local(async move {
+            match fetch_event_history(&id()).await {
+                Ok(events) => set_history.set(events),
+                Err(e) => console_log(&format!("Failed to load the event history: {}", e)),
+            }
+        });
+    };
+    Effect::new(move || load_history());
+
+    let server_events = use_server_events();
+    Effect::new(move || {
+        let Some(event) = server_events.get() else {
+            return;
+        };
+        let mut machine = [machine_details.get_untracked()];
+        if apply_state_change(&mut machine, &event) {
+            let [machine] = machine;
+            set_machine_details.set(machine);
+        }
+        // Events name machines by MAC
+        let ours = event.mac.as_deref() == Some(machine_details.get_untracked().mac.as_str());
+        if let ServerEventKind::KeepAwakeChanged { until } = event.kind {
+            if ours {
+                set_machine_details.update(|machine| machine.keep_awake_until = until);
+            }
+        }
+        // New events are recorded by the server as they are pushed, reload the timeline
+        if ours || matches!(event.kind, ServerEventKind::Lagged) {
+            load_history();
+        }
+    });
+
     // Form state
     let (name, set_name) = signal(String::new());
     let (ip, set_ip) = signal(String::new());
@@ -94,17 +173,63 @@ fn MachineDetailPage() -> impl IntoView {
     let (turn_off_feedback, set_turn_off_feedback) = signal::<Option<(bool, String)>>(None);
     let (wake_loading, set_wake_loading) = signal(false);
     let (wake_feedback, set_wake_feedback) = signal::<Option<(bool, String)>>(None);
+    let (keep_awake_feedback, set_keep_awake_feedback) = signal::<Option<String>>(None);
+
+    // Tick the keep-on countdown
+    let (now, set_now) = signal(chrono::Utc::now().timestamp());
+    if let Ok(handle) = set_interval_with_handle(
+        move || set_now.set(chrono::Utc::now().timestamp()),
+        std::time::Duration::from_secs(1),
+    ) {
+        on_cleanup(move || handle.clear());
+    }
+    let keep_awake_for = move |minutes: u32| {
+        set_keep_awake_feedback.set(None);
+        leptos::task::spawn_local(async move {
+            match keep_awake(&id(), minutes).await {
+                Ok(until) => set_machine_details.update(|machine| machine.keep_awake_until = until),
+                Err(e) => set_keep_awake_feedback.set(Some(e)),
+            }
+        });
+    };
+    let (ip_proposal_feedback, set_ip_proposal_feedback) = signal::<Option<String>>(None);
+    let resolve_ip_proposal = move |accept: bool| {
+        set_ip_proposal_feedback.set(None);
+        leptos::task::spawn_local(async move {
+            let result = if accept {
+                accept_ip_proposal(&id()).await
+            } else {
+                dismiss_ip_proposal(&id()).await
+            };
+            match result {
+                Ok(()) => {
+                    if let Ok(machine) = get_details_machine(&id()).await {
+                        set_machine_details.set(machine);
+                    }
+                }
+                Err(e) => set_ip_proposal_feedback.set(Some(e)),
+            }
+        });
+    };
+    let cancel_keep_awake_override = move |_| {
+        set_keep_awake_feedback.set(None);
+        leptos::task::spawn_local(async move {
+            match cancel_keep_awake(&id()).await {
+                Ok(()) => set_machine_details.update(|machine| machine.keep_awake_until = None),
+                Err(e) => set_keep_awake_feedback.set(Some(e)),
+            }
+        });
+    };
 
     let can_turn_off_machine = Memo::new(move |_| {
-        let machine = machine_details.get();
-        machine.can_be_turned_off && machine.turn_off_port.is_some()
+        machine_details.get().can_power_off()
     });
 
     // Update form fields when machine details load
     Effect::new(move || {
         let machine = machine_details.get();
         set_name.set(machine.name.clone());
-        set_ip.set(machine.ip.clone());
+        set_ip.set(machine.hostname.clone().unwrap_or_else(|| machine.ip.clone()));
         set_description.set(machine.description.clone().unwrap_or_default());
         set_turn_off_port.set(machine.turn_off_port); // This should now match the type
         set_can_be_turned_off.set(machine.can_be_turned_off);
@@ -116,9 +241,14 @@ fn MachineDetailPage() -> impl IntoView {
         ev.prevent_default();
         set_loading.set(true);
 
-        let updated_mac = mac();
+        let machine_id = id();
+        let updated_mac = machine_details.get_untracked().mac;
         let updated_name = name.get();
-        let updated_ip = ip.get();
+        // The field holds an address or a hostname, which keeps the resolved address
+        let (updated_ip, updated_hostname) = match ip.get() {
+            value if value.parse::<std::net::Ipv4Addr>().is_ok() => (value, None),
+            value => (machine_details.get_untracked().ip, Some(value)),
+        };
         let updated_description = if description.get().trim().is_empty() {
             None
         } else {
@@ -134,6 +264,7 @@ fn MachineDetailPage() -> impl IntoView {
 
         // Create updated machine object for local state refresh
         let updated_machine = Machine {
+            id: machine_details.get_untracked().id,
             name: updated_name,
             mac: updated_mac.clone(),
             ip: updated_ip,
@@ -142,11 +273,26 @@ fn MachineDetailPage() -> impl IntoView {
             can_be_turned_off: updated_can_be_turned_off,
             inactivity_period: inactivity_period.get(),
             port_forwards: updated_port_forwards.clone(),
+            pending_approval: machine_details.get_untracked().pending_approval,
+            wol_armed: machine_details.get_untracked().wol_armed,
+            power_off: machine_details.get_untracked().power_off,
+            power_on: machine_details.get_untracked().power_on,
+            state: machine_details.get_untracked().state,
+            schedules: machine_details.get_untracked().schedules,
+            keep_awake_until: machine_details.get_untracked().keep_awake_until,
+            depends_on: machine_details.get_untracked().depends_on,
+            tags: machine_details.get_untracked().tags,
+            interfaces: machine_details.get_untracked().interfaces,
+            hostname: updated_hostname,
+            ip_proposal: machine_details.get_untracked().ip_proposal,
         };
 
         let payload = UpdateMachinePayload {
             mac: updated_machine.mac.clone(),
-            ip: updated_machine.ip.clone(),
+            ip: updated_machine
+                .hostname
+                .clone()
+                .unwrap_or_else(|| updated_machine.ip.clone()),
             name: updated_machine.name.clone(),
             description: updated_machine.description.clone(),
             turn_off_port: updated_machine
@@ -166,11 +312,11 @@ fn MachineDetailPage() -> impl IntoView {
         };
 
         leptos::task::spawn_local(async move {
-            match crate::api::update_machine(&updated_mac, &payload).await {
+            match crate::api::update_machine(&machine_id, &payload).await {
                 Ok(_) => {
                     web_sys::console::log_1(&"Machine updated successfully".into());
                     // Reload the machine details to reflect changes
-                    if let Ok(updated_details) = get_details_machine(&updated_mac).await {
+                    if let Ok(updated_details) = get_details_machine(&machine_id).await {
                         set_machine_details.set(updated_details);
                     }
                     window()
@@ -195,7 +341,7 @@ fn MachineDetailPage() -> impl IntoView {
             return;
         }
 
-        let mac_address = mac();
+        let machine_id = id();
         set_turn_off_loading.set(true);
         set_turn_off_feedback.set(None);
 
@@ -203,7 +349,7 @@ fn MachineDetailPage() -> impl IntoView {
         let set_turn_off_feedback = set_turn_off_feedback;
 
         leptos::task::spawn_local(async move {
-            match turn_off_machine(&mac_address).await {
+            match turn_off_machine(&machine_id).await {
                 Ok(message) => {
                     set_turn_off_feedback.set(Some((true, message.clone())));
                     if let Some(window) = window() {
@@ -229,7 +375,7 @@ fn MachineDetailPage() -> impl IntoView {
             return;
         }
 
-        let mac_address = mac();
+        let machine_id = id();
         set_wake_loading.set(true);
         set_wake_feedback.set(None);
 
@@ -237,7 +383,7 @@ fn MachineDetailPage() -> impl IntoView {
         let set_wake_feedback = set_wake_feedback;
 
         leptos::task::spawn_local(async move {
-            match wake_machine(&mac_address).await {
+            match wake_machine(&machine_id).await {
                 Ok(message) => {
                     set_wake_feedback.set(Some((true, message.clone())));
                     if let Some(window) = window() {
@@ -299,7 +445,7 @@ fn MachineDetailPage() -> impl IntoView {
                             />
                         </div>
                         <div class="field">
-                            <label for="ip">"IP address"</label>
+                            <label for="ip">"IP address or hostname"</label>
                             <input
                                 type="text"
                                 id="ip"
@@ -573,15 +719,17 @@ fn MachineDetailPage() -> impl IntoView {
                         />
                     </div>
 
-                    <div class="form-footer">
-                        <button
-                            type="submit"
-                            class="btn btn-primary"
-                            disabled=move || loading.get()
-                        >
-                            {move || if loading.get() { "Saving..." } else { "Save changes" }}
-                        </button>
-                    </div>
+                    <Show when=move || user.get().is_admin() fallback=|| view! { <></> }>
+                        <div class="form-footer">
+                            <button
+                                type="submit"
+                                class="btn btn-primary"
+                                disabled=move || loading.get()
+                            >
+                                {move || if loading.get() { "Saving..." } else { "Save changes" }}
+                            </button>
+                        </div>
+                    </Show>
                 </form>
             </div>
 
@@ -590,30 +738,49 @@ fn MachineDetailPage() -> impl IntoView {
                     <h3 class="card-title">"Remote controls"</h3>
                     <p class="card-subtitle">"Send wake and shutdown signals instantly."</p>
                 </header>
-                <div class="actions-row">
-                    <button
-                        type="button"
-                        class="btn btn-success"
-                        on:click=trigger_wake
-                        disabled=move || wake_loading.get()
-                    >
-                        {move || if wake_loading.get() { "Waking..." } else { "Wake machine" }}
-                    </button>
-                    <button
-                        type="button"
-                        class="btn btn-danger"
-                        on:click=trigger_turn_off
-                        disabled=move || turn_off_loading.get() || !can_turn_off_machine.get()
-                    >
-                        {move || {
-                            if turn_off_loading.get() {
-                                "Turning off..."
-                            } else {
-                                "Turn off machine"
+                <Show
+                    when=move || user.get().can_wake()
+                    fallback=|| {
+                        view! {
+                            <p class="field-help">
+                                "Your account can only view this machine."
+                            </p>
+                        }
+                    }
+                >
+                    <div class="actions-row">
+                        <button
+                            type="button"
+                            class="btn btn-success"
+                            on:click=trigger_wake
+                            disabled=move || {
+                                wake_loading.get() || machine_details.get().state == PowerState::Waking
                             }
-                        }}
-                    </button>
-                </div>
+                        >
+                            {move || if wake_loading.get() { "Waking..." } else { "Wake machine" }}
+                        </button>
+                        <Show when=move || user.get().can_operate() fallback=|| view! { <></> }>
+                            <button
+                                type="button"
+                                class="btn btn-danger"
+                                on:click=trigger_turn_off
+                                disabled=move || {
+                                    turn_off_loading.get()
+                                        || !can_turn_off_machine.get()
+                                        || machine_details.get().state == PowerState::ShuttingDown
+                                }
+                            >
+                                {move || {
+                                    if turn_off_loading.get() {
+                                        "Turning off..."
+                                    } else {
+                                        "Turn off machine"
+                                    }
+                                }}
+                            </button>
+                        </Show>
+                    </div>
+                </Show>
                 {move || {
                     if let Some((success, message)) = wake_feedback.get() {
                         let class = if success {
@@ -649,6 +816,162 @@ fn MachineDetailPage() -> impl IntoView {
                         "Configure a remote shutdown port on the machine to activate this action."
                     </p>
                 </Show>
+                <Show when=move || can_turn_off_machine.get() fallback=|| view! { <></> }>
+                    <p class="field-help">
+                        {move || format!("Powered off via {}.", machine_details.get().power_off_label())}
+                    </p>
+                </Show>
+                <p class="field-help">
+                    {move || format!("Powered on via {}.", machine_details.get().power_on_label())}
+                </p>
+                <p class="field-help">
+                    {move || format!("Current state: {}.", machine_details.get().state.label())}
+                </p>
+                {move || {
+                    machine_details
+                        .get()
+                        .keep_awake_remaining(now.get())
+                        .map(|remaining| {
+                            view! {
+                                <p class="feedback feedback--success">
+                                    {format!("Kept on for another {}.", remaining)}
+                                </p>
+                            }
+                        })
+                }}
+                <Show when=move || user.get().can_operate() fallback=|| view! { <></> }>
+                    <div class="actions-row">
+                        <button type="button" class="btn btn-soft" on:click=move |_| keep_awake_for(60)>
+                            "Keep on 1h"
+                        </button>
+                        <button type="button" class="btn btn-soft" on:click=move |_| keep_awake_for(180)>
+                            "Keep on 3h"
+                        </button>
+                        <Show
+                            when=move || machine_details.get().keep_awake_remaining(now.get()).is_some()
+                            fallback=|| view! { <></> }
+                        >
+                            <button type="button" class="btn btn-soft" on:click=cancel_keep_awake_override>
+                                "Cancel keep-on"
+                            </button>
+                        </Show>
+                    </div>
+                </Show>
+                {move || {
+                    keep_awake_feedback
+                        .get()
+                        .map(|message| view! { <p class="feedback feedback--danger">{message}</p> })
+                }}
+                {move || {
+                    machine_details
+                        .get()
+                        .ip_proposal
+                        .map(|proposal| {
+                            view! {
+                                <p class="feedback feedback--danger">
+                                    {format!(
+                                        "{} was seen at {} on {}.",
+                                        proposal.mac,
+                                        proposal.ip,
+                                        local_time(proposal.seen_at),
+                                    )}
+                                </p>
+                                <Show when=move || user.get().is_admin() fallback=|| view! { <></> }>
+                                    <div class="actions-row">
+                                        <button type="button" class="btn btn-soft" on:click=move |_| resolve_ip_proposal(true)>
+                                            "Use new address"
+                                        </button>
+                                        <button type="button" class="btn btn-soft" on:click=move |_| resolve_ip_proposal(false)>
+                                            "Keep current address"
+                                        </button>
+                                    </div>
+                                </Show>
+                            }
+                        })
+                }}
+                {move || {
+                    ip_proposal_feedback
+                        .get()
+                        .map(|message| view! { <p class="feedback feedback--danger">{message}</p> })
+                }}
+                {move || {
+                    let depends_on = machine_details.get().depends_on;
+                    (!depends_on.is_empty())
+                        .then(|| {
+                            view! {
+                                <p class="field-help">
+                                    {format!("Wakes {} first.", depends_on.join(", "))}
+                                </p>
+                            }
+                        })
+                }}
+                {move || {
+                    let interfaces = machine_details.get().interfaces;
+                    (!interfaces.is_empty())
+                        .then(|| {
+                            let interfaces: Vec<String> = interfaces
+                                .iter()
+                                .map(|interface| interface.summary())
+                                .collect();
+                            view! {
+                                <p class="field-help">
+                                    {format!("Other interfaces: {}.", interfaces.join(", "))}
+                                </p>
+                            }
+                        })
+                }}
+                {move || {
+                    machine_details
+                        .get()
+                        .schedules
+                        .iter()
+                        .map(|schedule| view! { <p class="field-help">{schedule.label()}</p> })
+                        .collect_view()
+                }}
+                <Show
+                    when=move || machine_details.get().wol_armed == Some(false)
+                    fallback=|| view! { <></> }
+                >
+                    <p class="feedback feedback--danger">
+                        "WOL not armed: the agent reports magic packet wake is disabled on this NIC. "
+                        "Re-enable it (e.g. ethtool -s <iface> wol g) or start the agent with --allow-wol-config."
+                    </p>
+                </Show>
+            </div>
+
+            <div class="card">
+                <header class="card-header">
+                    <h3 class="card-title">"History"</h3>
+                    <p class="card-subtitle">"Who woke, shut down or edited this machine, and why."</p>
+                </header>
+                <Show
+                    when=move || !history.get().is_empty()
+                    fallback=|| view! { <p class="field-help">"Nothing recorded yet."</p> }
+                >
+                    <ul class="timeline">
+                        {move || {
+                            history
+                                .get()
+                                .into_iter()
+                                .map(|event| {
+                                    let cause = match (&event.actor, &event.reason) {
+                                        (Some(actor), Some(reason)) => format!("{} ({})", actor, reason),
+                                        (Some(actor), None) => actor.clone(),
+                                        (None, Some(reason)) => reason.clone(),
+                                        (None, None) => String::new(),
+                                    };
+                                    view! {
+                                        <li class="timeline__item">
+                                            <span class="timeline__time">{event.time()}</span>
+                                            <span class="timeline__summary">{event.summary()}</span>
+                                            <span class="timeline__cause">{cause}</span>
+                                        </li>
+                                    }
+                                })
+                                .collect_view()
+                        }}
+                    </ul>
+                </Show>
             </div>
 
             <div class="card">
@@ -669,6 +992,22 @@ fn MachineDetailPage() -> impl IntoView {
 #[component]
 fn App() -> impl IntoView {
     provide_meta_context();
+    let (user, set_user) = signal(CurrentUser::default());
+    provide_context(user);
+    let (server_event, set_server_event) = signal::<Option<ServerEvent>>(None);
+    provide_context(server_event);
+
+    // Also restores the CSRF token after a reload; redirects to the login page without a session
+    Effect::new(move || {
+        leptos::task::spawn_local(async move {
+            if let Ok(current) = current_user().await {
+                set_user.set(current);
+                if let Err(e) = subscribe_events(move |event| set_server_event.set(Some(event))) {
+                    console_log(&format!("Error subscribing to server events: {}", e));
+                }
+            }
+        });
+    });
 
     view! {
         <Html attr:lang="en" />
@@ -676,15 +1015,113 @@ fn App() -> impl IntoView {
         <Title text="Wakezilla" />
         <Router>
             <main class="container">
+                <UserBar />
                 <Routes fallback=|| "Page not found">
                     <Route path=path!("/") view=HomePage />
-                    <Route path=path!("/machines/:mac") view=MachineDetailPage />
+                    <Route path=path!("/login") view=LoginPage />
+                    <Route path=path!("/machines/:id") view=MachineDetailPage />
                 </Routes>
             </main>
         </Router>
     }
 }
 
+#[component]
+fn UserBar() -> impl IntoView {
+    let user = use_current_user();
+
+    let on_logout = move |_| {
+        leptos::task::spawn_local(async move {
+            if let Err(e) = logout().await {
+                console_log(&format!("Error logging out: {}", e));
+            }
+        });
+    };
+
+    view! {
+        <Show when=move || user.get().username.is_some() fallback=|| view! {}>
+            <div class="form-footer">
+                <span class="field-help">
+                    {move || format!("Signed in as {}", user.get().username.unwrap_or_default())}
+                </span>
+                <button class="btn btn-soft btn-sm" on:click=on_logout>
+                    "Log out"
+                </button>
+            </div>
+        </Show>
+    }
+}
+
+#[component]
+fn LoginPage() -> impl IntoView {
+    let (username, set_username) = signal(String::new());
+    let (password, set_password) = signal(String::new());
+    let (error, set_error) = signal::<Option<String>>(None);
+    let (loading, set_loading) = signal(false);
+
+    let on_submit = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        set_loading.set(true);
+        leptos::task::spawn_local(async move {
+            match login(&username.get_untracked(), &password.get_untracked()).await {
+                Ok(_) => {
+                    // Full reload so every component picks up the new session
+                    if let Some(window) = window() {
+                        let _ = window.location().set_href("/");
+                    }
+                }
+                Err(e) => {
+                    set_error.set(Some(e));
+                    set_loading.set(false);
+                }
+            }
+        });
+    };
+
+    view! {
+        <section class="card">
+            <header class="card-header">
+                <h3 class="card-title">"Sign in"</h3>
+                <p class="card-subtitle">"Log in to manage your machines."</p>
+            </header>
+            <form on:submit=on_submit class="form-grid">
+                <div class="field">
+                    <label for="username">"Username"</label>
+                    <input
+                        id="username"
+                        class="input"
+                        type="text"
+                        autocomplete="username"
+                        prop:value=username
+                        on:input=move |ev| set_username.set(event_target_value(&ev))
+                    />
+                </div>
+                <div class="field">
+                    <label for="password">"Password"</label>
+                    <input
+                        id="password"
+                        class="input"
+                        type="password"
+                        autocomplete="current-password"
+                        prop:value=password
+                        on:input=move |ev| set_password.set(event_target_value(&ev))
+                    />
+                </div>
+                {move || {
+                    error
+                        .get()
+                        .map(|message| view! { <p class="feedback feedback--danger">{message}</p> })
+                }}
+                <div class="form-footer">
+                    <button type="submit" class="btn btn-primary" disabled=move || loading.get()>
+                        {move || if loading.get() { "Signing in…" } else { "Sign in" }}
+                    </button>
+                </div>
+            </form>
+        </section>
+    }
+}
+
 #[component]
 fn Header(
     set_machine: WriteSignal<Machine>,
@@ -694,6 +1131,7 @@ fn Header(
     let (interfaces, set_interfaces) = signal::<Vec<NetworkInterface>>(vec![]);
     let (interface, set_interface) = signal::<String>("".to_string());
     let (loading, set_loading) = signal(false);
+    let (scan_note, set_scan_note) = signal::<Option<String>>(None);
 
     // Load initial data
     Effect::new(move || {
@@ -704,6 +1142,28 @@ fn Header(
         });
     });
 
+    // Show scans started from other tabs or by other admins as well
+    let server_events = use_server_events();
+    Effect::new(move || {
+        if let Some(ServerEvent {
+            kind: ServerEventKind::ScanCompleted { devices },
+            ..
+        }) = server_events.get()
+        {
+            let registred_machines = registred_machines.get_untracked();
+            set_discovered_devices.set(
+                devices
+                    .into_iter()
+                    .filter(|device| {
+                        !registred_machines
+                            .iter()
+                            .any(|machine| machine.mac == device.mac)
+                    })
+                    .collect(),
+            );
+        }
+    });
+
     fn handle_interface_change(value: String, set_interface: WriteSignal<String>) {
         let log_mesasge = format!("Selected interface: {}", value);
         console_log(&log_mesasge);
@@ -714,14 +1174,22 @@ fn Header(
         let set_loading = set_loading;
         set_loading.set(true);
         set_discovered_devices.set(vec![]);
+        set_scan_note.set(None);
         // stop the page from reloading!
         ev.prevent_default();
         console::log_1(&format!("Form submitted with value: {}", interface.get()).into());
         leptos::task::spawn_local(async move {
             fetch_scan_network(interface.get())
                 .await
-                .map(|devices| {
-                    console::log_1(&format!("Discovered devices: {:?}", devices).into());
+                .map(|scan| {
+                    console::log_1(&format!("Discovered devices: {:?}", scan.devices).into());
+                    if scan.incomplete {
+                        set_scan_note.set(Some(format!(
+                            "Found with the {} method, some devices may be missing.",
+                            scan.method.replace('_', " ")
+                        )));
+                    }
+                    let devices = scan.devices;
                     // does not diplay the machine if it's already registred
                     let registred_machines = registred_machines.get();
                     let devices: Vec<DiscoveredDevice> = devices
@@ -753,6 +1221,7 @@ fn Header(
         set_discovered_devices: WriteSignal<Vec<DiscoveredDevice>>,
     ) {
         let new_machine = Machine {
+            id: String::new(),
             name: device.hostname.clone().unwrap_or_default(),
             mac: device.mac.clone(),
             ip: device.ip.clone(),
@@ -765,6 +1234,18 @@ fn Header(
                 local_port: 0,
                 target_port: 0,
             }],
+            pending_approval: false,
+            wol_armed: None,
+            power_off: PowerOff::Agent,
+            power_on: PowerOn::MagicPacket,
+            state: PowerState::Unknown,
+            schedules: vec![],
+            keep_awake_until: None,
+            depends_on: vec![],
+            tags: vec![],
+            interfaces: vec![],
+            hostname: None,
+            ip_proposal: None,
         };
         set_machine.set(new_machine);
         set_discovered_devices.set(vec![]);
@@ -827,6 +1308,7 @@ fn Header(
                         <p class="card-subtitle">
                             "Tap a device to pre-fill the create form below."
                         </p>
+                        {move || scan_note.get().map(|note| view! { <p class="field-help">{note}</p> })}
                     </div>
                     <div class="table-container">
                         <table class="table" id="scan-results-table">
@@ -884,12 +1366,49 @@ fn Header(
 #[component]
 fn RegistredMachines(
     machines: ReadSignal<Vec<Machine>>,
-    status_machine: ReadSignal<HashMap<String, bool>>,
     set_registred_machines: WriteSignal<Vec<Machine>>,
 ) -> impl IntoView {
+    let user = use_current_user();
     let (wake_in_progress, set_wake_in_progress) = signal::<Option<String>>(None);
     let (turn_off_in_progress, set_turn_off_in_progress) = signal::<Option<String>>(None);
 
+    // Group filter and bulk actions, "" shows every machine
+    let (group, set_group) = signal(String::new());
+    let (group_busy, set_group_busy) = signal(false);
+    let (group_results, set_group_results) = signal::<Vec<GroupOutcome>>(vec![]);
+    let (group_error, set_group_error) = signal::<Option<String>>(None);
+    let groups = Memo::new(move |_| {
+        let mut groups: Vec<String> = machines.with(|machines| {
+            machines.iter().flat_map(|machine| machine.tags.clone()).collect()
+        });
+        groups.sort();
+        groups.dedup();
+        groups
+    });
+    let visible_machines = Signal::derive(move || {
+        let group = group.get();
+        machines.with(|machines| {
+            machines
+                .iter()
+                .filter(|machine| group.is_empty() || machine.tags.contains(&group))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    });
+    let run_group_action = move |action: &'static str| {
+        let name = group.get_untracked();
+        set_group_busy.set(true);
+        set_group_error.set(None);
+        set_group_results.set(vec![]);
+        leptos::task::spawn_local(async move {
+            match group_action(&name, action).await {
+                Ok(results) => set_group_results.set(results),
+                Err(e) => set_group_error.set(Some(e)),
+            }
+            set_group_busy.set(false);
+        });
+    };
+
     let on_delete = move |mac_to_delete: String| {
         leptos::task::spawn_local(async move {
             // Call the API to delete the machine
@@ -917,6 +1436,24 @@ fn RegistredMachines(
         });
     };
 
+    let on_approve = move |mac_to_approve: String| {
+        leptos::task::spawn_local(async move {
+            if let Err(err) = approve_machine(&mac_to_approve).await {
+                window()
+                    .unwrap()
+                    .alert_with_message(&format!("Error approving machine: {}", err))
+                    .unwrap();
+                return;
+            }
+
+            set_registred_machines.update(|machines| {
+                if let Some(machine) = machines.iter_mut().find(|m| m.mac == mac_to_approve) {
+                    machine.pending_approval = false;
+                }
+            });
+        });
+    };
+
     view! {
         <section class="card table-card">
             <div class="card-header">
@@ -924,7 +1461,7 @@ fn RegistredMachines(
                     <h2 class="card-title">"Registered machines"</h2>
                     <p class="card-subtitle">
                         {move || {
-                            let count = machines.get().len();
+                            let count = visible_machines.get().len();
                             if count == 0 {
                                 "No machines registered yet.".to_string()
                             } else if count == 1 {
@@ -935,7 +1472,77 @@ fn RegistredMachines(
                         }}
                     </p>
                 </div>
+                <Show when=move || !groups.get().is_empty() fallback=|| view! { <></> }>
+                    <div class="actions-row">
+                        <select
+                            class="input"
+                            on:change:target=move |ev| {
+                                set_group.set(ev.target().value());
+                                set_group_results.set(vec![]);
+                                set_group_error.set(None);
+                            }
+                            prop:value=move || group.get()
+                        >
+                            <option value="">"All machines"</option>
+                            {move || {
+                                groups
+                                    .get()
+                                    .into_iter()
+                                    .map(|name| view! { <option value=name.clone()>{name.clone()}</option> })
+                                    .collect::<Vec<_>>()
+                            }}
+                        </select>
+                        <Show
+                            when=move || !group.get().is_empty() && user.get().can_wake()
+                            fallback=|| view! { <></> }
+                        >
+                            <button
+                                type="button"
+                                class="btn btn-success"
+                                disabled=move || group_busy.get()
+                                on:click=move |_| run_group_action("wake")
+                            >
+                                "Wake group"
+                            </button>
+                            <Show when=move || user.get().can_operate() fallback=|| view! { <></> }>
+                                <button
+                                    type="button"
+                                    class="btn btn-danger"
+                                    disabled=move || group_busy.get()
+                                    on:click=move |_| run_group_action("turn-off")
+                                >
+                                    "Turn off group"
+                                </button>
+                            </Show>
+                        </Show>
+                    </div>
+                </Show>
             </div>
+            {move || group_error.get().map(|e| view! { <p class="feedback feedback--danger">{e}</p> })}
+            <Show when=move || !group_results.get().is_empty() fallback=|| view! { <></> }>
+                <ul class="timeline">
+                    {move || {
+                        group_results
+                            .get()
+                            .into_iter()
+                            .map(|outcome| {
+                                let class = if outcome.success {
+                                    "timeline__summary"
+                                } else {
+                                    "timeline__summary feedback--danger"
+                                };
+                                view! {
+                                    <li class="timeline__item">
+                                        <span class="timeline__time">{outcome.name}</span>
+                                        <span class=class>{outcome.message}</span>
+                                        <span class="timeline__cause">{outcome.mac}</span>
+                                    </li>
+                                }
+                            })
+                            .collect_view()
+                    }}
+                </ul>
+            </Show>
             <div class="table-container">
                 <table class="table">
                     <thead>
@@ -953,7 +1560,7 @@ fn RegistredMachines(
                     </thead>
                     <tbody>
                         <Show
-                            when=move || !machines.get().is_empty()
+                            when=move || !visible_machines.get().is_empty()
                             fallback=|| {
                                 view! {
                                     <tr>
@@ -965,17 +1572,32 @@ fn RegistredMachines(
                             }
                         >
                             <For
-                                each=move || machines.get()
-                                key=|machine| machine.mac.clone()
+                                each=move || visible_machines.get()
+                                key=|machine| machine.id.clone()
                                 children=move |machine| {
+                                    let id_href = machine.id.clone();
                                     let mac_href = machine.mac.clone();
                                     let mac_display = mac_href.clone();
-                                    let ip_display = machine.ip.clone();
+                                    let ip_display = match &machine.hostname {
+                                        Some(hostname) => format!("{} ({})", hostname, machine.ip),
+                                        None => machine.ip.clone(),
+                                    };
                                     let description_display = machine
                                         .description
                                         .clone()
                                         .unwrap_or_else(|| "-".to_string());
-                                    let status_mac = mac_href.clone();
+                                    let state_mac = mac_href.clone();
+                                    // Rows are keyed by MAC, so read the pushed state reactively
+                                    let power_state = Memo::new(move |_| {
+                                        machines
+                                            .with(|machines| {
+                                                machines
+                                                    .iter()
+                                                    .find(|m| m.mac == state_mac)
+                                                    .map(|m| m.state)
+                                                    .unwrap_or_default()
+                                            })
+                                    });
                                     let wake_mac_disabled = mac_href.clone();
                                     let wake_mac_click = mac_href.clone();
                                     let wake_mac_task = mac_href.clone();
@@ -984,6 +1606,9 @@ fn RegistredMachines(
                                     let turn_off_mac_task = mac_href.clone();
                                     let turn_off_mac_label = mac_href.clone();
                                     let delete_mac = mac_href.clone();
+                                    let approve_mac = mac_href.clone();
+                                    let pending_approval = machine.pending_approval;
+                                    let wol_not_armed = machine.wol_armed == Some(false);
                                     let name_link = machine.name.clone();
                                     let name_for_wake = machine.name.clone();
                                     let name_for_turnoff = machine.name.clone();
@@ -995,8 +1620,7 @@ fn RegistredMachines(
                                     let turn_off_in_progress_for_disable = turn_off_in_progress;
                                     let turn_off_in_progress_for_click = turn_off_in_progress;
                                     let turn_off_in_progress_for_label = turn_off_in_progress;
-                                    let can_turn_off_machine = machine.can_be_turned_off
-                                        && machine.turn_off_port.is_some();
+                                    let can_turn_off_machine = machine.can_power_off();
                                     let turn_off_port_text = machine
                                         .turn_off_port
                                         .map(|port| port.to_string())
@@ -1056,7 +1680,7 @@ fn RegistredMachines(
                                             <td>
                                                 <a
                                                     class="text-link"
-                                                    href=format!("/machines/{}", mac_href.clone())
+                                                    href=format!("/machines/{}", id_href.clone())
                                                 >
                                                     {name_link}
                                                 </a>
@@ -1079,27 +1703,22 @@ fn RegistredMachines(
                                                 </span>
                                             </td>
                                             <td>
-                                                {move || {
-                                                    let key = status_mac.clone();
-                                                    let is_online = status_machine
-                                                        .get()
-                                                        .get(&key)
-                                                        .cloned()
-                                                        .unwrap_or(false);
-                                                    if is_online {
-                                                        view! {
-                                                            <span class="status-pill status-pill--online">
-                                                                "Online"
-                                                            </span>
-                                                        }
-                                                    } else {
-                                                        view! {
-                                                            <span class="status-pill status-pill--offline">
-                                                                "Offline"
-                                                            </span>
-                                                        }
-                                                    }
-                                                }}
+                                                <Show when=move || pending_approval fallback=|| ()>
+                                                    <span class="status-pill status-pill--pending">
+                                                        "Pending approval"
+                                                    </span>
+                                                </Show>
+                                                <Show when=move || wol_not_armed fallback=|| ()>
+                                                    <span
+                                                        class="status-pill status-pill--pending"
+                                                        title="The agent reports Wake-on-LAN is disabled on this NIC"
+                                                    >
+                                                        "WOL not armed"
+                                                    </span>
+                                                </Show>
+                                                <span class=move || power_state.get().pill_class()>
+                                                    {move || power_state.get().label()}
+                                                </span>
                                             </td>
                                             <td class="hide-mobile">
                                                 <span class="font-mono text-xs sm:text-sm">
@@ -1107,15 +1726,32 @@ fn RegistredMachines(
                                                 </span>
                                             </td>
                                             <td class="table-actions">
+                                                <Show
+                                                    when=move || pending_approval && user.get().is_admin()
+                                                    fallback=|| ()
+                                                >
+                                                    <button
+                                                        class="btn-icon btn-icon--positive"
+                                                        title="Approve registered machine"
+                                                        on:click={
+                                                            let approve_mac = approve_mac.clone();
+                                                            move |_| on_approve(approve_mac.clone())
+                                                        }
+                                                    >
+                                                        "✔"
+                                                    </button>
+                                                </Show>
                                                 <button
                                                     class="btn-icon btn-icon--positive"
+                                                    class=("btn-icon--hidden", move || !user.get().can_wake())
                                                     title="Wake machine"
                                                     disabled=move || {
-                                                        wake_in_progress_for_disable
-                                                            .get()
-                                                            .as_ref()
-                                                            .map(|current| current == &wake_mac_disabled)
-                                                            .unwrap_or(false)
+                                                        power_state.get() == PowerState::Waking
+                                                            || wake_in_progress_for_disable
+                                                                .get()
+                                                                .as_ref()
+                                                                .map(|current| current == &wake_mac_disabled)
+                                                                .unwrap_or(false)
                                                     }
                                                     on:click=move |_| {
                                                         if wake_in_progress_for_click
@@ -1169,9 +1805,11 @@ fn RegistredMachines(
                                                 </button>
                                                 <button
                                                     class="btn-icon"
+                                                    class=("btn-icon--hidden", move || !user.get().can_operate())
                                                     title="Turn off machine"
                                                     disabled=move || {
                                                         !can_turn_off_machine
+                                                            || power_state.get() == PowerState::ShuttingDown
                                                             || turn_off_in_progress_for_disable
                                                                 .get()
                                                                 .as_ref()
@@ -1251,6 +1889,7 @@ fn RegistredMachines(
                                                 </button>
                                                 <button
                                                     class="btn-icon btn-icon--danger"
+                                                    class=("btn-icon--hidden", move || !user.get().is_admin())
                                                     title="Delete machine"
                                                     on:click=move |_| {
                                                         if window()
@@ -1384,6 +2023,7 @@ fn AddMachine(
                         set_registred_machines.set(new_machines);
                         // Clear the form
                         set_machine_form_data.set(Machine {
+                            id: String::new(),
                             name: "".to_string(),
                             mac: "".to_string(),
                             ip: "".to_string(),
@@ -1392,6 +2032,18 @@ fn AddMachine(
                             can_be_turned_off: false,
                             inactivity_period: 60,
                             port_forwards: vec![],
+                            pending_approval: false,
+                            wol_armed: None,
+                            power_off: PowerOff::Agent,
+                            power_on: PowerOn::MagicPacket,
+                            state: PowerState::Unknown,
+                            schedules: vec![],
+                            keep_awake_until: None,
+                            depends_on: vec![],
+                            tags: vec![],
+                            interfaces: vec![],
+                            hostname: None,
+                            ip_proposal: None,
                         });
                         set_port_forwards.set(vec![]);
                         set_show_turn_off_port.set(false);
@@ -1484,7 +2136,7 @@ fn AddMachine(
 
                 <div class="form-grid two-column">
                     <div class="field">
-                        <label for="ip">"IP address"</label>
+                        <label for="ip">"IP address or hostname"</label>
                         <input
                             type="text"
                             id="ip"
@@ -1771,6 +2423,7 @@ fn AddMachine(
 #[component]
 fn HomePage() -> impl IntoView {
     let default_machine = Machine {
+        id: String::new(),
         name: "".to_string(),
         mac: "".to_string(),
         ip: "".to_string(),
@@ -1783,11 +2436,22 @@ fn HomePage() -> impl IntoView {
             local_port: 0,
             target_port: 0,
         }],
+        pending_approval: false,
+        wol_armed: None,
+        power_off: PowerOff::Agent,
+        power_on: PowerOn::MagicPacket,
+        state: PowerState::Unknown,
+        schedules: vec![],
+        keep_awake_until: None,
+        depends_on: vec![],
+        tags: vec![],
+        interfaces: vec![],
+        hostname: None,
+        ip_proposal: None,
     };
     let (machine, set_machine) = signal::<Machine>(default_machine);
 
     let (registred_machines, set_registred_machines) = signal::<Vec<Machine>>(vec![]);
-    let (status_machine, set_status_machine) = signal::<HashMap<String, bool>>(HashMap::new());
 
     // Load initial registred machines
     Effect::new(move || {
@@ -1799,54 +2463,45 @@ fn HomePage() -> impl IntoView {
         });
     });
 
-    // check the status of registred machines when they change
-    Effect::new(move |_| {
-        let machines = registred_machines.get();
-        if machines.is_empty() {
-            // console_log("No registred machines");
+    // Keep the status badges up to date with the states pushed by the server
+    let server_events = use_server_events();
+    Effect::new(move || {
+        let Some(event) = server_events.get() else {
+            return;
+        };
+        if matches!(event.kind, ServerEventKind::Lagged) {
+            leptos::task::spawn_local(async move {
+                if let Ok(machines) = fetch_machines().await {
+                    set_registred_machines.set(machines);
+                }
+            });
             return;
         }
-
-        // Spawn the async task to check all machines
-        leptos::task::spawn_local(async move {
-            // Create a vector of futures to check each machine concurrently
-            let mut futures = Vec::new();
-
-            for m in machines {
-                let machine_mac = m.mac.clone();
-                let machine_name = m.name.clone();
-
-                console_log(&format!("Checking machine {}", machine_name));
-                let future = async move { (machine_mac, is_machine_online(&m.mac).await) };
-                futures.push(future);
-            }
-
-            // Wait for all futures to complete, regardless of individual failures
-            let results = futures::future::join_all(futures).await;
-
-            // Build the status map from all results
-            let mut status_map = HashMap::new();
-            for (mac, is_online) in results {
-                status_map.insert(mac, is_online);
-            }
-            set_status_machine.set(status_map);
-        });
+        let mut machines = registred_machines.get_untracked();
+        if apply_state_change(&mut machines, &event) {
+            set_registred_machines.set(machines);
+        }
     });
 
+    let user = use_current_user();
+
     view! {
-        <Header set_machine=set_machine registred_machines=registred_machines />
+        <Show when=move || user.get().is_admin() fallback=|| view! {}>
+            <Header set_machine=set_machine registred_machines=registred_machines />
+        </Show>
         <Show when=move || { !registred_machines.get().is_empty() } fallback=|| view! {}>
             <RegistredMachines
                 machines=registred_machines
-                status_machine=status_machine
                 set_registred_machines=set_registred_machines
             />
         </Show>
-        <AddMachine
-            machine=machine
-            registred_machines=registred_machines
-            set_registred_machines=set_registred_machines
-        />
+        <Show when=move || user.get().is_admin() fallback=|| view! {}>
+            <AddMachine
+                machine=machine
+                registred_machines=registred_machines
+                set_registred_machines=set_registred_machines
+            />
+        </Show>
     }
 }
 
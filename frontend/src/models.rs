@@ -22,8 +22,21 @@ pub struct DiscoveredDevice {
     pub hostname: Option<String>,
 }
 
+/// Response of `GET /api/scan`
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScanResult {
+    /// `arp`, or `neighbor_table` when the server can't send raw ARP requests
+    pub method: String,
+    /// Devices may be missing
+    pub incomplete: bool,
+    pub devices: Vec<DiscoveredDevice>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Machine {
+    /// Stable ID assigned by the server, empty until the machine is saved
+    #[serde(default, skip_serializing)]
+    pub id: String,
     pub name: String,
     pub mac: String,
     pub ip: String,
@@ -32,6 +45,379 @@ pub struct Machine {
     pub can_be_turned_off: bool,
     pub inactivity_period: u32,
     pub port_forwards: Vec<PortForward>,
+    #[serde(default)]
+    pub pending_approval: bool,
+    #[serde(default)]
+    pub wol_armed: Option<bool>,
+    /// Only read for display, the server keeps the full backend configuration
+    #[serde(default, skip_serializing)]
+    pub power_off: PowerOff,
+    #[serde(default, skip_serializing)]
+    pub power_on: PowerOn,
+    /// Live power state tracked by the server
+    #[serde(default, skip_serializing)]
+    pub state: PowerState,
+    /// Only read for display, schedules are edited through the API
+    #[serde(default, skip_serializing)]
+    pub schedules: Vec<Schedule>,
+    /// Unix time until which the inactivity monitor leaves the machine on
+    #[serde(default, skip_serializing)]
+    pub keep_awake_until: Option<i64>,
+    /// IDs or MACs of machines woken before this one, edited through the API
+    #[serde(default, skip_serializing)]
+    pub depends_on: Vec<String>,
+    /// Groups the machine belongs to, edited through the API
+    #[serde(default, skip_serializing)]
+    pub tags: Vec<String>,
+    /// Further NICs, edited through the API
+    #[serde(default, skip_serializing)]
+    pub interfaces: Vec<MachineInterface>,
+    /// DNS name `ip` was resolved from
+    #[serde(default, skip_serializing)]
+    pub hostname: Option<String>,
+    /// New address seen by neighbor discovery, waiting to be accepted
+    #[serde(default, skip_serializing)]
+    pub ip_proposal: Option<IpProposal>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct IpProposal {
+    pub mac: String,
+    pub ip: String,
+    pub seen_at: i64,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct MachineInterface {
+    pub mac: String,
+    #[serde(default)]
+    pub ip: Option<String>,
+    pub wake_on_lan: bool,
+    pub priority: u32,
+}
+
+impl MachineInterface {
+    pub fn summary(&self) -> String {
+        let mut summary = self.mac.clone();
+        if let Some(ip) = &self.ip {
+            summary.push_str(&format!(" ({})", ip));
+        }
+        if self.wake_on_lan {
+            summary.push_str(" · Wake-on-LAN");
+        }
+        summary
+    }
+}
+
+/// The outcome of a group wake or turn-off for one machine
+#[derive(Debug, Deserialize, Clone)]
+pub struct GroupOutcome {
+    pub mac: String,
+    pub name: String,
+    pub success: bool,
+    pub message: String,
+}
+
+/// A cron-style wake or shutdown time, see `Schedule` on the server
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Schedule {
+    pub action: String,
+    pub cron: String,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub until: Option<String>,
+}
+
+impl Schedule {
+    pub fn label(&self) -> String {
+        let action = match self.action.as_str() {
+            "wake" => "Wake".to_string(),
+            "shutdown" => "Shut down".to_string(),
+            "keep_awake" => format!(
+                "Keep awake until {}",
+                self.until.clone().unwrap_or_default()
+            ),
+            other => other.to_string(),
+        };
+        format!(
+            "{} at `{}` ({})",
+            action,
+            self.cron,
+            self.timezone.clone().unwrap_or_else(|| "UTC".to_string())
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    #[default]
+    Unknown,
+    Offline,
+    Waking,
+    Online,
+    ShuttingDown,
+}
+
+impl PowerState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PowerState::Unknown => "Unknown",
+            PowerState::Offline => "Offline",
+            PowerState::Waking => "Waking",
+            PowerState::Online => "Online",
+            PowerState::ShuttingDown => "Shutting down",
+        }
+    }
+
+    pub fn pill_class(&self) -> &'static str {
+        match self {
+            PowerState::Online => "status-pill status-pill--online",
+            PowerState::Offline => "status-pill status-pill--offline",
+            PowerState::Waking | PowerState::ShuttingDown => "status-pill status-pill--pending",
+            PowerState::Unknown => "status-pill status-pill--unknown",
+        }
+    }
+}
+
+/// How the server powers a machine off
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerOff {
+    #[default]
+    Agent,
+    Ssh {
+        user: String,
+        host: Option<String>,
+    },
+    Http {
+        url: String,
+    },
+    Redfish {
+        url: String,
+    },
+    SmartPlug {
+        url: String,
+    },
+    Local,
+}
+
+/// How the server powers a machine on
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerOn {
+    #[default]
+    MagicPacket,
+    Redfish {
+        url: String,
+    },
+    SmartPlug {
+        url: String,
+    },
+}
+
+impl Machine {
+    /// Time left on a keep-on override at `now` (Unix seconds), e.g. `2h 59m 10s`
+    pub fn keep_awake_remaining(&self, now: i64) -> Option<String> {
+        let left = self.keep_awake_until? - now;
+        if left <= 0 {
+            return None;
+        }
+        Some(format!("{}h {:02}m {:02}s", left / 3600, left % 3600 / 60, left % 60))
+    }
+
+    pub fn can_power_off(&self) -> bool {
+        self.can_be_turned_off && (self.turn_off_port.is_some() || self.power_off != PowerOff::Agent)
+    }
+
+    pub fn power_off_label(&self) -> String {
+        match &self.power_off {
+            PowerOff::Agent => "Wakezilla client".to_string(),
+            PowerOff::Ssh { user, host } => {
+                format!("SSH ({}@{})", user, host.clone().unwrap_or_else(|| self.ip.clone()))
+            }
+            PowerOff::Http { url } => format!("HTTP ({})", url),
+            PowerOff::Redfish { url } => format!("Redfish ({})", url),
+            PowerOff::SmartPlug { url } => format!("smart plug ({})", url),
+            PowerOff::Local => "the wakezilla server itself".to_string(),
+        }
+    }
+
+    pub fn power_on_label(&self) -> String {
+        match &self.power_on {
+            PowerOn::MagicPacket => "Wake-on-LAN".to_string(),
+            PowerOn::Redfish { url } => format!("Redfish ({})", url),
+            PowerOn::SmartPlug { url } => format!("smart plug ({})", url),
+        }
+    }
+}
+
+/// Pushed by the server on `/api/events`
+#[derive(Debug, Deserialize, Clone)]
+pub struct ServerEvent {
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(flatten)]
+    pub kind: ServerEventKind,
+}
+
+/// The events the web interface reacts to, see `EventKind` on the server
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEventKind {
+    StateChanged { to: PowerState },
+    ScanCompleted { devices: Vec<DiscoveredDevice> },
+    KeepAwakeChanged { until: Option<i64> },
+    /// The connection missed some events, everything should be reloaded
+    Lagged,
+    #[serde(other)]
+    Other,
+}
+
+/// A Unix timestamp in the browser's time zone
+pub fn local_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// A recorded event from `GET /api/events/history`
+#[derive(Debug, Deserialize, Clone)]
+pub struct HistoryEvent {
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Fields specific to the kind, e.g. `error` or `changes`
+    #[serde(flatten)]
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl HistoryEvent {
+    pub fn time(&self) -> String {
+        local_time(self.timestamp)
+    }
+
+    pub fn summary(&self) -> String {
+        let detail = |key: &str| {
+            self.details
+                .get(key)
+                .map(|value| match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                })
+                .unwrap_or_default()
+        };
+        match self.kind.as_str() {
+            "wake_requested" => "Wake requested".to_string(),
+            "wake_succeeded" => "Came up after wake".to_string(),
+            "wake_failed" => format!("Wake failed: {}", detail("error")),
+            "wake_suppressed" => format!(
+                "Wake by {} suppressed: {}",
+                detail("client"),
+                detail("reason")
+            ),
+            "shutdown_requested" => "Shutdown sent".to_string(),
+            "shutdown_verified" => format!("Shutdown verified after {} attempt(s)", detail("attempts")),
+            "shutdown_unverified" => "Shutdown sent, no port to verify it".to_string(),
+            "shutdown_failed" => format!("Shutdown failed: {}", detail("error")),
+            "state_changed" => format!("State {} → {}", detail("from"), detail("to")),
+            "forwarder_started" => format!(
+                "Forwarding port {} to {}",
+                detail("local_port"),
+                detail("target_port")
+            ),
+            "forwarder_stopped" => format!("Stopped forwarding port {}", detail("local_port")),
+            "forwarder_failed" => format!(
+                "Forwarder on port {} failed: {}",
+                detail("local_port"),
+                detail("error")
+            ),
+            "ip_changed" => format!("{} moved from {} to {}", detail("interface"), detail("from"), detail("to")),
+            "ip_change_proposed" => format!(
+                "{} seen at {} instead of {}",
+                detail("interface"),
+                detail("to"),
+                detail("from")
+            ),
+            "machine_added" => "Machine added".to_string(),
+            "machine_deleted" => "Machine deleted".to_string(),
+            "keep_awake_changed" => match self.details.get("until").and_then(|until| until.as_i64()) {
+                Some(until) => format!("Kept on until {}", local_time(until)),
+                None => "Keep-on override cancelled".to_string(),
+            },
+            "machine_changed" => {
+                let changes = self
+                    .details
+                    .get("changes")
+                    .and_then(|changes| changes.as_array())
+                    .map(|changes| {
+                        changes
+                            .iter()
+                            .map(|change| {
+                                format!(
+                                    "{}: {} → {}",
+                                    change["field"].as_str().unwrap_or_default(),
+                                    change["before"],
+                                    change["after"]
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("; ")
+                    })
+                    .unwrap_or_default();
+                format!("Configuration changed ({})", changes)
+            }
+            other => other.replace('_', " "),
+        }
+    }
+}
+
+/// What the logged in user may do, each role includes the ones before it
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Waker,
+    Operator,
+    Admin,
+}
+
+/// Who is logged in, from `GET /api/auth/me`
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct CurrentUser {
+    pub auth_enabled: bool,
+    pub username: Option<String>,
+    pub csrf_token: Option<String>,
+    #[serde(default)]
+    pub role: Role,
+}
+
+impl CurrentUser {
+    /// Wake machines
+    pub fn can_wake(&self) -> bool {
+        self.role >= Role::Waker
+    }
+
+    /// Shut down machines and keep them awake
+    pub fn can_operate(&self) -> bool {
+        self.role >= Role::Operator
+    }
+
+    /// Add, edit, approve and delete machines, and scan the network
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
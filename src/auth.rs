@@ -0,0 +1,819 @@
+//! Local user accounts, login sessions and API tokens for the proxy's web API.
+//!
+//! Users and tokens live in a JSON file next to the machines database. Passwords are
+//! stored as argon2 hashes and API tokens as SHA-256 digests, so the file never holds a
+//! usable secret. Sessions are kept in memory: restarting the server logs everyone out.
+//!
+//! Browsers authenticate with the session cookie set by `POST /api/auth/login` and must
+//! echo the session's CSRF token in the `X-CSRF-Token` header on every request that
+//! changes something. Scripts send `Authorization: Bearer <token>` instead.
+//!
+//! Every user has a [`Role`] and optionally a list of machines and tags they are limited
+//! to. API tokens act with the role and grants of the user who owns them.
+
+use anyhow::{bail, Context, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::web::{AppState, Machine};
+
+pub const SESSION_COOKIE: &str = "wakezilla_session";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+const TOKEN_PREFIX: &str = "wz_";
+/// The only path tokens with [`TokenScope::Register`] are accepted on.
+pub const REGISTER_PATH: &str = "/api/agents/register";
+/// File next to the users database holding the generated password of the first admin.
+const INITIAL_PASSWORD_FILE: &str = "initial-admin-password";
+
+/// What a user may do, each role includes the ones before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// See machines and whether they are online
+    #[default]
+    Viewer,
+    /// Also wake machines
+    Waker,
+    /// Also shut machines down and keep them awake
+    Operator,
+    /// Also add, edit, approve and delete machines, and scan the network
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Waker => "waker",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "waker" => Ok(Role::Waker),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            other => bail!(
+                "Unknown role '{}', expected viewer, waker, operator or admin",
+                other
+            ),
+        }
+    }
+}
+
+/// Machines a non-admin user is limited to; empty lists mean every machine.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Grants {
+    /// Machine IDs or MAC addresses
+    #[serde(default)]
+    pub machines: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Grants {
+    pub fn is_unrestricted(&self) -> bool {
+        self.machines.is_empty() && self.tags.is_empty()
+    }
+
+    pub fn covers(&self, machine: &Machine) -> bool {
+        self.is_unrestricted()
+            || self.machines.iter().any(|key| machine.matches(key))
+            || self.tags.iter().any(|tag| machine.tags.contains(tag))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub username: String,
+    pub password_hash: String,
+    /// Users saved before roles existed load as viewers
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub grants: Grants,
+}
+
+/// What an API token may be used for.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// Everything its owner may do
+    #[default]
+    Full,
+    /// Only announcing agents on `/api/agents/register`
+    Register,
+}
+
+impl std::str::FromStr for TokenScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(TokenScope::Full),
+            "register" => Ok(TokenScope::Register),
+            other => bail!("Unknown token scope '{}', expected full or register", other),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub username: String,
+    /// SHA-256 of the secret, the secret itself is only shown once
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub token_hash: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub scope: TokenScope,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct UsersDb {
+    #[serde(default)]
+    users: Vec<User>,
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    pub csrf_token: String,
+    expires_at: SystemTime,
+}
+
+/// The authenticated caller, added to the request extensions by [`require_auth`].
+#[derive(Clone, Debug)]
+pub struct CurrentUser {
+    /// `None` when authentication is disabled
+    pub username: Option<String>,
+    /// Session the request was made with; token and anonymous requests have none
+    pub session: Option<Session>,
+    pub role: Role,
+    pub grants: Grants,
+    /// Scope of the API token the request was made with, `Full` for sessions
+    pub scope: TokenScope,
+}
+
+impl CurrentUser {
+    /// The caller when authentication is disabled, allowed to do everything.
+    pub fn anonymous() -> Self {
+        Self {
+            username: None,
+            session: None,
+            role: Role::Admin,
+            grants: Grants::default(),
+            scope: TokenScope::Full,
+        }
+    }
+
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+
+    /// Name recorded in the event log for actions of this user.
+    pub fn actor(&self) -> String {
+        self.username
+            .clone()
+            .unwrap_or_else(|| "anonymous".to_string())
+    }
+
+    /// Whether the user may see `machine` at all. Admins see every machine.
+    pub fn covers(&self, machine: &Machine) -> bool {
+        self.role == Role::Admin || self.grants.covers(machine)
+    }
+
+    /// Whether the user is limited to some machines.
+    pub fn is_restricted(&self) -> bool {
+        self.role != Role::Admin && !self.grants.is_unrestricted()
+    }
+}
+
+pub struct Auth {
+    enabled: bool,
+    session_ttl: Duration,
+    secure_cookies: bool,
+    /// `None` keeps users in memory only, used by tests
+    db_path: Option<PathBuf>,
+    db: Mutex<UsersDb>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Auth {
+    /// No authentication, every request is let through.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            session_ttl: Duration::ZERO,
+            secure_cookies: false,
+            db_path: None,
+            db: Mutex::new(UsersDb::default()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Authentication with users kept in memory only.
+    pub fn in_memory(session_ttl: Duration) -> Self {
+        Self {
+            enabled: true,
+            session_ttl,
+            ..Self::disabled()
+        }
+    }
+
+    /// Load users from the configured database. When authentication is enabled and there
+    /// are no users yet, an `admin` user with a random password is created and the password
+    /// is written to `initial-admin-password` next to the database, readable by its owner only.
+    pub fn load(config: &Config) -> Result<Self> {
+        if !config.auth.enabled {
+            warn!("Authentication is disabled, anyone who can reach the API can use it");
+            return Ok(Self::disabled());
+        }
+        let path = config.storage.users_db_path();
+        let auth = Self {
+            enabled: true,
+            session_ttl: Duration::from_secs(config.auth.session_ttl_secs),
+            secure_cookies: config.server.tls_enabled,
+            db: Mutex::new(load_db(&path)?),
+            db_path: Some(path.clone()),
+            sessions: Mutex::new(HashMap::new()),
+        };
+        if auth.db.lock().unwrap().users.is_empty() {
+            let password = random_hex(12);
+            let password_path = path.with_file_name(INITIAL_PASSWORD_FILE);
+            write_private(&password_path, format!("{}\n", password).as_bytes())?;
+            auth.add_user("admin", &password, Some(Role::Admin))?;
+            warn!(
+                "Created user 'admin', its password is in {}; change it with `wakezilla user add admin` and delete the file",
+                password_path.display()
+            );
+        } else if !auth
+            .db
+            .lock()
+            .unwrap()
+            .users
+            .iter()
+            .any(|u| u.role == Role::Admin)
+        {
+            warn!(
+                "No user is an admin; promote one with `wakezilla user grant <username> --role admin`"
+            );
+        }
+        Ok(auth)
+    }
+
+    /// Open the users database for the `user` and `token` CLI commands.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            db: Mutex::new(load_db(path)?),
+            db_path: Some(path.to_path_buf()),
+            ..Self::in_memory(Duration::ZERO)
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Create a user with `role`, or reset the password of an existing one and change its
+    /// role when one is given. New users default to viewers.
+    pub fn add_user(&self, username: &str, password: &str, role: Option<Role>) -> Result<()> {
+        if username.trim().is_empty() {
+            bail!("Username must not be empty");
+        }
+        if password.len() < 8 {
+            bail!("Password must be at least 8 characters long");
+        }
+        let password_hash = hash_password(password)?;
+        let mut db = self.db.lock().unwrap();
+        match db.users.iter_mut().find(|u| u.username == username) {
+            Some(user) => {
+                user.password_hash = password_hash;
+                user.role = role.unwrap_or(user.role);
+            }
+            None => db.users.push(User {
+                username: username.to_string(),
+                password_hash,
+                role: role.unwrap_or(Role::Viewer),
+                grants: Grants::default(),
+            }),
+        }
+        self.save(&db)
+    }
+
+    /// Change a user's role and the machines they are limited to.
+    pub fn set_access(&self, username: &str, role: Option<Role>, grants: Grants) -> Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .with_context(|| format!("Unknown user '{}'", username))?;
+        user.role = role.unwrap_or(user.role);
+        user.grants = grants;
+        self.save(&db)
+    }
+
+    /// Users without their password hashes.
+    pub fn users(&self) -> Vec<User> {
+        let db = self.db.lock().unwrap();
+        db.users
+            .iter()
+            .map(|u| User {
+                password_hash: String::new(),
+                ..u.clone()
+            })
+            .collect()
+    }
+
+    /// Role and grants of `username`, `None` once the user has been removed.
+    pub fn current_user(&self, username: &str, session: Option<Session>) -> Option<CurrentUser> {
+        let db = self.db.lock().unwrap();
+        let user = db.users.iter().find(|u| u.username == username)?;
+        Some(CurrentUser {
+            username: Some(user.username.clone()),
+            session,
+            role: user.role,
+            grants: user.grants.clone(),
+            scope: TokenScope::Full,
+        })
+    }
+
+    /// Remove a user together with their tokens and sessions.
+    pub fn remove_user(&self, username: &str) -> Result<bool> {
+        let mut db = self.db.lock().unwrap();
+        let before = db.users.len();
+        db.users.retain(|u| u.username != username);
+        if db.users.len() == before {
+            return Ok(false);
+        }
+        db.tokens.retain(|t| t.username != username);
+        self.save(&db)?;
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, s| s.username != username);
+        Ok(true)
+    }
+
+    /// Check a username and password. This is slow on purpose, call it off the async workers.
+    pub fn verify_password(&self, username: &str, password: &str) -> bool {
+        let hash = {
+            let db = self.db.lock().unwrap();
+            db.users
+                .iter()
+                .find(|u| u.username == username)
+                .map(|u| u.password_hash.clone())
+        };
+        match hash {
+            Some(hash) => check_password(&hash, password),
+            None => {
+                // Spend the same time on unknown users so they can't be told apart
+                let _ = hash_password(password);
+                false
+            }
+        }
+    }
+
+    pub fn create_session(&self, username: &str) -> Session {
+        let session = Session {
+            id: random_hex(32),
+            username: username.to_string(),
+            csrf_token: random_hex(32),
+            expires_at: SystemTime::now() + self.session_ttl,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = SystemTime::now();
+        sessions.retain(|_, s| s.expires_at > now);
+        sessions.insert(session.id.clone(), session.clone());
+        session
+    }
+
+    pub fn session(&self, id: &str) -> Option<Session> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(id)
+            .filter(|s| s.expires_at > SystemTime::now())
+            .cloned()
+    }
+
+    pub fn end_session(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// Create an API token for `username`, returning the record and the secret.
+    pub fn create_token(
+        &self,
+        username: &str,
+        name: &str,
+        scope: TokenScope,
+    ) -> Result<(ApiToken, String)> {
+        let mut db = self.db.lock().unwrap();
+        let Some(user) = db.users.iter().find(|u| u.username == username) else {
+            bail!("Unknown user '{}'", username);
+        };
+        // Registering agents takes an admin, a register token of anyone else would be useless
+        if scope == TokenScope::Register && user.role != Role::Admin {
+            bail!("Only admins can create agent registration tokens");
+        }
+        let secret = format!("{}{}", TOKEN_PREFIX, random_hex(32));
+        let token = ApiToken {
+            id: random_hex(8),
+            name: name.to_string(),
+            username: username.to_string(),
+            token_hash: sha256_hex(&secret),
+            created_at: unix_now(),
+            scope,
+        };
+        db.tokens.push(token.clone());
+        self.save(&db)?;
+        Ok((token, secret))
+    }
+
+    /// Tokens of `username`, without their hashes.
+    pub fn tokens(&self, username: &str) -> Vec<ApiToken> {
+        let db = self.db.lock().unwrap();
+        db.tokens
+            .iter()
+            .filter(|t| t.username == username)
+            .map(|t| ApiToken {
+                token_hash: String::new(),
+                ..t.clone()
+            })
+            .collect()
+    }
+
+    pub fn revoke_token(&self, username: &str, id: &str) -> Result<bool> {
+        let mut db = self.db.lock().unwrap();
+        let before = db.tokens.len();
+        db.tokens
+            .retain(|t| !(t.username == username && t.id == id));
+        if db.tokens.len() == before {
+            return Ok(false);
+        }
+        self.save(&db)?;
+        Ok(true)
+    }
+
+    /// Owner and scope of the API token `secret`, if it is valid.
+    pub fn authenticate_token(&self, secret: &str) -> Option<(String, TokenScope)> {
+        let hash = sha256_hex(secret);
+        let db = self.db.lock().unwrap();
+        db.tokens
+            .iter()
+            .find(|t| constant_time_eq(&t.token_hash, &hash))
+            .map(|t| (t.username.clone(), t.scope))
+    }
+
+    /// `Set-Cookie` value for a new session.
+    pub fn session_cookie(&self, session: &Session) -> String {
+        self.cookie(&session.id, self.session_ttl.as_secs())
+    }
+
+    /// `Set-Cookie` value that removes the session cookie.
+    pub fn clear_cookie(&self) -> String {
+        self.cookie("", 0)
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        let secure = if self.secure_cookies { "; Secure" } else { "" };
+        format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}",
+            SESSION_COOKIE, value, max_age, secure
+        )
+    }
+
+    fn save(&self, db: &UsersDb) -> Result<()> {
+        let Some(path) = &self.db_path else {
+            return Ok(());
+        };
+        let data = serde_json::to_string_pretty(db).context("Failed to serialize users")?;
+        write_private(path, data.as_bytes())
+    }
+}
+
+fn load_db(path: &Path) -> Result<UsersDb> {
+    if !path.exists() {
+        return Ok(UsersDb::default());
+    }
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read users database from {}", path.display()))?;
+    let db: UsersDb = serde_json::from_str(&data)
+        .with_context(|| format!("Failed to parse users database {}", path.display()))?;
+    info!(
+        "Loaded {} users and {} API tokens from {}",
+        db.users.len(),
+        db.tokens.len(),
+        path.display()
+    );
+    Ok(db)
+}
+
+/// Write `data` to `path` readable only by its owner, tightening the mode of existing files.
+#[cfg(unix)]
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    std::fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).context("No randomness available")?;
+    let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow::anyhow!(e))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+fn check_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+pub(crate) fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    getrandom::getrandom(&mut buf).expect("the OS random number generator to be available");
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_hex(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Value of the session cookie in the request, if any.
+pub fn session_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+fn unauthorized(message: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(serde_json::json!({ "error": message })),
+    )
+        .into_response()
+}
+
+/// Middleware for the API routes: resolves the caller from an API token or a session
+/// cookie, checks the CSRF token on state-changing session requests and rejects the rest.
+pub async fn require_auth(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let auth = &state.auth;
+    if !auth.enabled() {
+        req.extensions_mut().insert(CurrentUser::anonymous());
+        return next.run(req).await;
+    }
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    if let Some(token) = bearer {
+        let user = auth
+            .authenticate_token(&token)
+            .and_then(|(username, scope)| {
+                auth.current_user(&username, None)
+                    .map(|user| CurrentUser { scope, ..user })
+            });
+        return match user {
+            Some(user)
+                if user.scope == TokenScope::Register && req.uri().path() != REGISTER_PATH =>
+            {
+                (
+                    StatusCode::FORBIDDEN,
+                    Json(serde_json::json!({ "error": "This token can only register agents" })),
+                )
+                    .into_response()
+            }
+            Some(user) => {
+                req.extensions_mut().insert(user);
+                next.run(req).await
+            }
+            None => unauthorized("Invalid API token"),
+        };
+    }
+
+    let Some(session) = session_id(req.headers()).and_then(|id| auth.session(&id)) else {
+        return unauthorized("Authentication required");
+    };
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe {
+        let csrf_ok = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|token| constant_time_eq(token, &session.csrf_token));
+        if !csrf_ok {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({ "error": "Missing or invalid CSRF token" })),
+            )
+                .into_response();
+        }
+    }
+    let Some(user) = auth.current_user(&session.username.clone(), Some(session)) else {
+        return unauthorized("Authentication required");
+    };
+    req.extensions_mut().insert(user);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_hashed_and_verified() {
+        let auth = Auth::in_memory(Duration::from_secs(60));
+        auth.add_user("alice", "correct horse", None).unwrap();
+        assert!(auth.verify_password("alice", "correct horse"));
+        assert!(!auth.verify_password("alice", "wrong password"));
+        assert!(!auth.verify_password("bob", "correct horse"));
+        assert!(auth.add_user("carol", "short", None).is_err());
+
+        let hash = &auth.db.lock().unwrap().users[0].password_hash;
+        assert!(hash.starts_with("$argon2"));
+    }
+
+    #[test]
+    fn tokens_authenticate_until_revoked() {
+        let auth = Auth::in_memory(Duration::from_secs(60));
+        auth.add_user("alice", "correct horse", None).unwrap();
+        let (token, secret) = auth
+            .create_token("alice", "backup script", TokenScope::Full)
+            .unwrap();
+        assert!(secret.starts_with(TOKEN_PREFIX));
+        assert_eq!(
+            auth.authenticate_token(&secret),
+            Some(("alice".to_string(), TokenScope::Full))
+        );
+        assert!(auth.authenticate_token("wz_nope").is_none());
+        assert!(auth.tokens("alice")[0].token_hash.is_empty());
+
+        assert!(auth.revoke_token("alice", &token.id).unwrap());
+        assert!(auth.authenticate_token(&secret).is_none());
+    }
+
+    #[test]
+    fn users_database_round_trips_without_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let auth = Auth::open(&path).unwrap();
+        auth.add_user("alice", "correct horse", None).unwrap();
+        let (_, secret) = auth
+            .create_token("alice", "script", TokenScope::Full)
+            .unwrap();
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("correct horse"));
+        assert!(!data.contains(&secret));
+
+        let reopened = Auth::open(&path).unwrap();
+        assert!(reopened.verify_password("alice", "correct horse"));
+        assert_eq!(
+            reopened.authenticate_token(&secret),
+            Some(("alice".to_string(), TokenScope::Full))
+        );
+    }
+
+    #[test]
+    fn first_admin_password_goes_to_a_private_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.storage.users_db_path = Some(dir.path().join("users.json").display().to_string());
+        let auth = Auth::load(&config).unwrap();
+
+        let password_path = dir.path().join(INITIAL_PASSWORD_FILE);
+        let password = std::fs::read_to_string(&password_path).unwrap();
+        assert!(auth.verify_password("admin", password.trim()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&password_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn grants_limit_non_admins_to_machines_and_tags() {
+        let auth = Auth::in_memory(Duration::from_secs(60));
+        auth.add_user("kid", "correct horse", None).unwrap();
+        auth.set_access(
+            "kid",
+            Some(Role::Operator),
+            Grants {
+                machines: vec!["aa:bb:cc:dd:ee:01".to_string()],
+                tags: vec!["lab".to_string()],
+            },
+        )
+        .unwrap();
+        let user = auth.current_user("kid", None).unwrap();
+        assert!(user.has_role(Role::Operator));
+        assert!(!user.has_role(Role::Admin));
+
+        let mut machine = crate::test_support::machine("AA:BB:CC:DD:EE:01");
+        assert!(user.covers(&machine));
+        machine.mac = "AA:BB:CC:DD:EE:02".to_string();
+        assert!(!user.covers(&machine));
+        machine.tags = vec!["lab".to_string()];
+        assert!(user.covers(&machine));
+
+        assert!(CurrentUser::anonymous().covers(&crate::test_support::machine("11:22:33:44:55:66")));
+    }
+
+    #[test]
+    fn users_without_a_role_are_viewers() {
+        let user: User = serde_json::from_value(serde_json::json!({
+            "username": "old",
+            "password_hash": "$argon2id$..."
+        }))
+        .unwrap();
+        assert_eq!(user.role, Role::Viewer);
+        assert!(user.grants.is_unrestricted());
+    }
+
+    #[test]
+    fn session_cookie_is_parsed_from_the_cookie_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            "theme=dark; wakezilla_session=abc123".parse().unwrap(),
+        );
+        assert_eq!(session_id(&headers).as_deref(), Some("abc123"));
+    }
+}
@@ -1,45 +1,343 @@
 use anyhow::Result;
 use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Json},
     routing::{get, post},
     Router,
 };
-use std::net::SocketAddr;
-use tokio::net::TcpListener;
-use tracing::info;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tracing::{info, warn};
 
+use crate::auth;
+use crate::ethtool;
+use crate::hooks::{self, HookConfig};
 use crate::system;
+use crate::tls::{self, TlsFiles};
+use crate::web::AgentRegistration;
 
-pub async fn start(port: u16) -> Result<()> {
-    let app = Router::new()
-        .route("/health", get(health_check))
-        .route("/machines/turn-off", post(turn_off_machine));
+/// Where and how often the agent announces itself to a proxy server.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub proxy_url: String,
+    pub interval: Duration,
+    /// Pinned SHA-256 fingerprint of the proxy's certificate, for https proxies with self-signed certificates
+    pub proxy_fingerprint: Option<String>,
+    /// API token for proxies that require authentication
+    pub token: Option<String>,
+}
+
+/// Optional behaviour of the client agent.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    pub registration: Option<Registration>,
+    /// Allow the proxy to change the NIC's Wake-on-LAN settings through `/wol`
+    pub allow_wol_config: bool,
+    /// Shared secret callers of `/wol/:interface/enable` must send as a bearer token; `None` when
+    /// the route sits behind the proxy's own authentication (all-in-one)
+    pub wol_config_token: Option<String>,
+    pub hooks: HookConfig,
+    /// Serve https with these certificate files
+    pub tls: Option<TlsFiles>,
+}
+
+pub async fn start(port: u16, options: ClientOptions) -> Result<()> {
+    let tls = options.tls.clone();
+    if let Some(files) = &tls {
+        // Generate the certificate before registering so its fingerprint can be announced
+        files.ensure_exists()?;
+    }
+    if let Some(registration) = options.registration.clone() {
+        tokio::spawn(run_registration(registration, port, tls.clone()));
+    }
+    hooks::spawn_resume_watcher(options.hooks.clone());
+
+    let app = router(options);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    info!("listening on http://{}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    tls::serve(addr, app, tls).await
+}
+
+pub fn router(options: ClientOptions) -> Router {
+    status_router(options.clone()).merge(control_router(options))
+}
+
+/// Read-only routes: health, system status and Wake-on-LAN settings.
+pub fn status_router(options: ClientOptions) -> Router {
+    Router::new()
+        .route("/health", get(health_check))
+        .route("/status", get(status))
+        .route("/wol", get(list_wol_settings))
+        .with_state(options)
+}
+
+/// Routes that shut the machine down or change its Wake-on-LAN settings.
+pub fn control_router(options: ClientOptions) -> Router {
+    Router::new()
+        .route("/wol/:interface/enable", post(enable_wol))
+        .route("/machines/turn-off", post(turn_off_machine))
+        .with_state(options)
+}
+
+async fn run_registration(registration: Registration, port: u16, tls: Option<TlsFiles>) {
+    let mut interval = tokio::time::interval(registration.interval);
+    loop {
+        interval.tick().await;
+        let result = match tls.as_ref().map(TlsFiles::fingerprint).transpose() {
+            Ok(fingerprint) => register_with_proxy(&registration, port, fingerprint).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to register with proxy {}: {}",
+                registration.proxy_url, e
+            );
+        }
+    }
+}
+
+/// Announce this machine to the proxy. `tls_fingerprint` is the fingerprint of this
+/// agent's own certificate when it serves https.
+pub async fn register_with_proxy(
+    registration: &Registration,
+    port: u16,
+    tls_fingerprint: Option<String>,
+) -> Result<()> {
+    let proxy_url = registration.proxy_url.trim_end_matches('/');
+    let mut payload = tokio::task::spawn_blocking({
+        let proxy_url = proxy_url.to_string();
+        move || build_registration(&proxy_url, port)
+    })
+    .await??;
+    payload.tls_fingerprint = tls_fingerprint;
 
+    let client = tls::pinned_client_builder(registration.proxy_fingerprint.as_deref())?
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let mut request = client
+        .post(format!("{}/api/agents/register", proxy_url))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&payload)?);
+    if let Some(token) = &registration.token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!("proxy answered with status {}", response.status());
+    }
+    info!(
+        "Registered with proxy {} as {} ({}, {})",
+        proxy_url, payload.hostname, payload.mac, payload.ip
+    );
     Ok(())
 }
 
+fn build_registration(proxy_url: &str, port: u16) -> Result<AgentRegistration> {
+    let url = reqwest::Url::parse(proxy_url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("proxy URL {} has no host", proxy_url))?;
+    let proxy_addr = (host, url.port_or_known_default().unwrap_or(3000))
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("could not resolve proxy host {}", host))?;
+    registration_towards(proxy_addr, port)
+}
+
+/// Registration for the host running the proxy in all-in-one mode.
+pub fn local_registration(port: u16) -> Result<AgentRegistration> {
+    // Any routed address picks the interface other machines reach us on, nothing is sent
+    registration_towards(SocketAddr::from(([192, 0, 2, 1], 9)), port)
+}
+
+fn registration_towards(target: SocketAddr, port: u16) -> Result<AgentRegistration> {
+    let (mac, ip) = system::primary_interface(target)
+        .ok_or_else(|| anyhow::anyhow!("no network interface with a MAC and IPv4 address"))?;
+
+    let status = system::collect_status();
+    Ok(AgentRegistration {
+        hostname: status.hostname.unwrap_or_else(|| ip.to_string()),
+        wol_armed: system::wol_armed_for(&status.wake_on_lan, &mac),
+        mac,
+        ip: ip.to_string(),
+        port,
+        shutdown_actions: system::supported_shutdown_actions(),
+        tls_fingerprint: None,
+    })
+}
+
 async fn health_check() -> impl IntoResponse {
     let status = serde_json::json!({ "status": "ok" });
     Json(status)
 }
 
-async fn turn_off_machine() -> impl IntoResponse {
-    system::shutdown_machine();
-    (
-        axum::http::StatusCode::OK,
-        "Shutting down this machine".to_string(),
-    )
+async fn status() -> impl IntoResponse {
+    // sysinfo and `who` do blocking I/O, keep them off the async workers
+    match tokio::task::spawn_blocking(system::collect_status).await {
+        Ok(status) => Ok(Json(status)),
+        Err(e) => {
+            tracing::error!("Failed to collect system status: {}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn list_wol_settings() -> impl IntoResponse {
+    match tokio::task::spawn_blocking(ethtool::list_wol_settings).await {
+        Ok(settings) => Ok(Json(settings)),
+        Err(e) => {
+            tracing::error!("Failed to read Wake-on-LAN settings: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn enable_wol(
+    State(options): State<ClientOptions>,
+    Path(interface): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !options.allow_wol_config {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "error": "Changing Wake-on-LAN settings is disabled, start the agent with --allow-wol-config"
+            })),
+        );
+    }
+    if let Some(expected) = &options.wol_config_token {
+        let presented = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !presented.is_some_and(|token| auth::constant_time_eq(token.trim(), expected)) {
+            warn!(
+                "Rejected unauthenticated request to change Wake-on-LAN on {}",
+                interface
+            );
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(serde_json::json!({ "error": "A valid WoL config token is required" })),
+            );
+        }
+    }
+
+    let iface = interface.clone();
+    match tokio::task::spawn_blocking(move || ethtool::enable_magic_packet(&iface)).await {
+        Ok(Ok(settings)) => {
+            info!("Enabled magic packet Wake-on-LAN on {}", interface);
+            (StatusCode::OK, Json(serde_json::json!(settings)))
+        }
+        Ok(Err(e)) => {
+            warn!("Failed to enable Wake-on-LAN on {}: {}", interface, e);
+            let status = match e.kind() {
+                std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                std::io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+                std::io::ErrorKind::Unsupported => StatusCode::NOT_IMPLEMENTED,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, Json(serde_json::json!({ "error": e.to_string() })))
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        ),
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct TurnOffParams {
+    action: Option<String>,
+}
+
+/// Why the agent refused to shut down.
+#[derive(Debug)]
+pub enum ShutdownRefused {
+    UnsupportedAction(String),
+    HookFailed(Vec<hooks::HookResult>),
+}
+
+impl std::fmt::Display for ShutdownRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownRefused::UnsupportedAction(action) => {
+                write!(f, "Unsupported shutdown action '{}'", action)
+            }
+            ShutdownRefused::HookFailed(_) => {
+                write!(f, "Pre-shutdown hook failed, shutdown aborted")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShutdownRefused {}
+
+/// Run the pre-shutdown hooks and shut this machine down, returning the hook results.
+pub async fn shutdown(
+    hooks: &HookConfig,
+    action: Option<String>,
+) -> Result<Vec<hooks::HookResult>, ShutdownRefused> {
+    if let Some(action) = &action {
+        if !system::supported_shutdown_actions().contains(action) {
+            return Err(ShutdownRefused::UnsupportedAction(action.clone()));
+        }
+    }
+
+    let run = match &hooks.pre_shutdown_dir {
+        Some(dir) => hooks::run_hooks(dir, hooks.timeout, hooks.budget).await,
+        None => hooks::HookRun {
+            success: true,
+            results: Vec::new(),
+        },
+    };
+
+    if !run.success {
+        warn!("Pre-shutdown hook failed, not shutting down");
+        return Err(ShutdownRefused::HookFailed(run.results));
+    }
+
+    if hooks.post_resume_dir.is_some() {
+        hooks::record_shutdown(&hooks.state_dir);
+    }
+    system::shutdown_machine_with(action);
+    Ok(run.results)
+}
+
+async fn turn_off_machine(
+    State(options): State<ClientOptions>,
+    Query(params): Query<TurnOffParams>,
+) -> impl IntoResponse {
+    match shutdown(&options.hooks, params.action).await {
+        Ok(results) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "message": "Shutting down this machine",
+                "hooks": results,
+            })),
+        ),
+        Err(e @ ShutdownRefused::UnsupportedAction(_)) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": e.to_string(),
+                "supported": system::supported_shutdown_actions(),
+            })),
+        ),
+        Err(ShutdownRefused::HookFailed(results)) => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": "Pre-shutdown hook failed, shutdown aborted",
+                "hooks": results,
+            })),
+        ),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::http::StatusCode;
     use axum::response::IntoResponse;
 
     #[tokio::test]
@@ -47,4 +345,88 @@ mod tests {
         let response = health_check().await.into_response();
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn enable_wol_is_forbidden_unless_allowed() {
+        let response = enable_wol(
+            State(ClientOptions::default()),
+            Path("eth0".to_string()),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn enable_wol_requires_the_shared_secret() {
+        let options = ClientOptions {
+            allow_wol_config: true,
+            wol_config_token: Some("s3cret".to_string()),
+            ..ClientOptions::default()
+        };
+        let mut wrong = HeaderMap::new();
+        wrong.insert(header::AUTHORIZATION, "Bearer guess".parse().unwrap());
+        for headers in [HeaderMap::new(), wrong] {
+            let response = enable_wol(State(options.clone()), Path("eth0".to_string()), headers)
+                .await
+                .into_response();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn turn_off_is_aborted_when_a_pre_shutdown_hook_fails() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("10-refuse");
+        std::fs::write(&hook, "#!/bin/sh\necho backup running\nexit 1\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let options = ClientOptions {
+            hooks: HookConfig {
+                pre_shutdown_dir: Some(dir.path().to_path_buf()),
+                ..HookConfig::default()
+            },
+            ..ClientOptions::default()
+        };
+        let response = turn_off_machine(State(options), Query(TurnOffParams::default()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body to be readable");
+        let json: serde_json::Value = serde_json::from_slice(&body).expect("valid json");
+        assert_eq!(json["hooks"][0]["name"], "10-refuse");
+        assert_eq!(json["hooks"][0]["stdout"], "backup running\n");
+    }
+
+    #[tokio::test]
+    async fn turn_off_rejects_unsupported_actions() {
+        let params = TurnOffParams {
+            action: Some("reboot".to_string()),
+        };
+        let response = turn_off_machine(State(ClientOptions::default()), Query(params))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn status_returns_system_snapshot() {
+        let response = status().await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body to be readable");
+        let json: serde_json::Value = serde_json::from_slice(&body).expect("valid json");
+        assert_eq!(json["agent_version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["uptime_secs"].is_u64());
+        assert!(json["mac_addresses"].is_array());
+    }
 }
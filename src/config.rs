@@ -33,6 +33,14 @@ pub struct Config {
     /// Health check configuration
     #[serde(default)]
     pub health: HealthConfig,
+
+    /// Shutdown verification and retries
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+
+    /// Authentication of the web interface and API
+    #[serde(default)]
+    pub auth: AuthConfig,
 }
 
 impl Config {
@@ -63,6 +71,18 @@ pub struct ServerConfig {
     /// HTTP health check timeout in seconds (default: 5)
     #[serde(default = "default_health_timeout_secs")]
     pub health_timeout_secs: u64,
+
+    /// Serve the web interface and the client agent over https (default: false)
+    #[serde(default)]
+    pub tls_enabled: bool,
+
+    /// PEM certificate, generated self-signed on first run if missing (default: "wakezilla-cert.pem")
+    #[serde(default = "default_tls_cert_path")]
+    pub tls_cert_path: String,
+
+    /// PEM private key, generated on first run if missing (default: "wakezilla-key.pem")
+    #[serde(default = "default_tls_key_path")]
+    pub tls_key_path: String,
 }
 
 impl Default for ServerConfig {
@@ -71,6 +91,9 @@ impl Default for ServerConfig {
             proxy_port: default_proxy_port(),
             client_port: default_client_port(),
             health_timeout_secs: default_health_timeout_secs(),
+            tls_enabled: false,
+            tls_cert_path: default_tls_cert_path(),
+            tls_key_path: default_tls_key_path(),
         }
     }
 }
@@ -131,6 +154,36 @@ pub struct NetworkConfig {
     /// Network read timeout in seconds (default: 2)
     #[serde(default = "default_network_read_timeout_secs")]
     pub read_timeout_secs: u64,
+
+    /// How long hostnames of machines are cached after a lookup in seconds (default: 60)
+    #[serde(default = "default_network_dns_ttl_secs")]
+    pub dns_ttl_secs: u64,
+
+    /// Interval of background scans matching discovered MACs against known machines in
+    /// seconds, 0 only follows scans run through the API (default: 600)
+    #[serde(default = "default_network_discovery_interval_secs")]
+    pub discovery_interval_secs: u64,
+
+    /// Interface scanned in the background (default: picked like for API scans)
+    #[serde(default)]
+    pub discovery_interface: Option<String>,
+
+    /// What to do when a known machine shows up at a new address (default: update)
+    #[serde(default)]
+    pub ip_drift: IpDriftMode,
+}
+
+/// Handling of machines seen at a new address by neighbor discovery
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpDriftMode {
+    /// Change the machine's address and restart its forwarders
+    #[default]
+    Update,
+    /// Keep the address and record the new one for an admin to accept
+    Propose,
+    /// Don't look for address changes
+    Off,
 }
 
 impl Default for NetworkConfig {
@@ -138,6 +191,10 @@ impl Default for NetworkConfig {
         Self {
             scan_duration_secs: default_network_scan_duration_secs(),
             read_timeout_secs: default_network_read_timeout_secs(),
+            dns_ttl_secs: default_network_dns_ttl_secs(),
+            discovery_interval_secs: default_network_discovery_interval_secs(),
+            discovery_interface: None,
+            ip_drift: IpDriftMode::default(),
         }
     }
 }
@@ -148,12 +205,78 @@ pub struct StorageConfig {
     /// Path to the machines database file (default: "machines.json")
     #[serde(default = "default_machines_db_path")]
     pub machines_db_path: String,
+
+    /// Path to the users and API tokens database (default: "users.json" next to the machines database)
+    #[serde(default)]
+    pub users_db_path: Option<String>,
+
+    /// Path to the event history (default: "events.jsonl" next to the machines database)
+    #[serde(default)]
+    pub events_log_path: Option<String>,
+
+    /// Size in bytes after which the event history is rotated, 0 keeps no history (default: 10 MiB)
+    #[serde(default = "default_events_log_max_bytes")]
+    pub events_log_max_bytes: u64,
+
+    /// Number of rotated event history files kept (default: 5)
+    #[serde(default = "default_events_log_keep")]
+    pub events_log_keep: usize,
+
+    /// Directory for the client agent's own state (default: `$STATE_DIRECTORY` under
+    /// systemd, otherwise `$XDG_STATE_HOME/wakezilla` or `~/.local/state/wakezilla`)
+    #[serde(default)]
+    pub agent_state_dir: Option<String>,
 }
 
 impl Default for StorageConfig {
     fn default() -> Self {
         Self {
             machines_db_path: default_machines_db_path(),
+            users_db_path: None,
+            events_log_path: None,
+            events_log_max_bytes: default_events_log_max_bytes(),
+            events_log_keep: default_events_log_keep(),
+            agent_state_dir: None,
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Users database path, defaulting to `users.json` in the machines database's directory
+    pub fn users_db_path(&self) -> std::path::PathBuf {
+        match &self.users_db_path {
+            Some(path) => path.into(),
+            None => std::path::Path::new(&self.machines_db_path).with_file_name("users.json"),
+        }
+    }
+
+    /// Event history path, defaulting to `events.jsonl` in the machines database's directory
+    pub fn events_log_path(&self) -> std::path::PathBuf {
+        match &self.events_log_path {
+            Some(path) => path.into(),
+            None => std::path::Path::new(&self.machines_db_path).with_file_name("events.jsonl"),
+        }
+    }
+
+    /// Client agent state directory, which unlike the working directory of a service is
+    /// writable and survives reboots
+    pub fn agent_state_dir(&self) -> std::path::PathBuf {
+        if let Some(dir) = &self.agent_state_dir {
+            return dir.into();
+        }
+        // systemd's StateDirectory= may list several directories separated by colons
+        if let Some(dir) = std::env::var_os("STATE_DIRECTORY")
+            .and_then(|dirs| std::env::split_paths(&dirs).next())
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            return dir;
+        }
+        if let Some(dir) = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+            return std::path::PathBuf::from(dir).join("wakezilla");
+        }
+        match std::env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+            Some(home) => std::path::PathBuf::from(home).join(".local/state/wakezilla"),
+            None => std::path::PathBuf::from("/var/lib/wakezilla"),
         }
     }
 }
@@ -194,6 +317,76 @@ impl Default for HealthConfig {
     }
 }
 
+/// Shutdown verification configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownConfig {
+    /// Power-off requests sent before a shutdown counts as failed, the first with the
+    /// machine's backend and the others with its fallback (default: 3)
+    #[serde(default = "default_shutdown_attempts")]
+    pub attempts: u32,
+
+    /// Time in seconds a machine has to go dark after each request (default: 120)
+    #[serde(default = "default_shutdown_verify_timeout_secs")]
+    pub verify_timeout_secs: u64,
+
+    /// Interval in seconds between reachability checks while verifying (default: 5)
+    #[serde(default = "default_shutdown_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+
+    /// Pause in seconds before the second attempt, doubled for every further one (default: 30)
+    #[serde(default = "default_shutdown_backoff_secs")]
+    pub backoff_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            attempts: default_shutdown_attempts(),
+            verify_timeout_secs: default_shutdown_verify_timeout_secs(),
+            poll_interval_secs: default_shutdown_poll_interval_secs(),
+            backoff_secs: default_shutdown_backoff_secs(),
+        }
+    }
+}
+
+/// Authentication configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Require a login or API token for the API (default: true)
+    #[serde(default = "default_auth_enabled")]
+    pub enabled: bool,
+
+    /// Lifetime of a login session in seconds (default: 604800, one week)
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+
+    /// Comma separated origins allowed to call the API from a browser, e.g.
+    /// "http://localhost:8080" for the trunk dev server (default: none, same origin only)
+    #[serde(default)]
+    pub cors_allowed_origins: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_auth_enabled(),
+            session_ttl_secs: default_session_ttl_secs(),
+            cors_allowed_origins: String::new(),
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Parsed `cors_allowed_origins`
+    pub fn cors_origins(&self) -> Vec<String> {
+        self.cors_allowed_origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect()
+    }
+}
+
 // Default value functions for serde
 
 fn default_proxy_port() -> u16 {
@@ -205,6 +398,12 @@ fn default_client_port() -> u16 {
 fn default_health_timeout_secs() -> u64 {
     5
 }
+fn default_tls_cert_path() -> String {
+    "wakezilla-cert.pem".into()
+}
+fn default_tls_key_path() -> String {
+    "wakezilla-key.pem".into()
+}
 fn default_wol_port() -> u16 {
     9
 }
@@ -232,9 +431,23 @@ fn default_network_scan_duration_secs() -> u64 {
 fn default_network_read_timeout_secs() -> u64 {
     2
 }
+fn default_network_dns_ttl_secs() -> u64 {
+    60
+}
+fn default_network_discovery_interval_secs() -> u64 {
+    600
+}
 fn default_machines_db_path() -> String {
     DEFAULT_MACHINES_DB_PATH.into()
 }
+fn default_events_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_events_log_keep() -> usize {
+    5
+}
+
 fn default_health_check_interval_ms() -> u64 {
     30000
 }
@@ -250,6 +463,24 @@ fn default_system_shutdown_sleep_secs() -> u64 {
 fn default_rate_limit_sample_interval_secs() -> u64 {
     1
 }
+fn default_shutdown_attempts() -> u32 {
+    3
+}
+fn default_shutdown_verify_timeout_secs() -> u64 {
+    120
+}
+fn default_shutdown_poll_interval_secs() -> u64 {
+    5
+}
+fn default_shutdown_backoff_secs() -> u64 {
+    30
+}
+fn default_auth_enabled() -> bool {
+    true
+}
+fn default_session_ttl_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
 
 /// Convenience functions to get commonly used values
 #[allow(dead_code)]
@@ -282,11 +513,32 @@ impl Config {
         std::time::Duration::from_secs(self.network.read_timeout_secs)
     }
 
+    /// Get hostname cache lifetime as Duration
+    pub fn dns_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.network.dns_ttl_secs)
+    }
+
+    /// Get background discovery interval as Duration, `None` when disabled
+    pub fn discovery_interval(&self) -> Option<std::time::Duration> {
+        (self.network.discovery_interval_secs > 0)
+            .then(|| std::time::Duration::from_secs(self.network.discovery_interval_secs))
+    }
+
     /// Get health check interval as Duration
     pub fn health_check_interval(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.health.check_interval_ms)
     }
 
+    /// Get how shutdowns are verified and retried
+    pub fn shutdown_policy(&self) -> crate::power::ShutdownPolicy {
+        crate::power::ShutdownPolicy {
+            attempts: self.shutdown.attempts.max(1),
+            verify_timeout: std::time::Duration::from_secs(self.shutdown.verify_timeout_secs),
+            poll_interval: std::time::Duration::from_secs(self.shutdown.poll_interval_secs.max(1)),
+            backoff: std::time::Duration::from_secs(self.shutdown.backoff_secs),
+        }
+    }
+
     /// Get system shutdown sleep duration as Duration
     pub fn system_shutdown_sleep_duration(&self) -> std::time::Duration {
         std::time::Duration::from_secs(self.health.system_shutdown_sleep_secs)
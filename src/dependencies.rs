@@ -0,0 +1,239 @@
+//! Machine dependencies.
+//!
+//! A machine can list the IDs or MACs of machines it needs in `depends_on`, e.g. the NAS a
+//! media server mounts shares from or the VM host of a guest. Waking it wakes its
+//! dependencies first and waits for them to come up, and the inactivity monitor leaves a
+//! dependency on while anything that depends on it is online. The graph is checked for
+//! unknown machines and cycles whenever machines are saved.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::events::{Cause, EventKind};
+use crate::forward::TurnOffLimiter;
+use crate::machine_state::{PowerState, StateTracker};
+use crate::power;
+use crate::web::{find_machine as find, Machine};
+
+/// How long a dependency may take to come up after it was woken
+const READY_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// How often a waking dependency is probed
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Check that every dependency is a configured machine and that there are no cycles.
+pub fn validate_dependencies(machines: &[Machine]) -> Result<()> {
+    for machine in machines {
+        for mac in &machine.depends_on {
+            if find(machines, mac).is_none() {
+                bail!("{} depends on unknown machine {}", machine.name, mac);
+            }
+        }
+    }
+    // Depth-first search keeping the current path, a machine seen twice on it closes a cycle
+    fn visit<'a>(
+        machines: &'a [Machine],
+        machine: &'a Machine,
+        path: &mut Vec<&'a Machine>,
+        done: &mut HashSet<&'a str>,
+    ) -> Result<()> {
+        if done.contains(machine.mac.as_str()) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|m| m.mac == machine.mac) {
+            let names: Vec<&str> = path[start..]
+                .iter()
+                .chain(std::iter::once(&machine))
+                .map(|m| m.name.as_str())
+                .collect();
+            bail!("dependency cycle: {}", names.join(" -> "));
+        }
+        path.push(machine);
+        for mac in &machine.depends_on {
+            let dependency =
+                find(machines, mac).ok_or_else(|| anyhow!("unknown machine {}", mac))?;
+            visit(machines, dependency, path, done)?;
+        }
+        path.pop();
+        done.insert(machine.mac.as_str());
+        Ok(())
+    }
+    let mut done = HashSet::new();
+    for machine in machines {
+        visit(machines, machine, &mut Vec::new(), &mut done)?;
+    }
+    Ok(())
+}
+
+/// Direct and indirect dependencies of `machine`, each after the ones it depends on.
+pub fn wake_order<'a>(machines: &'a [Machine], machine: &Machine) -> Vec<&'a Machine> {
+    fn visit<'a>(
+        machines: &'a [Machine],
+        machine: &Machine,
+        seen: &mut HashSet<String>,
+        order: &mut Vec<&'a Machine>,
+    ) {
+        for mac in &machine.depends_on {
+            let Some(dependency) = find(machines, mac) else {
+                continue;
+            };
+            if seen.insert(dependency.mac.clone()) {
+                visit(machines, dependency, seen, order);
+                order.push(dependency);
+            }
+        }
+    }
+    let mut seen = HashSet::from([machine.mac.clone()]);
+    let mut order = Vec::new();
+    visit(machines, machine, &mut seen, &mut order);
+    order
+}
+
+/// MACs of machines that must stay on because something depending on them is online or
+/// waking.
+pub fn held_by_dependents(machines: &[Machine], states: &StateTracker) -> HashSet<String> {
+    machines
+        .iter()
+        .filter(|machine| {
+            !machine.depends_on.is_empty()
+                && matches!(
+                    states.get(&machine.mac).state,
+                    PowerState::Online | PowerState::Waking
+                )
+        })
+        .flat_map(|machine| wake_order(machines, machine))
+        .map(|dependency| dependency.mac.clone())
+        .collect()
+}
+
+/// Wake the dependencies of `machine` that are down, in order, waiting for each to come up.
+pub async fn wake_dependencies(
+    limiter: &TurnOffLimiter,
+    machines: &[Machine],
+    machine: &Machine,
+    cause: &Cause,
+) -> Result<()> {
+    for dependency in wake_order(machines, machine) {
+        if power::is_host_up(dependency).await == Some(true) {
+            continue;
+        }
+        let cause = Cause {
+            actor: cause.actor.clone(),
+            reason: Some(format!("dependency of {}", machine.name)),
+        };
+        info!(
+            "Waking {} ({}) before its dependent {}",
+            dependency.name, dependency.mac, machine.name
+        );
+        if let Err(e) = power::power_on(dependency, 9).await {
+            limiter.events().emit_caused(
+                &dependency.mac,
+                EventKind::WakeFailed {
+                    error: format!("{:#}", e),
+                },
+                &cause,
+            );
+            return Err(e.context(format!("failed to wake dependency {}", dependency.name)));
+        }
+        limiter.mark_woken(&dependency.mac);
+        limiter
+            .events()
+            .emit_caused(&dependency.mac, EventKind::WakeRequested, &cause);
+
+        let deadline = tokio::time::Instant::now() + READY_TIMEOUT;
+        loop {
+            match power::is_host_up(dependency).await {
+                Some(true) => break,
+                None => {
+                    warn!(
+                        "Dependency {} has no port to probe, not waiting for it",
+                        dependency.name
+                    );
+                    break;
+                }
+                Some(false) if tokio::time::Instant::now() >= deadline => bail!(
+                    "dependency {} did not come up within {}s",
+                    dependency.name,
+                    READY_TIMEOUT.as_secs()
+                ),
+                Some(false) => tokio::time::sleep(READY_POLL_INTERVAL).await,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::test_support::machine;
+
+    fn named(mac: &str, name: &str, depends_on: &[&str]) -> Machine {
+        let mut m = machine(mac);
+        m.name = name.to_string();
+        m.depends_on = depends_on.iter().map(|mac| mac.to_string()).collect();
+        m
+    }
+
+    #[test]
+    fn dependencies_are_woken_before_their_dependents() {
+        let machines = vec![
+            named("AA:AA:AA:AA:AA:01", "guest", &["AA:AA:AA:AA:AA:02"]),
+            named(
+                "AA:AA:AA:AA:AA:02",
+                "vm host",
+                &["AA:AA:AA:AA:AA:03", "aa:aa:aa:aa:aa:04"],
+            ),
+            named("AA:AA:AA:AA:AA:03", "nas", &[]),
+            named("AA:AA:AA:AA:AA:04", "switch", &["AA:AA:AA:AA:AA:03"]),
+        ];
+        validate_dependencies(&machines).unwrap();
+        let order: Vec<&str> = wake_order(&machines, &machines[0])
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(order, vec!["nas", "switch", "vm host"]);
+        assert!(wake_order(&machines, &machines[2]).is_empty());
+    }
+
+    #[test]
+    fn unknown_dependencies_and_cycles_are_rejected() {
+        let machines = vec![named("AA:AA:AA:AA:AA:01", "guest", &["AA:AA:AA:AA:AA:09"])];
+        let error = validate_dependencies(&machines).unwrap_err().to_string();
+        assert!(error.contains("unknown machine"), "{error}");
+
+        let machines = vec![
+            named("AA:AA:AA:AA:AA:01", "a", &["AA:AA:AA:AA:AA:02"]),
+            named("AA:AA:AA:AA:AA:02", "b", &["AA:AA:AA:AA:AA:03"]),
+            named("AA:AA:AA:AA:AA:03", "c", &["AA:AA:AA:AA:AA:01"]),
+        ];
+        let error = validate_dependencies(&machines).unwrap_err().to_string();
+        assert_eq!(error, "dependency cycle: a -> b -> c -> a");
+
+        let machines = vec![named("AA:AA:AA:AA:AA:01", "a", &["AA:AA:AA:AA:AA:01"])];
+        assert!(validate_dependencies(&machines).is_err());
+    }
+
+    #[test]
+    fn online_dependents_hold_their_dependencies() {
+        let machines = vec![
+            named("AA:AA:AA:AA:AA:01", "media", &["AA:AA:AA:AA:AA:02"]),
+            named("AA:AA:AA:AA:AA:02", "nas", &["AA:AA:AA:AA:AA:03"]),
+            named("AA:AA:AA:AA:AA:03", "storage", &[]),
+        ];
+        let states = StateTracker::new(EventBus::new());
+        assert!(held_by_dependents(&machines, &states).is_empty());
+
+        states.set("AA:AA:AA:AA:AA:01", PowerState::Online);
+        let held = held_by_dependents(&machines, &states);
+        assert_eq!(
+            held,
+            HashSet::from([
+                "AA:AA:AA:AA:AA:02".to_string(),
+                "AA:AA:AA:AA:AA:03".to_string()
+            ])
+        );
+    }
+}
@@ -0,0 +1,534 @@
+//! Wake-on-LAN settings of the local network interfaces.
+//!
+//! On Linux the settings are read and changed through the ethtool generic netlink
+//! family. Unlike the legacy `SIOCETHTOOL` ioctl, reading over netlink does not need
+//! `CAP_NET_ADMIN`, so an unprivileged agent can still report whether WOL is armed.
+//! Other platforms report no interfaces and refuse changes.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// `ethtool` letters for the WoL mode bits, indexed by bit position.
+const WOL_MODE_LETTERS: [char; 8] = ['p', 'u', 'm', 'b', 'a', 'g', 's', 'f'];
+
+/// Magic packet mode (`WAKE_MAGIC`, ethtool letter `g`)
+const WAKE_MAGIC: u32 = 1 << 5;
+
+/// Wake-on-LAN state of a single network interface.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WolSettings {
+    pub interface: String,
+    pub mac: Option<String>,
+    /// Supported modes in `ethtool` notation, e.g. "pumbg"
+    pub supported: String,
+    /// Enabled modes in `ethtool` notation, e.g. "g" ("d" when disabled)
+    pub enabled: String,
+    /// Value of `/sys/class/net/<iface>/device/power/wakeup`, if the device exposes it
+    pub device_wakeup: Option<bool>,
+}
+
+impl WolSettings {
+    /// Whether a magic packet would wake the machine through this interface.
+    pub fn magic_packet_armed(&self) -> bool {
+        self.enabled.contains('g') && self.device_wakeup != Some(false)
+    }
+}
+
+fn modes_to_string(modes: u32) -> String {
+    let letters: String = WOL_MODE_LETTERS
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| modes & (1 << bit) != 0)
+        .map(|(_, letter)| *letter)
+        .collect();
+    if letters.is_empty() {
+        "d".to_string()
+    } else {
+        letters
+    }
+}
+
+/// Wake-on-LAN settings of every physical interface.
+pub fn list_wol_settings() -> Vec<WolSettings> {
+    physical_interfaces()
+        .into_iter()
+        .filter_map(|iface| match get_wol_settings(&iface) {
+            Ok(settings) => Some(settings),
+            Err(e) => {
+                tracing::debug!("Failed to read WoL settings for {}: {}", iface, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn sysfs_path(iface: &str) -> std::path::PathBuf {
+    std::path::Path::new("/sys/class/net").join(iface)
+}
+
+/// Interfaces backed by a device (skips loopback, bridges, tunnels and other virtual links).
+#[cfg(target_os = "linux")]
+fn physical_interfaces() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/sys/class/net") else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("device").exists())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names
+}
+
+#[cfg(not(target_os = "linux"))]
+fn physical_interfaces() -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn validate_interface_name(iface: &str) -> io::Result<()> {
+    if iface.is_empty() || iface.contains(['/', '\0']) || iface == "." || iface == ".." {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid interface name '{}'", iface),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn no_such_device(err: io::Error, iface: &str) -> io::Error {
+    if err.raw_os_error() == Some(libc::ENODEV) {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such interface '{}'", iface),
+        )
+    } else {
+        err
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_device_wakeup(iface: &str) -> Option<bool> {
+    let value = std::fs::read_to_string(sysfs_path(iface).join("device/power/wakeup")).ok()?;
+    match value.trim() {
+        "enabled" => Some(true),
+        "disabled" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_wol_settings(iface: &str) -> io::Result<WolSettings> {
+    validate_interface_name(iface)?;
+    let (supported, enabled) = netlink::get_wol(iface).map_err(|e| no_such_device(e, iface))?;
+    let mac = std::fs::read_to_string(sysfs_path(iface).join("address"))
+        .ok()
+        .map(|mac| mac.trim().to_string());
+
+    Ok(WolSettings {
+        interface: iface.to_string(),
+        mac,
+        supported: modes_to_string(supported),
+        enabled: modes_to_string(enabled),
+        device_wakeup: read_device_wakeup(iface),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_wol_settings(_iface: &str) -> io::Result<WolSettings> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading Wake-on-LAN settings is only supported on Linux",
+    ))
+}
+
+/// Enable magic packet wake (`ethtool -s <iface> wol g`) and allow the device to wake
+/// the system. Requires `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn enable_magic_packet(iface: &str) -> io::Result<WolSettings> {
+    validate_interface_name(iface)?;
+    netlink::set_wol_modes(iface, WAKE_MAGIC, WAKE_MAGIC).map_err(|e| no_such_device(e, iface))?;
+
+    let wakeup_path = sysfs_path(iface).join("device/power/wakeup");
+    if read_device_wakeup(iface) == Some(false) {
+        std::fs::write(&wakeup_path, "enabled")?;
+    }
+
+    get_wol_settings(iface)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_magic_packet(_iface: &str) -> io::Result<WolSettings> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "changing Wake-on-LAN settings is only supported on Linux",
+    ))
+}
+
+/// Minimal generic netlink client for the ethtool WOL_GET / WOL_SET messages.
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const NLMSG_ERROR: u16 = 2;
+    const NLMSG_DONE: u16 = 3;
+    const NLM_F_REQUEST: u16 = 1;
+    const NLM_F_ACK: u16 = 4;
+    const NLA_F_NESTED: u16 = 1 << 15;
+    const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | (1 << 14));
+    const NLMSG_HDRLEN: usize = 16;
+    const GENL_HDRLEN: usize = 4;
+
+    const GENL_ID_CTRL: u16 = 0x10;
+    const CTRL_CMD_GETFAMILY: u8 = 3;
+    const CTRL_ATTR_FAMILY_ID: u16 = 1;
+    const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+    const ETHTOOL_GENL_VERSION: u8 = 1;
+    const ETHTOOL_MSG_WOL_GET: u8 = 9;
+    const ETHTOOL_MSG_WOL_SET: u8 = 10;
+    const ETHTOOL_A_HEADER_DEV_NAME: u16 = 2;
+    const ETHTOOL_A_HEADER_FLAGS: u16 = 3;
+    const ETHTOOL_FLAG_COMPACT_BITSETS: u32 = 1;
+    const ETHTOOL_A_BITSET_SIZE: u16 = 2;
+    const ETHTOOL_A_BITSET_VALUE: u16 = 4;
+    const ETHTOOL_A_BITSET_MASK: u16 = 5;
+    const ETHTOOL_A_WOL_HEADER: u16 = 1;
+    const ETHTOOL_A_WOL_MODES: u16 = 2;
+    const WOL_MODE_COUNT: u32 = 8;
+
+    /// Returns the `(supported, enabled)` WoL mode bitmaps of `iface`.
+    pub(super) fn get_wol(iface: &str) -> io::Result<(u32, u32)> {
+        let mut socket = GenlSocket::open()?;
+        let family = socket.resolve_family("ethtool")?;
+
+        let mut attrs = Vec::new();
+        push_nested(&mut attrs, ETHTOOL_A_WOL_HEADER, |header| {
+            push_attr(header, ETHTOOL_A_HEADER_DEV_NAME, &c_string(iface));
+            push_attr(
+                header,
+                ETHTOOL_A_HEADER_FLAGS,
+                &ETHTOOL_FLAG_COMPACT_BITSETS.to_ne_bytes(),
+            );
+        });
+
+        let reply = socket.request(family, ETHTOOL_MSG_WOL_GET, &attrs, 0)?;
+        let modes = find_attr(&reply, ETHTOOL_A_WOL_MODES)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WOL reply without modes"))?;
+        // In a compact reply the mask holds the supported modes and the value the enabled ones
+        let supported = find_attr(modes, ETHTOOL_A_BITSET_MASK)
+            .map(read_u32)
+            .unwrap_or(0);
+        let enabled = find_attr(modes, ETHTOOL_A_BITSET_VALUE)
+            .map(read_u32)
+            .unwrap_or(0);
+        Ok((supported, enabled))
+    }
+
+    /// Set the WoL modes selected by `mask` to the bits in `value`, leaving others untouched.
+    pub(super) fn set_wol_modes(iface: &str, value: u32, mask: u32) -> io::Result<()> {
+        let mut socket = GenlSocket::open()?;
+        let family = socket.resolve_family("ethtool")?;
+
+        let mut attrs = Vec::new();
+        push_nested(&mut attrs, ETHTOOL_A_WOL_HEADER, |header| {
+            push_attr(header, ETHTOOL_A_HEADER_DEV_NAME, &c_string(iface));
+        });
+        push_nested(&mut attrs, ETHTOOL_A_WOL_MODES, |modes| {
+            push_attr(modes, ETHTOOL_A_BITSET_SIZE, &WOL_MODE_COUNT.to_ne_bytes());
+            push_attr(modes, ETHTOOL_A_BITSET_VALUE, &value.to_ne_bytes());
+            push_attr(modes, ETHTOOL_A_BITSET_MASK, &mask.to_ne_bytes());
+        });
+
+        socket.request(family, ETHTOOL_MSG_WOL_SET, &attrs, NLM_F_ACK)?;
+        Ok(())
+    }
+
+    struct GenlSocket {
+        fd: OwnedFd,
+        seq: u32,
+    }
+
+    impl GenlSocket {
+        fn open() -> io::Result<Self> {
+            // SAFETY: plain socket(2) call, the returned descriptor is owned below.
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_GENERIC,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a freshly created descriptor nobody else owns.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let timeout = libc::timeval {
+                tv_sec: 2,
+                tv_usec: 0,
+            };
+            // SAFETY: `timeout` outlives the call and the length matches its type.
+            let rc = unsafe {
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVTIMEO,
+                    &timeout as *const libc::timeval as *const libc::c_void,
+                    std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+                )
+            };
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { fd, seq: 0 })
+        }
+
+        fn resolve_family(&mut self, name: &str) -> io::Result<u16> {
+            let mut attrs = Vec::new();
+            push_attr(&mut attrs, CTRL_ATTR_FAMILY_NAME, &c_string(name));
+            let reply = self.request(GENL_ID_CTRL, CTRL_CMD_GETFAMILY, &attrs, 0)?;
+            find_attr(&reply, CTRL_ATTR_FAMILY_ID)
+                .filter(|id| id.len() >= 2)
+                .map(|id| u16::from_ne_bytes([id[0], id[1]]))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("generic netlink family '{}' not available", name),
+                    )
+                })
+        }
+
+        /// Send a request and return the attributes of the reply (empty for a bare ACK).
+        fn request(
+            &mut self,
+            family: u16,
+            cmd: u8,
+            attrs: &[u8],
+            extra_flags: u16,
+        ) -> io::Result<Vec<u8>> {
+            self.seq = self.seq.wrapping_add(1);
+            let message = build_message(family, NLM_F_REQUEST | extra_flags, self.seq, cmd, attrs);
+
+            // SAFETY: a zeroed sockaddr_nl addresses the kernel (pid 0).
+            let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            // SAFETY: `message` and `addr` are valid for the lengths passed.
+            let sent = unsafe {
+                libc::sendto(
+                    self.fd.as_raw_fd(),
+                    message.as_ptr() as *const libc::c_void,
+                    message.len(),
+                    0,
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut buf = vec![0u8; 16 * 1024];
+            loop {
+                // SAFETY: `buf` is valid for writes of `buf.len()` bytes.
+                let received = unsafe {
+                    libc::recv(
+                        self.fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if received < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if let Some(result) = parse_reply(&buf[..received as usize], family, self.seq) {
+                    return result;
+                }
+            }
+        }
+    }
+
+    fn build_message(family: u16, flags: u16, seq: u32, cmd: u8, attrs: &[u8]) -> Vec<u8> {
+        let len = NLMSG_HDRLEN + GENL_HDRLEN + attrs.len();
+        let mut message = Vec::with_capacity(len);
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&family.to_ne_bytes());
+        message.extend_from_slice(&flags.to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&[cmd, ETHTOOL_GENL_VERSION, 0, 0]);
+        message.extend_from_slice(attrs);
+        message
+    }
+
+    /// Walk the netlink messages in `buf`. Returns `None` when none of them answers `seq`.
+    fn parse_reply(buf: &[u8], family: u16, seq: u32) -> Option<io::Result<Vec<u8>>> {
+        let mut offset = 0;
+        while offset + NLMSG_HDRLEN <= buf.len() {
+            let header = &buf[offset..];
+            let len = read_u32(&header[0..4]) as usize;
+            let msg_type = u16::from_ne_bytes([header[4], header[5]]);
+            let msg_seq = read_u32(&header[8..12]);
+            if len < NLMSG_HDRLEN || offset + len > buf.len() {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink message",
+                )));
+            }
+            let payload = &header[NLMSG_HDRLEN..len];
+
+            if msg_seq == seq {
+                match msg_type {
+                    NLMSG_ERROR if payload.len() >= 4 => {
+                        let errno =
+                            i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                        return Some(if errno == 0 {
+                            Ok(Vec::new())
+                        } else {
+                            Err(io::Error::from_raw_os_error(-errno))
+                        });
+                    }
+                    NLMSG_DONE => return Some(Ok(Vec::new())),
+                    t if t == family && payload.len() >= GENL_HDRLEN => {
+                        return Some(Ok(payload[GENL_HDRLEN..].to_vec()));
+                    }
+                    _ => {}
+                }
+            }
+
+            offset += align(len);
+        }
+        None
+    }
+
+    fn align(len: usize) -> usize {
+        (len + 3) & !3
+    }
+
+    fn c_string(value: &str) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        bytes
+    }
+
+    fn read_u32(bytes: &[u8]) -> u32 {
+        let mut word = [0u8; 4];
+        let n = bytes.len().min(4);
+        word[..n].copy_from_slice(&bytes[..n]);
+        u32::from_ne_bytes(word)
+    }
+
+    fn push_attr(buf: &mut Vec<u8>, attr_type: u16, payload: &[u8]) {
+        let len = 4 + payload.len();
+        buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        buf.extend_from_slice(&attr_type.to_ne_bytes());
+        buf.extend_from_slice(payload);
+        buf.resize(buf.len() + align(len) - len, 0);
+    }
+
+    fn push_nested(buf: &mut Vec<u8>, attr_type: u16, fill: impl FnOnce(&mut Vec<u8>)) {
+        let mut nested = Vec::new();
+        fill(&mut nested);
+        push_attr(buf, attr_type | NLA_F_NESTED, &nested);
+    }
+
+    fn find_attr(attrs: &[u8], wanted: u16) -> Option<&[u8]> {
+        let mut offset = 0;
+        while offset + 4 <= attrs.len() {
+            let len = u16::from_ne_bytes([attrs[offset], attrs[offset + 1]]) as usize;
+            let attr_type = u16::from_ne_bytes([attrs[offset + 2], attrs[offset + 3]]);
+            if len < 4 || offset + len > attrs.len() {
+                return None;
+            }
+            if attr_type & NLA_TYPE_MASK == wanted {
+                return Some(&attrs[offset + 4..offset + len]);
+            }
+            offset += align(len);
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn nested_attributes_round_trip() {
+            let mut attrs = Vec::new();
+            push_nested(&mut attrs, ETHTOOL_A_WOL_HEADER, |header| {
+                push_attr(header, ETHTOOL_A_HEADER_DEV_NAME, &c_string("eth0"));
+            });
+            push_nested(&mut attrs, ETHTOOL_A_WOL_MODES, |modes| {
+                push_attr(modes, ETHTOOL_A_BITSET_VALUE, &0x20u32.to_ne_bytes());
+                push_attr(modes, ETHTOOL_A_BITSET_MASK, &0x2fu32.to_ne_bytes());
+            });
+
+            let modes = find_attr(&attrs, ETHTOOL_A_WOL_MODES).expect("modes attribute");
+            assert_eq!(
+                find_attr(modes, ETHTOOL_A_BITSET_VALUE).map(read_u32),
+                Some(0x20)
+            );
+            assert_eq!(
+                find_attr(modes, ETHTOOL_A_BITSET_MASK).map(read_u32),
+                Some(0x2f)
+            );
+            let header = find_attr(&attrs, ETHTOOL_A_WOL_HEADER).expect("header attribute");
+            assert_eq!(
+                find_attr(header, ETHTOOL_A_HEADER_DEV_NAME),
+                Some(&b"eth0\0"[..])
+            );
+        }
+
+        #[test]
+        fn parse_reply_maps_error_acks() {
+            let mut message = build_message(NLMSG_ERROR, 0, 7, 0, &[]);
+            // Replace the genl header with the errno of the error message
+            message.truncate(NLMSG_HDRLEN);
+            message.extend_from_slice(&(-libc::EPERM).to_ne_bytes());
+            let len = message.len() as u32;
+            message[0..4].copy_from_slice(&len.to_ne_bytes());
+
+            let err = parse_reply(&message, 0x20, 7)
+                .expect("reply for seq 7")
+                .expect_err("EPERM should be reported");
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+            assert!(parse_reply(&message, 0x20, 8).is_none());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_to_string_uses_ethtool_letters() {
+        assert_eq!(modes_to_string(0), "d");
+        assert_eq!(modes_to_string(WAKE_MAGIC), "g");
+        assert_eq!(modes_to_string(0b10_1111), "pumbg");
+    }
+
+    #[test]
+    fn magic_packet_armed_requires_device_wakeup() {
+        let mut settings = WolSettings {
+            interface: "eth0".to_string(),
+            mac: None,
+            supported: "pumbg".to_string(),
+            enabled: "g".to_string(),
+            device_wakeup: None,
+        };
+        assert!(settings.magic_packet_armed());
+        settings.device_wakeup = Some(false);
+        assert!(!settings.magic_packet_armed());
+        settings.device_wakeup = Some(true);
+        settings.enabled = "d".to_string();
+        assert!(!settings.magic_packet_armed());
+    }
+}
@@ -0,0 +1,214 @@
+//! Persistent history of machine events.
+//!
+//! The [`EventLog`] follows the [`EventBus`] and appends every event as a line of JSON to
+//! `events.jsonl` next to the machines database, so who woke, shut down or edited a machine
+//! can be looked up after the fact through `GET /api/events/history`. The file is rotated to
+//! `events.jsonl.1`, `events.jsonl.2`, ... once it grows past the configured size.
+
+use anyhow::{Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::{error, warn};
+
+use crate::config::StorageConfig;
+use crate::events::{Event, EventBus};
+
+pub struct EventLog {
+    /// `None` keeps no history, used by tests
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    keep: usize,
+    /// Serialises appends and rotation
+    lock: Mutex<()>,
+}
+
+impl EventLog {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> Self {
+        Self {
+            path: Some(path.into()),
+            max_bytes,
+            keep,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The configured history, disabled when its size limit is 0.
+    pub fn from_config(storage: &StorageConfig) -> Self {
+        if storage.events_log_max_bytes == 0 {
+            return Self::disabled();
+        }
+        Self::open(
+            storage.events_log_path(),
+            storage.events_log_max_bytes,
+            storage.events_log_keep,
+        )
+    }
+
+    /// Keep no history at all.
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            max_bytes: 0,
+            keep: 0,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Append `event`, rotating the file first when it would grow past the size limit.
+    pub fn append(&self, event: &Event) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate(path)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open event log {}", path.display()))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Shift `events.jsonl.N` to `events.jsonl.N+1`, dropping the oldest file.
+    fn rotate(&self, path: &Path) -> Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+        let _ = fs::remove_file(rotated(path, self.keep));
+        for n in (1..self.keep).rev() {
+            let from = rotated(path, n);
+            if from.exists() {
+                fs::rename(&from, rotated(path, n + 1))?;
+            }
+        }
+        fs::rename(path, rotated(path, 1))?;
+        Ok(())
+    }
+
+    /// Events about `mac` (or all events when `None`) at or after `since`, newest first.
+    pub fn history(&self, mac: Option<&str>, since: Option<u64>) -> Result<Vec<Event>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let _guard = self.lock.lock().unwrap();
+        let files = (1..=self.keep)
+            .rev()
+            .map(|n| rotated(path, n))
+            .chain(std::iter::once(path.clone()));
+
+        let mut events = Vec::new();
+        for file in files {
+            let file = match fs::File::open(&file) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context("Failed to read the event log"),
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                let event: Event = match serde_json::from_str(&line) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Skipping unreadable event log entry: {}", e);
+                        continue;
+                    }
+                };
+                let matches_mac = mac.is_none_or(|mac| {
+                    event
+                        .mac
+                        .as_deref()
+                        .is_some_and(|m| m.eq_ignore_ascii_case(mac))
+                });
+                if matches_mac && since.is_none_or(|since| event.timestamp >= since) {
+                    events.push(event);
+                }
+            }
+        }
+        events.reverse();
+        Ok(events)
+    }
+
+    /// Record every event published on `events` from now on.
+    pub fn start(self: &Arc<Self>, events: &EventBus) -> tokio::task::JoinHandle<()> {
+        let log = self.clone();
+        let mut rx = events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        if let Err(e) = log.append(&event) {
+                            error!("Failed to record event: {:#}", e);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Event log missed {} events", missed)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Cause, EventKind};
+
+    fn event(mac: &str, timestamp: u64) -> Event {
+        Event {
+            timestamp,
+            mac: Some(mac.to_string()),
+            kind: EventKind::WakeRequested,
+            cause: Cause::new("alice", "requested through the API"),
+        }
+    }
+
+    #[test]
+    fn history_filters_by_machine_and_time_across_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        // Roughly two events per file
+        let line_len = serde_json::to_string(&event("AA:BB:CC:DD:EE:FF", 100))
+            .unwrap()
+            .len() as u64;
+        let log = EventLog::open(&path, line_len * 2 + 2, 2);
+
+        for timestamp in 100..108 {
+            let mac = if timestamp % 2 == 0 {
+                "AA:BB:CC:DD:EE:FF"
+            } else {
+                "11:22:33:44:55:66"
+            };
+            log.append(&event(mac, timestamp)).unwrap();
+        }
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+
+        // The oldest file was dropped, the rest is read back newest first
+        let all = log.history(None, None).unwrap();
+        let timestamps: Vec<u64> = all.iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, vec![107, 106, 105, 104, 103, 102]);
+        assert_eq!(all[0].cause.actor.as_deref(), Some("alice"));
+
+        let mine = log.history(Some("aa:bb:cc:dd:ee:ff"), Some(104)).unwrap();
+        let timestamps: Vec<u64> = mine.iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, vec![106, 104]);
+    }
+}
@@ -0,0 +1,302 @@
+//! Machine lifecycle events.
+//!
+//! Background tasks (inactivity monitor, shutdown verification, forwarders) publish events
+//! on an [`EventBus`] held in `AppState`, so API consumers can follow what happened to a
+//! machine. `GET /api/events` streams them to the web interface, and the
+//! [`EventLog`](crate::event_log::EventLog) keeps them on disk as an audit trail.
+
+use crate::machine_state::PowerState;
+use crate::power::SECRET_MASK;
+use crate::scanner::DiscoveredDevice;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+/// Number of events buffered for slow subscribers before they start missing some
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A power-on request was sent
+    WakeRequested,
+    /// The machine came up after a wake request
+    WakeSucceeded,
+    /// The machine didn't come up in time after a wake request
+    WakeFailed {
+        error: String,
+    },
+    /// A power-off request was sent and is being verified
+    ShutdownRequested,
+    /// The machine went dark after a shutdown request
+    ShutdownVerified {
+        attempts: u32,
+    },
+    /// A power-off request was accepted, but the machine has no probe port to confirm it
+    /// went dark
+    ShutdownUnverified,
+    /// The machine was still reachable after every shutdown attempt
+    ShutdownFailed {
+        attempts: u32,
+        error: String,
+    },
+    /// The state tracker saw the machine change power state
+    StateChanged {
+        from: PowerState,
+        to: PowerState,
+    },
+    /// A forwarder started listening on `local_port`
+    ForwarderStarted {
+        local_port: u16,
+        target_port: u16,
+    },
+    /// A forwarder was stopped, e.g. because its machine was edited or deleted
+    ForwarderStopped {
+        local_port: u16,
+        target_port: u16,
+    },
+    ForwarderFailed {
+        local_port: u16,
+        target_port: u16,
+        error: String,
+    },
+    /// A network scan finished; not tied to a machine
+    ScanCompleted {
+        devices: Vec<DiscoveredDevice>,
+    },
+    /// A network scan could not run
+    ScanFailed {
+        error: String,
+    },
+    /// A machine was added, by hand or by an agent registering itself
+    MachineAdded,
+    /// A machine's configuration was edited
+    MachineChanged {
+        changes: Vec<FieldChange>,
+    },
+    MachineDeleted,
+    /// A connection from `client` was dropped instead of waking the machine
+    WakeSuppressed {
+        client: String,
+        reason: String,
+    },
+    /// The inactivity monitor leaves the machine on until `until` (Unix seconds); `None`
+    /// when an override was cancelled
+    KeepAwakeChanged {
+        until: Option<u64>,
+    },
+    /// Neighbor discovery saw the interface with MAC `interface` at a new address, which
+    /// the machine now uses
+    IpChanged {
+        interface: String,
+        from: String,
+        to: String,
+    },
+    /// Like `IpChanged`, but the new address waits for an admin to accept it
+    IpChangeProposed {
+        interface: String,
+        from: String,
+        to: String,
+    },
+}
+
+/// One top-level field of a machine's configuration that was edited
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+}
+
+/// Keys whose values are credentials and never end up in the event log
+const REDACTED_KEYS: &[&str] = &["password", "headers"];
+
+/// Fields of `after` that differ from `before`, compared on their JSON representation.
+/// Credentials nested in power backends are redacted, a changed password still shows up.
+pub fn diff<T: Serialize>(before: &T, after: &T) -> Vec<FieldChange> {
+    let to_map = |value: &T| match serde_json::to_value(value) {
+        Ok(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    let (before, mut after) = (to_map(before), to_map(after));
+    let mut changes: Vec<(String, serde_json::Value, serde_json::Value)> = before
+        .into_iter()
+        .filter_map(|(field, before)| {
+            let after = after.remove(&field).unwrap_or(serde_json::Value::Null);
+            (before != after).then_some((field, before, after))
+        })
+        .collect();
+    changes.extend(
+        after
+            .into_iter()
+            .map(|(field, after)| (field, serde_json::Value::Null, after)),
+    );
+    changes
+        .into_iter()
+        .map(|(field, mut before, mut after)| {
+            redact(&mut before);
+            redact(&mut after);
+            FieldChange {
+                field,
+                before,
+                after,
+            }
+        })
+        .collect()
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    *value = serde_json::Value::String(SECRET_MASK.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Who or what caused an event, and why
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Cause {
+    /// User name, `client <ip>`, `agent <ip>` or the background task responsible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Cause {
+    pub fn new(actor: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            actor: Some(actor.into()),
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Event {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    /// Machine the event is about, `None` for events about the whole network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+    #[serde(flatten)]
+    pub cause: Cause,
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    /// Publish an event that follows from an earlier one, e.g. a state change.
+    pub fn emit(&self, mac: &str, kind: EventKind) {
+        self.send(Some(mac.to_string()), kind, Cause::default());
+    }
+
+    /// Publish an event about `mac` that was triggered by `cause`.
+    pub fn emit_caused(&self, mac: &str, kind: EventKind, cause: &Cause) {
+        self.send(Some(mac.to_string()), kind, cause.clone());
+    }
+
+    /// Publish an event that isn't about a single machine.
+    pub fn emit_global(&self, kind: EventKind, cause: &Cause) {
+        self.send(None, kind, cause.clone());
+    }
+
+    fn send(&self, mac: Option<String>, kind: EventKind, cause: Cause) {
+        let event = Event {
+            timestamp: unix_now(),
+            mac,
+            kind,
+            cause,
+        };
+        let subject = event.mac.as_deref().unwrap_or("network");
+        match &event.kind {
+            EventKind::ShutdownFailed { attempts, error } => error!(
+                "Shutdown of {} failed after {} attempt(s): {}",
+                subject, attempts, error
+            ),
+            EventKind::ScanCompleted { devices } => {
+                info!("Network scan found {} device(s)", devices.len())
+            }
+            EventKind::MachineChanged { changes } => info!(
+                "{} edited by {}: {}",
+                subject,
+                event.cause.actor.as_deref().unwrap_or("unknown"),
+                changes
+                    .iter()
+                    .map(|change| change.field.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            kind => info!("Event for {}: {:?}", subject, kind),
+        }
+        // Nobody listening is fine, the event has been logged
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// Current Unix timestamp in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::power::{PowerOnBackend, RedfishConfig};
+    use crate::test_support::machine;
+
+    #[test]
+    fn diff_lists_changed_fields_and_hides_credentials() {
+        let before = machine("AA:BB:CC:DD:EE:FF");
+        let mut after = before.clone();
+        after.name = "Renamed".to_string();
+        after.power_on = PowerOnBackend::Redfish(RedfishConfig {
+            url: "https://10.0.0.20".to_string(),
+            username: "root".to_string(),
+            password: "calvin".to_string(),
+            system_id: None,
+            off_reset_type: "ForceOff".to_string(),
+            accept_invalid_certs: false,
+        });
+
+        let changes = diff(&before, &after);
+        let fields: Vec<&str> = changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(fields, vec!["name", "power_on"]);
+        assert_eq!(changes[0].after, "Renamed");
+        assert_eq!(changes[1].after["password"], SECRET_MASK);
+        assert_eq!(changes[1].after["username"], "root");
+        assert!(diff(&before, &before).is_empty());
+    }
+}
@@ -1,52 +1,168 @@
 use crate::connection_pool::ConnectionPool;
-use crate::{web::Machine, wol};
+use crate::events::{unix_now, Cause, EventBus, EventKind};
+use crate::hooks::HookConfig;
+use crate::machine_state::StateTracker;
+use crate::metrics::Metrics;
+use crate::{dependencies, power, resolver, tls, web::Machine, wol};
 use anyhow::{Context, Result};
-use std::collections::{HashMap, VecDeque};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::copy_bidirectional;
 use tokio::net::TcpListener;
-use tokio::sync::watch;
+use tokio::sync::{watch, RwLock};
 use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
-fn turn_off_url(remote_ip: &str, turn_off_port: u16) -> String {
-    format!("http://{}:{}/machines/turn-off", remote_ip, turn_off_port)
+/// How often a machine's suppressed wakes are logged as warnings and events
+const SUPPRESSION_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a turn-off request may take; the agent runs its pre-shutdown hooks before
+/// answering, so this stays above `hooks::DEFAULT_TOTAL_BUDGET`
+const TURN_OFF_REQUEST_TIMEOUT: Duration = Duration::from_secs(180);
+
+fn turn_off_url(scheme: &str, remote_ip: &str, turn_off_port: u16) -> String {
+    format!(
+        "{}://{}:{}/machines/turn-off",
+        scheme, remote_ip, turn_off_port
+    )
 }
 
 struct MachineConfig {
     request_times: VecDeque<Instant>,
     max_requests: usize,
     window: Duration,
-    turn_off_port: u16,
-    mac: String,
+    machine: Machine,
     triggered: AtomicBool,
     last_request: Instant,
 }
 
+/// Every configured machine and its power state, for dependency chains
+#[derive(Clone)]
+struct Fleet {
+    machines: Arc<RwLock<Vec<Machine>>>,
+    states: StateTracker,
+}
+
 #[derive(Clone)]
 pub struct TurnOffLimiter {
     machines: Arc<Mutex<HashMap<Ipv4Addr, MachineConfig>>>,
+    events: EventBus,
+    metrics: Arc<Metrics>,
+    /// Machines (by MAC) the inactivity monitor leaves alone until the given Unix time
+    keep_awake: Arc<Mutex<HashMap<String, u64>>>,
+    /// When each machine (by MAC) was last woken, for the policies' minimum uptime
+    woken_at: Arc<Mutex<HashMap<String, Instant>>>,
+    /// When the inactivity monitor last shut each machine (by MAC) down, for wake cooldowns
+    shut_down_at: Arc<Mutex<HashMap<String, Instant>>>,
+    /// When a suppressed wake was last reported for each machine (by MAC)
+    suppression_reported: Arc<Mutex<HashMap<String, Instant>>>,
+    shutdown_policy: power::ShutdownPolicy,
+    /// Hooks of the agent running inside the proxy in all-in-one mode, for the `local` backend
+    local_agent: Option<HookConfig>,
+    /// `None` ignores machine dependencies, used by tests
+    fleet: Option<Fleet>,
+}
+
+impl Default for TurnOffLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TurnOffLimiter {
     pub fn new() -> Self {
+        Self::with_events(EventBus::new())
+    }
+
+    pub fn with_events(events: EventBus) -> Self {
         Self {
             machines: Arc::new(Mutex::new(HashMap::new())),
+            events,
+            metrics: Arc::new(Metrics::new()),
+            keep_awake: Arc::new(Mutex::new(HashMap::new())),
+            woken_at: Arc::new(Mutex::new(HashMap::new())),
+            shut_down_at: Arc::new(Mutex::new(HashMap::new())),
+            suppression_reported: Arc::new(Mutex::new(HashMap::new())),
+            shutdown_policy: power::ShutdownPolicy::default(),
+            local_agent: None,
+            fleet: None,
+        }
+    }
+
+    /// Count forwarded connections and bytes in `metrics`.
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> Self {
+        Self { metrics, ..self }
+    }
+
+    /// Retry and verify shutdowns according to `shutdown_policy`.
+    pub fn with_shutdown_policy(self, shutdown_policy: power::ShutdownPolicy) -> Self {
+        Self {
+            shutdown_policy,
+            ..self
         }
     }
 
-    pub fn initialize_machine(&self, machine: &Machine, turn_off_port: u16) {
+    pub fn shutdown_policy(&self) -> &power::ShutdownPolicy {
+        &self.shutdown_policy
+    }
+
+    /// Shut the proxy host down in-process with `hooks` for the `local` backend.
+    pub fn with_local_agent(self, local_agent: Option<HookConfig>) -> Self {
+        Self {
+            local_agent,
+            ..self
+        }
+    }
+
+    pub fn local_agent(&self) -> Option<&HookConfig> {
+        self.local_agent.as_ref()
+    }
+
+    /// Follow the dependencies between `machines`: forwarded connections wake dependencies
+    /// first, and dependencies of machines that are on according to `states` stay on.
+    pub fn with_fleet(self, machines: Arc<RwLock<Vec<Machine>>>, states: StateTracker) -> Self {
+        Self {
+            fleet: Some(Fleet { machines, states }),
+            ..self
+        }
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Wake the dependencies of `machine` that are down, see
+    /// [`dependencies::wake_dependencies`].
+    pub async fn wake_dependencies(&self, machine: &Machine, cause: &Cause) -> Result<()> {
+        let Some(fleet) = &self.fleet else {
+            return Ok(());
+        };
+        let machines = fleet.machines.read().await.clone();
+        dependencies::wake_dependencies(self, &machines, machine, cause).await
+    }
+
+    /// MACs the monitor leaves on because machines depending on them are on.
+    async fn held_by_dependents(&self) -> HashSet<String> {
+        match &self.fleet {
+            Some(fleet) => {
+                let machines = fleet.machines.read().await;
+                dependencies::held_by_dependents(&machines, &fleet.states)
+            }
+            None => HashSet::new(),
+        }
+    }
+
+    pub fn initialize_machine(&self, machine: &Machine) {
         let window_minutes = machine.inactivity_period.max(1);
         let window_secs = window_minutes.saturating_mul(60);
         let config = MachineConfig {
             request_times: VecDeque::new(),
             max_requests: 0, // No longer used for rate limiting
             window: Duration::from_secs(window_secs as u64),
-            turn_off_port,
-            mac: machine.mac.clone(),
+            machine: machine.clone(),
             triggered: AtomicBool::new(false),
             last_request: Instant::now(),
         };
@@ -54,16 +170,27 @@ impl TurnOffLimiter {
         machines.insert(machine.ip, config);
     }
 
+    /// Stop watching the machine with ID `id`, which is keyed by the address it had.
+    pub fn forget_machine(&self, id: &str) {
+        let mut machines = self.machines.lock().unwrap();
+        machines.retain(|_, config| config.machine.id != id);
+    }
+
+    /// Whether the inactivity monitor watches a machine at `ip`.
+    #[allow(dead_code)]
+    pub fn is_monitoring(&self, ip: Ipv4Addr) -> bool {
+        self.machines.lock().unwrap().contains_key(&ip)
+    }
+
     #[allow(dead_code)]
-    pub fn update_machine(&self, machine: &Machine, turn_off_port: u16) {
+    pub fn update_machine(&self, machine: &Machine) {
         let window_minutes = machine.inactivity_period.max(1);
         let window_secs = window_minutes.saturating_mul(60);
         let mut machines = self.machines.lock().unwrap();
         if let Some(config) = machines.get_mut(&machine.ip) {
             // Update existing configuration
             config.window = Duration::from_secs(window_secs as u64);
-            config.turn_off_port = turn_off_port;
-            config.mac = machine.mac.clone();
+            config.machine = machine.clone();
             // Reset triggered flag so it can trigger again if needed
             config.triggered.store(false, Ordering::SeqCst);
             debug!(
@@ -73,11 +200,11 @@ impl TurnOffLimiter {
         } else {
             // Machine not found, initialize it
             drop(machines);
-            self.initialize_machine(machine, turn_off_port);
+            self.initialize_machine(machine);
         }
     }
 
-    fn record_request(&self, ip: Ipv4Addr) -> Option<(usize, u16, String, Duration)> {
+    fn record_request(&self, ip: Ipv4Addr) -> Option<(usize, Machine, Duration)> {
         let mut machines = self.machines.lock().unwrap();
         let config = machines.get_mut(&ip)?;
 
@@ -99,12 +226,7 @@ impl TurnOffLimiter {
 
         let current = config.request_times.len();
         if current >= config.max_requests && !config.triggered.swap(true, Ordering::SeqCst) {
-            Some((
-                current,
-                config.turn_off_port,
-                config.mac.clone(),
-                config.window,
-            ))
+            Some((current, config.machine.clone(), config.window))
         } else {
             None
         }
@@ -114,26 +236,150 @@ impl TurnOffLimiter {
         let mut machines = self.machines.lock().unwrap();
         if let Some(config) = machines.get_mut(&ip) {
             config.last_request = Instant::now();
+            // New traffic starts a new idle period, which may end in another shutdown
+            config.triggered.store(false, Ordering::SeqCst);
             debug!(
                 "Updated last_request for machine {} (IP: {})",
-                config.mac, ip
+                config.machine.mac, ip
             );
         }
     }
 
+    /// Keep the inactivity monitor from shutting `mac` down for `duration`.
+    pub fn keep_awake(&self, mac: &str, duration: Duration, cause: &Cause) {
+        let now = unix_now();
+        let until = now + duration.as_secs();
+        {
+            let mut keep_awake = self.keep_awake.lock().unwrap();
+            keep_awake.retain(|_, until| *until > now);
+            keep_awake.insert(mac.to_string(), until);
+        }
+        self.events.emit_caused(
+            mac,
+            EventKind::KeepAwakeChanged { until: Some(until) },
+            cause,
+        );
+    }
+
+    /// End a keep-awake override early.
+    pub fn clear_keep_awake(&self, mac: &str, cause: &Cause) {
+        let removed = self.keep_awake.lock().unwrap().remove(mac);
+        if removed.is_some_and(|until| until > unix_now()) {
+            self.events
+                .emit_caused(mac, EventKind::KeepAwakeChanged { until: None }, cause);
+        }
+    }
+
+    /// Unix time until which `mac` is kept awake, if it currently is.
+    pub fn kept_awake_until(&self, mac: &str) -> Option<u64> {
+        let keep_awake = self.keep_awake.lock().unwrap();
+        keep_awake
+            .get(mac)
+            .copied()
+            .filter(|until| *until > unix_now())
+    }
+
+    /// Start the minimum uptime of `mac`'s inactivity policy. Also ends its wake cooldown.
+    pub fn mark_woken(&self, mac: &str) {
+        let mut woken_at = self.woken_at.lock().unwrap();
+        woken_at.insert(mac.to_string(), Instant::now());
+        self.shut_down_at.lock().unwrap().remove(mac);
+    }
+
+    /// Start the wake cooldown of `mac` after an automatic shutdown.
+    fn mark_shut_down(&self, mac: &str) {
+        let mut shut_down_at = self.shut_down_at.lock().unwrap();
+        shut_down_at.insert(mac.to_string(), Instant::now());
+    }
+
+    /// Why a connection from `client` may not wake `machine`, `None` if it may.
+    fn wake_suppression(&self, machine: &Machine, client: IpAddr) -> Option<String> {
+        let since_shutdown = self
+            .shut_down_at
+            .lock()
+            .unwrap()
+            .get(&machine.mac)
+            .map(|at| at.elapsed());
+        machine
+            .wake_policy
+            .suppression(client, chrono::Utc::now(), since_shutdown)
+    }
+
+    /// Log and record a suppressed wake, at most once a minute per machine so polling
+    /// clients don't flood the event history.
+    fn report_suppressed_wake(&self, mac: &str, client: IpAddr, local_port: u16, reason: String) {
+        let now = Instant::now();
+        {
+            let mut reported = self.suppression_reported.lock().unwrap();
+            if reported
+                .get(mac)
+                .is_some_and(|at| now.duration_since(*at) < SUPPRESSION_REPORT_INTERVAL)
+            {
+                debug!(
+                    "Dropped connection from {} to port {}, not waking {}: {}",
+                    client, local_port, mac, reason
+                );
+                return;
+            }
+            reported.insert(mac.to_string(), now);
+        }
+        warn!(
+            "Dropped connection from {} to port {}, not waking {}: {}",
+            client, local_port, mac, reason
+        );
+        self.events.emit_caused(
+            mac,
+            EventKind::WakeSuppressed {
+                client: client.to_string(),
+                reason,
+            },
+            &Cause::new(
+                format!("client {}", client),
+                format!("connection to port {}", local_port),
+            ),
+        );
+    }
+
+    /// Idle time after which `machine` is shut down, `None` while its policy keeps it on.
+    fn idle_threshold(
+        &self,
+        machine: &Machine,
+        window: Duration,
+        now: Instant,
+    ) -> Option<Duration> {
+        if self.kept_awake_until(&machine.mac).is_some() {
+            return None;
+        }
+        let policy = &machine.inactivity_policy;
+        let woken_at = self.woken_at.lock().unwrap().get(&machine.mac).copied();
+        if woken_at.is_some_and(|woken| now.duration_since(woken) < policy.min_uptime()) {
+            return None;
+        }
+        policy.idle_threshold(window, chrono::Utc::now())
+    }
+
+    /// Let the monitor try again after a full inactivity window, e.g. after a failed shutdown.
+    fn rearm(&self, ip: Ipv4Addr) {
+        let mut machines = self.machines.lock().unwrap();
+        if let Some(config) = machines.get_mut(&ip) {
+            config.last_request = Instant::now();
+            config.triggered.store(false, Ordering::SeqCst);
+        }
+    }
+
     fn check_and_trigger_turn_off(&self, ip: Ipv4Addr) {
         debug!("Checking request limit for {}", ip);
-        if let Some((hit_count, turn_off_port, mac, window)) = self.record_request(ip) {
-            let remote_ip = ip.to_string();
+        if let Some((hit_count, machine, window)) = self.record_request(ip) {
+            let local_agent = self.local_agent.clone();
             tokio::spawn(async move {
                 info!(
                     "Request limit reached for {}: {} requests within {:?}, sending turn-off signal",
-                    mac, hit_count, window
+                    machine.mac, hit_count, window
                 );
-                if let Err(e) = turn_off_remote_machine(&remote_ip, turn_off_port).await {
+                if let Err(e) = power::power_off(&machine, local_agent.as_ref()).await {
                     error!(
-                        "Failed to send turn-off signal for {} on {}:{}: {}",
-                        mac, remote_ip, turn_off_port, e
+                        "Failed to send turn-off signal for {} ({}): {}",
+                        machine.mac, machine.ip, e
                     );
                 }
             });
@@ -146,25 +392,34 @@ impl TurnOffLimiter {
             let mut interval = tokio::time::interval(Duration::from_secs(1));
             loop {
                 interval.tick().await;
+                let held = limiter.held_by_dependents().await;
                 let now = Instant::now();
-                let machines_to_check: Vec<(Ipv4Addr, u16, String)> = {
+                let machines_to_check: Vec<Machine> = {
                     let machines = limiter.machines.lock().unwrap();
                     machines
                         .iter()
                         .filter_map(|(ip, config)| {
+                            if held.contains(&config.machine.mac) {
+                                debug!(
+                                    "Machine {} (IP: {}) stays on for machines that depend on it",
+                                    config.machine.mac, ip
+                                );
+                                return None;
+                            }
                             let time_since_last_request = now.duration_since(config.last_request);
+                            let threshold = limiter.idle_threshold(&config.machine, config.window, now)?;
                             debug!(
-                                "Checking inactivity for machine {} (IP: {}): last request was {:?} ago, window is {:?}",
-                                config.mac, ip, time_since_last_request, config.window
+                                "Checking inactivity for machine {} (IP: {}): last request was {:?} ago, threshold is {:?}",
+                                config.machine.mac, ip, time_since_last_request, threshold
                             );
-                            if time_since_last_request > config.window {
+                            if time_since_last_request > threshold {
                                 // Use swap to atomically check and set triggered flag
                                 if !config.triggered.swap(true, Ordering::SeqCst) {
                                     debug!(
                                         "Machine {} (IP: {}) has been inactive for {:?}, exceeding window of {:?}",
-                                        config.mac, ip, time_since_last_request, config.window
+                                        config.machine.mac, ip, time_since_last_request, config.window
                                     );
-                                    Some((*ip, config.turn_off_port, config.mac.clone()))
+                                    Some(config.machine.clone())
                                 } else {
                                     None
                                 }
@@ -175,18 +430,29 @@ impl TurnOffLimiter {
                         .collect()
                 };
 
-                for (ip, turn_off_port, mac) in machines_to_check {
-                    let remote_ip = ip.to_string();
+                for machine in machines_to_check {
                     debug!(
                         "Sending turn-off signal for inactive machine {} (IP: {})",
-                        mac, remote_ip
+                        machine.mac, machine.ip
                     );
+                    let limiter = limiter.clone();
+                    limiter.mark_shut_down(&machine.mac);
                     tokio::spawn(async move {
-                        if let Err(e) = turn_off_remote_machine(&remote_ip, turn_off_port).await {
-                            error!(
-                                "Failed to send turn-off signal for inactive machine {} on {}:{}: {}",
-                                mac, remote_ip, turn_off_port, e
-                            );
+                        let cause = Cause::new(
+                            "inactivity monitor",
+                            format!("no traffic for {} min", machine.inactivity_period),
+                        );
+                        let outcome = power::shutdown_and_verify(
+                            &machine,
+                            &limiter.shutdown_policy,
+                            limiter.local_agent.as_ref(),
+                            &limiter.events,
+                            &cause,
+                        )
+                        .await;
+                        // Try again after another window unless the machine is known to be off
+                        if outcome != power::ShutdownOutcome::Verified {
+                            limiter.rearm(machine.ip);
                         }
                     });
                 }
@@ -212,6 +478,13 @@ impl TurnOffLimiter {
             "TCP Forwarder listening on {}, proxying to {}, inactivity period: {}min",
             listen_addr, remote_addr, machine.inactivity_period
         );
+        self.events.emit(
+            &machine.mac,
+            EventKind::ForwarderStarted {
+                local_port,
+                target_port: remote_addr.port(),
+            },
+        );
 
         let machine_ip = machine.ip;
 
@@ -233,93 +506,124 @@ impl TurnOffLimiter {
                         client_addr, remote_addr
                     );
 
-                    let remote_addr_clone = remote_addr;
                     let mac_str_clone = machine.mac.clone();
+                    let machine_clone = machine.clone();
                     let rate_limiter = self.clone();
                     let machine_ip_clone = machine_ip;
 
                     let connection_pool_clone = connection_pool.clone();
                     tokio::spawn(async move {
+                        let connect_timeout = Duration::from_millis(1000);
+                        let targets = current_targets(&machine_clone, remote_addr).await;
+                        let mut target = first_reachable(&targets, connect_timeout).await;
+                        let host_up = target.is_some();
+                        if !host_up {
+                            // Dropped connections don't count as activity either
+                            if let Some(reason) = rate_limiter.wake_suppression(&machine_clone, client_addr.ip()) {
+                                rate_limiter.report_suppressed_wake(&mac_str_clone, client_addr.ip(), local_port, reason);
+                                return;
+                            }
+                        }
+
+                        let connection = rate_limiter.metrics.connection_opened(&mac_str_clone, local_port);
                         // Update last_request whenever we receive a connection
                         rate_limiter.update_last_request(machine_ip_clone);
                         rate_limiter.check_and_trigger_turn_off(machine_ip_clone);
 
-                        let connect_timeout = Duration::from_millis(1000);
-                        if !wol::tcp_check(remote_addr_clone, connect_timeout) {
+                        if !host_up {
                             info!(
-                                "Host {} seems to be down. Sending WOL packet to MAC {}.",
-                                remote_addr_clone, mac_str_clone
+                                "Host {} seems to be down. Powering on {} via {}.",
+                                remote_addr, mac_str_clone, machine_clone.power_on.kind()
                             );
 
-                            let mac = match wol::parse_mac(&mac_str_clone) {
-                                Ok(m) => m,
-                                Err(e) => {
-                                    error!("Invalid MAC for WOL on proxy: {}: {}", mac_str_clone, e);
-                                    return;
-                                }
-                            };
-
-                            let broadcast_addr = Ipv4Addr::new(255, 255, 255, 255);
-                            let wol_config = Default::default();
-                            if let Err(e) = crate::wol::send_packets(&mac, broadcast_addr, wol_port, 3, &wol_config).await {
-                                error!("Failed to send WOL packet for {}: {}", mac_str_clone, e);
+                            let cause = Cause::new(
+                                format!("client {}", client_addr.ip()),
+                                format!("connection to port {}", local_port),
+                            );
+                            if let Err(e) = rate_limiter.wake_dependencies(&machine_clone, &cause).await {
+                                error!("Not waking {}: {:#}", mac_str_clone, e);
+                                rate_limiter.events.emit_caused(
+                                    &mac_str_clone,
+                                    EventKind::WakeFailed {
+                                        error: format!("{:#}", e),
+                                    },
+                                    &cause,
+                                );
+                                return;
+                            }
+                            if let Err(e) = power::power_on(&machine_clone, wol_port).await {
+                                error!("Failed to power on {}: {:#}", mac_str_clone, e);
+                                rate_limiter.events.emit_caused(
+                                    &mac_str_clone,
+                                    EventKind::WakeFailed {
+                                        error: format!("{:#}", e),
+                                    },
+                                    &cause,
+                                );
                                 return;
                             }
+                            rate_limiter.mark_woken(&mac_str_clone);
+                            rate_limiter.events.emit_caused(
+                                &mac_str_clone,
+                                EventKind::WakeRequested,
+                                &cause,
+                            );
 
                             info!(
-                                "WOL packet sent. Waiting up to 60s for {} to become reachable...",
-                                remote_addr_clone
+                                "Power-on request sent. Waiting up to 60s for {} to become reachable...",
+                                remote_addr
                             );
 
                             let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
-                            let mut host_up = false;
                             while tokio::time::Instant::now() < deadline {
-                                if wol::tcp_check(remote_addr_clone, connect_timeout) {
-                                    info!("Host {} is now up.", remote_addr_clone);
-                                    host_up = true;
+                                // The machine may register a new address as it comes up
+                                let targets = current_targets(&machine_clone, remote_addr).await;
+                                target = first_reachable(&targets, connect_timeout).await;
+                                if let Some(addr) = target {
+                                    info!("Host {} is now up.", addr);
                                     break;
                                 }
                                 tokio::time::sleep(Duration::from_secs(2)).await;
                             }
-
-                            if !host_up {
-                                warn!(
-                                    "Timeout waiting for host {} to come up. Dropping connection from {}.",
-                                    remote_addr_clone, client_addr
-                                );
-                                return;
-                            }
                         }
+                        let Some(remote_addr) = target else {
+                            warn!(
+                                "Timeout waiting for host {} to come up. Dropping connection from {}.",
+                                remote_addr, client_addr
+                            );
+                            return;
+                        };
 
-                        let mut outbound = match connection_pool_clone.get_connection(remote_addr_clone).await {
+                        let mut outbound = match connection_pool_clone.get_connection(remote_addr).await {
                             Ok(stream) => {
-                                debug!("Successfully obtained or created connection to {}", remote_addr_clone);
+                                debug!("Successfully obtained or created connection to {}", remote_addr);
                                 stream
                             }
                             Err(e) => {
-                                error!("Failed to obtain connection to remote {}: {}", remote_addr_clone, e);
+                                error!("Failed to obtain connection to remote {}: {}", remote_addr, e);
                                 return;
                             }
                         };
 
                         match copy_bidirectional(&mut inbound, &mut outbound).await {
-                            Ok(_) => {
+                            Ok((to_target, to_client)) => {
+                                connection.transferred(to_target, to_client);
                                 // Most targets close the connection after each request.
                                 // Drop the stream instead of reusing a socket that is very
                                 // likely already shut down by the remote endpoint.
                                 drop(outbound);
                                 debug!(
                                     "Completed data transfer for {} (connection closed)",
-                                    remote_addr_clone
+                                    remote_addr
                                 );
                             }
                             Err(e) => {
                                 // Drop the broken connection so it isn't re-used from the pool.
                                 drop(outbound);
-                                connection_pool_clone.remove_target(remote_addr_clone).await;
+                                connection_pool_clone.remove_target(remote_addr).await;
                                 warn!(
                                     "Error forwarding data between {} and {}: {}",
-                                    client_addr, remote_addr_clone, e
+                                    client_addr, remote_addr, e
                                 );
                             }
                         }
@@ -341,17 +645,15 @@ impl TurnOffLimiter {
     ) -> Result<()> {
         // Initialize machine configuration if turn-off is enabled
         if machine.can_be_turned_off {
-            if let Some(port) = machine.turn_off_port {
-                limiter.initialize_machine(&machine, port);
-                info!(
-                    "Initialized inactivity monitoring for machine {} ({}): {}min",
-                    machine.mac, machine.ip, machine.inactivity_period
-                );
-            } else {
-                debug!(
-                    "Turn off port not configured for {}, skipping inactivity-based shutdown",
-                    machine.mac
-                );
+            match power::missing_power_off_config(&machine) {
+                None => {
+                    limiter.initialize_machine(&machine);
+                    info!(
+                        "Initialized inactivity monitoring for machine {} ({}): {}min",
+                        machine.mac, machine.ip, machine.inactivity_period
+                    );
+                }
+                Some(missing) => debug!("{}, skipping inactivity-based shutdown", missing),
             }
         } else {
             info!(
@@ -360,7 +662,9 @@ impl TurnOffLimiter {
             );
         }
 
-        limiter
+        let mac = machine.mac.clone();
+        let target_port = remote_addr.port();
+        let result = limiter
             .proxy_internal(
                 local_port,
                 remote_addr,
@@ -369,34 +673,100 @@ impl TurnOffLimiter {
                 rx,
                 connection_pool,
             )
-            .await
+            .await;
+        let kind = match &result {
+            Ok(()) => EventKind::ForwarderStopped {
+                local_port,
+                target_port,
+            },
+            Err(e) => EventKind::ForwarderFailed {
+                local_port,
+                target_port,
+                error: format!("{:#}", e),
+            },
+        };
+        limiter.events.emit(&mac, kind);
+        result
     }
 }
 
+/// Addresses a forwarder to `remote_addr` tries, in order: the machine's interfaces by
+/// priority, with `remote_addr` first when it isn't one of them.
+fn forward_targets(machine: &Machine, remote_addr: SocketAddr) -> Vec<SocketAddr> {
+    let mut targets: Vec<SocketAddr> = machine
+        .addresses()
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip.into(), remote_addr.port()))
+        .collect();
+    if !targets.contains(&remote_addr) {
+        targets.insert(0, remote_addr);
+    }
+    targets
+}
+
+/// [`forward_targets`] with the current address of a machine configured by hostname in
+/// place of the one `remote_addr` was built from.
+async fn current_targets(machine: &Machine, remote_addr: SocketAddr) -> Vec<SocketAddr> {
+    if machine.hostname.is_none() {
+        return forward_targets(machine, remote_addr);
+    }
+    let machine = resolver::resolved(machine).await;
+    forward_targets(
+        &machine,
+        SocketAddr::new(machine.ip.into(), remote_addr.port()),
+    )
+}
+
+/// The first of `targets` that accepts connections, checked off the async workers.
+async fn first_reachable(targets: &[SocketAddr], timeout: Duration) -> Option<SocketAddr> {
+    let targets = targets.to_vec();
+    tokio::task::spawn_blocking(move || {
+        targets
+            .into_iter()
+            .find(|addr| wol::tcp_check(*addr, timeout))
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Ask the agent to shut down, optionally with a specific action (`suspend`, `poweroff`, ...).
+/// The agent is reached over https when its certificate `fingerprint` is pinned.
 pub async fn turn_off_remote_machine(
     remote_ip: &str,
     turn_off_port: u16,
-) -> Result<(), reqwest::Error> {
-    let url = turn_off_url(remote_ip, turn_off_port);
-    info!("Sending turn-off signal to {}", url);
-    let client = reqwest::Client::builder()
-        .no_proxy()
-        .timeout(Duration::from_secs(5))
+    action: Option<&str>,
+    fingerprint: Option<&str>,
+) -> Result<()> {
+    let url = turn_off_url(tls::agent_scheme(fingerprint), remote_ip, turn_off_port);
+    info!(
+        "Sending turn-off signal to {} (action: {})",
+        url,
+        action.unwrap_or("default")
+    );
+    let client = tls::pinned_client_builder(fingerprint)?
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(TURN_OFF_REQUEST_TIMEOUT)
         .build()?;
 
-    let response = client.post(&url).send().await?;
+    let mut request = client.post(&url);
+    if let Some(action) = action {
+        request = request.query(&[("action", action)]);
+    }
+    let response = request.send().await?;
     if response.status().is_success() {
         info!(
             "Successfully sent turn-off signal to {}:{}",
             remote_ip, turn_off_port
         );
     } else {
+        let status = response.status();
+        // The body carries the output of any pre-shutdown hook that aborted the shutdown
+        let body = response.text().await.unwrap_or_default();
         error!(
-            "Failed to send turn-off signal to {}:{}, status: {}",
-            remote_ip,
-            turn_off_port,
-            response.status()
+            "Failed to send turn-off signal to {}:{}, status: {}, response: {}",
+            remote_ip, turn_off_port, status, body
         );
+        anyhow::bail!("agent answered with status {}: {}", status, body);
     }
     Ok(())
 }
@@ -412,8 +782,101 @@ mod tests {
 
     #[test]
     fn turn_off_url_formats_expected_path() {
-        let url = super::turn_off_url("192.168.1.10", 8080);
+        let url = super::turn_off_url("http", "192.168.1.10", 8080);
         assert_eq!(url, "http://192.168.1.10:8080/machines/turn-off");
+        let url = super::turn_off_url("https", "192.168.1.10", 8080);
+        assert_eq!(url, "https://192.168.1.10:8080/machines/turn-off");
+    }
+
+    #[test]
+    fn keep_awake_and_minimum_uptime_suspend_the_idle_threshold() {
+        let limiter = TurnOffLimiter::new();
+        let mut events = limiter.events.subscribe();
+        let mut machine = crate::test_support::machine("AA:BB:CC:DD:EE:FF");
+        machine.inactivity_policy.min_uptime_minutes = 10;
+        let window = Duration::from_secs(60);
+        let now = Instant::now();
+        assert_eq!(limiter.idle_threshold(&machine, window, now), Some(window));
+
+        limiter.mark_woken(&machine.mac);
+        assert_eq!(
+            limiter.idle_threshold(&machine, window, Instant::now()),
+            None
+        );
+        machine.inactivity_policy.min_uptime_minutes = 0;
+        assert_eq!(
+            limiter.idle_threshold(&machine, window, Instant::now()),
+            Some(window)
+        );
+
+        let cause = Cause::new("alice", "testing");
+        limiter.keep_awake(&machine.mac, Duration::from_secs(3600), &cause);
+        assert!(limiter.kept_awake_until(&machine.mac).is_some());
+        assert_eq!(
+            limiter.idle_threshold(&machine, window, Instant::now()),
+            None
+        );
+        let event = events.try_recv().unwrap();
+        assert!(matches!(
+            event.kind,
+            EventKind::KeepAwakeChanged { until: Some(_) }
+        ));
+
+        limiter.clear_keep_awake(&machine.mac, &cause);
+        assert_eq!(limiter.kept_awake_until(&machine.mac), None);
+        let event = events.try_recv().unwrap();
+        assert!(matches!(
+            event.kind,
+            EventKind::KeepAwakeChanged { until: None }
+        ));
+    }
+
+    #[tokio::test]
+    async fn forwards_use_the_first_address_that_answers() {
+        let listener = match std::net::TcpListener::bind("127.0.0.1:0") {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("failed to bind listener: {err}"),
+        };
+        let port = listener.local_addr().unwrap().port();
+        // The primary address is down, the second NIC answers
+        let mut machine = crate::test_support::machine("AA:BB:CC:DD:EE:FF");
+        machine.ip = Ipv4Addr::new(127, 0, 0, 2);
+        machine.interfaces = vec![crate::web::MachineInterface {
+            mac: "AA:BB:CC:DD:EE:01".to_string(),
+            ip: Some(Ipv4Addr::LOCALHOST),
+            wake_on_lan: false,
+            priority: 1,
+        }];
+        let primary = SocketAddr::from((machine.ip, port));
+        let targets = forward_targets(&machine, primary);
+        assert_eq!(
+            targets,
+            vec![primary, SocketAddr::from((Ipv4Addr::LOCALHOST, port))]
+        );
+        assert_eq!(
+            first_reachable(&targets, Duration::from_millis(500)).await,
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        );
+    }
+
+    #[test]
+    fn automatic_shutdowns_start_a_wake_cooldown_until_the_next_wake() {
+        let limiter = TurnOffLimiter::new();
+        let mut machine = crate::test_support::machine("AA:BB:CC:DD:EE:FF");
+        machine.wake_policy.cooldown_minutes = 10;
+        machine.wake_policy.allowed_clients = vec!["192.168.1.20".to_string()];
+        let phone: IpAddr = "192.168.1.99".parse().unwrap();
+        let backup: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(limiter.wake_suppression(&machine, phone), None);
+
+        limiter.mark_shut_down(&machine.mac);
+        assert!(limiter.wake_suppression(&machine, phone).is_some());
+        assert_eq!(limiter.wake_suppression(&machine, backup), None);
+
+        // An explicit wake ends the cooldown
+        limiter.mark_woken(&machine.mac);
+        assert_eq!(limiter.wake_suppression(&machine, phone), None);
     }
 
     #[tokio::test]
@@ -454,7 +917,7 @@ mod tests {
             }
         });
 
-        turn_off_remote_machine(&addr.ip().to_string(), addr.port())
+        turn_off_remote_machine(&addr.ip().to_string(), addr.port(), None, None)
             .await
             .expect("turn_off_remote_machine should succeed");
 
@@ -0,0 +1,409 @@
+//! Pre-shutdown and post-resume hook scripts for the client agent.
+//!
+//! Hooks are executable files in a directory, run one after another in lexical order
+//! (like `run-parts`). A hook that exits non-zero or exceeds the timeout stops the
+//! remaining hooks; for pre-shutdown hooks it also aborts the shutdown. All hooks of a
+//! run share a total budget, so the agent answers a turn-off request before the proxy
+//! gives up on it.
+//!
+//! Resumes are detected two ways: a jump between the wall clock and the monotonic clock
+//! (which does not advance while suspended), and a changed boot time after the agent
+//! itself powered the machine off.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+/// Maximum number of bytes of stdout/stderr kept per hook
+const MAX_OUTPUT_BYTES: usize = 16 * 1024;
+
+/// How often the resume watcher compares the clocks
+const RESUME_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Clock drift that counts as the machine having been suspended
+const RESUME_JUMP_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// File in the agent's state directory remembering an agent-initiated shutdown
+const SHUTDOWN_MARKER_FILE: &str = "wakezilla-agent-shutdown.json";
+
+/// Default time all hooks of one run may take together, below the proxy's turn-off
+/// request timeout (see `forward::TURN_OFF_REQUEST_TIMEOUT`)
+pub const DEFAULT_TOTAL_BUDGET: Duration = Duration::from_secs(150);
+
+#[derive(Debug, Clone)]
+pub struct HookConfig {
+    pub pre_shutdown_dir: Option<PathBuf>,
+    pub post_resume_dir: Option<PathBuf>,
+    /// Timeout applied to each hook individually
+    pub timeout: Duration,
+    /// Time all hooks of one run may take together
+    pub budget: Duration,
+    /// Directory of the shutdown marker, see `storage.agent_state_dir`
+    pub state_dir: PathBuf,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            pre_shutdown_dir: None,
+            post_resume_dir: None,
+            timeout: Duration::from_secs(60),
+            budget: DEFAULT_TOTAL_BUDGET,
+            state_dir: crate::config::StorageConfig::default().agent_state_dir(),
+        }
+    }
+}
+
+/// Outcome of a single hook script.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookResult {
+    pub name: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration_ms: u64,
+}
+
+/// Outcome of a whole hook directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookRun {
+    pub success: bool,
+    pub results: Vec<HookResult>,
+}
+
+/// Executable regular files in `dir`, sorted by name. Hidden and backup files are skipped.
+pub fn list_hooks(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut hooks: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !name.starts_with('.') && !name.ends_with('~')
+        })
+        .filter(|entry| entry.metadata().is_ok_and(|meta| is_executable(&meta)))
+        .map(|entry| entry.path())
+        .collect();
+    hooks.sort();
+    Ok(hooks)
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.is_file() && meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    meta.is_file()
+}
+
+/// Run every hook in `dir`, stopping at the first failure.
+pub async fn run_hooks(dir: &Path, timeout: Duration, budget: Duration) -> HookRun {
+    let hooks = match list_hooks(dir) {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            warn!("Failed to list hooks in {}: {}", dir.display(), e);
+            return HookRun {
+                success: false,
+                results: vec![HookResult {
+                    name: dir.display().to_string(),
+                    success: false,
+                    exit_code: None,
+                    timed_out: false,
+                    stdout: String::new(),
+                    stderr: format!("failed to list hooks: {}", e),
+                    duration_ms: 0,
+                }],
+            };
+        }
+    };
+
+    let mut run = HookRun {
+        success: true,
+        results: Vec::with_capacity(hooks.len()),
+    };
+    let deadline = Instant::now() + budget;
+    for hook in hooks {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut result = run_hook(&hook, timeout.min(remaining)).await;
+        if result.timed_out && remaining < timeout {
+            result.stderr = format!("hooks exceeded their total budget of {:?}", budget);
+        }
+        let success = result.success;
+        run.results.push(result);
+        if !success {
+            run.success = false;
+            break;
+        }
+    }
+    run
+}
+
+async fn run_hook(path: &Path, timeout: Duration) -> HookResult {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    info!("Running hook {}", path.display());
+
+    let started = Instant::now();
+    let child = Command::new(path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+
+    let mut result = HookResult {
+        name,
+        success: false,
+        exit_code: None,
+        timed_out: false,
+        stdout: String::new(),
+        stderr: String::new(),
+        duration_ms: 0,
+    };
+
+    match child {
+        Ok(child) => match tokio::time::timeout(timeout, child.wait_with_output()).await {
+            Ok(Ok(output)) => {
+                result.success = output.status.success();
+                result.exit_code = output.status.code();
+                result.stdout = truncate_output(&output.stdout);
+                result.stderr = truncate_output(&output.stderr);
+            }
+            Ok(Err(e)) => result.stderr = format!("failed to wait for hook: {}", e),
+            Err(_) => {
+                // The child is killed when the future owning it is dropped
+                result.timed_out = true;
+                result.stderr = format!("hook timed out after {:?}", timeout);
+            }
+        },
+        Err(e) => result.stderr = format!("failed to start hook: {}", e),
+    }
+    result.duration_ms = started.elapsed().as_millis() as u64;
+
+    if result.success {
+        debug!("Hook {} finished in {}ms", result.name, result.duration_ms);
+    } else {
+        warn!(
+            "Hook {} failed (exit code {:?}, timed out: {}): {}",
+            result.name,
+            result.exit_code,
+            result.timed_out,
+            result.stderr.trim()
+        );
+    }
+    result
+}
+
+fn truncate_output(bytes: &[u8]) -> String {
+    let end = bytes.len().min(MAX_OUTPUT_BYTES);
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShutdownMarker {
+    boot_time: u64,
+    requested_at: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn shutdown_marker_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(SHUTDOWN_MARKER_FILE)
+}
+
+/// Remember that the agent is about to shut the machine down, so the next boot can
+/// run the post-resume hooks.
+pub fn record_shutdown(state_dir: &Path) {
+    let marker = ShutdownMarker {
+        boot_time: sysinfo::System::boot_time(),
+        requested_at: unix_now(),
+    };
+    let path = shutdown_marker_path(state_dir);
+    let written = serde_json::to_vec(&marker)
+        .map_err(std::io::Error::from)
+        .and_then(|data| {
+            std::fs::create_dir_all(state_dir)?;
+            std::fs::write(&path, data)
+        });
+    if let Err(e) = written {
+        warn!("Failed to write shutdown marker {}: {}", path.display(), e);
+    }
+}
+
+/// Whether the machine booted again since the agent last shut it down.
+fn booted_after_shutdown(state_dir: &Path) -> bool {
+    let Ok(data) = std::fs::read(shutdown_marker_path(state_dir)) else {
+        return false;
+    };
+    let marker: Option<ShutdownMarker> = serde_json::from_slice(&data).ok();
+    marker.is_some_and(|m| m.boot_time != sysinfo::System::boot_time())
+}
+
+/// Forget an agent-initiated shutdown once the post-resume hooks ran for it.
+fn clear_shutdown_marker(state_dir: &Path) {
+    let path = shutdown_marker_path(state_dir);
+    match std::fs::remove_file(&path) {
+        Ok(()) => debug!("Removed shutdown marker {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove shutdown marker {}: {}", path.display(), e),
+    }
+}
+
+/// Whether the wall clock advanced noticeably more than the monotonic clock, which
+/// stops while the machine is suspended.
+fn is_resume_jump(wall_elapsed: Duration, monotonic_elapsed: Duration) -> bool {
+    wall_elapsed.saturating_sub(monotonic_elapsed) > RESUME_JUMP_THRESHOLD
+}
+
+/// Watch for resumes and run the post-resume hooks after each one.
+pub fn spawn_resume_watcher(config: HookConfig) -> Option<tokio::task::JoinHandle<()>> {
+    let dir = config.post_resume_dir?;
+    Some(tokio::spawn(async move {
+        if booted_after_shutdown(&config.state_dir) {
+            info!("Machine booted after an agent shutdown, running post-resume hooks");
+            run_hooks(&dir, config.timeout, config.budget).await;
+            clear_shutdown_marker(&config.state_dir);
+        }
+
+        let mut last_wall = SystemTime::now();
+        let mut last_monotonic = Instant::now();
+        loop {
+            tokio::time::sleep(RESUME_POLL_INTERVAL).await;
+            let now_wall = SystemTime::now();
+            let now_monotonic = Instant::now();
+            let wall_elapsed = now_wall.duration_since(last_wall).unwrap_or_default();
+            let monotonic_elapsed = now_monotonic.duration_since(last_monotonic);
+            last_wall = now_wall;
+            last_monotonic = now_monotonic;
+
+            if is_resume_jump(wall_elapsed, monotonic_elapsed) {
+                info!(
+                    "Detected resume from suspend (slept ~{}s), running post-resume hooks",
+                    wall_elapsed.saturating_sub(monotonic_elapsed).as_secs()
+                );
+                run_hooks(&dir, config.timeout, config.budget).await;
+                // A suspend requested by the agent is handled now, not at the next boot
+                clear_shutdown_marker(&config.state_dir);
+                // Hooks may take a while, do not count that as another jump
+                last_wall = SystemTime::now();
+                last_monotonic = Instant::now();
+            }
+        }
+    }))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    fn write_hook(dir: &Path, name: &str, body: &str) {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn list_hooks_skips_non_executable_and_hidden_files() {
+        let dir = tempdir().unwrap();
+        write_hook(dir.path(), "20-second", "true");
+        write_hook(dir.path(), "10-first", "true");
+        write_hook(dir.path(), ".hidden", "true");
+        std::fs::write(dir.path().join("README"), "not a hook").unwrap();
+
+        let hooks = list_hooks(dir.path()).unwrap();
+        let names: Vec<_> = hooks
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["10-first", "20-second"]);
+    }
+
+    #[tokio::test]
+    async fn run_hooks_stops_at_first_failure() {
+        let dir = tempdir().unwrap();
+        write_hook(dir.path(), "10-ok", "echo unmounted");
+        write_hook(dir.path(), "20-fail", "echo busy >&2; exit 3");
+        write_hook(dir.path(), "30-never", "echo should not run");
+
+        let run = run_hooks(dir.path(), Duration::from_secs(5), DEFAULT_TOTAL_BUDGET).await;
+        assert!(!run.success);
+        assert_eq!(run.results.len(), 2);
+        assert_eq!(run.results[0].stdout.trim(), "unmounted");
+        assert_eq!(run.results[1].exit_code, Some(3));
+        assert_eq!(run.results[1].stderr.trim(), "busy");
+    }
+
+    #[tokio::test]
+    async fn run_hooks_times_out_slow_hooks() {
+        let dir = tempdir().unwrap();
+        write_hook(dir.path(), "10-slow", "sleep 5");
+
+        let run = run_hooks(dir.path(), Duration::from_millis(200), DEFAULT_TOTAL_BUDGET).await;
+        assert!(!run.success);
+        assert!(run.results[0].timed_out);
+    }
+
+    #[tokio::test]
+    async fn run_hooks_share_a_total_budget() {
+        let dir = tempdir().unwrap();
+        write_hook(dir.path(), "10-slow", "sleep 0.3");
+        write_hook(dir.path(), "20-slow", "sleep 0.3");
+        write_hook(dir.path(), "30-never", "echo should not run");
+
+        // Each hook fits its own timeout, but not both within the budget
+        let started = Instant::now();
+        let run = run_hooks(
+            dir.path(),
+            Duration::from_secs(5),
+            Duration::from_millis(500),
+        )
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!run.success);
+        assert_eq!(run.results.len(), 2);
+        assert!(run.results[0].success);
+        assert!(run.results[1].timed_out);
+        assert!(run.results[1].stderr.contains("total budget"));
+    }
+
+    #[test]
+    fn shutdown_marker_is_kept_until_cleared() {
+        let dir = tempdir().unwrap();
+        let state_dir = dir.path().join("state");
+        record_shutdown(&state_dir);
+        assert!(shutdown_marker_path(&state_dir).exists());
+        // Still the same boot, as after a suspend
+        assert!(!booted_after_shutdown(&state_dir));
+
+        clear_shutdown_marker(&state_dir);
+        assert!(!shutdown_marker_path(&state_dir).exists());
+        clear_shutdown_marker(&state_dir);
+    }
+
+    #[test]
+    fn is_resume_jump_ignores_small_drift() {
+        assert!(!is_resume_jump(
+            Duration::from_secs(6),
+            Duration::from_secs(5)
+        ));
+        assert!(is_resume_jump(
+            Duration::from_secs(600),
+            Duration::from_secs(5)
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+//! Inactivity policies.
+//!
+//! By default a machine is shut down after `inactivity_period` minutes without traffic, day
+//! and night. An [`InactivityPolicy`] adds a minimum uptime after wakes and time windows with
+//! their own idle threshold, or none at all to keep the machine on during e.g. working hours.
+//! The inactivity monitor in `forward.rs` evaluates it every second.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::schedule::{parse_time_of_day, parse_timezone};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<chrono::Weekday> for Day {
+    fn from(day: chrono::Weekday) -> Self {
+        match day {
+            chrono::Weekday::Mon => Day::Mon,
+            chrono::Weekday::Tue => Day::Tue,
+            chrono::Weekday::Wed => Day::Wed,
+            chrono::Weekday::Thu => Day::Thu,
+            chrono::Weekday::Fri => Day::Fri,
+            chrono::Weekday::Sat => Day::Sat,
+            chrono::Weekday::Sun => Day::Sun,
+        }
+    }
+}
+
+/// A time of day range on some days of the week, e.g. working hours.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TimeWindow {
+    /// Days the window starts on, every day when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Day>,
+    /// `HH:MM`
+    pub start: String,
+    /// `HH:MM`, before `start` for windows that run past midnight
+    pub end: String,
+}
+
+impl TimeWindow {
+    pub fn validate(&self) -> Result<()> {
+        parse_time_of_day(&self.start)?;
+        parse_time_of_day(&self.end)?;
+        if self.start == self.end {
+            return Err(anyhow!(
+                "window {}-{} is empty, start and end must differ",
+                self.start,
+                self.end
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether `now` falls inside the window in time zone `tz`.
+    pub fn contains(&self, tz: Tz, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&tz);
+        let (Ok(start), Ok(end), Some(time)) = (
+            parse_time_of_day(&self.start),
+            parse_time_of_day(&self.end),
+            NaiveTime::from_hms_opt(local.hour(), local.minute(), local.second()),
+        ) else {
+            return false;
+        };
+        let day = Day::from(local.weekday());
+        let previous_day = Day::from(local.weekday().pred());
+        let on = |day: Day| self.days.is_empty() || self.days.contains(&day);
+        if start <= end {
+            on(day) && start <= time && time < end
+        } else {
+            // Runs past midnight: the early morning part belongs to the previous day's window
+            (on(day) && time >= start) || (on(previous_day) && time < end)
+        }
+    }
+}
+
+/// A time window with its own idle threshold.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PolicyWindow {
+    #[serde(flatten)]
+    pub hours: TimeWindow,
+    /// Idle minutes before a shutdown inside the window, `None` never shuts down
+    #[serde(default)]
+    pub inactivity_period: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+pub struct InactivityPolicy {
+    /// Minutes a machine stays on after a wake before it may be shut down for inactivity
+    #[serde(default)]
+    pub min_uptime_minutes: u32,
+    /// IANA time zone the windows are in, UTC when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// The first window containing the current time wins; outside every window the
+    /// machine's `inactivity_period` applies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<PolicyWindow>,
+}
+
+impl InactivityPolicy {
+    pub fn validate(&self) -> Result<()> {
+        parse_timezone(self.timezone.as_deref())?;
+        for window in &self.windows {
+            window.hours.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn min_uptime(&self) -> Duration {
+        Duration::from_secs(u64::from(self.min_uptime_minutes) * 60)
+    }
+
+    /// Idle time after which the machine is shut down at `now`, `None` when it must stay on.
+    /// `default` is the machine's `inactivity_period`.
+    pub fn idle_threshold(&self, default: Duration, now: DateTime<Utc>) -> Option<Duration> {
+        let Ok(tz) = parse_timezone(self.timezone.as_deref()) else {
+            return Some(default);
+        };
+        match self
+            .windows
+            .iter()
+            .find(|window| window.hours.contains(tz, now))
+        {
+            Some(window) => window
+                .inactivity_period
+                .map(|minutes| Duration::from_secs(u64::from(minutes.max(1)) * 60)),
+            None => Some(default),
+        }
+    }
+}
+
+/// Validator for the `inactivity_policy` of machine payloads.
+pub fn validate_inactivity_policy(
+    policy: &InactivityPolicy,
+) -> Result<(), validator::ValidationError> {
+    policy.validate().map_err(|e| {
+        let mut error = validator::ValidationError::new("invalid_inactivity_policy");
+        error.code = e.to_string().into();
+        error
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn window(days: Vec<Day>, start: &str, end: &str, minutes: Option<u32>) -> PolicyWindow {
+        PolicyWindow {
+            hours: TimeWindow {
+                days,
+                start: start.to_string(),
+                end: end.to_string(),
+            },
+            inactivity_period: minutes,
+        }
+    }
+
+    #[test]
+    fn windows_override_the_default_threshold() {
+        let weekdays = vec![Day::Mon, Day::Tue, Day::Wed, Day::Thu, Day::Fri];
+        let policy = InactivityPolicy {
+            min_uptime_minutes: 0,
+            timezone: Some("Europe/Berlin".to_string()),
+            windows: vec![
+                window(weekdays, "08:00", "18:00", None),
+                window(vec![], "22:00", "06:00", Some(5)),
+            ],
+        };
+        policy.validate().unwrap();
+        let default = Duration::from_secs(30 * 60);
+
+        // Friday 10:00 in Berlin: working hours, never shut down
+        assert_eq!(
+            policy.idle_threshold(default, utc("2024-01-05T09:00:00Z")),
+            None
+        );
+        // Saturday 10:00: no window
+        assert_eq!(
+            policy.idle_threshold(default, utc("2024-01-06T09:00:00Z")),
+            Some(default)
+        );
+        // Saturday 02:00, inside the window that started on Friday night
+        assert_eq!(
+            policy.idle_threshold(default, utc("2024-01-06T01:00:00Z")),
+            Some(Duration::from_secs(5 * 60))
+        );
+    }
+
+    #[test]
+    fn overnight_windows_belong_to_the_day_they_start() {
+        let policy = InactivityPolicy {
+            windows: vec![window(vec![Day::Fri], "22:00", "06:00", None)],
+            ..Default::default()
+        };
+        let default = Duration::from_secs(60);
+        assert_eq!(
+            policy.idle_threshold(default, utc("2024-01-05T23:00:00Z")),
+            None
+        );
+        assert_eq!(
+            policy.idle_threshold(default, utc("2024-01-06T05:00:00Z")),
+            None
+        );
+        // Friday early morning belongs to Thursday's window, which doesn't exist
+        assert_eq!(
+            policy.idle_threshold(default, utc("2024-01-05T05:00:00Z")),
+            Some(default)
+        );
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        let policy = InactivityPolicy {
+            windows: vec![window(vec![], "25:00", "06:00", None)],
+            ..Default::default()
+        };
+        assert!(validate_inactivity_policy(&policy).is_err());
+        let policy = InactivityPolicy {
+            timezone: Some("Nowhere/Special".to_string()),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! IP drift detection.
+//!
+//! DHCP may hand a sleeping machine a new address, which silently breaks its forwards.
+//! Devices found by network scans, both scans run through the API and background scans
+//! every `WAKEZILLA__NETWORK__DISCOVERY_INTERVAL_SECS`, are matched against the MACs of
+//! known machines. A machine seen at a new address is updated or gets an [`IpProposal`],
+//! depending on `WAKEZILLA__NETWORK__IP_DRIFT`.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{Config, IpDriftMode};
+use crate::events::{unix_now, Cause, EventKind};
+use crate::scanner::{self, DiscoveredDevice};
+use crate::web::{self, AppState, IpProposal, Machine};
+use crate::wol;
+
+/// An interface of a known machine seen at another address than the configured one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    /// ID of the machine
+    pub id: String,
+    /// MAC of the interface that moved
+    pub mac: String,
+    pub from: Ipv4Addr,
+    pub to: Ipv4Addr,
+}
+
+/// Interfaces of `machines` that `devices` were found at a new address. Machines
+/// configured by hostname follow DNS instead, and interfaces without an address are left
+/// alone.
+pub fn detect(machines: &[Machine], devices: &[DiscoveredDevice]) -> Vec<Drift> {
+    let mut drifts = Vec::new();
+    for device in devices {
+        let (Ok(mac), Ok(to)) = (wol::normalize_mac(&device.mac), device.ip.parse()) else {
+            continue;
+        };
+        let Some(machine) = machines.iter().find(|m| m.macs().any(|own| own == mac)) else {
+            continue;
+        };
+        let from = if mac == machine.mac {
+            machine.hostname.is_none().then_some(machine.ip)
+        } else {
+            machine
+                .interfaces
+                .iter()
+                .find(|i| i.mac == mac)
+                .and_then(|i| i.ip)
+        };
+        match from {
+            Some(from) if from != to => drifts.push(Drift {
+                id: machine.id.clone(),
+                mac,
+                from,
+                to,
+            }),
+            _ => {}
+        }
+    }
+    drifts
+}
+
+/// Move the interface `mac` of `machine` to `ip`, settling a pending proposal for that interface.
+pub fn apply(machine: &mut Machine, mac: &str, ip: Ipv4Addr) {
+    if machine.mac == mac {
+        machine.ip = ip;
+    } else if let Some(interface) = machine.interfaces.iter_mut().find(|i| i.mac == mac) {
+        interface.ip = Some(ip);
+    }
+    if machine.ip_proposal.as_ref().is_some_and(|p| p.mac == mac) {
+        machine.ip_proposal = None;
+    }
+}
+
+/// Update or propose the addresses of known machines found at new ones in `devices`.
+pub async fn handle_devices(state: &AppState, devices: &[DiscoveredDevice], mode: IpDriftMode) {
+    if mode == IpDriftMode::Off {
+        return;
+    }
+    let mut machines = state.machines.write().await;
+    let drifts = detect(&machines, devices);
+    let mut events = Vec::new();
+    // Machines whose forwarders need a restart, with every address they moved away from
+    let mut moved: Vec<(Machine, Vec<Ipv4Addr>)> = Vec::new();
+    for drift in drifts {
+        let Some(machine) = machines.iter_mut().find(|m| m.id == drift.id) else {
+            continue;
+        };
+        let (interface, from, to) = (
+            drift.mac.clone(),
+            drift.from.to_string(),
+            drift.to.to_string(),
+        );
+        match mode {
+            IpDriftMode::Update => {
+                info!(
+                    "{} ({}) moved from {} to {}",
+                    machine.name, drift.mac, drift.from, drift.to
+                );
+                apply(machine, &drift.mac, drift.to);
+                events.push((
+                    machine.mac.clone(),
+                    EventKind::IpChanged {
+                        interface,
+                        from,
+                        to,
+                    },
+                ));
+                match moved.iter_mut().find(|(m, _)| m.id == machine.id) {
+                    Some((m, froms)) => {
+                        *m = machine.clone();
+                        froms.push(drift.from);
+                    }
+                    None => moved.push((machine.clone(), vec![drift.from])),
+                }
+            }
+            _ => {
+                let seen = machine
+                    .ip_proposal
+                    .as_ref()
+                    .is_some_and(|p| p.mac == drift.mac && p.ip == drift.to);
+                if seen {
+                    continue;
+                }
+                info!(
+                    "{} ({}) was seen at {} instead of {}, waiting for the change to be accepted",
+                    machine.name, drift.mac, drift.to, drift.from
+                );
+                machine.ip_proposal = Some(IpProposal {
+                    mac: drift.mac,
+                    ip: drift.to,
+                    seen_at: unix_now(),
+                });
+                events.push((
+                    machine.mac.clone(),
+                    EventKind::IpChangeProposed {
+                        interface,
+                        from,
+                        to,
+                    },
+                ));
+            }
+        }
+    }
+    if events.is_empty() {
+        return;
+    }
+    if let Err(e) = web::save_machines(&machines) {
+        error!("Error saving machines: {}", e);
+    }
+    drop(machines);
+
+    let cause = Cause::new("neighbor discovery", "seen at a new address");
+    for (mac, kind) in events {
+        state.events.emit_caused(&mac, kind, &cause);
+    }
+    for (machine, froms) in moved {
+        for from in froms {
+            forget_address(state, &machine, from).await;
+        }
+        web::restart_proxies(state, &machine).await;
+    }
+}
+
+/// Drop pooled connections to the address a machine moved away from.
+pub async fn forget_address(state: &AppState, machine: &Machine, ip: Ipv4Addr) {
+    for pf in &machine.port_forwards {
+        state
+            .connection_pool
+            .remove_target(SocketAddr::new(ip.into(), pf.target_port))
+            .await;
+    }
+}
+
+/// Follow scans run through the API and scan the network every discovery interval.
+pub fn start(state: &AppState, config: &Config) -> Option<tokio::task::JoinHandle<()>> {
+    let mode = config.network.ip_drift;
+    if mode == IpDriftMode::Off {
+        return None;
+    }
+    let state = state.clone();
+    let interval = config.discovery_interval();
+    let interface = config.network.discovery_interface.clone();
+    let mut rx = state.events.subscribe();
+    match interval {
+        Some(interval) => info!("Looking for moved machines every {:?}", interval),
+        None => info!("Looking for moved machines in the results of network scans"),
+    }
+    Some(tokio::spawn(async move {
+        // Scan after the first interval rather than during startup
+        let mut ticker = interval.map(|interval| {
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+        });
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => {
+                        if let EventKind::ScanCompleted { devices } = event.kind {
+                            handle_devices(&state, &devices, mode).await;
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("IP drift detection missed {} events", missed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                },
+                _ = tick(&mut ticker) => {
+                    let started = std::time::Instant::now();
+                    let result = scanner::NetworkInterface::scan_network_with_interface(interface.as_deref()).await;
+                    state.metrics.observe_scan(started.elapsed());
+                    match result {
+                        Ok(devices) => handle_devices(&state, &devices, mode).await,
+                        Err(e) => warn!("Background network scan failed: {:#}", e),
+                    }
+                }
+            }
+        }
+    }))
+}
+
+/// The next tick of `ticker`, never without one.
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::machine;
+    use crate::web::MachineInterface;
+
+    fn device(mac: &str, ip: &str) -> DiscoveredDevice {
+        DiscoveredDevice {
+            ip: ip.to_string(),
+            mac: mac.to_string(),
+            hostname: None,
+        }
+    }
+
+    #[test]
+    fn moved_interfaces_of_known_machines_are_detected() {
+        let mut desktop = machine("AA:BB:CC:DD:EE:01");
+        desktop.id = "desktop".to_string();
+        desktop.ip = Ipv4Addr::new(192, 168, 1, 10);
+        desktop.interfaces = vec![MachineInterface {
+            mac: "AA:BB:CC:DD:EE:02".to_string(),
+            ip: Some(Ipv4Addr::new(192, 168, 1, 11)),
+            wake_on_lan: false,
+            priority: 1,
+        }];
+        let mut nas = machine("AA:BB:CC:DD:EE:03");
+        nas.id = "nas".to_string();
+        nas.hostname = Some("nas.lan".to_string());
+
+        let devices = vec![
+            // Scanners report MACs in lower case
+            device("aa:bb:cc:dd:ee:01", "192.168.1.20"),
+            device("aa:bb:cc:dd:ee:02", "192.168.1.11"),
+            // Machines configured by hostname follow DNS
+            device("aa:bb:cc:dd:ee:03", "192.168.1.30"),
+            device("aa:bb:cc:dd:ee:99", "192.168.1.40"),
+        ];
+        let drifts = detect(&[desktop.clone(), nas], &devices);
+        assert_eq!(
+            drifts,
+            vec![Drift {
+                id: "desktop".to_string(),
+                mac: "AA:BB:CC:DD:EE:01".to_string(),
+                from: Ipv4Addr::new(192, 168, 1, 10),
+                to: Ipv4Addr::new(192, 168, 1, 20),
+            }]
+        );
+
+        apply(
+            &mut desktop,
+            "AA:BB:CC:DD:EE:02",
+            Ipv4Addr::new(192, 168, 1, 21),
+        );
+        assert_eq!(
+            desktop.interfaces[0].ip,
+            Some(Ipv4Addr::new(192, 168, 1, 21))
+        );
+        apply(
+            &mut desktop,
+            "AA:BB:CC:DD:EE:01",
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        assert_eq!(desktop.ip, Ipv4Addr::new(192, 168, 1, 20));
+    }
+
+    #[test]
+    fn applying_one_interface_keeps_the_proposal_of_another() {
+        let mut desktop = machine("AA:BB:CC:DD:EE:01");
+        desktop.interfaces = vec![MachineInterface {
+            mac: "AA:BB:CC:DD:EE:02".to_string(),
+            ip: Some(Ipv4Addr::new(192, 168, 1, 11)),
+            wake_on_lan: false,
+            priority: 1,
+        }];
+        desktop.ip_proposal = Some(IpProposal {
+            mac: "AA:BB:CC:DD:EE:02".to_string(),
+            ip: Ipv4Addr::new(192, 168, 1, 21),
+            seen_at: 0,
+        });
+
+        apply(
+            &mut desktop,
+            "AA:BB:CC:DD:EE:01",
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        assert!(desktop.ip_proposal.is_some());
+        apply(
+            &mut desktop,
+            "AA:BB:CC:DD:EE:02",
+            Ipv4Addr::new(192, 168, 1, 21),
+        );
+        assert!(desktop.ip_proposal.is_none());
+    }
+}
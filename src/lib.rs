@@ -1,10 +1,25 @@
+pub mod auth;
 pub mod client_server;
 pub mod config;
 pub mod connection_pool;
+pub mod dependencies;
+pub mod ethtool;
+pub mod event_log;
+pub mod events;
 pub mod forward;
+pub mod hooks;
+pub mod inactivity;
+pub mod ip_drift;
+pub mod machine_state;
+pub mod metrics;
+pub mod power;
 pub mod proxy_server;
+pub mod resolver;
 pub mod scanner;
+pub mod schedule;
 pub mod system;
+pub mod tls;
+pub mod wake_policy;
 pub mod web;
 pub mod wol;
 
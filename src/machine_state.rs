@@ -0,0 +1,297 @@
+//! Live power state of every machine.
+//!
+//! The [`StateTracker`] held in `AppState` probes every machine on the health check
+//! interval and follows the wake and shutdown events published on the [`EventBus`], so the
+//! API can report whether a machine is offline, waking, online or shutting down without
+//! asking the machine on every request.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::events::{unix_now, EventBus, EventKind};
+use crate::power;
+use crate::resolver;
+use crate::web::{AppState, Machine};
+
+/// How long a machine may take to come up after a wake request before it counts as offline
+const WAKE_TIMEOUT_SECS: u64 = 5 * 60;
+/// How long a machine may take to go dark after a shutdown request before it counts as online
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    /// Not probed yet, or the machine has no port to probe
+    #[default]
+    Unknown,
+    Offline,
+    /// A wake request was sent and the machine hasn't answered yet
+    Waking,
+    Online,
+    /// A shutdown request was sent and the machine still answers
+    ShuttingDown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct MachineState {
+    pub state: PowerState,
+    /// Unix timestamp in seconds of the last state change, `None` while unknown
+    pub state_since: Option<u64>,
+    /// Unix timestamp in seconds of the last probe
+    pub last_checked: Option<u64>,
+}
+
+impl MachineState {
+    /// State after a probe answered `up` at `now`; `None` when there was nothing to probe.
+    /// Pending wakes and shutdowns win over the probe until they time out.
+    fn after_probe(&self, up: Option<bool>, now: u64) -> PowerState {
+        let elapsed = now.saturating_sub(self.state_since.unwrap_or(now));
+        match (self.state, up) {
+            (_, Some(true)) if self.state != PowerState::ShuttingDown => PowerState::Online,
+            (PowerState::ShuttingDown, Some(true)) if elapsed < SHUTDOWN_TIMEOUT_SECS => {
+                PowerState::ShuttingDown
+            }
+            (_, Some(true)) => PowerState::Online,
+            (PowerState::Waking, _) if elapsed < WAKE_TIMEOUT_SECS => PowerState::Waking,
+            (_, Some(false)) => PowerState::Offline,
+            (PowerState::ShuttingDown, None) if elapsed < SHUTDOWN_TIMEOUT_SECS => {
+                PowerState::ShuttingDown
+            }
+            (_, None) => PowerState::Unknown,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StateTracker {
+    states: Arc<Mutex<HashMap<String, MachineState>>>,
+    events: EventBus,
+}
+
+impl StateTracker {
+    pub fn new(events: EventBus) -> Self {
+        Self {
+            states: Arc::new(Mutex::new(HashMap::new())),
+            events,
+        }
+    }
+
+    pub fn get(&self, mac: &str) -> MachineState {
+        let states = self.states.lock().unwrap();
+        states.get(mac).cloned().unwrap_or_default()
+    }
+
+    /// Move `mac` to `state`, emitting a `StateChanged` event when it differs. A wake
+    /// only counts as failed when the machine is still offline after `WAKE_TIMEOUT_SECS`;
+    /// a shutdown request or a machine without a port to probe ends it without a verdict.
+    pub fn set(&self, mac: &str, state: PowerState) {
+        let now = unix_now();
+        let (from, elapsed) = {
+            let mut states = self.states.lock().unwrap();
+            let entry = states.entry(mac.to_string()).or_default();
+            if entry.state == state {
+                return;
+            }
+            let from = entry.state;
+            let elapsed = now.saturating_sub(entry.state_since.unwrap_or(now));
+            entry.state = state;
+            entry.state_since = Some(now);
+            (from, elapsed)
+        };
+        self.events
+            .emit(mac, EventKind::StateChanged { from, to: state });
+        match (from, state) {
+            (PowerState::Waking, PowerState::Online) => {
+                self.events.emit(mac, EventKind::WakeSucceeded)
+            }
+            (PowerState::Waking, PowerState::Offline) if elapsed >= WAKE_TIMEOUT_SECS => {
+                self.events.emit(
+                    mac,
+                    EventKind::WakeFailed {
+                        error: format!("not reachable within {}s", WAKE_TIMEOUT_SECS),
+                    },
+                )
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply the result of probing `mac`, see [`MachineState::after_probe`].
+    pub fn record_probe(&self, mac: &str, up: Option<bool>) {
+        let now = unix_now();
+        let next = {
+            let mut states = self.states.lock().unwrap();
+            let entry = states.entry(mac.to_string()).or_default();
+            entry.last_checked = Some(now);
+            entry.after_probe(up, now)
+        };
+        self.set(mac, next);
+    }
+
+    /// Forget machines that are no longer configured.
+    fn retain(&self, machines: &[Machine]) {
+        let mut states = self.states.lock().unwrap();
+        states.retain(|mac, _| machines.iter().any(|m| &m.mac == mac));
+    }
+
+    /// Follow wake and shutdown events published by the API and the inactivity monitor.
+    pub fn start_listener(&self) -> tokio::task::JoinHandle<()> {
+        let tracker = self.clone();
+        let mut rx = self.events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("State tracker missed {} events", missed);
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                };
+                let Some(mac) = event.mac else {
+                    continue;
+                };
+                match event.kind {
+                    EventKind::WakeRequested => tracker.set(&mac, PowerState::Waking),
+                    EventKind::ShutdownRequested => tracker.set(&mac, PowerState::ShuttingDown),
+                    EventKind::ShutdownVerified { .. } => tracker.set(&mac, PowerState::Offline),
+                    // Every attempt failed because the host was still reachable
+                    EventKind::ShutdownFailed { .. } => tracker.set(&mac, PowerState::Online),
+                    _ => {}
+                }
+            }
+        })
+    }
+}
+
+/// Probe every machine of `state` once per `interval`, and store the addresses machines
+/// configured by hostname resolve to.
+pub fn start_poller(state: &AppState, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let state = state.clone();
+    info!("Checking machine states every {:?}", interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let machines = state.machines.read().await.clone();
+            state.machine_states.retain(&machines);
+            let probes = machines.iter().map(|machine| async move {
+                (machine.mac.clone(), power::is_host_up(machine).await)
+            });
+            for (mac, up) in futures_util::future::join_all(probes).await {
+                state.machine_states.record_probe(&mac, up);
+            }
+            resolver::remember_addresses(&state, &machines).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(state: PowerState, since: u64) -> MachineState {
+        MachineState {
+            state,
+            state_since: Some(since),
+            last_checked: None,
+        }
+    }
+
+    #[test]
+    fn probes_move_machines_online_and_offline() {
+        let unknown = MachineState::default();
+        assert_eq!(unknown.after_probe(Some(true), 100), PowerState::Online);
+        assert_eq!(unknown.after_probe(Some(false), 100), PowerState::Offline);
+        assert_eq!(unknown.after_probe(None, 100), PowerState::Unknown);
+        assert_eq!(
+            state(PowerState::Online, 0).after_probe(None, 100),
+            PowerState::Unknown
+        );
+    }
+
+    #[test]
+    fn pending_wakes_and_shutdowns_wait_for_their_timeout() {
+        let waking = state(PowerState::Waking, 1000);
+        assert_eq!(waking.after_probe(Some(false), 1030), PowerState::Waking);
+        assert_eq!(waking.after_probe(Some(true), 1030), PowerState::Online);
+        assert_eq!(
+            waking.after_probe(Some(false), 1000 + WAKE_TIMEOUT_SECS),
+            PowerState::Offline
+        );
+
+        let stopping = state(PowerState::ShuttingDown, 1000);
+        assert_eq!(
+            stopping.after_probe(Some(true), 1030),
+            PowerState::ShuttingDown
+        );
+        assert_eq!(stopping.after_probe(Some(false), 1030), PowerState::Offline);
+        assert_eq!(
+            stopping.after_probe(Some(true), 1000 + SHUTDOWN_TIMEOUT_SECS),
+            PowerState::Online
+        );
+    }
+
+    #[tokio::test]
+    async fn events_drive_transitions_and_changes_are_published() {
+        let events = EventBus::new();
+        let tracker = StateTracker::new(events.clone());
+        let mut rx = events.subscribe();
+        let listener = tracker.start_listener();
+
+        events.emit("AA:BB:CC:DD:EE:FF", EventKind::WakeRequested);
+        let mut changed = None;
+        for _ in 0..10 {
+            match rx.recv().await.unwrap().kind {
+                EventKind::StateChanged { from, to } => {
+                    changed = Some((from, to));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+        assert_eq!(changed, Some((PowerState::Unknown, PowerState::Waking)));
+        let current = tracker.get("AA:BB:CC:DD:EE:FF");
+        assert_eq!(current.state, PowerState::Waking);
+        assert!(current.state_since.is_some());
+
+        tracker.record_probe("AA:BB:CC:DD:EE:FF", Some(true));
+        assert_eq!(tracker.get("AA:BB:CC:DD:EE:FF").state, PowerState::Online);
+        listener.abort();
+    }
+
+    #[test]
+    fn wakes_only_fail_when_offline_past_the_timeout() {
+        let events = EventBus::new();
+        let tracker = StateTracker::new(events.clone());
+        let mut rx = events.subscribe();
+        let mut wake_failures = || {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter(|event| matches!(event.kind, EventKind::WakeFailed { .. }))
+                .count()
+        };
+        let mac = "AA:BB:CC:DD:EE:FF";
+
+        tracker.set(mac, PowerState::Waking);
+        tracker.set(mac, PowerState::ShuttingDown);
+        tracker.set(mac, PowerState::Waking);
+        tracker.set(mac, PowerState::Offline);
+        tracker.set(mac, PowerState::Waking);
+        tracker.set(mac, PowerState::Unknown);
+        assert_eq!(wake_failures(), 0);
+
+        tracker.set(mac, PowerState::Waking);
+        tracker
+            .states
+            .lock()
+            .unwrap()
+            .get_mut(mac)
+            .unwrap()
+            .state_since = Some(unix_now() - WAKE_TIMEOUT_SECS);
+        tracker.set(mac, PowerState::Offline);
+        assert_eq!(wake_failures(), 1);
+    }
+}
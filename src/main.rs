@@ -3,13 +3,28 @@ use clap::{Parser, Subcommand};
 use std::net::{IpAddr, Ipv4Addr};
 use tracing::{error, info, instrument, warn};
 
+mod auth;
 mod client_server;
 mod config;
 mod connection_pool;
+mod dependencies;
+mod ethtool;
+mod event_log;
+mod events;
 mod forward;
+mod hooks;
+mod inactivity;
+mod ip_drift;
+mod machine_state;
+mod metrics;
+mod power;
 mod proxy_server;
+mod resolver;
 mod scanner;
+mod schedule;
 mod system;
+mod tls;
+mod wake_policy;
 mod web;
 mod wol;
 
@@ -32,6 +47,63 @@ pub enum Commands {
     ProxyServer(ServeArgs),
     /// Start a client server
     ClientServer(ClientServerArgs),
+    /// Start the proxy server with the client agent built in, for a proxy host that should
+    /// itself be shut down or queried like any other machine
+    AllInOne(AllInOneArgs),
+    /// Manage users of the web interface
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage API tokens for scripts and agents
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Wake, turn off or check the machines of a group (tag) through a running proxy server
+    Group(GroupArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Add a user, or reset an existing user's password; the password is read from stdin
+    Add {
+        username: String,
+        /// viewer, waker, operator or admin (default: viewer for new users, unchanged otherwise)
+        #[arg(long)]
+        role: Option<auth::Role>,
+    },
+    /// Change a user's role and limit them to some machines; without --machine and --tag
+    /// the user may access every machine
+    Grant {
+        username: String,
+        /// viewer, waker, operator or admin
+        #[arg(long)]
+        role: Option<auth::Role>,
+        /// ID or MAC address of a machine the user may access, repeatable
+        #[arg(long = "machine", value_name = "ID_OR_MAC")]
+        machines: Vec<String>,
+        /// Tag of machines the user may access, repeatable
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
+    /// Remove a user and their API tokens
+    Remove { username: String },
+    /// List users
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokenCommand {
+    /// Create an API token for a user and print it
+    Create {
+        username: String,
+        /// What the token is used for, e.g. "backup script"
+        name: String,
+        /// full, or register for a token that can only announce agents (admins only)
+        #[arg(long, default_value = "full")]
+        scope: auth::TokenScope,
+    },
+    /// List a user's API tokens
+    List { username: String },
+    /// Revoke an API token
+    Revoke { username: String, id: String },
 }
 
 #[derive(Parser, Debug)]
@@ -58,6 +130,103 @@ pub struct ClientServerArgs {
         help_heading = "Client Server Options"
     )]
     port: u16,
+
+    /// Proxy server URL to announce this machine to (e.g. http://proxy:3000)
+    #[arg(long, value_name = "URL", help_heading = "Client Server Options")]
+    register: Option<String>,
+
+    /// Interval in seconds between registration announcements
+    #[arg(long, default_value_t = 300, help_heading = "Client Server Options")]
+    register_interval_secs: u64,
+
+    /// SHA-256 fingerprint of the proxy's certificate to pin when registering over https
+    #[arg(
+        long,
+        value_name = "FINGERPRINT",
+        help_heading = "Client Server Options"
+    )]
+    register_fingerprint: Option<String>,
+
+    /// File holding the API token used to register with a proxy that requires
+    /// authentication (default: the WAKEZILLA_TOKEN environment variable)
+    #[arg(long, value_name = "FILE", help_heading = "Client Server Options")]
+    register_token_file: Option<std::path::PathBuf>,
+
+    /// File holding the shared secret callers of `POST /wol/<iface>/enable` must send as a
+    /// bearer token; required with --allow-wol-config
+    #[arg(long, value_name = "FILE", help_heading = "Client Server Options")]
+    wol_config_token_file: Option<std::path::PathBuf>,
+
+    #[command(flatten)]
+    agent: AgentArgs,
+}
+
+/// Options of the client agent, shared by `client-server` and `all-in-one`.
+#[derive(clap::Args, Debug)]
+pub struct AgentArgs {
+    /// Allow enabling magic packet Wake-on-LAN on this machine's NICs through the API
+    #[arg(long, help_heading = "Agent Options")]
+    allow_wol_config: bool,
+
+    /// Directory of executable hooks run in order before shutting down; a failing hook aborts the shutdown
+    #[arg(long, value_name = "DIR", help_heading = "Agent Options")]
+    pre_shutdown_hooks: Option<std::path::PathBuf>,
+
+    /// Directory of executable hooks run in order after resuming or booting from an agent shutdown
+    #[arg(long, value_name = "DIR", help_heading = "Agent Options")]
+    post_resume_hooks: Option<std::path::PathBuf>,
+
+    /// Timeout in seconds for each hook
+    #[arg(long, default_value_t = 60, help_heading = "Agent Options")]
+    hook_timeout_secs: u64,
+}
+
+impl AgentArgs {
+    fn hooks(&self, config: &config::Config) -> hooks::HookConfig {
+        hooks::HookConfig {
+            pre_shutdown_dir: self.pre_shutdown_hooks.clone(),
+            post_resume_dir: self.post_resume_hooks.clone(),
+            timeout: std::time::Duration::from_secs(self.hook_timeout_secs.max(1)),
+            budget: hooks::DEFAULT_TOTAL_BUDGET,
+            state_dir: config.storage.agent_state_dir(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command()]
+pub struct AllInOneArgs {
+    #[command(flatten)]
+    agent: AgentArgs,
+}
+
+#[derive(Parser, Debug)]
+#[command()]
+pub struct GroupArgs {
+    #[command(subcommand)]
+    action: GroupAction,
+    /// URL of the proxy server (default: http://localhost:<proxy port>)
+    #[arg(long, global = true, value_name = "URL")]
+    server: Option<String>,
+    /// File holding an API token, see `wakezilla token create` (default: the
+    /// WAKEZILLA_TOKEN environment variable)
+    #[arg(long, global = true, value_name = "FILE")]
+    token_file: Option<std::path::PathBuf>,
+    /// SHA-256 fingerprint to pin when the server uses a self-signed certificate
+    #[arg(long, global = true)]
+    fingerprint: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GroupAction {
+    /// List groups and how many machines they have
+    List,
+    /// Show the power state of every machine in a group
+    Status { name: String },
+    /// Wake every machine in a group, one after the other
+    Wake { name: String },
+    /// Turn off every machine in a group
+    TurnOff { name: String },
 }
 
 #[derive(Parser, Debug)]
@@ -131,22 +300,247 @@ async fn main() -> Result<()> {
             handle_send_command(args, &config)?;
         }
         Commands::ProxyServer(_args) => {
-            if let Err(e) = proxy_server::start(config.server.proxy_port).await {
+            if let Err(e) = proxy_server::start(&config, None).await {
                 error!("Proxy server error: {}", e);
                 std::process::exit(1);
             }
         }
-        Commands::ClientServer(_args) => {
-            if let Err(e) = client_server::start(config.server.client_port).await {
+        Commands::ClientServer(args) => {
+            let token = read_token(args.register_token_file.as_deref())?;
+            let registration = args.register.map(|proxy_url| client_server::Registration {
+                proxy_url,
+                interval: std::time::Duration::from_secs(args.register_interval_secs.max(1)),
+                proxy_fingerprint: args.register_fingerprint,
+                token,
+            });
+            let wol_config_token = match args.wol_config_token_file.as_deref() {
+                Some(file) => read_token(Some(file))?,
+                None => None,
+            };
+            if args.agent.allow_wol_config && wol_config_token.is_none() {
+                anyhow::bail!("--allow-wol-config requires --wol-config-token-file");
+            }
+            let options = client_server::ClientOptions {
+                registration,
+                allow_wol_config: args.agent.allow_wol_config,
+                wol_config_token,
+                hooks: args.agent.hooks(&config),
+                tls: tls::TlsFiles::from_config(&config.server),
+            };
+            if let Err(e) = client_server::start(config.server.client_port, options).await {
                 error!("Client server error: {}", e);
                 std::process::exit(1);
             }
         }
+        Commands::AllInOne(args) => {
+            let agent = client_server::ClientOptions {
+                registration: None,
+                allow_wol_config: args.agent.allow_wol_config,
+                // The proxy's authentication already guards the local agent routes
+                wol_config_token: None,
+                hooks: args.agent.hooks(&config),
+                tls: tls::TlsFiles::from_config(&config.server),
+            };
+            if let Err(e) = proxy_server::start(&config, Some(agent)).await {
+                error!("All-in-one server error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::User(command) => handle_user_command(command, &config)?,
+        Commands::Token(command) => handle_token_command(command, &config)?,
+        Commands::Group(args) => handle_group_command(args, &config).await?,
     }
 
     Ok(())
 }
 
+fn handle_user_command(command: UserCommand, config: &config::Config) -> Result<()> {
+    let auth = auth::Auth::open(&config.storage.users_db_path())?;
+    match command {
+        UserCommand::Add { username, role } => {
+            let password = read_password()?;
+            auth.add_user(&username, &password, role)?;
+            println!("Saved user {}", username);
+        }
+        UserCommand::Grant {
+            username,
+            role,
+            machines,
+            tags,
+        } => {
+            auth.set_access(&username, role, auth::Grants { machines, tags })?;
+            println!("Updated access of {}", username);
+        }
+        UserCommand::Remove { username } => {
+            if !auth.remove_user(&username)? {
+                anyhow::bail!("No user named {}", username);
+            }
+            println!("Removed user {}", username);
+        }
+        UserCommand::List => {
+            for user in auth.users() {
+                let scope = if user.grants.is_unrestricted() {
+                    "all machines".to_string()
+                } else {
+                    [user.grants.machines, user.grants.tags].concat().join(", ")
+                };
+                println!("{}\t{}\t{}", user.username, user.role, scope);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_token_command(command: TokenCommand, config: &config::Config) -> Result<()> {
+    let auth = auth::Auth::open(&config.storage.users_db_path())?;
+    match command {
+        TokenCommand::Create {
+            username,
+            name,
+            scope,
+        } => {
+            let (token, secret) = auth.create_token(&username, &name, scope)?;
+            eprintln!(
+                "Created token {} ({}), it is only shown once:",
+                token.id, token.name
+            );
+            println!("{}", secret);
+        }
+        TokenCommand::List { username } => {
+            for token in auth.tokens(&username) {
+                println!("{}\t{}\t{}", token.id, token.name, token.created_at);
+            }
+        }
+        TokenCommand::Revoke { username, id } => {
+            if !auth.revoke_token(&username, &id)? {
+                anyhow::bail!("No token {} for user {}", id, username);
+            }
+            println!("Revoked token {}", id);
+        }
+    }
+    Ok(())
+}
+
+/// Environment variable holding an API token when no token file is given.
+const TOKEN_ENV: &str = "WAKEZILLA_TOKEN";
+
+/// Reads an API token from `file`, or from `WAKEZILLA_TOKEN` without one. Tokens are never
+/// taken from the command line, where `ps` and the shell history would show them.
+fn read_token(file: Option<&std::path::Path>) -> Result<Option<String>> {
+    let Some(file) = file else {
+        return Ok(std::env::var(TOKEN_ENV)
+            .ok()
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty()));
+    };
+    let token = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read token file {}", file.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("Token file {} is empty", file.display());
+    }
+    Ok(Some(token.to_string()))
+}
+
+async fn handle_group_command(args: GroupArgs, config: &config::Config) -> Result<()> {
+    let server = args
+        .server
+        .unwrap_or_else(|| format!("http://localhost:{}", config.server.proxy_port));
+    let server = server.trim_end_matches('/');
+    let client = tls::pinned_client_builder(args.fingerprint.as_deref())?
+        .timeout(std::time::Duration::from_secs(300))
+        .build()?;
+    let (method, segments) = match &args.action {
+        GroupAction::List => (reqwest::Method::GET, vec!["api", "groups"]),
+        GroupAction::Status { name } => (reqwest::Method::GET, vec!["api", "groups", name]),
+        GroupAction::Wake { name } => (reqwest::Method::POST, vec!["api", "groups", name, "wake"]),
+        GroupAction::TurnOff { name } => (
+            reqwest::Method::POST,
+            vec!["api", "groups", name, "turn-off"],
+        ),
+    };
+    // Group names are tags, which may contain `/`, `?` or spaces; the segments are encoded
+    let mut url = reqwest::Url::parse(server).with_context(|| format!("Invalid URL {}", server))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid URL {}", server))?
+        .pop_if_empty()
+        .extend(segments);
+    let mut request = client.request(method, url);
+    if let Some(token) = read_token(args.token_file.as_deref())? {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", server))?;
+    let status = response.status();
+    let body: serde_json::Value =
+        serde_json::from_slice(&response.bytes().await?).unwrap_or_default();
+    if !status.is_success() {
+        anyhow::bail!(
+            "server answered with status {}: {}",
+            status,
+            body["error"].as_str().unwrap_or_default()
+        );
+    }
+
+    let text = |value: &serde_json::Value| match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    match args.action {
+        GroupAction::List => {
+            for group in body.as_array().into_iter().flatten() {
+                println!("{}\t{}", text(&group["name"]), group["machines"]);
+            }
+        }
+        GroupAction::Status { .. } => {
+            for machine in body.as_array().into_iter().flatten() {
+                println!(
+                    "{}\t{}\t{}",
+                    text(&machine["mac"]),
+                    text(&machine["name"]),
+                    text(&machine["state"])
+                );
+            }
+        }
+        GroupAction::Wake { .. } | GroupAction::TurnOff { .. } => {
+            let results = body["results"].as_array().cloned().unwrap_or_default();
+            let failed = results.iter().filter(|r| r["success"] != true).count();
+            for result in &results {
+                let outcome = if result["success"] == true {
+                    "ok"
+                } else {
+                    "failed"
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    text(&result["mac"]),
+                    text(&result["name"]),
+                    outcome,
+                    text(&result["message"])
+                );
+            }
+            if failed > 0 {
+                anyhow::bail!("{} of {} machines failed", failed, results.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a password from the first line of stdin, prompting when it is a terminal.
+fn read_password() -> Result<String> {
+    use std::io::{BufRead, IsTerminal, Write};
+    if std::io::stdin().is_terminal() {
+        eprint!("Password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
 #[instrument(name = "handle_send_command", skip(args, config))]
 fn handle_send_command(args: SendArgs, config: &config::Config) -> Result<()> {
     info!("Processing WOL send command");
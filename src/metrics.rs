@@ -0,0 +1,560 @@
+//! Prometheus metrics.
+//!
+//! [`Metrics`] counts wakes and shutdowns by following the [`EventBus`], and is updated
+//! directly by the forwarders (connections and bytes) and the network scanner. `GET /metrics`
+//! renders it together with the machines' power states and the connection pool sizes in the
+//! Prometheus text format.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::events::{EventBus, EventKind};
+use crate::machine_state::{PowerState, StateTracker};
+use crate::web::Machine;
+
+/// Upper bounds in seconds of the wake-to-ready latency buckets
+const WAKE_BUCKETS: &[f64] = &[5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+/// Upper bounds in seconds of the scan duration buckets
+const SCAN_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+#[derive(Default)]
+struct MachineCounters {
+    wake_requests: u64,
+    wake_successes: u64,
+    wake_failures: u64,
+    shutdown_requests: u64,
+    shutdown_failures: u64,
+}
+
+/// Reads one counter of [`MachineCounters`]
+type CounterField = fn(&MachineCounters) -> u64;
+
+#[derive(Default)]
+struct ForwardCounters {
+    active: u64,
+    accepted: u64,
+    bytes_to_target: u64,
+    bytes_to_client: u64,
+}
+
+struct Inner {
+    machines: BTreeMap<String, MachineCounters>,
+    /// When the pending wake of each machine was requested
+    pending_wakes: HashMap<String, Instant>,
+    wake_duration: Histogram,
+    /// Keyed by MAC and local port
+    forwards: BTreeMap<(String, u16), ForwardCounters>,
+    scan_duration: Histogram,
+}
+
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                machines: BTreeMap::new(),
+                pending_wakes: HashMap::new(),
+                wake_duration: Histogram::new(WAKE_BUCKETS),
+                forwards: BTreeMap::new(),
+                scan_duration: Histogram::new(SCAN_BUCKETS),
+            }),
+        }
+    }
+
+    fn record_event(&self, mac: &str, kind: &EventKind) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        match kind {
+            EventKind::WakeRequested => {
+                inner
+                    .machines
+                    .entry(mac.to_string())
+                    .or_default()
+                    .wake_requests += 1;
+                inner
+                    .pending_wakes
+                    .entry(mac.to_string())
+                    .or_insert_with(Instant::now);
+            }
+            EventKind::WakeSucceeded => {
+                inner
+                    .machines
+                    .entry(mac.to_string())
+                    .or_default()
+                    .wake_successes += 1;
+                if let Some(requested) = inner.pending_wakes.remove(mac) {
+                    inner
+                        .wake_duration
+                        .observe(requested.elapsed().as_secs_f64());
+                }
+            }
+            EventKind::WakeFailed { .. } => {
+                inner
+                    .machines
+                    .entry(mac.to_string())
+                    .or_default()
+                    .wake_failures += 1;
+                inner.pending_wakes.remove(mac);
+            }
+            EventKind::ShutdownRequested => {
+                inner
+                    .machines
+                    .entry(mac.to_string())
+                    .or_default()
+                    .shutdown_requests += 1;
+                // A shutdown supersedes any wake still waiting to be timed
+                inner.pending_wakes.remove(mac);
+            }
+            EventKind::ShutdownFailed { .. } => {
+                inner
+                    .machines
+                    .entry(mac.to_string())
+                    .or_default()
+                    .shutdown_failures += 1;
+            }
+            // A wake that ended anywhere but online must not time a later, unrelated boot
+            EventKind::StateChanged {
+                from: PowerState::Waking,
+                to,
+            } if *to != PowerState::Online => {
+                inner.pending_wakes.remove(mac);
+            }
+            _ => {}
+        }
+    }
+
+    /// Count wakes and shutdowns published on `events` from now on.
+    pub fn start(self: &Arc<Self>, events: &EventBus) -> tokio::task::JoinHandle<()> {
+        let metrics = self.clone();
+        let mut rx = events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        if let Some(mac) = &event.mac {
+                            metrics.record_event(mac, &event.kind);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Metrics missed {} events", missed)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    /// Count a connection accepted by the forwarder of `mac` on `local_port`. It stays active
+    /// until the returned guard is dropped.
+    pub fn connection_opened(self: &Arc<Self>, mac: &str, local_port: u16) -> ConnectionGuard {
+        let key = (mac.to_string(), local_port);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let forward = inner.forwards.entry(key.clone()).or_default();
+            forward.accepted += 1;
+            forward.active += 1;
+        }
+        ConnectionGuard {
+            metrics: self.clone(),
+            key,
+        }
+    }
+
+    pub fn observe_scan(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.scan_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Everything in the Prometheus text exposition format. `pool` is
+    /// [`ConnectionPool::get_stats`](crate::connection_pool::ConnectionPool::get_stats).
+    pub fn render(
+        &self,
+        machines: &[Machine],
+        states: &StateTracker,
+        pool: &HashMap<String, usize>,
+    ) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "wakezilla_machine_up",
+            "Whether the machine answers probes, absent while unknown",
+            "gauge",
+        );
+        for machine in machines {
+            let up = match states.get(&machine.mac).state {
+                PowerState::Unknown => continue,
+                PowerState::Online | PowerState::ShuttingDown => 1,
+                PowerState::Offline | PowerState::Waking => 0,
+            };
+            let _ = writeln!(
+                out,
+                "wakezilla_machine_up{{mac=\"{}\",name=\"{}\"}} {}",
+                escape(&machine.mac),
+                escape(&machine.name),
+                up
+            );
+        }
+        header(
+            &mut out,
+            "wakezilla_machine_state",
+            "Power state of the machine, 1 for the current state",
+            "gauge",
+        );
+        for machine in machines {
+            let current = states.get(&machine.mac).state;
+            for state in [
+                PowerState::Unknown,
+                PowerState::Offline,
+                PowerState::Waking,
+                PowerState::Online,
+                PowerState::ShuttingDown,
+            ] {
+                let _ = writeln!(
+                    out,
+                    "wakezilla_machine_state{{mac=\"{}\",name=\"{}\",state=\"{}\"}} {}",
+                    escape(&machine.mac),
+                    escape(&machine.name),
+                    state_label(state),
+                    u8::from(state == current)
+                );
+            }
+        }
+
+        let inner = self.inner.lock().unwrap();
+        let per_machine: [(&str, &str, CounterField); 5] = [
+            ("wakezilla_wake_requests_total", "Wake requests sent", |c| {
+                c.wake_requests
+            }),
+            (
+                "wakezilla_wake_successes_total",
+                "Wakes after which the machine came up",
+                |c| c.wake_successes,
+            ),
+            (
+                "wakezilla_wake_failures_total",
+                "Wakes that failed to send or after which the machine stayed down",
+                |c| c.wake_failures,
+            ),
+            (
+                "wakezilla_shutdown_requests_total",
+                "Shutdown requests sent",
+                |c| c.shutdown_requests,
+            ),
+            (
+                "wakezilla_shutdown_failures_total",
+                "Shutdowns after which the machine was still reachable",
+                |c| c.shutdown_failures,
+            ),
+        ];
+        for (name, help, value) in per_machine {
+            header(&mut out, name, help, "counter");
+            for (mac, counters) in &inner.machines {
+                let _ = writeln!(
+                    out,
+                    "{}{{mac=\"{}\"}} {}",
+                    name,
+                    escape(mac),
+                    value(counters)
+                );
+            }
+        }
+        inner.wake_duration.render(
+            &mut out,
+            "wakezilla_wake_duration_seconds",
+            "Time from a wake request until the machine answered probes",
+        );
+
+        header(
+            &mut out,
+            "wakezilla_forward_active_connections",
+            "Connections currently handled by a forwarder",
+            "gauge",
+        );
+        for ((mac, port), forward) in &inner.forwards {
+            let _ = writeln!(
+                out,
+                "wakezilla_forward_active_connections{{mac=\"{}\",local_port=\"{}\"}} {}",
+                escape(mac),
+                port,
+                forward.active
+            );
+        }
+        header(
+            &mut out,
+            "wakezilla_forward_connections_total",
+            "Connections accepted by a forwarder",
+            "counter",
+        );
+        for ((mac, port), forward) in &inner.forwards {
+            let _ = writeln!(
+                out,
+                "wakezilla_forward_connections_total{{mac=\"{}\",local_port=\"{}\"}} {}",
+                escape(mac),
+                port,
+                forward.accepted
+            );
+        }
+        header(
+            &mut out,
+            "wakezilla_forward_bytes_total",
+            "Bytes forwarded by closed connections",
+            "counter",
+        );
+        for ((mac, port), forward) in &inner.forwards {
+            for (direction, bytes) in [
+                ("to_target", forward.bytes_to_target),
+                ("to_client", forward.bytes_to_client),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "wakezilla_forward_bytes_total{{mac=\"{}\",local_port=\"{}\",direction=\"{}\"}} {}",
+                    escape(mac),
+                    port,
+                    direction,
+                    bytes
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "wakezilla_connection_pool_idle_connections",
+            "Idle connections kept per target",
+            "gauge",
+        );
+        let mut targets: Vec<_> = pool
+            .iter()
+            .filter(|(key, _)| *key != "total_pools")
+            .collect();
+        targets.sort();
+        for (target, size) in targets {
+            let _ = writeln!(
+                out,
+                "wakezilla_connection_pool_idle_connections{{target=\"{}\"}} {}",
+                escape(target),
+                size
+            );
+        }
+        header(
+            &mut out,
+            "wakezilla_connection_pool_targets",
+            "Targets with a connection pool",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "wakezilla_connection_pool_targets {}",
+            pool.get("total_pools").copied().unwrap_or_default()
+        );
+
+        inner.scan_duration.render(
+            &mut out,
+            "wakezilla_scan_duration_seconds",
+            "Duration of network scans",
+        );
+        out
+    }
+}
+
+/// Keeps a forwarded connection counted as active, see [`Metrics::connection_opened`].
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    key: (String, u16),
+}
+
+impl ConnectionGuard {
+    /// Count the bytes copied in each direction once the connection is done.
+    pub fn transferred(&self, to_target: u64, to_client: u64) {
+        let mut inner = self.metrics.inner.lock().unwrap();
+        if let Some(forward) = inner.forwards.get_mut(&self.key) {
+            forward.bytes_to_target += to_target;
+            forward.bytes_to_client += to_client;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut inner = self.metrics.inner.lock().unwrap();
+        if let Some(forward) = inner.forwards.get_mut(&self.key) {
+            forward.active = forward.active.saturating_sub(1);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn state_label(state: PowerState) -> &'static str {
+    match state {
+        PowerState::Unknown => "unknown",
+        PowerState::Offline => "offline",
+        PowerState::Waking => "waking",
+        PowerState::Online => "online",
+        PowerState::ShuttingDown => "shutting_down",
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::machine;
+
+    #[test]
+    fn renders_counters_histograms_and_machine_states() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.record_event("AA:BB:CC:DD:EE:FF", &EventKind::WakeRequested);
+        metrics.record_event("AA:BB:CC:DD:EE:FF", &EventKind::WakeSucceeded);
+        metrics.record_event(
+            "AA:BB:CC:DD:EE:FF",
+            &EventKind::ShutdownFailed {
+                attempts: 3,
+                error: "host still reachable".to_string(),
+            },
+        );
+        {
+            let connection = metrics.connection_opened("AA:BB:CC:DD:EE:FF", 8080);
+            connection.transferred(120, 4096);
+            let _still_open = metrics.connection_opened("AA:BB:CC:DD:EE:FF", 8080);
+            let text = metrics.render(&[], &StateTracker::new(EventBus::new()), &HashMap::new());
+            assert!(text.contains(
+                "wakezilla_forward_active_connections{mac=\"AA:BB:CC:DD:EE:FF\",local_port=\"8080\"} 2"
+            ));
+        }
+        metrics.observe_scan(Duration::from_secs(3));
+
+        let mut m = machine("AA:BB:CC:DD:EE:FF");
+        m.name = "nas \"main\"".to_string();
+        let states = StateTracker::new(EventBus::new());
+        states.record_probe(&m.mac, Some(true));
+        let pool = HashMap::from([
+            ("10.0.0.5:80".to_string(), 2),
+            ("total_pools".to_string(), 1),
+        ]);
+        let text = metrics.render(&[m], &states, &pool);
+
+        for line in [
+            "wakezilla_machine_up{mac=\"AA:BB:CC:DD:EE:FF\",name=\"nas \\\"main\\\"\"} 1",
+            "wakezilla_machine_state{mac=\"AA:BB:CC:DD:EE:FF\",name=\"nas \\\"main\\\"\",state=\"online\"} 1",
+            "wakezilla_wake_requests_total{mac=\"AA:BB:CC:DD:EE:FF\"} 1",
+            "wakezilla_wake_successes_total{mac=\"AA:BB:CC:DD:EE:FF\"} 1",
+            "wakezilla_shutdown_failures_total{mac=\"AA:BB:CC:DD:EE:FF\"} 1",
+            "wakezilla_wake_duration_seconds_bucket{le=\"5\"} 1",
+            "wakezilla_wake_duration_seconds_count 1",
+            "wakezilla_forward_active_connections{mac=\"AA:BB:CC:DD:EE:FF\",local_port=\"8080\"} 0",
+            "wakezilla_forward_connections_total{mac=\"AA:BB:CC:DD:EE:FF\",local_port=\"8080\"} 2",
+            "wakezilla_forward_bytes_total{mac=\"AA:BB:CC:DD:EE:FF\",local_port=\"8080\",direction=\"to_client\"} 4096",
+            "wakezilla_connection_pool_idle_connections{target=\"10.0.0.5:80\"} 2",
+            "wakezilla_connection_pool_targets 1",
+            "wakezilla_scan_duration_seconds_bucket{le=\"2\"} 0",
+            "wakezilla_scan_duration_seconds_bucket{le=\"5\"} 1",
+            "# TYPE wakezilla_scan_duration_seconds histogram",
+        ] {
+            assert!(text.contains(line), "missing {:?} in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn abandoned_wakes_are_not_timed() {
+        let metrics = Metrics::new();
+        let mac = "AA:BB:CC:DD:EE:FF";
+        let is_pending = || {
+            metrics
+                .inner
+                .lock()
+                .unwrap()
+                .pending_wakes
+                .contains_key(mac)
+        };
+
+        metrics.record_event(mac, &EventKind::WakeRequested);
+        metrics.record_event(mac, &EventKind::ShutdownRequested);
+        assert!(!is_pending());
+
+        metrics.record_event(mac, &EventKind::WakeRequested);
+        metrics.record_event(
+            mac,
+            &EventKind::StateChanged {
+                from: PowerState::Waking,
+                to: PowerState::Offline,
+            },
+        );
+        assert!(!is_pending());
+
+        metrics.record_event(mac, &EventKind::WakeRequested);
+        metrics.record_event(
+            mac,
+            &EventKind::StateChanged {
+                from: PowerState::Waking,
+                to: PowerState::Online,
+            },
+        );
+        assert!(is_pending());
+        metrics.record_event(mac, &EventKind::WakeSucceeded);
+        assert!(!is_pending());
+        let text = metrics.render(&[], &StateTracker::new(EventBus::new()), &HashMap::new());
+        assert!(text.contains("wakezilla_wake_duration_seconds_count 1"));
+    }
+}
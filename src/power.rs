@@ -0,0 +1,1277 @@
+//! Pluggable power-on and power-off backends.
+//!
+//! Every shutdown path (inactivity monitor, API, web interface) goes through
+//! [`power_off`], which dispatches on the machine's configured backend: the wakezilla
+//! client agent, a command run over SSH, an arbitrary HTTP request, a BMC's Redfish API,
+//! a smart plug, or the proxy process itself in all-in-one mode. Wakes go through
+//! [`power_on`], which sends magic packets unless the machine is powered on out-of-band
+//! through Redfish or a smart plug.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::client_server;
+use crate::events::{Cause, EventBus, EventKind};
+use crate::forward;
+use crate::hooks::HookConfig;
+use crate::resolver;
+use crate::web::Machine;
+use crate::wol;
+
+/// How a machine is powered on.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerOnBackend {
+    /// Wake-on-LAN magic packets to the machine's MAC
+    #[default]
+    MagicPacket,
+    /// `ComputerSystem.Reset` with `ResetType: On` on the machine's BMC
+    Redfish(RedfishConfig),
+    /// Switch the smart plug the machine is connected to on
+    SmartPlug(SmartPlugConfig),
+}
+
+/// Connection details of a BMC with a Redfish API (iDRAC, iLO, XClarity, OpenBMC, ...).
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RedfishConfig {
+    /// Base URL of the BMC, e.g. `https://10.0.0.20`
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /// Id under `/redfish/v1/Systems`, defaults to the first system the BMC lists
+    #[serde(default)]
+    pub system_id: Option<String>,
+    /// `ResetType` used to power off, e.g. `ForceOff` for machines without an OS agent
+    #[serde(default = "default_off_reset_type")]
+    pub off_reset_type: String,
+    /// BMCs usually ship with self-signed certificates
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+/// Smart plug firmware families with a local HTTP API.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlugDevice {
+    Tasmota,
+    /// Gen1 devices (`/relay/<id>`)
+    Shelly,
+    /// Gen2+ devices (`/rpc/Switch.Set`)
+    ShellyGen2,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SmartPlugConfig {
+    pub device: PlugDevice,
+    /// Base URL of the plug, e.g. `http://192.168.1.50`
+    pub url: String,
+    /// Zero-based relay/switch index for multi-channel devices
+    #[serde(default)]
+    pub relay: u8,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Shown in API responses and event diffs in place of credentials. Sending it back in an
+/// update keeps the stored value.
+pub const SECRET_MASK: &str = "********";
+
+fn mask(secret: &mut String) {
+    if !secret.is_empty() {
+        *secret = SECRET_MASK.to_string();
+    }
+}
+
+fn unmask(secret: &mut String, stored: &str) {
+    if secret == SECRET_MASK {
+        *secret = stored.to_string();
+    }
+}
+
+impl RedfishConfig {
+    fn redact(&mut self) {
+        mask(&mut self.password);
+    }
+
+    fn keep_secrets(&mut self, stored: &RedfishConfig) {
+        unmask(&mut self.password, &stored.password);
+    }
+}
+
+impl SmartPlugConfig {
+    /// Shelly Gen2 devices only accept digest authentication, which is not supported.
+    fn validate(&self) -> Result<(), String> {
+        let has_credentials = self.username.as_deref().is_some_and(|u| !u.is_empty())
+            || self.password.as_deref().is_some_and(|p| !p.is_empty());
+        if self.device == PlugDevice::ShellyGen2 && has_credentials {
+            return Err(
+                "shelly_gen2 plugs need digest authentication, which is not supported; \
+                 disable authentication on the plug and remove the credentials"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn redact(&mut self) {
+        if let Some(password) = &mut self.password {
+            mask(password);
+        }
+    }
+
+    fn keep_secrets(&mut self, stored: &SmartPlugConfig) {
+        if self.password.as_deref() == Some(SECRET_MASK) {
+            self.password = stored.password.clone();
+        }
+    }
+}
+
+fn default_off_reset_type() -> String {
+    "GracefulShutdown".to_string()
+}
+
+/// How a machine is powered off.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerOffBackend {
+    /// `POST /machines/turn-off` on the wakezilla client agent at `turn_off_port`
+    Agent {
+        /// One of the agent's `shutdown_actions`, e.g. `poweroff`; the agent decides if unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<String>,
+    },
+    /// Run `command` on the machine over SSH with key authentication
+    Ssh {
+        /// Defaults to the machine's IP
+        #[serde(default)]
+        host: Option<String>,
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+        user: String,
+        key_path: String,
+        #[serde(default = "default_ssh_command")]
+        command: String,
+    },
+    /// Call an HTTP endpoint, e.g. a Home Assistant webhook or a NAS API
+    Http {
+        url: String,
+        #[serde(default = "default_http_method")]
+        method: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        body: Option<String>,
+    },
+    /// `ComputerSystem.Reset` on the machine's BMC
+    Redfish(RedfishConfig),
+    /// Cut power at the smart plug; only for hosts that survive losing power
+    SmartPlug(SmartPlugConfig),
+    /// The host running the proxy in all-in-one mode, shut down in-process
+    Local {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<String>,
+    },
+}
+
+impl Default for PowerOffBackend {
+    fn default() -> Self {
+        PowerOffBackend::Agent { action: None }
+    }
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_ssh_command() -> String {
+    "sudo shutdown -h now".to_string()
+}
+
+fn default_http_method() -> String {
+    "POST".to_string()
+}
+
+impl PowerOffBackend {
+    /// Short human readable name used in logs and API messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PowerOffBackend::Agent { .. } => "agent",
+            PowerOffBackend::Ssh { .. } => "ssh",
+            PowerOffBackend::Http { .. } => "http",
+            PowerOffBackend::Redfish(_) => "redfish",
+            PowerOffBackend::SmartPlug(_) => "smart_plug",
+            PowerOffBackend::Local { .. } => "local",
+        }
+    }
+}
+
+impl PowerOffBackend {
+    /// The backend with passwords and webhook header values replaced by [`SECRET_MASK`]
+    pub fn redacted(&self) -> Self {
+        let mut backend = self.clone();
+        match &mut backend {
+            PowerOffBackend::Http { headers, .. } => headers.values_mut().for_each(mask),
+            PowerOffBackend::Redfish(config) => config.redact(),
+            PowerOffBackend::SmartPlug(config) => config.redact(),
+            PowerOffBackend::Agent { .. }
+            | PowerOffBackend::Ssh { .. }
+            | PowerOffBackend::Local { .. } => {}
+        }
+        backend
+    }
+
+    /// Replace masked secrets sent back by a client with those of the `stored` backend.
+    pub fn keep_secrets(&mut self, stored: &PowerOffBackend) {
+        match (self, stored) {
+            (
+                PowerOffBackend::Http { headers, .. },
+                PowerOffBackend::Http {
+                    headers: stored, ..
+                },
+            ) => {
+                for (name, value) in headers.iter_mut() {
+                    unmask(value, stored.get(name).map_or("", String::as_str));
+                }
+            }
+            (PowerOffBackend::Redfish(config), PowerOffBackend::Redfish(stored)) => {
+                config.keep_secrets(stored)
+            }
+            (PowerOffBackend::SmartPlug(config), PowerOffBackend::SmartPlug(stored)) => {
+                config.keep_secrets(stored)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl PowerOnBackend {
+    /// The backend with passwords replaced by [`SECRET_MASK`]
+    pub fn redacted(&self) -> Self {
+        let mut backend = self.clone();
+        match &mut backend {
+            PowerOnBackend::Redfish(config) => config.redact(),
+            PowerOnBackend::SmartPlug(config) => config.redact(),
+            PowerOnBackend::MagicPacket => {}
+        }
+        backend
+    }
+
+    /// Replace masked secrets sent back by a client with those of the `stored` backend.
+    pub fn keep_secrets(&mut self, stored: &PowerOnBackend) {
+        match (self, stored) {
+            (PowerOnBackend::Redfish(config), PowerOnBackend::Redfish(stored)) => {
+                config.keep_secrets(stored)
+            }
+            (PowerOnBackend::SmartPlug(config), PowerOnBackend::SmartPlug(stored)) => {
+                config.keep_secrets(stored)
+            }
+            _ => {}
+        }
+    }
+
+    /// Short human readable name used in logs and API messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PowerOnBackend::MagicPacket => "magic_packet",
+            PowerOnBackend::Redfish(_) => "redfish",
+            PowerOnBackend::SmartPlug(_) => "smart_plug",
+        }
+    }
+}
+
+fn invalid_backend(message: String) -> validator::ValidationError {
+    let mut error = validator::ValidationError::new("invalid_power_backend");
+    error.code = message.into();
+    error
+}
+
+pub fn validate_power_off(backend: &PowerOffBackend) -> Result<(), validator::ValidationError> {
+    match backend {
+        PowerOffBackend::SmartPlug(config) => config.validate().map_err(invalid_backend),
+        _ => Ok(()),
+    }
+}
+
+pub fn validate_power_on(backend: &PowerOnBackend) -> Result<(), validator::ValidationError> {
+    match backend {
+        PowerOnBackend::SmartPlug(config) => config.validate().map_err(invalid_backend),
+        PowerOnBackend::MagicPacket | PowerOnBackend::Redfish(_) => Ok(()),
+    }
+}
+
+/// What the machine's power-off backend is missing, if anything; `None` when it can be powered off.
+pub fn missing_power_off_config(machine: &Machine) -> Option<String> {
+    match machine.power_off {
+        PowerOffBackend::Agent { .. } if machine.turn_off_port.is_none() => Some(format!(
+            "No turn-off port configured for {} (agent backend)",
+            machine.mac
+        )),
+        PowerOffBackend::Agent { .. }
+        | PowerOffBackend::Ssh { .. }
+        | PowerOffBackend::Http { .. }
+        | PowerOffBackend::Redfish(_)
+        | PowerOffBackend::SmartPlug(_)
+        | PowerOffBackend::Local { .. } => None,
+    }
+}
+
+/// Power on `machine` using its configured backend.
+pub async fn power_on(machine: &Machine, wol_port: u16) -> Result<()> {
+    info!(
+        "Powering on {} ({}) via {} backend",
+        machine.name,
+        machine.mac,
+        machine.power_on.kind()
+    );
+    match &machine.power_on {
+        PowerOnBackend::MagicPacket => {
+            let macs = machine.wake_macs();
+            if macs.is_empty() {
+                bail!(
+                    "No Wake-on-LAN capable interface configured for {}",
+                    machine.mac
+                );
+            }
+            let broadcast_addr = std::net::Ipv4Addr::new(255, 255, 255, 255);
+            for mac in macs {
+                let parsed = wol::parse_mac(&mac)
+                    .with_context(|| format!("Invalid MAC for WOL: {}", mac))?;
+                wol::send_packets(&parsed, broadcast_addr, wol_port, 3, &Default::default())
+                    .await?;
+            }
+            Ok(())
+        }
+        PowerOnBackend::Redfish(config) => redfish_reset(config, "On").await,
+        PowerOnBackend::SmartPlug(config) => switch_plug(config, true).await,
+    }
+}
+
+/// Power off `machine` using its configured backend. `local_agent` holds the hooks of the
+/// agent running inside the proxy in all-in-one mode, used by the `local` backend.
+pub async fn power_off(machine: &Machine, local_agent: Option<&HookConfig>) -> Result<()> {
+    power_off_with(machine, &machine.power_off, local_agent).await
+}
+
+async fn power_off_with(
+    machine: &Machine,
+    backend: &PowerOffBackend,
+    local_agent: Option<&HookConfig>,
+) -> Result<()> {
+    let machine = &resolver::resolved(machine).await;
+    info!(
+        "Powering off {} ({}) via {} backend",
+        machine.name,
+        machine.mac,
+        backend.kind()
+    );
+    match backend {
+        PowerOffBackend::Agent { action } => {
+            let port = machine
+                .turn_off_port
+                .with_context(|| format!("No turn-off port configured for {}", machine.mac))?;
+            forward::turn_off_remote_machine(
+                &machine.ip.to_string(),
+                port,
+                action.as_deref(),
+                machine.agent_tls_fingerprint.as_deref(),
+            )
+            .await
+        }
+        PowerOffBackend::Ssh {
+            host,
+            port,
+            user,
+            key_path,
+            command,
+        } => {
+            let host = host.clone().unwrap_or_else(|| machine.ip.to_string());
+            run_ssh(&host, *port, user, key_path, command).await
+        }
+        PowerOffBackend::Http {
+            url,
+            method,
+            headers,
+            body,
+        } => call_http(url, method, headers, body.as_deref()).await,
+        PowerOffBackend::Redfish(config) => redfish_reset(config, &config.off_reset_type).await,
+        PowerOffBackend::SmartPlug(config) => switch_plug(config, false).await,
+        PowerOffBackend::Local { action } => {
+            let hooks = local_agent.context("The local backend only works in all-in-one mode")?;
+            client_server::shutdown(hooks, action.clone()).await?;
+            Ok(())
+        }
+    }
+}
+
+/// How hard to try before declaring a shutdown failed.
+#[derive(Debug, Clone)]
+pub struct ShutdownPolicy {
+    /// Power-off requests sent in total, the first with the primary backend
+    pub attempts: u32,
+    /// How long to wait for the host to go dark after each request
+    pub verify_timeout: Duration,
+    pub poll_interval: Duration,
+    /// Pause before the second attempt, doubled for every further one
+    pub backoff: Duration,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        crate::config::Config::default().shutdown_policy()
+    }
+}
+
+/// Ports used to tell whether a machine is still up: the agent and the forward targets, on
+/// each of its addresses.
+fn probe_addrs(machine: &Machine) -> Vec<std::net::SocketAddr> {
+    let mut ports: Vec<u16> = machine
+        .port_forwards
+        .iter()
+        .map(|pf| pf.target_port)
+        .collect();
+    if let (PowerOffBackend::Agent { .. } | PowerOffBackend::Local { .. }, Some(port)) =
+        (&machine.power_off, machine.turn_off_port)
+    {
+        ports.push(port);
+    }
+    ports.sort_unstable();
+    ports.dedup();
+    machine
+        .addresses()
+        .into_iter()
+        .flat_map(|ip| {
+            ports
+                .iter()
+                .map(move |port| std::net::SocketAddr::from((ip, *port)))
+        })
+        .collect()
+}
+
+/// Whether any probe port answers; `None` when there is nothing to probe.
+pub async fn is_host_up(machine: &Machine) -> Option<bool> {
+    let addrs = probe_addrs(&resolver::resolved(machine).await);
+    if addrs.is_empty() {
+        return None;
+    }
+    let up = tokio::task::spawn_blocking(move || {
+        addrs
+            .iter()
+            .any(|addr| wol::tcp_check(*addr, Duration::from_millis(1000)))
+    })
+    .await
+    .unwrap_or(true);
+    Some(up)
+}
+
+/// Poll until the host stops answering or the timeout passes.
+async fn wait_until_dark(machine: &Machine, policy: &ShutdownPolicy) -> Option<bool> {
+    let deadline = tokio::time::Instant::now() + policy.verify_timeout;
+    loop {
+        match is_host_up(machine).await {
+            None => return None,
+            Some(false) => return Some(true),
+            Some(true) if tokio::time::Instant::now() >= deadline => return Some(false),
+            Some(true) => tokio::time::sleep(policy.poll_interval).await,
+        }
+    }
+}
+
+/// Result of [`verify_shutdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// The machine stopped answering on its probe ports
+    Verified,
+    /// A power-off request was accepted, but there is no probe port to check it worked
+    Unverified,
+    /// Every attempt failed or left the machine reachable
+    Failed,
+}
+
+/// Power off `machine` and check it actually went dark, retrying with backoff and the
+/// machine's `shutdown_fallback` backend. Emits `ShutdownFailed` when every attempt failed,
+/// and `ShutdownUnverified` when the machine has no port to probe.
+pub async fn shutdown_and_verify(
+    machine: &Machine,
+    policy: &ShutdownPolicy,
+    local_agent: Option<&HookConfig>,
+    events: &EventBus,
+    cause: &Cause,
+) -> ShutdownOutcome {
+    let first = power_off(machine, local_agent).await;
+    verify_shutdown(machine, first, policy, local_agent, events, cause).await
+}
+
+/// Verify a power-off request that was already sent with the primary backend.
+pub async fn verify_shutdown(
+    machine: &Machine,
+    first_attempt: Result<()>,
+    policy: &ShutdownPolicy,
+    local_agent: Option<&HookConfig>,
+    events: &EventBus,
+    cause: &Cause,
+) -> ShutdownOutcome {
+    events.emit_caused(&machine.mac, EventKind::ShutdownRequested, cause);
+    let attempts = policy.attempts.max(1);
+    let mut result = first_attempt.map_err(|e| format!("{:#}", e));
+
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            let backoff = policy.backoff.saturating_mul(1 << (attempt - 2).min(16));
+            info!(
+                "Retrying shutdown of {} in {:?} (attempt {}/{})",
+                machine.mac, backoff, attempt, attempts
+            );
+            tokio::time::sleep(backoff).await;
+            let backend = machine
+                .shutdown_fallback
+                .as_ref()
+                .unwrap_or(&machine.power_off);
+            result = power_off_with(machine, backend, local_agent)
+                .await
+                .map_err(|e| format!("{:#}", e));
+        }
+
+        match (wait_until_dark(machine, policy).await, &result) {
+            (Some(true), _) => {
+                events.emit_caused(
+                    &machine.mac,
+                    EventKind::ShutdownVerified { attempts: attempt },
+                    cause,
+                );
+                return ShutdownOutcome::Verified;
+            }
+            (None, Ok(())) => {
+                warn!(
+                    "No probe port for {}, cannot verify the shutdown",
+                    machine.mac
+                );
+                events.emit_caused(&machine.mac, EventKind::ShutdownUnverified, cause);
+                return ShutdownOutcome::Unverified;
+            }
+            (None, Err(e)) => warn!(
+                "Shutdown attempt {}/{} of {} failed: {}",
+                attempt, attempts, machine.mac, e
+            ),
+            (Some(false), _) => warn!(
+                "{} is still up after shutdown attempt {}/{}: {}",
+                machine.mac,
+                attempt,
+                attempts,
+                result.as_ref().err().map_or("host still reachable", |e| e)
+            ),
+        }
+    }
+
+    let error = match result {
+        Ok(()) => "host still reachable".to_string(),
+        Err(e) => e,
+    };
+    events.emit_caused(
+        &machine.mac,
+        EventKind::ShutdownFailed { attempts, error },
+        cause,
+    );
+    ShutdownOutcome::Failed
+}
+
+fn ssh_args(host: &str, port: u16, user: &str, key_path: &str, command: &str) -> Vec<String> {
+    vec![
+        "-i".to_string(),
+        key_path.to_string(),
+        "-p".to_string(),
+        port.to_string(),
+        // Never prompt for passwords or host keys, there is nobody to answer
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "-o".to_string(),
+        "StrictHostKeyChecking=accept-new".to_string(),
+        "-o".to_string(),
+        "ConnectTimeout=5".to_string(),
+        format!("{}@{}", user, host),
+        command.to_string(),
+    ]
+}
+
+async fn run_ssh(host: &str, port: u16, user: &str, key_path: &str, command: &str) -> Result<()> {
+    info!(
+        "Running '{}' on {}@{}:{} over SSH",
+        command, user, host, port
+    );
+    let output = tokio::time::timeout(
+        Duration::from_secs(30),
+        Command::new("ssh")
+            .args(ssh_args(host, port, user, key_path, command))
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .with_context(|| format!("SSH command on {} timed out", host))?
+    .context("Failed to run ssh")?;
+
+    // A shutdown often drops the connection before the command returns (exit code 255)
+    if output.status.success() || (output.status.code() == Some(255) && output.stderr.is_empty()) {
+        Ok(())
+    } else {
+        bail!(
+            "SSH command on {} failed with {}: {}",
+            host,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+}
+
+async fn call_http(
+    url: &str,
+    method: &str,
+    headers: &BTreeMap<String, String>,
+    body: Option<&str>,
+) -> Result<()> {
+    let method = reqwest::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .with_context(|| format!("Invalid HTTP method '{}'", method))?;
+    info!("Calling {} {} to power off", method, url);
+
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let mut request = client.request(method, url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    if let Some(body) = body {
+        request = request.body(body.to_string());
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        bail!("{} answered with status {}", url, response.status());
+    }
+    Ok(())
+}
+
+fn http_client(accept_invalid_certs: bool) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(10))
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .build()?)
+}
+
+async fn redfish_reset(config: &RedfishConfig, reset_type: &str) -> Result<()> {
+    let client = http_client(config.accept_invalid_certs)?;
+    let base = config.url.trim_end_matches('/');
+
+    let system_path = match &config.system_id {
+        Some(id) => format!("/redfish/v1/Systems/{}", id),
+        None => {
+            let response = client
+                .get(format!("{}/redfish/v1/Systems", base))
+                .basic_auth(&config.username, Some(&config.password))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                bail!(
+                    "Redfish system listing on {} answered with status {}",
+                    base,
+                    response.status()
+                );
+            }
+            let collection: serde_json::Value = serde_json::from_slice(&response.bytes().await?)
+                .context("Invalid Redfish system collection")?;
+            collection["Members"][0]["@odata.id"]
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("BMC {} does not list any systems", base))?
+        }
+    };
+
+    info!(
+        "Requesting Redfish {} reset of {}{}",
+        reset_type, base, system_path
+    );
+    let payload = serde_json::json!({ "ResetType": reset_type });
+    let response = client
+        .post(format!(
+            "{}{}/Actions/ComputerSystem.Reset",
+            base,
+            system_path.trim_end_matches('/')
+        ))
+        .basic_auth(&config.username, Some(&config.password))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&payload)?)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        bail!("Redfish reset on {} failed with {}: {}", base, status, body);
+    }
+    Ok(())
+}
+
+fn plug_request(
+    client: &reqwest::Client,
+    config: &SmartPlugConfig,
+    on: bool,
+) -> reqwest::RequestBuilder {
+    let base = config.url.trim_end_matches('/');
+    match config.device {
+        PlugDevice::Tasmota => {
+            let command = format!(
+                "Power{} {}",
+                config.relay + 1,
+                if on { "On" } else { "Off" }
+            );
+            let request = client.get(format!("{}/cm", base));
+            // Tasmota only reads credentials from the query string; its web user is "admin"
+            let request = match &config.password {
+                Some(password) => request.query(&[
+                    ("user", config.username.as_deref().unwrap_or("admin")),
+                    ("password", password.as_str()),
+                ]),
+                None => request,
+            };
+            request.query(&[("cmnd", command)])
+        }
+        PlugDevice::Shelly => client
+            .get(format!("{}/relay/{}", base, config.relay))
+            .query(&[("turn", if on { "on" } else { "off" })]),
+        PlugDevice::ShellyGen2 => client
+            .get(format!("{}/rpc/Switch.Set", base))
+            .query(&[("id", config.relay.to_string()), ("on", on.to_string())]),
+    }
+}
+
+async fn switch_plug(config: &SmartPlugConfig, on: bool) -> Result<()> {
+    let client = http_client(false)?;
+    let mut request = plug_request(&client, config, on);
+    if config.device == PlugDevice::Shelly {
+        if let Some(user) = &config.username {
+            request = request.basic_auth(user, config.password.as_ref());
+        }
+    }
+
+    // Only the base URL is logged, Tasmota credentials are part of the query string
+    info!(
+        "Switching smart plug {} {}",
+        config.url,
+        if on { "on" } else { "off" }
+    );
+    let response = request.send().await.map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to reach smart plug {}: {}",
+            config.url,
+            e.without_url()
+        )
+    })?;
+    if !response.status().is_success() {
+        bail!(
+            "Smart plug {} answered with status {}",
+            config.url,
+            response.status()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn machine(power_off: PowerOffBackend) -> Machine {
+        Machine {
+            id: String::new(),
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            ip: Ipv4Addr::new(127, 0, 0, 1),
+            name: "nas".to_string(),
+            description: None,
+            turn_off_port: None,
+            can_be_turned_off: true,
+            inactivity_period: 30,
+            port_forwards: vec![],
+            pending_approval: false,
+            agent: None,
+            wol_armed: None,
+            power_off,
+            power_on: PowerOnBackend::MagicPacket,
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: Vec::new(),
+            schedules: Vec::new(),
+            inactivity_policy: Default::default(),
+            wake_policy: Default::default(),
+            depends_on: Vec::new(),
+            interfaces: Vec::new(),
+            ip_proposal: None,
+            hostname: None,
+        }
+    }
+
+    /// Answer `responses.len()` connections in order, returning the raw requests.
+    async fn mock_http(
+        responses: Vec<&'static str>,
+    ) -> Option<(std::net::SocketAddr, tokio::task::JoinHandle<Vec<String>>)> {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("skipping test because binding TCP sockets is not permitted: {err}");
+                return None;
+            }
+            Err(err) => panic!("failed to bind http test listener: {err}"),
+        };
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                requests.push(read_request(&mut socket).await);
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+            requests
+        });
+        Some((addr, handle))
+    }
+
+    async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if data.len() >= header_end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&data).to_string()
+    }
+
+    const OK_EMPTY: &str = "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+    #[test]
+    fn backend_defaults_to_agent_and_parses_tagged_json() {
+        let parsed: PowerOffBackend = serde_json::from_value(serde_json::json!({
+            "type": "ssh",
+            "user": "admin",
+            "key_path": "/etc/wakezilla/id_ed25519",
+        }))
+        .unwrap();
+        assert_eq!(
+            parsed,
+            PowerOffBackend::Ssh {
+                host: None,
+                port: 22,
+                user: "admin".to_string(),
+                key_path: "/etc/wakezilla/id_ed25519".to_string(),
+                command: "sudo shutdown -h now".to_string(),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(PowerOffBackend::default()).unwrap(),
+            serde_json::json!({ "type": "agent" })
+        );
+    }
+
+    #[tokio::test]
+    async fn local_backend_only_works_in_all_in_one_mode() {
+        let m = machine(PowerOffBackend::Local { action: None });
+        assert!(missing_power_off_config(&m).is_none());
+        let err = power_off(&m, None)
+            .await
+            .expect_err("no in-process agent in tests");
+        assert!(err.to_string().contains("all-in-one"));
+    }
+
+    #[test]
+    fn agent_backend_needs_a_turn_off_port() {
+        let mut m = machine(PowerOffBackend::default());
+        assert!(missing_power_off_config(&m)
+            .unwrap()
+            .contains("agent backend"));
+        m.turn_off_port = Some(3001);
+        assert!(missing_power_off_config(&m).is_none());
+    }
+
+    #[test]
+    fn ssh_args_disable_interactive_prompts() {
+        let args = ssh_args("10.0.0.5", 2222, "root", "/key", "poweroff");
+        assert!(args.contains(&"BatchMode=yes".to_string()));
+        assert_eq!(args[args.len() - 2], "root@10.0.0.5");
+        assert_eq!(args[args.len() - 1], "poweroff");
+    }
+
+    #[tokio::test]
+    async fn http_backend_sends_configured_request() {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("skipping http_backend_sends_configured_request: {}", err);
+                return;
+            }
+            Err(err) => panic!("failed to bind http test listener: {err}"),
+        };
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            let _ = socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let mut headers = BTreeMap::new();
+        headers.insert("X-Token".to_string(), "secret".to_string());
+        let m = machine(PowerOffBackend::Http {
+            url: format!("http://{}/api/shutdown", addr),
+            method: "put".to_string(),
+            headers,
+            body: Some("{\"force\":false}".to_string()),
+        });
+        power_off(&m, None)
+            .await
+            .expect("webhook call should succeed");
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("PUT /api/shutdown HTTP/1.1"));
+        assert!(request.to_ascii_lowercase().contains("x-token: secret"));
+        assert!(request.ends_with("{\"force\":false}"));
+    }
+
+    #[tokio::test]
+    async fn http_backend_reports_error_status() {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("skipping http_backend_reports_error_status: {}", err);
+                return;
+            }
+            Err(err) => panic!("failed to bind http test listener: {err}"),
+        };
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket
+                .write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n")
+                .await;
+        });
+
+        let m = machine(PowerOffBackend::Http {
+            url: format!("http://{}/", addr),
+            method: "POST".to_string(),
+            headers: BTreeMap::new(),
+            body: None,
+        });
+        assert!(power_off(&m, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn redfish_power_on_discovers_system_and_resets() {
+        let systems = "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: 67\r\n\r\n{\"Members\":[{\"@odata.id\":\"/redfish/v1/Systems/System.Embedded.1\"}]}";
+        let Some((addr, server)) = mock_http(vec![systems, OK_EMPTY]).await else {
+            return;
+        };
+
+        let mut m = machine(PowerOffBackend::default());
+        m.power_on = PowerOnBackend::Redfish(RedfishConfig {
+            url: format!("http://{}/", addr),
+            username: "root".to_string(),
+            password: "calvin".to_string(),
+            system_id: None,
+            off_reset_type: default_off_reset_type(),
+            accept_invalid_certs: false,
+        });
+        power_on(&m, 9)
+            .await
+            .expect("redfish power on should succeed");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /redfish/v1/Systems HTTP/1.1"));
+        assert!(requests[0]
+            .to_ascii_lowercase()
+            .contains("authorization: basic"));
+        assert!(requests[1].starts_with(
+            "POST /redfish/v1/Systems/System.Embedded.1/Actions/ComputerSystem.Reset"
+        ));
+        assert!(requests[1].ends_with("{\"ResetType\":\"On\"}"));
+    }
+
+    #[tokio::test]
+    async fn redfish_power_off_uses_configured_reset_type() {
+        let Some((addr, server)) = mock_http(vec![OK_EMPTY]).await else {
+            return;
+        };
+
+        let m = machine(PowerOffBackend::Redfish(RedfishConfig {
+            url: format!("http://{}", addr),
+            username: "admin".to_string(),
+            password: "admin".to_string(),
+            system_id: Some("1".to_string()),
+            off_reset_type: "ForceOff".to_string(),
+            accept_invalid_certs: true,
+        }));
+        power_off(&m, None)
+            .await
+            .expect("redfish power off should succeed");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /redfish/v1/Systems/1/Actions/ComputerSystem.Reset"));
+        assert!(requests[0].ends_with("{\"ResetType\":\"ForceOff\"}"));
+    }
+
+    #[tokio::test]
+    async fn smart_plugs_use_device_specific_commands() {
+        let Some((addr, server)) = mock_http(vec![OK_EMPTY, OK_EMPTY, OK_EMPTY]).await else {
+            return;
+        };
+
+        let plug = |device| SmartPlugConfig {
+            device,
+            url: format!("http://{}", addr),
+            relay: 1,
+            username: None,
+            password: Some("secret".to_string()),
+        };
+        let mut m = machine(PowerOffBackend::SmartPlug(plug(PlugDevice::Tasmota)));
+        m.power_on = PowerOnBackend::SmartPlug(SmartPlugConfig {
+            username: Some("admin".to_string()),
+            ..plug(PlugDevice::Shelly)
+        });
+        power_off(&m, None)
+            .await
+            .expect("tasmota off should succeed");
+        power_on(&m, 9).await.expect("shelly on should succeed");
+        m.power_on = PowerOnBackend::SmartPlug(SmartPlugConfig {
+            password: None,
+            ..plug(PlugDevice::ShellyGen2)
+        });
+        power_on(&m, 9)
+            .await
+            .expect("shelly gen2 on should succeed");
+
+        let requests = server.await.unwrap();
+        assert!(
+            requests[0].starts_with("GET /cm?user=admin&password=secret&cmnd=Power2+Off HTTP/1.1")
+        );
+        assert!(requests[1].starts_with("GET /relay/1?turn=on HTTP/1.1"));
+        assert!(requests[1]
+            .to_ascii_lowercase()
+            .contains("authorization: basic"));
+        assert!(requests[2].starts_with("GET /rpc/Switch.Set?id=1&on=true HTTP/1.1"));
+    }
+
+    #[test]
+    fn shelly_gen2_plugs_reject_credentials() {
+        let plug = |device, password: Option<&str>| SmartPlugConfig {
+            device,
+            url: "http://192.168.1.50".to_string(),
+            relay: 0,
+            username: None,
+            password: password.map(str::to_string),
+        };
+        let gen2 = PowerOnBackend::SmartPlug(plug(PlugDevice::ShellyGen2, Some("secret")));
+        let error = validate_power_on(&gen2).expect_err("digest auth is not supported");
+        assert!(error.code.contains("digest"));
+        assert!(validate_power_off(&PowerOffBackend::SmartPlug(plug(
+            PlugDevice::ShellyGen2,
+            None
+        )))
+        .is_ok());
+        assert!(validate_power_off(&PowerOffBackend::SmartPlug(plug(
+            PlugDevice::Tasmota,
+            Some("secret")
+        )))
+        .is_ok());
+    }
+
+    #[test]
+    fn secrets_are_masked_and_kept_when_sent_back() {
+        let mut headers = BTreeMap::new();
+        headers.insert("Authorization".to_string(), "Bearer abc".to_string());
+        let stored = PowerOffBackend::Http {
+            url: "http://ha.lan/api/webhook/off".to_string(),
+            method: "POST".to_string(),
+            headers,
+            body: None,
+        };
+        let mut sent = stored.redacted();
+        let PowerOffBackend::Http { headers, .. } = &sent else {
+            unreachable!()
+        };
+        assert_eq!(headers["Authorization"], SECRET_MASK);
+        sent.keep_secrets(&stored);
+        assert_eq!(sent, stored);
+
+        let stored = PowerOnBackend::Redfish(RedfishConfig {
+            url: "https://10.0.0.20".to_string(),
+            username: "root".to_string(),
+            password: "calvin".to_string(),
+            system_id: None,
+            off_reset_type: default_off_reset_type(),
+            accept_invalid_certs: true,
+        });
+        let mut sent = stored.redacted();
+        assert!(!serde_json::to_string(&sent).unwrap().contains("calvin"));
+        sent.keep_secrets(&stored);
+        assert_eq!(sent, stored);
+
+        // A new password replaces the stored one
+        let mut changed = PowerOnBackend::SmartPlug(SmartPlugConfig {
+            device: PlugDevice::Shelly,
+            url: "http://192.168.1.50".to_string(),
+            relay: 0,
+            username: Some("admin".to_string()),
+            password: Some("new".to_string()),
+        });
+        changed.keep_secrets(&stored);
+        let PowerOnBackend::SmartPlug(config) = changed else {
+            unreachable!()
+        };
+        assert_eq!(config.password.as_deref(), Some("new"));
+    }
+
+    fn fast_policy() -> ShutdownPolicy {
+        ShutdownPolicy {
+            attempts: 2,
+            verify_timeout: Duration::from_millis(300),
+            poll_interval: Duration::from_millis(50),
+            backoff: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_failure_retries_with_fallback_and_emits_event() {
+        // The listener stands in for a host that refuses to go down
+        let host = match std::net::TcpListener::bind("127.0.0.1:0") {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("skipping test because binding TCP sockets is not permitted: {err}");
+                return;
+            }
+            Err(err) => panic!("failed to bind test listener: {err}"),
+        };
+        let Some((addr, server)) = mock_http(vec![OK_EMPTY, OK_EMPTY]).await else {
+            return;
+        };
+
+        let webhook = |path: &str| PowerOffBackend::Http {
+            url: format!("http://{}/{}", addr, path),
+            method: "POST".to_string(),
+            headers: BTreeMap::new(),
+            body: None,
+        };
+        let mut m = machine(webhook("suspend"));
+        m.shutdown_fallback = Some(webhook("poweroff"));
+        m.port_forwards = vec![crate::web::PortForward {
+            name: "ssh".to_string(),
+            local_port: 0,
+            target_port: host.local_addr().unwrap().port(),
+        }];
+
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        assert_eq!(
+            shutdown_and_verify(&m, &fast_policy(), None, &events, &Cause::default()).await,
+            ShutdownOutcome::Failed
+        );
+        assert_eq!(rx.try_recv().unwrap().kind, EventKind::ShutdownRequested);
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /suspend "));
+        assert!(requests[1].starts_with("POST /poweroff "));
+        let event = rx.try_recv().expect("a shutdown failed event");
+        assert_eq!(event.mac.as_deref(), Some(m.mac.as_str()));
+        assert!(matches!(
+            event.kind,
+            EventKind::ShutdownFailed { attempts: 2, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_verified_once_the_host_goes_dark() {
+        let host = match std::net::TcpListener::bind("127.0.0.1:0") {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("skipping test because binding TCP sockets is not permitted: {err}");
+                return;
+            }
+            Err(err) => panic!("failed to bind test listener: {err}"),
+        };
+        let port = host.local_addr().unwrap().port();
+        drop(host);
+
+        let mut m = machine(PowerOffBackend::default());
+        m.port_forwards = vec![crate::web::PortForward {
+            name: "web".to_string(),
+            local_port: 0,
+            target_port: port,
+        }];
+
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        assert_eq!(
+            verify_shutdown(&m, Ok(()), &fast_policy(), None, &events, &Cause::default()).await,
+            ShutdownOutcome::Verified
+        );
+        assert_eq!(rx.try_recv().unwrap().kind, EventKind::ShutdownRequested);
+        assert!(matches!(
+            rx.try_recv().unwrap().kind,
+            EventKind::ShutdownVerified { attempts: 1 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_without_probe_port_is_unverified() {
+        let m = machine(PowerOffBackend::default());
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        assert_eq!(
+            verify_shutdown(&m, Ok(()), &fast_policy(), None, &events, &Cause::default()).await,
+            ShutdownOutcome::Unverified
+        );
+        assert_eq!(rx.try_recv().unwrap().kind, EventKind::ShutdownRequested);
+        assert_eq!(rx.try_recv().unwrap().kind, EventKind::ShutdownUnverified);
+
+        // A request that failed is retried, and fails without a port to check
+        let outcome = verify_shutdown(
+            &m,
+            Err(anyhow::anyhow!("agent unreachable")),
+            &fast_policy(),
+            None,
+            &events,
+            &Cause::default(),
+        )
+        .await;
+        assert_eq!(outcome, ShutdownOutcome::Failed);
+    }
+}
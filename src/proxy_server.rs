@@ -1,24 +1,46 @@
 use crate::connection_pool::ConnectionPool;
+use crate::event_log::EventLog;
 use anyhow::Result;
 use axum::{
     body::Body,
-    extract::{Json as JsonExtract, Path, Query, State},
-    http::{header, Method, Request, Response, StatusCode},
-    response::{IntoResponse, Json, Redirect},
+    extract::{Extension, Json as JsonExtract, Path, Query, State},
+    http::{header, HeaderName, HeaderValue, Method, Request, Response, StatusCode},
+    middleware,
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Json, Redirect,
+    },
     routing::{delete, get, post, put},
     Router,
 };
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::sync::{broadcast, RwLock};
 use tower::ServiceBuilder;
-use tower_http::cors::CorsLayer;
-use tracing::{debug, error, info};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{debug, error, info, warn};
 use validator::Validate;
 
+use crate::auth;
+use crate::client_server;
+use crate::config;
+use crate::dependencies;
+use crate::events::{self, Cause, Event, EventBus, EventKind};
 use crate::forward;
+use crate::hooks;
+use crate::ip_drift;
+use crate::machine_state::{self, MachineState, StateTracker};
+use crate::metrics::Metrics;
+use crate::power::{self, PowerOffBackend};
+use crate::resolver;
 use crate::scanner;
+use crate::schedule;
+use crate::system;
+use crate::tls;
 use crate::web::{self, AppState, DeleteForm, Machine};
 use crate::wol;
 use include_dir::{include_dir, Dir};
@@ -92,8 +114,17 @@ async fn spa_fallback(req: Request<Body>) -> Response<Body> {
     }
 }
 
-pub async fn start(port: u16) -> Result<()> {
+/// Start the proxy server. With `agent` set (all-in-one mode) the client agent's routes are
+/// served too and this host is registered as a machine that is shut down in-process.
+pub async fn start(
+    config: &config::Config,
+    agent: Option<client_server::ClientOptions>,
+) -> Result<()> {
+    let port = config.server.proxy_port;
+    let tls = tls::TlsFiles::from_config(&config.server);
+    let auth = Arc::new(auth::Auth::load(config)?);
     let initial_machines = web::load_machines().unwrap_or_default();
+    resolver::set_ttl(config.dns_ttl());
 
     // Create connection pool and start cleanup task
     let connection_pool = ConnectionPool::new();
@@ -104,56 +135,342 @@ pub async fn start(port: u16) -> Result<()> {
         cleanup_handle.await.ok();
     });
 
+    let events = EventBus::new();
+    let metrics = Arc::new(Metrics::new());
+    let machines = Arc::new(RwLock::new(initial_machines.clone()));
+    let machine_states = StateTracker::new(events.clone());
     let state = AppState {
-        machines: Arc::new(RwLock::new(initial_machines.clone())),
+        machines: machines.clone(),
         proxies: Arc::new(RwLock::new(HashMap::new())),
         connection_pool,
-        turn_off_limiter: Arc::new(forward::TurnOffLimiter::new()),
+        turn_off_limiter: Arc::new(
+            forward::TurnOffLimiter::with_events(events.clone())
+                .with_metrics(metrics.clone())
+                .with_shutdown_policy(config.shutdown_policy())
+                .with_local_agent(agent.as_ref().map(|agent| agent.hooks.clone()))
+                .with_fleet(machines, machine_states.clone()),
+        ),
+        machine_states,
+        event_log: Arc::new(EventLog::from_config(&config.storage)),
+        metrics,
+        events,
+        auth,
         monitor_handle: Arc::new(std::sync::Mutex::new(None)),
     };
 
     // Start global monitor
     web::start_global_monitor(&state);
+    state.machine_states.start_listener();
+    state.event_log.start(&state.events);
+    state.metrics.start(&state.events);
+    schedule::start_scheduler(&state);
+    machine_state::start_poller(&state, config.health_check_interval());
+    ip_drift::start(&state, config);
 
     for machine in &initial_machines {
         web::start_proxy_if_configured(machine, &state);
     }
 
-    let app = build_router(state.clone());
+    let mut app = build_router(state.clone());
     let endpoints = api_routes(state.clone());
-    let app = app.merge(endpoints);
+    app = app.merge(endpoints);
 
-    let cors_layer = CorsLayer::permissive();
+    if let Some(agent) = agent {
+        if let Some(files) = &tls {
+            files.ensure_exists()?;
+        }
+        let fingerprint = tls.as_ref().map(tls::TlsFiles::fingerprint).transpose()?;
+        hooks::spawn_resume_watcher(agent.hooks.clone());
+        app = app.merge(local_agent_routes(state.clone(), agent));
+
+        match tokio::task::spawn_blocking(move || client_server::local_registration(port)).await? {
+            Ok(mut registration) => {
+                registration.tls_fingerprint = fingerprint;
+                if let Err(e) = register_local_machine(&state, registration).await {
+                    error!("Failed to register the local machine: {:#}", e);
+                }
+            }
+            Err(e) => warn!("Not registering the local machine: {}", e),
+        }
+    }
 
-    let app = app.layer(ServiceBuilder::new().layer(cors_layer).into_inner());
+    let cors_layer = cors_layer(&config.auth.cors_origins());
+
+    let app = app.layer(ServiceBuilder::new().option_layer(cors_layer).into_inner());
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    info!("listening on http://{}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    tls::serve(addr, app, tls).await
+}
 
-    Ok(())
+/// CORS for browsers on other origins, `None` (same origin only) when none are configured.
+fn cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    let origins: Vec<HeaderValue> = origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!("Ignoring invalid CORS origin {}", origin);
+                None
+            }
+        })
+        .collect();
+    if origins.is_empty() {
+        return None;
+    }
+    Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_credentials(true)
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                HeaderName::from_static(auth::CSRF_HEADER),
+            ]),
+    )
 }
 
 pub fn api_routes(state: AppState) -> Router {
     Router::new()
+        .route("/api/auth/me", get(current_user_api))
+        .route("/api/auth/logout", post(logout_api))
+        .route(
+            "/api/auth/tokens",
+            get(list_tokens_api).post(create_token_api),
+        )
+        .route("/api/auth/tokens/:id", delete(revoke_token_api))
         .route("/api/interfaces", get(list_interfaces_handler))
         .route("/api/scan", get(scan_network_handler))
         .route(
             "/api/machines",
             get(show_machines_api).post(add_machine_api),
         )
-        .route("/api/machines/:mac", get(get_machine_details_api))
-        .route("/api/machines/:mac", put(update_machine_api))
+        .route("/api/machines/:id", get(get_machine_details_api))
+        .route("/api/machines/:id", put(update_machine_api))
         .route(
-            "/api/machines/:mac/remote-turn-off",
+            "/api/machines/:id/remote-turn-off",
             post(api_turn_off_remote_machine),
         )
-        .route("/api/machines/:mac/wake", post(api_wake_machine))
-        .route("/api/machines/:mac/is-on", get(is_machine_on_api))
+        .route("/api/machines/:id/wake", post(api_wake_machine))
+        .route("/api/machines/:id/is-on", get(is_machine_on_api))
+        .route("/api/machines/:id/approve", post(approve_machine_api))
+        .route(
+            "/api/machines/:id/ip-proposal",
+            post(accept_ip_proposal_api).delete(dismiss_ip_proposal_api),
+        )
+        .route(
+            "/api/machines/:id/keep-awake",
+            post(keep_awake_api).delete(cancel_keep_awake_api),
+        )
         .route("/api/machines/delete", delete(delete_machine_api))
+        .route("/api/groups", get(list_groups_api))
+        .route("/api/groups/:name", get(group_status_api))
+        .route("/api/groups/:name/wake", post(wake_group_api))
+        .route("/api/groups/:name/turn-off", post(turn_off_group_api))
+        .route(auth::REGISTER_PATH, post(register_agent_api))
+        .route("/api/events", get(events_api))
+        .route("/api/events/history", get(event_history_api))
+        .route("/metrics", get(metrics_api))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ))
+        .route("/api/auth/login", post(login_api))
         .with_state(state)
 }
 
+/// The client agent's routes in all-in-one mode. Shutting the host down and changing its
+/// Wake-on-LAN settings need the same login or API token as the API, so other pages and
+/// hosts on the network can't trigger them.
+pub fn local_agent_routes(state: AppState, agent: client_server::ClientOptions) -> Router {
+    client_server::status_router(agent.clone()).merge(
+        client_server::control_router(agent)
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_local_agent_access,
+            ))
+            .route_layer(middleware::from_fn_with_state(state, auth::require_auth)),
+    )
+}
+
+/// Shutting the proxy host down takes an operator who may access it, changing its
+/// Wake-on-LAN settings takes an admin.
+async fn require_local_agent_access(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    req: Request<Body>,
+    next: middleware::Next,
+) -> Response<Body> {
+    let access = if req.uri().path().starts_with("/wol/") {
+        require_role(&user, auth::Role::Admin)
+    } else {
+        let machines = state.machines.read().await;
+        match machines
+            .iter()
+            .find(|m| matches!(m.power_off, PowerOffBackend::Local { .. }))
+        {
+            Some(machine) => require_machine_access(&user, auth::Role::Operator, machine),
+            None => require_role(&user, auth::Role::Operator),
+        }
+    };
+    match access {
+        Ok(()) => next.run(req).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+async fn login_api(
+    State(state): State<AppState>,
+    JsonExtract(form): JsonExtract<LoginForm>,
+) -> axum::response::Response {
+    if !state.auth.enabled() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "Authentication is disabled" })),
+        )
+            .into_response();
+    }
+    let auth = state.auth.clone();
+    let username = form.username.clone();
+    // argon2 takes a while on purpose, keep it off the async workers
+    let valid =
+        tokio::task::spawn_blocking(move || auth.verify_password(&username, &form.password))
+            .await
+            .unwrap_or(false);
+    if !valid {
+        warn!("Failed login for user {}", form.username);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({ "error": "Invalid username or password" })),
+        )
+            .into_response();
+    }
+
+    info!("User {} logged in", form.username);
+    let session = state.auth.create_session(&form.username);
+    let cookie = state.auth.session_cookie(&session);
+    let Some(user) = state.auth.current_user(&form.username, Some(session)) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(serde_json::json!({
+            "auth_enabled": true,
+            "username": user.username,
+            "csrf_token": user.session.map(|s| s.csrf_token),
+            "role": user.role,
+            "grants": user.grants,
+        })),
+    )
+        .into_response()
+}
+
+async fn logout_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> axum::response::Response {
+    if let Some(session) = &user.session {
+        state.auth.end_session(&session.id);
+    }
+    (
+        [(header::SET_COOKIE, state.auth.clear_cookie())],
+        Json(serde_json::json!({ "message": "Logged out" })),
+    )
+        .into_response()
+}
+
+async fn current_user_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "auth_enabled": state.auth.enabled(),
+        "username": user.username,
+        "csrf_token": user.session.map(|s| s.csrf_token),
+        "role": user.role,
+        "grants": user.grants,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CreateTokenForm {
+    name: String,
+    #[serde(default)]
+    scope: auth::TokenScope,
+}
+
+/// Username of the caller, or an error response when authentication is disabled.
+fn token_owner(user: &auth::CurrentUser) -> Result<&str, (StatusCode, Json<serde_json::Value>)> {
+    user.username.as_deref().ok_or((
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "error": "Authentication is disabled" })),
+    ))
+}
+
+async fn list_tokens_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> impl IntoResponse {
+    let username = token_owner(&user)?;
+    Ok::<_, (StatusCode, Json<serde_json::Value>)>(Json(serde_json::json!(state
+        .auth
+        .tokens(username))))
+}
+
+async fn create_token_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    JsonExtract(form): JsonExtract<CreateTokenForm>,
+) -> impl IntoResponse {
+    let username = token_owner(&user)?;
+    if form.scope == auth::TokenScope::Register {
+        require_role(&user, auth::Role::Admin)?;
+    }
+    match state.auth.create_token(username, &form.name, form.scope) {
+        Ok((token, secret)) => {
+            info!("User {} created API token {}", username, token.name);
+            Ok((
+                StatusCode::CREATED,
+                Json(serde_json::json!({ "token": token, "secret": secret })),
+            ))
+        }
+        Err(e) => {
+            error!("Failed to create API token: {}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": "Failed to create API token" })),
+            ))
+        }
+    }
+}
+
+async fn revoke_token_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let username = token_owner(&user)?;
+    match state.auth.revoke_token(username, &id) {
+        Ok(true) => Ok(Json(serde_json::json!({ "message": "Token revoked" }))),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "Token not found" })),
+        )),
+        Err(e) => {
+            error!("Failed to revoke API token: {}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": "Failed to revoke API token" })),
+            ))
+        }
+    }
+}
+
 pub fn build_router(state: AppState) -> Router {
     Router::new()
         .route("/", get(serve_index))
@@ -161,46 +478,269 @@ pub fn build_router(state: AppState) -> Router {
         .with_state(state)
 }
 
-async fn scan_network_handler(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+/// Whether `user` may see `event`: events about a machine follow the machine's grants,
+/// events about the whole network (scans) are for admins.
+fn event_visible_to(machines: &[Machine], user: &auth::CurrentUser, event: &Event) -> bool {
+    match &event.mac {
+        Some(mac) => match machines.iter().find(|m| &m.mac == mac) {
+            Some(machine) => user.covers(machine),
+            None => !user.is_restricted(),
+        },
+        None => user.has_role(auth::Role::Admin),
+    }
+}
+
+/// Server-sent events with every machine event the caller may see. A `lagged` event tells
+/// clients they missed some and should reload.
+async fn events_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let rx = state.events.subscribe();
+    let stream =
+        futures_util::stream::unfold((rx, state, user), |(mut rx, state, user)| async move {
+            loop {
+                let sse = match rx.recv().await {
+                    Ok(event) => {
+                        let visible = event_visible_to(&state.machines.read().await, &user, &event);
+                        if !visible {
+                            continue;
+                        }
+                        match SseEvent::default().json_data(&event) {
+                            Ok(sse) => sse,
+                            Err(e) => {
+                                error!("Failed to serialize event: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        SseEvent::default().event("lagged").data(missed.to_string())
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                };
+                return Some((Ok(sse), (rx, state, user)));
+            }
+        });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Default and maximum number of events returned by `GET /api/events/history`
+const HISTORY_LIMIT: usize = 200;
+const HISTORY_MAX_LIMIT: usize = 5000;
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Machine ID or MAC
+    mac: Option<String>,
+    /// Unix timestamp in seconds
+    since: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Recorded events the caller may see, newest first.
+async fn event_history_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<Event>>, ApiError> {
+    let limit = query.limit.unwrap_or(HISTORY_LIMIT).min(HISTORY_MAX_LIMIT);
+    let history = {
+        let log = state.event_log.clone();
+        // Events are recorded by MAC, machine IDs are resolved to the current one
+        let machines = state.machines.read().await;
+        let mac = query.mac.as_deref().map(|key| {
+            web::find_machine(&machines, key).map_or_else(|| key.to_string(), |m| m.mac.clone())
+        });
+        drop(machines);
+        tokio::task::spawn_blocking(move || log.history(mac.as_deref(), query.since))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+    };
+    let history = history.map_err(|e| {
+        error!("Failed to read the event history: {:#}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": "Failed to read the event history" })),
+        )
+    })?;
+    let machines = state.machines.read().await;
+    Ok(Json(
+        history
+            .into_iter()
+            .filter(|event| event_visible_to(&machines, &user, event))
+            .take(limit)
+            .collect(),
+    ))
+}
+
+/// Prometheus metrics. They cover every machine, so users limited to some machines can't
+/// read them.
+async fn metrics_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    if user.is_restricted() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({ "error": "Metrics cover every machine" })),
+        ));
+    }
+    let pool = state.connection_pool.get_stats().await;
+    let machines = state.machines.read().await;
+    let body = state
+        .metrics
+        .render(&machines, &state.machine_states, &pool);
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
+type ApiError = (StatusCode, Json<serde_json::Value>);
+
+fn machine_not_found() -> ApiError {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({ "error": "Machine not found" })),
+    )
+}
+
+/// `400 Bad Request` for a MAC address that passed validation but cannot be parsed.
+fn invalid_mac(error: &anyhow::Error) -> ApiError {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "errors": { "mac": [error.to_string()] } })),
+    )
+}
+
+/// `400 Bad Request` listing the error codes of every invalid field.
+fn invalid_payload(errors: &validator::ValidationErrors) -> ApiError {
+    let errors_map = errors
+        .field_errors()
+        .iter()
+        .map(|(key, value)| {
+            let error_messages: Vec<String> =
+                value.iter().map(|error| error.code.to_string()).collect();
+            (key.to_string(), error_messages)
+        })
+        .collect::<HashMap<_, _>>();
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "errors": errors_map })),
+    )
+}
+
+/// Reject callers whose role is below `role`.
+fn require_role(user: &auth::CurrentUser, role: auth::Role) -> Result<(), ApiError> {
+    if user.has_role(role) {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({ "error": format!("Requires the {} role", role) })),
+        ))
+    }
+}
+
+/// Reject callers who may not act on `machine` with `role`. Machines outside the caller's
+/// grants answer as if they didn't exist.
+fn require_machine_access(
+    user: &auth::CurrentUser,
+    role: auth::Role,
+    machine: &Machine,
+) -> Result<(), ApiError> {
+    if !user.covers(machine) {
+        return Err(machine_not_found());
+    }
+    require_role(user, role)
+}
+
+/// Cause recorded for an action `user` took through the API.
+fn api_cause(user: &auth::CurrentUser) -> Cause {
+    Cause::new(user.actor(), "requested through the API")
+}
+
+async fn scan_network_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e.into_response();
+    }
     let interface = params.get("interface").map(|s| s.as_str());
-    match scanner::NetworkInterface::scan_network_with_interface(interface).await {
-        Ok(devices) => Ok(Json(devices)),
+    let started = std::time::Instant::now();
+    let result = scanner::NetworkInterface::scan(interface).await;
+    state.metrics.observe_scan(started.elapsed());
+    match result {
+        Ok(scan) => {
+            state.events.emit_global(
+                EventKind::ScanCompleted {
+                    devices: scan.devices.clone(),
+                },
+                &api_cause(&user),
+            );
+            Json(scan).into_response()
+        }
         Err(e) => {
             error!("Network scan failed: {}", e);
-            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+            state.events.emit_global(
+                EventKind::ScanFailed {
+                    error: e.to_string(),
+                },
+                &api_cause(&user),
+            );
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
 async fn is_machine_on_api(
     State(state): State<AppState>,
-    Path(mac): Path<String>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
 ) -> impl IntoResponse {
-    let machines = state.machines.read().await;
-    if let Some(machine) = machines.iter().find(|m| m.mac == mac) {
-        let url = format!(
-            "http://{}:{}/health",
+    let machine = {
+        let machines = state.machines.read().await;
+        machines
+            .iter()
+            .find(|m| m.matches(&id) && user.covers(m))
+            .cloned()
+    };
+    if let Some(machine) = machine {
+        let machine = resolver::resolved(&machine).await;
+        let fingerprint = machine.agent_tls_fingerprint.as_deref();
+        let base_url = format!(
+            "{}://{}:{}",
+            tls::agent_scheme(fingerprint),
             machine.ip,
             machine.turn_off_port.unwrap_or(3000)
         );
-        let response = reqwest::get(&url).await;
-        match response {
-            Ok(res) => {
-                if res.status() == 200 {
-                    Ok((
-                        axum::http::StatusCode::OK,
-                        Json(serde_json::json!({ "is_on": true })),
-                    ))
-                } else {
-                    Ok((
-                        axum::http::StatusCode::OK,
-                        Json(serde_json::json!({ "is_on": false })),
-                    ))
+        match probe_agent(&base_url, fingerprint).await {
+            Ok(AgentProbe::Online(status)) => {
+                if let Some(status) = &status {
+                    let wol_armed = system::wol_armed_for(&status.wake_on_lan, &machine.mac);
+                    record_wol_armed(&state, &machine.mac, wol_armed).await;
                 }
+                Ok((
+                    axum::http::StatusCode::OK,
+                    Json(serde_json::json!({ "is_on": true, "status": status })),
+                ))
             }
+            Ok(AgentProbe::Unhealthy) => Ok((
+                axum::http::StatusCode::OK,
+                Json(serde_json::json!({ "is_on": false })),
+            )),
+            // Unreachable agents usually mean the machine is off, not that it doesn't exist
             Err(e) => {
                 info!("Network error for machine {}: {}", machine.name, e);
-                Err(axum::http::StatusCode::NOT_FOUND)
+                Ok((
+                    axum::http::StatusCode::OK,
+                    Json(serde_json::json!({
+                        "is_on": false,
+                        "error": e.to_string(),
+                        "state": state.machine_states.get(&machine.mac).state,
+                    })),
+                ))
             }
         }
     } else {
@@ -208,39 +748,104 @@ async fn is_machine_on_api(
     }
 }
 
-async fn list_interfaces_handler() -> impl IntoResponse {
+/// Persist a change in the WoL state reported by a machine's agent.
+async fn record_wol_armed(state: &AppState, mac: &str, wol_armed: Option<bool>) {
+    if wol_armed.is_none() {
+        return;
+    }
+    let mut machines = state.machines.write().await;
+    let Some(machine) = machines.iter_mut().find(|m| m.matches(mac)) else {
+        return;
+    };
+    if machine.wol_armed == wol_armed {
+        return;
+    }
+    if wol_armed == Some(false) {
+        warn!(
+            "Wake-on-LAN is not armed on {} ({}), magic packets will not wake it",
+            machine.name, machine.mac
+        );
+    }
+    machine.wol_armed = wol_armed;
+    if let Err(e) = web::save_machines(&machines) {
+        error!("Error saving machines: {}", e);
+    }
+}
+
+enum AgentProbe {
+    /// The agent answered; the status is `None` for agents that predate `/status`.
+    Online(Option<system::SystemStatus>),
+    Unhealthy,
+}
+
+/// Query the client agent's `/status` endpoint, falling back to `/health` for older agents.
+async fn probe_agent(base_url: &str, fingerprint: Option<&str>) -> anyhow::Result<AgentProbe> {
+    let client = tls::pinned_client_builder(fingerprint)?.build()?;
+    let res = client.get(format!("{}/status", base_url)).send().await?;
+    if res.status().is_success() {
+        let body = res.bytes().await?;
+        let status = serde_json::from_slice::<system::SystemStatus>(&body)
+            .map_err(|e| debug!("Invalid status payload from {}: {}", base_url, e))
+            .ok();
+        return Ok(AgentProbe::Online(status));
+    }
+    if res.status() != reqwest::StatusCode::NOT_FOUND {
+        return Ok(AgentProbe::Unhealthy);
+    }
+
+    let res = client.get(format!("{}/health", base_url)).send().await?;
+    if res.status() == 200 {
+        Ok(AgentProbe::Online(None))
+    } else {
+        Ok(AgentProbe::Unhealthy)
+    }
+}
+
+async fn list_interfaces_handler(
+    Extension(user): Extension<auth::CurrentUser>,
+) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e.into_response();
+    }
     match scanner::NetworkInterface::list_interfaces().await {
-        Ok(interfaces) => Ok(Json(interfaces)),
+        Ok(interfaces) => Json(interfaces).into_response(),
         Err(e) => {
             error!("Failed to list interfaces: {}", e);
-            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
 async fn add_machine_api(
     State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
     JsonExtract(payload): JsonExtract<web::AddMachineForm>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e;
+    }
     if let Err(errors) = payload.validate() {
-        let errors_map = errors
-            .field_errors()
-            .iter()
-            .map(|(key, value)| {
-                let error_messages: Vec<String> =
-                    value.iter().map(|error| error.code.to_string()).collect();
-                (key.to_string(), error_messages)
-            })
-            .collect::<HashMap<_, _>>();
-
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({ "errors": errors_map })),
-        );
+        return invalid_payload(&errors);
     }
+    let mac = match wol::normalize_mac(&payload.mac) {
+        Ok(mac) => mac,
+        Err(e) => return invalid_mac(&e),
+    };
+    let (ip, hostname) = match resolver::machine_target(&payload.ip, None).await {
+        Ok(target) => target,
+        Err(e) => {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "errors": { "ip": [e.to_string()] } })),
+            );
+        }
+    };
     let new_machine = Machine {
-        mac: payload.mac,
-        ip: payload.ip.parse().expect("Invalid IP address"),
+        id: web::new_machine_id(),
+        mac,
+        ip,
+        hostname,
+        ip_proposal: None,
         name: payload.name,
         description: payload.description,
         turn_off_port: payload.turn_off_port,
@@ -249,10 +854,47 @@ async fn add_machine_api(
             .inactivity_period
             .unwrap_or(web::get_default_inactivity_period()),
         port_forwards: payload.port_forwards.unwrap_or_default(),
+        pending_approval: false,
+        agent: None,
+        wol_armed: None,
+        power_off: payload.power_off.unwrap_or_default(),
+        power_on: payload.power_on.unwrap_or_default(),
+        shutdown_fallback: payload.shutdown_fallback,
+        agent_tls_fingerprint: payload.agent_tls_fingerprint,
+        tags: payload.tags.unwrap_or_default(),
+        schedules: payload.schedules.unwrap_or_default(),
+        inactivity_policy: payload.inactivity_policy.unwrap_or_default(),
+        wake_policy: payload.wake_policy.unwrap_or_default(),
+        depends_on: payload
+            .depends_on
+            .as_deref()
+            .map(web::normalize_machine_keys)
+            .unwrap_or_default(),
+        interfaces: payload
+            .interfaces
+            .as_deref()
+            .map(web::normalize_interfaces)
+            .unwrap_or_default(),
     };
     let mut machines = state.machines.write().await;
+    if let Some(mac) = web::mac_conflict(&machines, &new_machine) {
+        return (
+            axum::http::StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "errors": { "mac": [format!("Another machine has the MAC {}", mac)] }
+            })),
+        );
+    }
+    machines.push(new_machine.clone());
+    if let Err(e) = dependencies::validate_dependencies(&machines) {
+        machines.pop();
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "errors": { "depends_on": [e.to_string()] } })),
+        );
+    }
     web::start_proxy_if_configured(&new_machine, &state);
-    machines.push(new_machine);
+    let mac = new_machine.mac.clone();
 
     if let Err(e) = web::save_machines(&machines) {
         error!("Error saving machines: {}", e);
@@ -261,25 +903,67 @@ async fn add_machine_api(
             Json(serde_json::json!({ "error": "Failed to save machines" })),
         );
     }
+    state
+        .events
+        .emit_caused(&mac, EventKind::MachineAdded, &api_cause(&user));
     (
         axum::http::StatusCode::CREATED,
         Json(serde_json::json!({ "status": "Machine added" })),
     )
 }
 
-async fn show_machines_api(State(state): State<AppState>) -> impl IntoResponse {
-    let mut machines = state.machines.read().await.clone();
+/// A machine together with its live power state, with its credentials masked.
+#[derive(Serialize, Debug)]
+struct MachineWithState {
+    #[serde(flatten)]
+    machine: Machine,
+    #[serde(flatten)]
+    state: MachineState,
+    /// Unix time until which the inactivity monitor leaves the machine on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_awake_until: Option<u64>,
+}
+
+impl MachineWithState {
+    fn new(machine: Machine, state: &AppState) -> Self {
+        let keep_awake_until = state.turn_off_limiter.kept_awake_until(&machine.mac);
+        let power = state.machine_states.get(&machine.mac);
+        Self {
+            machine: machine.redacted(),
+            state: power,
+            keep_awake_until,
+        }
+    }
+}
+
+async fn show_machines_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> impl IntoResponse {
+    let mut machines: Vec<MachineWithState> = state
+        .machines
+        .read()
+        .await
+        .iter()
+        .filter(|m| user.covers(m))
+        .map(|m| MachineWithState::new(m.clone(), &state))
+        .collect();
     machines.reverse();
     Json(machines)
 }
 
 async fn get_machine_details_api(
     State(state): State<AppState>,
-    Path(mac): Path<String>,
-) -> Result<Json<Machine>, (axum::http::StatusCode, Json<serde_json::Value>)> {
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> Result<Json<MachineWithState>, (axum::http::StatusCode, Json<serde_json::Value>)> {
     let machines = state.machines.read().await;
-    if let Some(machine) = machines.iter().find(|m| m.mac == mac).cloned() {
-        Ok(Json(machine))
+    if let Some(machine) = machines
+        .iter()
+        .find(|m| m.matches(&id) && user.covers(m))
+        .cloned()
+    {
+        Ok(Json(MachineWithState::new(machine, &state)))
     } else {
         Err((
             axum::http::StatusCode::NOT_FOUND,
@@ -290,28 +974,56 @@ async fn get_machine_details_api(
 
 async fn update_machine_api(
     State(state): State<AppState>,
-    Path(mac): Path<String>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
     JsonExtract(payload): JsonExtract<web::MachinePayload>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, Json<serde_json::Value>)> {
+    require_role(&user, auth::Role::Admin)?;
+    payload
+        .validate()
+        .map_err(|errors| invalid_payload(&errors))?;
+    let mac = wol::normalize_mac(&payload.mac).map_err(|e| invalid_mac(&e))?;
+    // A machine whose hostname stopped resolving while it sleeps keeps its last address
+    let last_ip = {
+        let hostname = payload.ip.trim_end_matches('.').to_ascii_lowercase();
+        let machines = state.machines.read().await;
+        machines
+            .iter()
+            .find(|m| m.matches(&id) && m.hostname.as_deref() == Some(hostname.as_str()))
+            .map(|m| m.ip)
+    };
+    let (ip, hostname) = match resolver::machine_target(&payload.ip, last_ip).await {
+        Ok(target) => target,
+        Err(e) => {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "errors": { "ip": [e.to_string()] } })),
+            ));
+        }
+    };
     let mut machines = state.machines.write().await;
 
     // check if the machine exists
-    let exists = machines.iter().any(|m| m.mac == mac);
-    if !exists {
+    let Some(position) = machines.iter().position(|m| m.matches(&id)) else {
         return Err((
             axum::http::StatusCode::NOT_FOUND,
             Json(serde_json::json!({ "error": "Machine not found" })),
         ));
-    }
-    // Find the old machine to get its IP for stopping proxies
-    let old_machine = machines.iter().find(|m| m.mac == mac).cloned();
-
-    // remove the machine to update
-    machines.retain(|m| m.mac != mac);
-
+    };
+    // remove the machine to update, it keeps its ID when the MAC changes
+    let old_machine = machines.remove(position);
+
+    // An edited address supersedes one proposed by neighbor discovery
+    let ip_proposal = old_machine
+        .ip_proposal
+        .clone()
+        .filter(|_| ip == old_machine.ip && hostname == old_machine.hostname);
     let new_machine = Machine {
-        mac: payload.mac.clone(),
-        ip: payload.ip.parse().expect("Invalid IP address"),
+        id: old_machine.id.clone(),
+        mac,
+        ip,
+        hostname,
+        ip_proposal,
         name: payload.name.clone(),
         description: payload.description.clone(),
         turn_off_port: payload.turn_off_port,
@@ -320,9 +1032,98 @@ async fn update_machine_api(
             .inactivity_period
             .unwrap_or(web::get_default_inactivity_period()),
         port_forwards: payload.port_forwards.clone().unwrap_or_default(),
+        pending_approval: old_machine.pending_approval,
+        agent: old_machine.agent.clone(),
+        wol_armed: old_machine.wol_armed,
+        // Clients that don't know about power-off backends keep the current one, and
+        // credentials sent back masked keep their stored value
+        power_off: match payload.power_off.clone() {
+            Some(mut backend) => {
+                backend.keep_secrets(&old_machine.power_off);
+                backend
+            }
+            None => old_machine.power_off.clone(),
+        },
+        power_on: match payload.power_on.clone() {
+            Some(mut backend) => {
+                backend.keep_secrets(&old_machine.power_on);
+                backend
+            }
+            None => old_machine.power_on.clone(),
+        },
+        shutdown_fallback: match (
+            payload.shutdown_fallback.clone(),
+            &old_machine.shutdown_fallback,
+        ) {
+            (Some(mut backend), Some(stored)) => {
+                backend.keep_secrets(stored);
+                Some(backend)
+            }
+            (backend, stored) => backend.or_else(|| stored.clone()),
+        },
+        // An empty fingerprint unpins the agent certificate and goes back to plain http
+        agent_tls_fingerprint: match payload.agent_tls_fingerprint.as_deref() {
+            Some("") => None,
+            Some(fingerprint) => Some(fingerprint.to_string()),
+            None => old_machine.agent_tls_fingerprint.clone(),
+        },
+        tags: payload
+            .tags
+            .clone()
+            .unwrap_or_else(|| old_machine.tags.clone()),
+        schedules: payload
+            .schedules
+            .clone()
+            .unwrap_or_else(|| old_machine.schedules.clone()),
+        inactivity_policy: payload
+            .inactivity_policy
+            .clone()
+            .unwrap_or_else(|| old_machine.inactivity_policy.clone()),
+        wake_policy: payload
+            .wake_policy
+            .clone()
+            .unwrap_or_else(|| old_machine.wake_policy.clone()),
+        depends_on: payload
+            .depends_on
+            .as_deref()
+            .map(web::normalize_machine_keys)
+            .unwrap_or_else(|| old_machine.depends_on.clone()),
+        interfaces: payload
+            .interfaces
+            .as_deref()
+            .map(web::normalize_interfaces)
+            .unwrap_or_else(|| old_machine.interfaces.clone()),
     };
+    if let Some(mac) = web::mac_conflict(&machines, &new_machine) {
+        machines.insert(position, old_machine);
+        return Err((
+            axum::http::StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "errors": { "mac": [format!("Another machine has the MAC {}", mac)] }
+            })),
+        ));
+    }
 
-    machines.push(new_machine.clone());
+    let mut updated = machines.clone();
+    // Dependents that name the machine by its old MAC follow it to the new one
+    if new_machine.mac != old_machine.mac {
+        for dependency in updated
+            .iter_mut()
+            .flat_map(|m| m.depends_on.iter_mut())
+            .filter(|d| **d == old_machine.mac)
+        {
+            *dependency = new_machine.mac.clone();
+        }
+    }
+    updated.push(new_machine.clone());
+    if let Err(e) = dependencies::validate_dependencies(&updated) {
+        machines.insert(position, old_machine);
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "errors": { "depends_on": [e.to_string()] } })),
+        ));
+    }
+    *machines = updated;
     if let Err(e) = web::save_machines(&machines) {
         error!("Error saving machines: {}", e);
         return Err((
@@ -331,26 +1132,18 @@ async fn update_machine_api(
         ));
     }
 
-    // Stop old proxies if machine existed
-    if old_machine.is_some() {
-        let mut proxies = state.proxies.write().await;
-        let keys_to_stop: Vec<String> = proxies
-            .keys()
-            .filter(|key| key.starts_with(&mac))
-            .cloned()
-            .collect();
-
-        for key in keys_to_stop {
-            if let Some(tx) = proxies.get(&key) {
-                if tx.send(false).is_ok() {
-                    info!("Stopped old proxy for key: {}", key);
-                }
-            }
-            proxies.remove(&key);
-        }
-        drop(proxies);
+    drop(machines);
+    let changes = events::diff(&old_machine, &new_machine);
+    if !changes.is_empty() {
+        state.events.emit_caused(
+            &new_machine.mac,
+            EventKind::MachineChanged { changes },
+            &api_cause(&user),
+        );
     }
 
+    web::stop_proxies(&state, &old_machine.id).await;
+
     // Restart proxy with updated configuration
     web::start_proxy_if_configured(&new_machine, &state);
 
@@ -362,76 +1155,411 @@ async fn update_machine_api(
 
 async fn delete_machine_api(
     State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
     JsonExtract(payload): JsonExtract<DeleteForm>,
 ) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e;
+    }
+    let mut machines = state.machines.write().await;
+    let Some(position) = machines.iter().position(|m| m.matches(&payload.mac)) else {
+        // Deleting is idempotent
+        return (
+            axum::http::StatusCode::OK,
+            Json(serde_json::json!({ "status": "Machine deleted" })),
+        );
+    };
+    let dependents: Vec<String> = machines
+        .iter()
+        .filter(|m| m.depends_on.iter().any(|d| machines[position].matches(d)))
+        .map(|m| m.name.clone())
+        .collect();
+    if !dependents.is_empty() {
+        return (
+            axum::http::StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("{} depend on this machine, remove the dependency first", dependents.join(", "))
+            })),
+        );
+    }
+    let machine = machines.remove(position);
+    info!("Deleting machine {} ({})", machine.name, machine.mac);
     // Stop all proxies associated with this machine
-    info!("Deleting machine with MAC: {}", payload.mac);
-    let mut proxies = state.proxies.write().await;
-    proxies.retain(|key, tx| {
-        if key.starts_with(&payload.mac) {
-            if tx.send(false).is_ok() {
-                info!("Sent stop signal to proxy for MAC/key: {}", key);
-            }
-            false // Remove the entry
-        } else {
-            true // Keep the entry
+    web::stop_proxies(&state, &machine.id).await;
+
+    // Remove connections from pool for this machine's IP
+    let target_addr = SocketAddr::from((machine.ip, 0));
+    state.connection_pool.remove_target(target_addr).await;
+    debug!("Removed connections from pool for machine {}", machine.ip);
+
+    if let Err(e) = web::save_machines(&machines) {
+        error!("Error saving machines: {}", e);
+        return (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": "Failed to save machines" })),
+        );
+    }
+    drop(machines);
+    state
+        .events
+        .emit_caused(&machine.mac, EventKind::MachineDeleted, &api_cause(&user));
+    (
+        axum::http::StatusCode::OK,
+        Json(serde_json::json!({ "status": "Machine deleted" })),
+    )
+}
+
+async fn approve_machine_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e;
+    }
+    let mut machines = state.machines.write().await;
+    let Some(machine) = machines.iter_mut().find(|m| m.matches(&id)) else {
+        return (
+            axum::http::StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "Machine not found" })),
+        );
+    };
+    let before = machine.clone();
+    machine.pending_approval = false;
+    let approved = machine.clone();
+
+    if let Err(e) = web::save_machines(&machines) {
+        error!("Error saving machines: {}", e);
+        return (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": "Failed to save machines" })),
+        );
+    }
+    drop(machines);
+
+    info!("Approved machine {} ({})", approved.name, approved.mac);
+    let changes = events::diff(&before, &approved);
+    if !changes.is_empty() {
+        state.events.emit_caused(
+            &approved.mac,
+            EventKind::MachineChanged { changes },
+            &Cause::new(user.actor(), "approved through the API"),
+        );
+    }
+    web::start_proxy_if_configured(&approved, &state);
+    (
+        axum::http::StatusCode::OK,
+        Json(serde_json::json!({ "status": "Machine approved" })),
+    )
+}
+
+/// Move a machine to the address neighbor discovery proposed for it.
+async fn accept_ip_proposal_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e;
+    }
+    let mut machines = state.machines.write().await;
+    let Some(machine) = machines.iter_mut().find(|m| m.matches(&id)) else {
+        return machine_not_found();
+    };
+    let Some(proposal) = machine.ip_proposal.clone() else {
+        return (
+            axum::http::StatusCode::CONFLICT,
+            Json(serde_json::json!({ "error": "No address change was proposed" })),
+        );
+    };
+    let before = machine.clone();
+    ip_drift::apply(machine, &proposal.mac, proposal.ip);
+    let accepted = machine.clone();
+
+    if let Err(e) = web::save_machines(&machines) {
+        error!("Error saving machines: {}", e);
+        return (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": "Failed to save machines" })),
+        );
+    }
+    drop(machines);
+
+    info!(
+        "Moved {} ({}) to the proposed address {}",
+        accepted.name, proposal.mac, proposal.ip
+    );
+    state.events.emit_caused(
+        &accepted.mac,
+        EventKind::MachineChanged {
+            changes: events::diff(&before, &accepted),
+        },
+        &Cause::new(user.actor(), "accepted an address change"),
+    );
+    ip_drift::forget_address(&state, &accepted, before.ip).await;
+    web::restart_proxies(&state, &accepted).await;
+    (
+        axum::http::StatusCode::OK,
+        Json(serde_json::json!({ "status": "Address updated", "ip": proposal.ip })),
+    )
+}
+
+/// Keep a machine's address and forget the one neighbor discovery proposed.
+async fn dismiss_ip_proposal_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e;
+    }
+    let mut machines = state.machines.write().await;
+    let Some(machine) = machines.iter_mut().find(|m| m.matches(&id)) else {
+        return machine_not_found();
+    };
+    if machine.ip_proposal.take().is_some() {
+        if let Err(e) = web::save_machines(&machines) {
+            error!("Error saving machines: {}", e);
+            return (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": "Failed to save machines" })),
+            );
         }
-    });
-    drop(proxies); // Release the write lock
+    }
+    (
+        axum::http::StatusCode::OK,
+        Json(serde_json::json!({ "status": "Proposal dismissed" })),
+    )
+}
+
+async fn register_agent_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    JsonExtract(payload): JsonExtract<web::AgentRegistration>,
+) -> impl IntoResponse {
+    if let Err(e) = require_role(&user, auth::Role::Admin) {
+        return e;
+    }
+    let parsed = match payload.validate() {
+        Ok(()) => payload
+            .ip
+            .parse::<std::net::Ipv4Addr>()
+            .ok()
+            .zip(wol::normalize_mac(&payload.mac).ok()),
+        Err(_) => None,
+    };
+    let Some((ip, mac)) = parsed else {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "Invalid MAC or IPv4 address" })),
+        );
+    };
+
+    let cause = Cause::new(format!("agent {}", payload.hostname), "agent registration");
+    match register_machine(&state, &payload, ip, mac, Announcer::Agent, &cause).await {
+        Ok(true) => (
+            axum::http::StatusCode::CREATED,
+            Json(serde_json::json!({ "status": "pending" })),
+        ),
+        Ok(false) => (
+            axum::http::StatusCode::OK,
+            Json(serde_json::json!({ "status": "updated" })),
+        ),
+        Err(e) => {
+            error!("Error saving machines: {}", e);
+            (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": "Failed to save machines" })),
+            )
+        }
+    }
+}
+
+/// Add or refresh the machine running this process. Its shutdown runs in-process, but only
+/// once the user enables turning it off, so the proxy never shuts its own host down unasked.
+pub async fn register_local_machine(
+    state: &AppState,
+    registration: web::AgentRegistration,
+) -> Result<()> {
+    let ip = registration.ip.parse::<std::net::Ipv4Addr>()?;
+    let mac = wol::normalize_mac(&registration.mac)?;
+    let cause = Cause::new("wakezilla", "all-in-one startup");
+    register_machine(state, &registration, ip, mac, Announcer::LocalHost, &cause).await?;
+    Ok(())
+}
+
+/// Who announced a machine: a client agent through the API, or this host in all-in-one mode.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Announcer {
+    Agent,
+    LocalHost,
+}
+
+/// Add the machine announced by `registration`, or refresh the one owning its MAC, save the
+/// machines and restart the forwarders of a machine that moved. Returns whether the machine
+/// is new. Agents and the local host go through here so both follow the same rules.
+async fn register_machine(
+    state: &AppState,
+    registration: &web::AgentRegistration,
+    ip: std::net::Ipv4Addr,
+    mac: String,
+    announcer: Announcer,
+    cause: &Cause,
+) -> Result<bool> {
+    let agent = web::AgentInfo {
+        hostname: registration.hostname.clone(),
+        port: registration.port,
+        shutdown_actions: registration.shutdown_actions.clone(),
+        last_seen: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
 
     let mut machines = state.machines.write().await;
+    let (created, moved, event) = if let Some(machine) =
+        machines.iter_mut().find(|m| m.matches(&mac))
+    {
+        let before = machine.clone();
+        // Machines configured by hostname follow their name, not the agent's report, and
+        // an address reported for another interface isn't the primary one
+        let ip_changed = machine.hostname.is_none() && machine.mac == mac && machine.ip != ip;
+        if ip_changed {
+            info!(
+                "Agent {} reported a new IP for {}: {} -> {}",
+                registration.hostname, machine.mac, machine.ip, ip
+            );
+            machine.ip = ip;
+        }
+        if machine.turn_off_port.is_none() {
+            machine.turn_off_port = Some(registration.port);
+        }
+        machine.agent = Some(agent);
+        if registration.wol_armed.is_some() {
+            machine.wol_armed = registration.wol_armed;
+        }
+        // Trust on first use, a changed certificate has to be accepted through the API
+        if machine.agent_tls_fingerprint.is_none() {
+            machine.agent_tls_fingerprint = registration.tls_fingerprint.clone();
+        }
+        if announcer == Announcer::LocalHost && machine.power_off == PowerOffBackend::default() {
+            machine.power_off = PowerOffBackend::Local { action: None };
+        }
+        let moved = ip_changed.then(|| machine.clone());
+        // Every registration refreshes `agent.last_seen`, only record real changes
+        let changes: Vec<_> = events::diff(&before, machine)
+            .into_iter()
+            .filter(|change| change.field != "agent")
+            .collect();
+        let event = (!changes.is_empty())
+            .then(|| (machine.mac.clone(), EventKind::MachineChanged { changes }));
+        (false, moved, event)
+    } else {
+        let (description, power_off) = match announcer {
+            Announcer::Agent => {
+                info!(
+                    "Agent {} ({}) registered, pending approval",
+                    registration.hostname, registration.mac
+                );
+                ("Registered by client agent", PowerOffBackend::default())
+            }
+            Announcer::LocalHost => {
+                info!(
+                    "Registering this host {} ({}) as a managed machine",
+                    registration.hostname, registration.mac
+                );
+                (
+                    "Host running the wakezilla server",
+                    PowerOffBackend::Local { action: None },
+                )
+            }
+        };
+        machines.push(Machine {
+            id: web::new_machine_id(),
+            mac: mac.clone(),
+            ip,
+            name: registration.hostname.clone(),
+            description: Some(description.to_string()),
+            turn_off_port: Some(registration.port),
+            can_be_turned_off: false,
+            inactivity_period: web::get_default_inactivity_period(),
+            port_forwards: vec![],
+            pending_approval: announcer == Announcer::Agent,
+            agent: Some(agent),
+            wol_armed: registration.wol_armed,
+            power_off,
+            power_on: Default::default(),
+            shutdown_fallback: None,
+            agent_tls_fingerprint: registration.tls_fingerprint.clone(),
+            tags: Vec::new(),
+            schedules: Vec::new(),
+            inactivity_policy: Default::default(),
+            wake_policy: Default::default(),
+            depends_on: Vec::new(),
+            interfaces: Vec::new(),
+            ip_proposal: None,
+            hostname: None,
+        });
+        (true, None, Some((mac, EventKind::MachineAdded)))
+    };
 
-    // Remove connections from pool for this machine's IP
-    if let Some(machine) = machines.iter().find(|m| m.mac == payload.mac) {
-        let target_addr = SocketAddr::from((machine.ip, 0));
-        state.connection_pool.remove_target(target_addr).await;
-        debug!("Removed connections from pool for machine {}", machine.ip);
+    web::save_machines(&machines)?;
+    drop(machines);
+    if let Some((mac, kind)) = event {
+        state.events.emit_caused(&mac, kind, cause);
     }
 
-    machines.retain(|m| m.mac != payload.mac);
-
-    if let Err(e) = web::save_machines(&machines) {
-        error!("Error saving machines: {}", e);
-        return (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({ "error": "Failed to save machines" })),
-        );
+    // Forwarders target the old address, restart them on the new one
+    if let Some(machine) = moved {
+        web::restart_proxies(state, &machine).await;
     }
-    (
-        axum::http::StatusCode::OK,
-        Json(serde_json::json!({ "status": "Machine deleted" })),
-    )
+    Ok(created)
 }
 
-async fn execute_remote_turn_off(state: &AppState, mac: &str) -> (axum::http::StatusCode, String) {
+pub(crate) async fn execute_remote_turn_off(
+    state: &AppState,
+    mac: &str,
+    cause: &Cause,
+) -> (axum::http::StatusCode, String) {
     let machine = {
         let machines = state.machines.read().await;
         machines.iter().find(|m| m.mac == mac).cloned()
     };
 
     if let Some(machine) = machine {
-        if let Some(port) = machine.turn_off_port {
-            info!("Sending turn-off request to {}:{}", machine.ip, port);
-            match forward::turn_off_remote_machine(&machine.ip.to_string(), port).await {
-                Ok(_) => {
-                    return (
-                        axum::http::StatusCode::OK,
-                        format!("Sent turn-off request to {}", mac),
-                    );
-                }
-                Err(e) => {
-                    return (
-                        axum::http::StatusCode::BAD_GATEWAY,
-                        format!("Failed to send turn-off request: {}", e),
-                    );
-                }
-            }
-        } else {
-            return (
-                axum::http::StatusCode::BAD_REQUEST,
-                format!("No turn-off port configured for {}", mac),
-            );
+        if let Some(missing) = power::missing_power_off_config(&machine) {
+            return (axum::http::StatusCode::BAD_REQUEST, missing);
         }
+        let local_agent = state.turn_off_limiter.local_agent().cloned();
+        let result = power::power_off(&machine, local_agent.as_ref()).await;
+        let response = match &result {
+            Ok(_) => (
+                axum::http::StatusCode::OK,
+                format!("Sent turn-off request to {}", mac),
+            ),
+            Err(e) => (
+                axum::http::StatusCode::BAD_GATEWAY,
+                format!("Failed to send turn-off request: {:#}", e),
+            ),
+        };
+        if result.is_ok() || machine.shutdown_fallback.is_some() {
+            // Answer right away, checking that the host went dark takes minutes
+            let events = state.events.clone();
+            let policy = state.turn_off_limiter.shutdown_policy().clone();
+            let cause = cause.clone();
+            tokio::spawn(async move {
+                power::verify_shutdown(
+                    &machine,
+                    result,
+                    &policy,
+                    local_agent.as_ref(),
+                    &events,
+                    &cause,
+                )
+                .await;
+            });
+        }
+        return response;
     }
 
     (
@@ -442,9 +1570,22 @@ async fn execute_remote_turn_off(state: &AppState, mac: &str) -> (axum::http::St
 
 async fn api_turn_off_remote_machine(
     State(state): State<AppState>,
-    Path(mac): Path<String>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
 ) -> impl IntoResponse {
-    let (status, message) = execute_remote_turn_off(&state, &mac).await;
+    let machine = {
+        let machines = state.machines.read().await;
+        web::find_machine(&machines, &id).cloned()
+    };
+    let access = match &machine {
+        Some(machine) => require_machine_access(&user, auth::Role::Operator, machine),
+        None => require_role(&user, auth::Role::Operator),
+    };
+    if let Err(e) = access {
+        return e;
+    }
+    let mac = machine.as_ref().map_or(id, |m| m.mac.clone());
+    let (status, message) = execute_remote_turn_off(&state, &mac, &api_cause(&user)).await;
     (
         status,
         Json(serde_json::json!({
@@ -480,8 +1621,102 @@ async fn execute_wake(mac_input: &str) -> (axum::http::StatusCode, String) {
     }
 }
 
-async fn api_wake_machine(Path(mac): Path<String>) -> impl IntoResponse {
-    let (status, message) = execute_wake(&mac).await;
+async fn execute_power_on(machine: &Machine) -> (axum::http::StatusCode, String) {
+    match power::power_on(machine, 9).await {
+        Ok(_) => (
+            axum::http::StatusCode::OK,
+            format!(
+                "Sent {} power-on request to {}",
+                machine.power_on.kind(),
+                machine.mac
+            ),
+        ),
+        Err(e) => (
+            axum::http::StatusCode::BAD_GATEWAY,
+            format!("Failed to power on {}: {:#}", machine.mac, e),
+        ),
+    }
+}
+
+/// Power on a configured machine with its backend, recording the request as `cause`.
+/// Machines with dependencies are woken in the background once their dependencies are up.
+pub(crate) async fn execute_wake_machine(
+    state: &AppState,
+    machine: &Machine,
+    cause: &Cause,
+) -> (axum::http::StatusCode, String) {
+    let machines = state.machines.read().await.clone();
+    let pending = dependencies::wake_order(&machines, machine).len();
+    if pending == 0 {
+        return execute_wake_single(state, machine, cause).await;
+    }
+    let (state, machine, cause) = (state.clone(), machine.clone(), cause.clone());
+    let message = format!(
+        "Waking {} after its {} dependencies are up",
+        machine.name, pending
+    );
+    tokio::spawn(async move {
+        if let Err(e) =
+            dependencies::wake_dependencies(&state.turn_off_limiter, &machines, &machine, &cause)
+                .await
+        {
+            error!("Not waking {}: {:#}", machine.mac, e);
+            state.events.emit_caused(
+                &machine.mac,
+                EventKind::WakeFailed {
+                    error: format!("{:#}", e),
+                },
+                &cause,
+            );
+            return;
+        }
+        execute_wake_single(&state, &machine, &cause).await;
+    });
+    (StatusCode::ACCEPTED, message)
+}
+
+async fn execute_wake_single(
+    state: &AppState,
+    machine: &Machine,
+    cause: &Cause,
+) -> (axum::http::StatusCode, String) {
+    let (status, message) = execute_power_on(machine).await;
+    let kind = match status {
+        StatusCode::OK => {
+            state.turn_off_limiter.mark_woken(&machine.mac);
+            EventKind::WakeRequested
+        }
+        _ => EventKind::WakeFailed {
+            error: message.clone(),
+        },
+    };
+    state.events.emit_caused(&machine.mac, kind, cause);
+    (status, message)
+}
+
+async fn api_wake_machine(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let machine = {
+        let machines = state.machines.read().await;
+        web::find_machine(&machines, &id).cloned()
+    };
+    let access = match &machine {
+        Some(machine) => require_machine_access(&user, auth::Role::Waker, machine),
+        // Waking arbitrary MACs is only for users who aren't limited to some machines
+        None if user.is_restricted() => Err(machine_not_found()),
+        None => require_role(&user, auth::Role::Waker),
+    };
+    if let Err(e) = access {
+        return e;
+    }
+    // Unknown MACs can still be woken with a plain magic packet
+    let (status, message) = match &machine {
+        Some(machine) => execute_wake_machine(&state, machine, &api_cause(&user)).await,
+        None => execute_wake(&id).await,
+    };
     (
         status,
         Json(serde_json::json!({
@@ -490,7 +1725,214 @@ async fn api_wake_machine(Path(mac): Path<String>) -> impl IntoResponse {
     )
 }
 
+/// Pause between the wakes of a group, so a rack doesn't power up all at once
+const GROUP_WAKE_STAGGER: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Approved machines tagged `group` that `user` may access.
+async fn group_members(state: &AppState, user: &auth::CurrentUser, group: &str) -> Vec<Machine> {
+    state
+        .machines
+        .read()
+        .await
+        .iter()
+        .filter(|m| !m.pending_approval && m.tags.iter().any(|tag| tag == group) && user.covers(m))
+        .cloned()
+        .collect()
+}
+
+fn group_not_found() -> ApiError {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({ "error": "Group not found" })),
+    )
+}
+
+/// The outcome of a group action for one machine.
+#[derive(Serialize)]
+struct GroupOutcome {
+    mac: String,
+    name: String,
+    success: bool,
+    message: String,
+}
+
+impl GroupOutcome {
+    fn new(machine: &Machine, (status, message): (StatusCode, String)) -> Self {
+        Self {
+            mac: machine.mac.clone(),
+            name: machine.name.clone(),
+            success: status.is_success(),
+            message,
+        }
+    }
+}
+
+async fn list_groups_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+) -> impl IntoResponse {
+    let mut groups: std::collections::BTreeMap<String, usize> = Default::default();
+    for machine in state
+        .machines
+        .read()
+        .await
+        .iter()
+        .filter(|m| user.covers(m))
+    {
+        for tag in &machine.tags {
+            *groups.entry(tag.clone()).or_default() += 1;
+        }
+    }
+    let groups: Vec<serde_json::Value> = groups
+        .into_iter()
+        .map(|(name, machines)| serde_json::json!({ "name": name, "machines": machines }))
+        .collect();
+    Json(groups)
+}
+
+async fn group_status_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<MachineWithState>>, ApiError> {
+    let members = group_members(&state, &user, &name).await;
+    if members.is_empty() {
+        return Err(group_not_found());
+    }
+    Ok(Json(
+        members
+            .into_iter()
+            .map(|machine| MachineWithState::new(machine, &state))
+            .collect(),
+    ))
+}
+
+async fn wake_group_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_role(&user, auth::Role::Waker)?;
+    let members = group_members(&state, &user, &name).await;
+    if members.is_empty() {
+        return Err(group_not_found());
+    }
+    let cause = Cause::new(user.actor(), format!("woke group {} through the API", name));
+    let mut results = Vec::with_capacity(members.len());
+    for (i, machine) in members.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(GROUP_WAKE_STAGGER).await;
+        }
+        let outcome = execute_wake_machine(&state, machine, &cause).await;
+        results.push(GroupOutcome::new(machine, outcome));
+    }
+    Ok(Json(serde_json::json!({ "results": results })))
+}
+
+async fn turn_off_group_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_role(&user, auth::Role::Operator)?;
+    let members = group_members(&state, &user, &name).await;
+    if members.is_empty() {
+        return Err(group_not_found());
+    }
+    let cause = Cause::new(
+        user.actor(),
+        format!("turned off group {} through the API", name),
+    );
+    let outcomes = futures_util::future::join_all(
+        members
+            .iter()
+            .map(|machine| execute_remote_turn_off(&state, &machine.mac, &cause)),
+    )
+    .await;
+    let results: Vec<GroupOutcome> = members
+        .iter()
+        .zip(outcomes)
+        .map(|(machine, outcome)| GroupOutcome::new(machine, outcome))
+        .collect();
+    Ok(Json(serde_json::json!({ "results": results })))
+}
+
+#[derive(Deserialize)]
+struct KeepAwakeRequest {
+    minutes: u32,
+}
+
+/// Longest keep-awake override, one week
+const MAX_KEEP_AWAKE_MINUTES: u32 = 7 * 24 * 60;
+
+async fn keep_awake_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+    JsonExtract(request): JsonExtract<KeepAwakeRequest>,
+) -> impl IntoResponse {
+    let machine = {
+        let machines = state.machines.read().await;
+        web::find_machine(&machines, &id).cloned()
+    };
+    let Some(machine) = machine else {
+        return machine_not_found();
+    };
+    if let Err(e) = require_machine_access(&user, auth::Role::Operator, &machine) {
+        return e;
+    }
+    if request.minutes == 0 || request.minutes > MAX_KEEP_AWAKE_MINUTES {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": format!("minutes must be between 1 and {}", MAX_KEEP_AWAKE_MINUTES)
+            })),
+        );
+    }
+    let cause = Cause::new(
+        user.actor(),
+        format!("kept on for {} min through the API", request.minutes),
+    );
+    state.turn_off_limiter.keep_awake(
+        &machine.mac,
+        std::time::Duration::from_secs(u64::from(request.minutes) * 60),
+        &cause,
+    );
+    (
+        axum::http::StatusCode::OK,
+        Json(serde_json::json!({
+            "keep_awake_until": state.turn_off_limiter.kept_awake_until(&machine.mac)
+        })),
+    )
+}
+
+async fn cancel_keep_awake_api(
+    State(state): State<AppState>,
+    Extension(user): Extension<auth::CurrentUser>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let machine = {
+        let machines = state.machines.read().await;
+        web::find_machine(&machines, &id).cloned()
+    };
+    let Some(machine) = machine else {
+        return machine_not_found();
+    };
+    if let Err(e) = require_machine_access(&user, auth::Role::Operator, &machine) {
+        return e;
+    }
+    state
+        .turn_off_limiter
+        .clear_keep_awake(&machine.mac, &api_cause(&user));
+    (
+        axum::http::StatusCode::OK,
+        Json(serde_json::json!({ "keep_awake_until": null })),
+    )
+}
+
 #[cfg(test)]
+// The env lock only serialises access to process-wide env vars between tests.
+#[allow(clippy::await_holding_lock)]
 mod tests {
     use super::*;
     use crate::test_support::ENV_LOCK;
@@ -539,6 +1981,11 @@ mod tests {
             proxies: Arc::new(RwLock::new(HashMap::new())),
             connection_pool: ConnectionPool::new(),
             turn_off_limiter: Arc::new(forward::TurnOffLimiter::new()),
+            events: EventBus::new(),
+            auth: Arc::new(auth::Auth::disabled()),
+            machine_states: StateTracker::new(EventBus::new()),
+            event_log: Arc::new(EventLog::disabled()),
+            metrics: Arc::new(Metrics::new()),
             monitor_handle: Arc::new(std::sync::Mutex::new(None)),
         };
         web::start_global_monitor(&state);
@@ -547,6 +1994,7 @@ mod tests {
 
     fn sample_machine() -> Machine {
         Machine {
+            id: "0123456789abcdef".to_string(),
             mac: "AA:BB:CC:DD:EE:FF".to_string(),
             ip: Ipv4Addr::new(10, 0, 0, 1),
             name: "Sample".to_string(),
@@ -555,14 +2003,53 @@ mod tests {
             can_be_turned_off: false,
             inactivity_period: 30,
             port_forwards: vec![],
+            pending_approval: false,
+            agent: None,
+            wol_armed: None,
+            power_off: Default::default(),
+            power_on: Default::default(),
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: Vec::new(),
+            schedules: Vec::new(),
+            inactivity_policy: Default::default(),
+            wake_policy: Default::default(),
+            depends_on: Vec::new(),
+            interfaces: Vec::new(),
+            ip_proposal: None,
+            hostname: None,
         }
     }
 
+    #[tokio::test]
+    async fn show_machines_api_includes_the_tracked_state() {
+        let state = state_with_machines(vec![sample_machine()]);
+        state
+            .machine_states
+            .set("AA:BB:CC:DD:EE:FF", machine_state::PowerState::Waking);
+        let response = show_machines_api(State(state), Extension(auth::CurrentUser::anonymous()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body to be readable");
+        let json: serde_json::Value =
+            serde_json::from_slice(&body_bytes).expect("response to be valid json");
+        assert_eq!(json[0]["mac"], "AA:BB:CC:DD:EE:FF");
+        assert_eq!(json[0]["state"], "waking");
+        assert!(json[0]["state_since"].is_u64());
+    }
+
     #[tokio::test]
     async fn get_machine_details_api_returns_not_found() {
         let state = state_with_machines(vec![]);
-        let result =
-            get_machine_details_api(State(state), Path("AA:BB:CC:DD:EE:FF".to_string())).await;
+        let result = get_machine_details_api(
+            State(state),
+            Extension(auth::CurrentUser::anonymous()),
+            Path("AA:BB:CC:DD:EE:FF".to_string()),
+        )
+        .await;
         let (status, body) = result.expect_err("expected missing machine");
         assert_eq!(status, StatusCode::NOT_FOUND);
         let json = body.0;
@@ -572,7 +2059,8 @@ mod tests {
     #[tokio::test]
     async fn execute_remote_turn_off_handles_missing_machine() {
         let state = state_with_machines(vec![]);
-        let (status, message) = execute_remote_turn_off(&state, "AA:BB:CC:DD:EE:FF").await;
+        let (status, message) =
+            execute_remote_turn_off(&state, "AA:BB:CC:DD:EE:FF", &Cause::default()).await;
         assert_eq!(status, StatusCode::NOT_FOUND);
         assert!(message.contains("not found"));
     }
@@ -582,7 +2070,8 @@ mod tests {
         let mut machine = sample_machine();
         machine.turn_off_port = None;
         let state = state_with_machines(vec![machine]);
-        let (status, message) = execute_remote_turn_off(&state, "AA:BB:CC:DD:EE:FF").await;
+        let (status, message) =
+            execute_remote_turn_off(&state, "AA:BB:CC:DD:EE:FF", &Cause::default()).await;
         assert_eq!(status, StatusCode::BAD_REQUEST);
         assert!(message.contains("No turn-off port"));
     }
@@ -590,10 +2079,13 @@ mod tests {
     #[tokio::test]
     async fn api_turn_off_remote_machine_returns_json_message() {
         let state = state_with_machines(vec![]);
-        let response =
-            api_turn_off_remote_machine(State(state), Path("AA:BB:CC:DD:EE:FF".to_string()))
-                .await
-                .into_response();
+        let response = api_turn_off_remote_machine(
+            State(state),
+            Extension(auth::CurrentUser::anonymous()),
+            Path("AA:BB:CC:DD:EE:FF".to_string()),
+        )
+        .await
+        .into_response();
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
         let body_bytes = to_bytes(response.into_body(), usize::MAX)
             .await
@@ -626,11 +2118,25 @@ mod tests {
             can_be_turned_off: true,
             inactivity_period: Some(6),
             port_forwards: None,
+            power_off: None,
+            power_on: None,
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: None,
+            schedules: None,
+            inactivity_policy: None,
+            wake_policy: None,
+            depends_on: None,
+            interfaces: None,
         };
 
-        let response = add_machine_api(State(state.clone()), Json(form))
-            .await
-            .into_response();
+        let response = add_machine_api(
+            State(state.clone()),
+            Extension(auth::CurrentUser::anonymous()),
+            Json(form),
+        )
+        .await
+        .into_response();
         assert_eq!(response.status(), StatusCode::CREATED);
 
         let machines = state.machines.read().await;
@@ -640,6 +2146,57 @@ mod tests {
         assert_eq!(machines[0].inactivity_period, 6);
     }
 
+    #[tokio::test]
+    async fn register_local_machine_uses_the_in_process_backend() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let tmp_dir = tempdir().expect("failed to create temp dir");
+        let file_path = tmp_dir.path().join("machines.json");
+        let _guard = EnvGuard::set_path("WAKEZILLA__STORAGE__MACHINES_DB_PATH", &file_path);
+
+        let mut existing = sample_machine();
+        existing.can_be_turned_off = true;
+        let state = state_with_machines(vec![existing]);
+        let registration = |mac: &str| web::AgentRegistration {
+            hostname: "proxy-host".to_string(),
+            mac: mac.to_string(),
+            ip: "10.0.0.2".to_string(),
+            port: 3000,
+            shutdown_actions: vec!["poweroff".to_string()],
+            wol_armed: None,
+            tls_fingerprint: None,
+        };
+
+        register_local_machine(&state, registration("11:22:33:44:55:66"))
+            .await
+            .expect("registration to succeed");
+        {
+            let machines = state.machines.read().await;
+            let local = machines
+                .iter()
+                .find(|m| m.mac == "11:22:33:44:55:66")
+                .unwrap();
+            assert_eq!(local.power_off, PowerOffBackend::Local { action: None });
+            assert_eq!(local.turn_off_port, Some(3000));
+            assert!(!local.pending_approval);
+            assert!(!local.can_be_turned_off);
+        }
+
+        // An existing entry keeps the user's settings and switches to the in-process backend
+        register_local_machine(&state, registration("aa:bb:cc:dd:ee:ff"))
+            .await
+            .expect("registration to succeed");
+        let machines = state.machines.read().await;
+        assert_eq!(machines.len(), 2);
+        let local = machines
+            .iter()
+            .find(|m| m.mac == "AA:BB:CC:DD:EE:FF")
+            .unwrap();
+        assert_eq!(local.ip, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(local.name, "Sample");
+        assert!(local.can_be_turned_off);
+        assert_eq!(local.power_off, PowerOffBackend::Local { action: None });
+    }
+
     #[tokio::test]
     async fn add_machine_api_returns_errors_for_invalid_payload() {
         let state = state_with_machines(vec![]);
@@ -652,11 +2209,25 @@ mod tests {
             can_be_turned_off: false,
             inactivity_period: None,
             port_forwards: None,
+            power_off: None,
+            power_on: None,
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: None,
+            schedules: None,
+            inactivity_policy: None,
+            wake_policy: None,
+            depends_on: None,
+            interfaces: None,
         };
 
-        let response = add_machine_api(State(state.clone()), Json(form))
-            .await
-            .into_response();
+        let response = add_machine_api(
+            State(state.clone()),
+            Extension(auth::CurrentUser::anonymous()),
+            Json(form),
+        )
+        .await
+        .into_response();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         assert!(state.machines.read().await.is_empty());
     }
@@ -698,7 +2269,8 @@ mod tests {
         machine.ip = addr.ip().to_string().parse().unwrap();
         let state = state_with_machines(vec![machine]);
 
-        let (status, message) = execute_remote_turn_off(&state, "AA:BB:CC:DD:EE:FF").await;
+        let (status, message) =
+            execute_remote_turn_off(&state, "AA:BB:CC:DD:EE:FF", &Cause::default()).await;
 
         assert_eq!(status, StatusCode::OK);
         assert!(message.contains("Sent turn-off request"));
@@ -710,6 +2282,54 @@ mod tests {
         assert!(request.starts_with("POST /machines/turn-off"));
     }
 
+    #[tokio::test]
+    async fn is_machine_on_api_returns_agent_status() {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("skipping is_machine_on_api_returns_agent_status: {}", err);
+                return;
+            }
+            Err(err) => panic!("failed to bind listener: {err}"),
+        };
+        let addr = listener.local_addr().expect("failed to get addr");
+
+        let status = serde_json::to_string(&system::collect_status()).unwrap();
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    status.len(),
+                    status
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let mut machine = sample_machine();
+        machine.turn_off_port = Some(addr.port());
+        machine.ip = Ipv4Addr::LOCALHOST;
+        let state = state_with_machines(vec![machine]);
+
+        let response = is_machine_on_api(
+            State(state),
+            Extension(auth::CurrentUser::anonymous()),
+            Path("AA:BB:CC:DD:EE:FF".to_string()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body to be readable");
+        let json: serde_json::Value =
+            serde_json::from_slice(&body_bytes).expect("response to be valid json");
+        assert_eq!(json["is_on"], true);
+        assert_eq!(json["status"]["agent_version"], env!("CARGO_PKG_VERSION"));
+    }
+
     #[tokio::test]
     async fn execute_wake_rejects_invalid_mac() {
         let (status, message) = execute_wake("invalid").await;
@@ -719,9 +2339,13 @@ mod tests {
 
     #[tokio::test]
     async fn api_wake_machine_returns_json_for_invalid_mac() {
-        let response = api_wake_machine(Path("invalid".to_string()))
-            .await
-            .into_response();
+        let response = api_wake_machine(
+            State(state_with_machines(vec![])),
+            Extension(auth::CurrentUser::anonymous()),
+            Path("invalid".to_string()),
+        )
+        .await
+        .into_response();
 
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         let body_bytes = to_bytes(response.into_body(), usize::MAX)
@@ -742,7 +2366,18 @@ mod tests {
         let file_path = tmp_dir.path().join("machines.json");
         let _guard = EnvGuard::set_path("WAKEZILLA__STORAGE__MACHINES_DB_PATH", &file_path);
 
-        let state = state_with_machines(vec![sample_machine()]);
+        let mut machine = sample_machine();
+        machine.power_off = power::PowerOffBackend::Http {
+            url: "http://nas.local/shutdown".to_string(),
+            method: "POST".to_string(),
+            headers: [("X-Token".to_string(), "secret".to_string())].into(),
+            body: None,
+        };
+        let power_off = machine.power_off.clone();
+        // The client sends back the masked token it was shown
+        let shown = serde_json::to_value(machine.redacted()).unwrap();
+        assert_eq!(shown["power_off"]["headers"]["X-Token"], power::SECRET_MASK);
+        let state = state_with_machines(vec![machine]);
         let payload = web::MachinePayload {
             mac: "AA:BB:CC:DD:EE:FF".to_string(),
             ip: "10.0.0.2".to_string(),
@@ -752,10 +2387,21 @@ mod tests {
             can_be_turned_off: true,
             inactivity_period: Some(12),
             port_forwards: Some(vec![]),
+            power_off: Some(serde_json::from_value(shown["power_off"].clone()).unwrap()),
+            power_on: None,
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: None,
+            schedules: None,
+            inactivity_policy: None,
+            wake_policy: None,
+            depends_on: None,
+            interfaces: None,
         };
 
         let response = update_machine_api(
             State(state.clone()),
+            Extension(auth::CurrentUser::anonymous()),
             Path("AA:BB:CC:DD:EE:FF".to_string()),
             Json(payload),
         )
@@ -771,9 +2417,48 @@ mod tests {
         assert!(updated.can_be_turned_off);
         assert_eq!(updated.inactivity_period, 12);
         assert_eq!(updated.turn_off_port, Some(9090));
+        assert_eq!(updated.power_off, power_off);
         assert_eq!(updated.ip, Ipv4Addr::new(10, 0, 0, 2));
     }
 
+    #[tokio::test]
+    async fn update_machine_api_reports_every_invalid_field() {
+        let state = state_with_machines(vec![sample_machine()]);
+        let payload = web::MachinePayload {
+            mac: "invalid".to_string(),
+            ip: "not an ip".to_string(),
+            name: "Bad".to_string(),
+            description: None,
+            turn_off_port: None,
+            can_be_turned_off: false,
+            inactivity_period: None,
+            port_forwards: None,
+            power_off: None,
+            power_on: None,
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: None,
+            schedules: None,
+            inactivity_policy: None,
+            wake_policy: None,
+            depends_on: None,
+            interfaces: None,
+        };
+
+        let (status, Json(body)) = update_machine_api(
+            State(state.clone()),
+            Extension(auth::CurrentUser::anonymous()),
+            Path("AA:BB:CC:DD:EE:FF".to_string()),
+            Json(payload),
+        )
+        .await
+        .expect_err("update should be rejected");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"]["mac"][0], "Invalid MAC address");
+        assert_eq!(body["errors"]["ip"][0], "Invalid IP address or hostname");
+        assert_eq!(state.machines.read().await[0].name, "Sample");
+    }
+
     #[tokio::test]
     async fn delete_machine_api_stops_proxy_and_removes_machine() {
         let _lock = ENV_LOCK.lock().unwrap();
@@ -787,11 +2472,12 @@ mod tests {
         {
             let mut proxies = state.proxies.write().await;
             let (tx, _rx) = watch::channel(true);
-            proxies.insert(format!("{}-proxy", machine.mac), tx);
+            proxies.insert(format!("{}-8080-80", machine.id), tx);
         }
 
         let response = delete_machine_api(
             State(state.clone()),
+            Extension(auth::CurrentUser::anonymous()),
             Json(DeleteForm {
                 mac: machine.mac.clone(),
             }),
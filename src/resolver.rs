@@ -0,0 +1,186 @@
+//! DNS resolution for machines configured by hostname.
+//!
+//! A machine's `hostname` is looked up when a forwarded connection is made and when the
+//! machine is probed. Answers are cached for `WAKEZILLA__NETWORK__DNS_TTL_SECS`, and the
+//! state poller stores the last address in `Machine::ip`, so a sleeping machine whose DNS
+//! record expired can still be reached and woken.
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::events::{self, EventKind};
+use crate::web::{self, AppState, Machine};
+
+/// Lifetime of cached lookups unless configured otherwise
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+static TTL: OnceLock<Duration> = OnceLock::new();
+static CACHE: Lazy<Mutex<HashMap<String, (Ipv4Addr, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Set how long lookups are cached; only the first call has an effect.
+pub fn set_ttl(ttl: Duration) {
+    let _ = TTL.set(ttl);
+}
+
+fn ttl() -> Duration {
+    TTL.get().copied().unwrap_or(DEFAULT_TTL)
+}
+
+fn cached(hostname: &str, ttl: Duration) -> Option<Ipv4Addr> {
+    let cache = CACHE.lock().unwrap();
+    cache
+        .get(hostname)
+        .filter(|(_, resolved_at)| resolved_at.elapsed() < ttl)
+        .map(|(ip, _)| *ip)
+}
+
+/// IPv4 address of `hostname`, from the cache while it is fresh.
+pub async fn resolve(hostname: &str) -> Result<Ipv4Addr> {
+    if let Some(ip) = cached(hostname, ttl()) {
+        return Ok(ip);
+    }
+    let ip = tokio::net::lookup_host((hostname, 0))
+        .await
+        .with_context(|| format!("Could not resolve {}", hostname))?
+        .find_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .with_context(|| format!("{} has no IPv4 address", hostname))?;
+    debug!("Resolved {} to {}", hostname, ip);
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(hostname.to_string(), (ip, Instant::now()));
+    Ok(ip)
+}
+
+/// `machine` with `ip` set to the current address of its hostname. Machines without a
+/// hostname, or whose hostname doesn't resolve, keep their last known address.
+pub async fn resolved(machine: &Machine) -> Machine {
+    let mut machine = machine.clone();
+    if let Some(hostname) = &machine.hostname {
+        match resolve(hostname).await {
+            Ok(ip) => machine.ip = ip,
+            Err(e) => debug!(
+                "Using the last known address {} of {}: {:#}",
+                machine.ip, hostname, e
+            ),
+        }
+    }
+    machine
+}
+
+/// Address and hostname of a machine from the `ip` field of an API payload, which holds
+/// either an IPv4 address or a DNS name. Names are resolved right away; when that fails
+/// the machine keeps `last`, e.g. while it sleeps and its DNS record has expired.
+pub async fn machine_target(
+    value: &str,
+    last: Option<Ipv4Addr>,
+) -> Result<(Ipv4Addr, Option<String>)> {
+    if let Ok(ip) = value.parse::<Ipv4Addr>() {
+        return Ok((ip, None));
+    }
+    let hostname = value.trim_end_matches('.').to_ascii_lowercase();
+    match (resolve(&hostname).await, last) {
+        (Ok(ip), _) => Ok((ip, Some(hostname))),
+        (Err(e), Some(ip)) => {
+            warn!("{:#}, keeping the last known address {}", e, ip);
+            Ok((ip, Some(hostname)))
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Store the current addresses of machines configured by hostname.
+pub async fn remember_addresses(state: &AppState, machines: &[Machine]) {
+    let mut moved = Vec::new();
+    for machine in machines.iter().filter(|m| m.hostname.is_some()) {
+        let current = resolved(machine).await;
+        if current.ip != machine.ip {
+            moved.push((machine.clone(), current.ip));
+        }
+    }
+    if moved.is_empty() {
+        return;
+    }
+    let mut all = state.machines.write().await;
+    let mut changes = Vec::new();
+    for (before, ip) in moved {
+        let Some(machine) = all.iter_mut().find(|m| m.id == before.id) else {
+            continue;
+        };
+        // Skip machines edited while resolving
+        if machine.ip != before.ip || machine.hostname != before.hostname {
+            continue;
+        }
+        info!(
+            "{} ({}) now resolves to {} instead of {}",
+            machine.hostname.as_deref().unwrap_or_default(),
+            machine.mac,
+            ip,
+            machine.ip
+        );
+        machine.ip = ip;
+        changes.push((machine.mac.clone(), events::diff(&before, machine)));
+    }
+    if changes.is_empty() {
+        return;
+    }
+    if let Err(e) = web::save_machines(&all) {
+        error!("Error saving machines: {}", e);
+    }
+    drop(all);
+    for (mac, changes) in changes {
+        state
+            .events
+            .emit(&mac, EventKind::MachineChanged { changes });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn addresses_and_hostnames_are_told_apart() {
+        assert_eq!(
+            machine_target("192.168.1.10", None).await.unwrap(),
+            (Ipv4Addr::new(192, 168, 1, 10), None)
+        );
+        assert_eq!(
+            machine_target("LOCALHOST.", None).await.unwrap(),
+            (Ipv4Addr::LOCALHOST, Some("localhost".to_string()))
+        );
+
+        // A sleeping machine's expired record leaves its last address in place
+        let last = Ipv4Addr::new(192, 168, 1, 20);
+        assert_eq!(
+            machine_target("nas.invalid", Some(last)).await.unwrap(),
+            (last, Some("nas.invalid".to_string()))
+        );
+        assert!(machine_target("nas.invalid", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached_until_the_ttl_passes() {
+        let ip = Ipv4Addr::new(192, 168, 1, 30);
+        CACHE
+            .lock()
+            .unwrap()
+            .insert("cached.invalid".to_string(), (ip, Instant::now()));
+        assert_eq!(resolve("cached.invalid").await.unwrap(), ip);
+
+        let mut machine = crate::test_support::machine("AA:BB:CC:DD:EE:FF");
+        machine.hostname = Some("cached.invalid".to_string());
+        assert_eq!(resolved(&machine).await.ip, ip);
+
+        assert_eq!(cached("cached.invalid", Duration::ZERO), None);
+    }
+}
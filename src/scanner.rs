@@ -6,17 +6,91 @@ use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPa
 use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
 use pnet::packet::Packet;
 use pnet::util::MacAddr;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use std::time::Duration;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DiscoveredDevice {
     pub ip: String,
     pub mac: String,
     pub hostname: Option<String>,
 }
+
+/// How a network scan found its devices
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanMethod {
+    /// ARP requests sent to every host over a raw socket, needs root or `CAP_NET_RAW`
+    Arp,
+    /// The kernel's neighbor cache, read without privileges after a UDP datagram to every
+    /// host
+    NeighborTable,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScanResult {
+    pub method: ScanMethod,
+    /// Set when devices may be missing, e.g. hosts that ignored the datagrams and weren't
+    /// in the neighbor cache yet
+    pub incomplete: bool,
+    pub devices: Vec<DiscoveredDevice>,
+}
+
+/// Kernel neighbor cache on Linux
+#[cfg(target_os = "linux")]
+const NEIGHBOR_TABLE_PATH: &str = "/proc/net/arp";
+/// How long the kernel gets to resolve the hosts the datagrams went to
+#[cfg(target_os = "linux")]
+const NEIGHBOR_RESOLVE_WAIT: Duration = Duration::from_secs(3);
+/// `ATF_COM`, set on neighbor entries with a resolved MAC
+const NEIGHBOR_COMPLETE: u32 = 0x2;
+
+/// Resolved entries of `/proc/net/arp` on `interface` within `network`.
+///
+/// The table keeps stale entries until they expire, so a MAC listed at more than one
+/// address may have moved and is left out rather than reported at a guessed address.
+pub fn parse_neighbor_table(
+    contents: &str,
+    interface: &str,
+    network: IpNetwork,
+) -> Vec<DiscoveredDevice> {
+    let mut devices: Vec<DiscoveredDevice> = Vec::new();
+    let mut ambiguous: Vec<String> = Vec::new();
+    // IP address, HW type, Flags, HW address, Mask, Device
+    for line in contents.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [ip, _, flags, mac, _, device] = fields[..] else {
+            continue;
+        };
+        let complete = u32::from_str_radix(flags.trim_start_matches("0x"), 16)
+            .is_ok_and(|flags| flags & NEIGHBOR_COMPLETE != 0);
+        let in_network = ip.parse::<IpAddr>().is_ok_and(|ip| network.contains(ip));
+        if device != interface || !complete || !in_network || mac == "00:00:00:00:00:00" {
+            continue;
+        }
+        let mac = mac.to_uppercase();
+        match devices.iter().find(|d| d.mac == mac) {
+            Some(known) if known.ip != ip => ambiguous.push(mac),
+            Some(_) => {}
+            None => devices.push(DiscoveredDevice {
+                ip: ip.to_string(),
+                mac,
+                hostname: None,
+            }),
+        }
+    }
+    if !ambiguous.is_empty() {
+        debug!(
+            "Ignoring neighbor entries listed at several addresses: {:?}",
+            ambiguous
+        );
+        devices.retain(|d| !ambiguous.contains(&d.mac));
+    }
+    devices
+}
+
 impl DiscoveredDevice {
     fn scan_with_pnet(
         interface: PnetNetworkInterface,
@@ -125,6 +199,34 @@ impl DiscoveredDevice {
 
         Ok(devices)
     }
+
+    /// Read the kernel's neighbor cache after sending a UDP datagram to every host of
+    /// `network`, which makes the kernel resolve their MACs. Needs no privileges, but hosts
+    /// that don't answer ARP in time are missing.
+    #[cfg(target_os = "linux")]
+    fn scan_neighbor_table(
+        interface: &str,
+        network: IpNetwork,
+        source_ip: IpAddr,
+    ) -> Result<Vec<DiscoveredDevice>> {
+        let socket = std::net::UdpSocket::bind((source_ip, 0))
+            .context("Failed to bind a UDP socket to prime the neighbor cache")?;
+        for target_ip in network.iter() {
+            if target_ip == source_ip
+                || target_ip == network.network()
+                || target_ip == network.broadcast()
+            {
+                continue;
+            }
+            // The discard port, the datagram only needs to trigger an ARP lookup
+            let _ = socket.send_to(&[], (target_ip, 9));
+        }
+        std::thread::sleep(NEIGHBOR_RESOLVE_WAIT);
+
+        let contents = std::fs::read_to_string(NEIGHBOR_TABLE_PATH)
+            .with_context(|| format!("Failed to read {}", NEIGHBOR_TABLE_PATH))?;
+        Ok(parse_neighbor_table(&contents, interface, network))
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -176,6 +278,12 @@ impl NetworkInterface {
     pub async fn scan_network_with_interface(
         interface_name: Option<&str>,
     ) -> Result<Vec<DiscoveredDevice>> {
+        Ok(Self::scan(interface_name).await?.devices)
+    }
+
+    /// Scan the network with ARP requests, falling back to the kernel's neighbor cache on
+    /// Linux when raw sockets aren't available.
+    pub async fn scan(interface_name: Option<&str>) -> Result<ScanResult> {
         info!("Starting network scan on interface: {:?}", interface_name);
 
         let pnet_iface = if let Some(name) = interface_name {
@@ -265,8 +373,20 @@ impl NetworkInterface {
             .mac
             .ok_or_else(|| anyhow::anyhow!("Interface has no MAC address"))?;
 
-        let discovered_devices_no_hostname = tokio::task::spawn_blocking(move || {
-        DiscoveredDevice::scan_with_pnet(pnet_iface, network, source_ip, source_mac)
+        #[cfg(target_os = "linux")]
+        let scanned_interface = pnet_iface.name.clone();
+        let (method, discovered_devices_no_hostname) = tokio::task::spawn_blocking(move || {
+        match DiscoveredDevice::scan_with_pnet(pnet_iface, network, source_ip, source_mac) {
+            Ok(devices) => Ok((ScanMethod::Arp, devices)),
+            #[cfg(target_os = "linux")]
+            Err(e) => {
+                warn!("ARP scan failed, reading the kernel neighbor cache instead: {:#}", e);
+                DiscoveredDevice::scan_neighbor_table(&scanned_interface, network, source_ip)
+                    .map(|devices| (ScanMethod::NeighborTable, devices))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Err(e) => Err(e),
+        }
     })
     .await
     .context("Failed to join network scanning task")?
@@ -298,6 +418,10 @@ impl NetworkInterface {
             "Network scan finished. Found {} devices.",
             discovered_devices.len()
         );
-        Ok(discovered_devices)
+        Ok(ScanResult {
+            method,
+            incomplete: method == ScanMethod::NeighborTable,
+            devices: discovered_devices,
+        })
     }
 }
@@ -0,0 +1,290 @@
+//! Cron-style wake and shutdown schedules.
+//!
+//! Every machine can carry [`Schedule`]s that wake it, shut it down, or wake it and keep the
+//! inactivity monitor from shutting it down until a given time of day. The scheduler task
+//! started by `proxy_server::start` fires them through the same code paths as the API.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::events::Cause;
+use crate::proxy_server;
+use crate::web::{AppState, Machine};
+
+/// How often the scheduler looks for schedules that are due
+const TICK: Duration = Duration::from_secs(20);
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    Wake,
+    /// Shut the machine down even if it is in use
+    Shutdown,
+    /// Wake the machine and leave it running until `until`
+    KeepAwake,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Schedule {
+    pub action: ScheduleAction,
+    /// Cron expression with five fields, e.g. `30 7 * * 1-5` for 07:30 on weekdays
+    pub cron: String,
+    /// IANA time zone the expression is evaluated in, e.g. `Europe/Berlin`; UTC when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// `HH:MM` until which a `keep_awake` schedule keeps the machine running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+}
+
+impl Schedule {
+    fn parsed_cron(&self) -> Result<Cron> {
+        Cron::new(&self.cron)
+            .parse()
+            .map_err(|e| anyhow!("invalid cron expression '{}': {}", self.cron, e))
+    }
+
+    fn tz(&self) -> Result<Tz> {
+        parse_timezone(self.timezone.as_deref())
+    }
+
+    fn until_time(&self) -> Result<Option<NaiveTime>> {
+        self.until.as_deref().map(parse_time_of_day).transpose()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.parsed_cron()?;
+        self.tz()?;
+        let until = self.until_time()?;
+        if self.action == ScheduleAction::KeepAwake && until.is_none() {
+            return Err(anyhow!("keep_awake schedules need an `until` time"));
+        }
+        Ok(())
+    }
+
+    /// First time the schedule fires strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let cron = self.parsed_cron().ok()?;
+        let tz = self.tz().ok()?;
+        cron.find_next_occurrence(&after.with_timezone(&tz), false)
+            .ok()
+            .map(|next| next.with_timezone(&Utc))
+    }
+
+    /// End of the window a `keep_awake` schedule that fired at `fired` opens: the next time
+    /// the clock shows `until` in the schedule's time zone.
+    pub fn keep_awake_until(&self, fired: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let until = self.until_time().ok()??;
+        let tz = self.tz().ok()?;
+        let local = fired.with_timezone(&tz);
+        let mut day = local.date_naive();
+        // Also covers an `until` earlier in the day than the firing time, and DST gaps
+        for _ in 0..3 {
+            if let Some(end) = tz.from_local_datetime(&day.and_time(until)).earliest() {
+                if end > local {
+                    return Some(end.with_timezone(&Utc));
+                }
+            }
+            day = day.succ_opt()?;
+        }
+        None
+    }
+
+    fn describe(&self) -> String {
+        match &self.timezone {
+            Some(tz) => format!("schedule '{}' ({})", self.cron, tz),
+            None => format!("schedule '{}' (UTC)", self.cron),
+        }
+    }
+}
+
+/// An IANA time zone name, UTC when `None`.
+pub(crate) fn parse_timezone(name: Option<&str>) -> Result<Tz> {
+    match name {
+        Some(name) => name
+            .parse()
+            .map_err(|_| anyhow!("unknown time zone '{}'", name)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// A time of day written as `HH:MM`.
+pub(crate) fn parse_time_of_day(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("invalid time '{}', expected HH:MM", time))
+}
+
+/// Validator for the `schedules` of machine payloads.
+pub fn validate_schedules(schedules: &[Schedule]) -> Result<(), validator::ValidationError> {
+    for schedule in schedules {
+        if let Err(e) = schedule.validate() {
+            let mut error = validator::ValidationError::new("invalid_schedule");
+            error.code = e.to_string().into();
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Schedules of `machines` that fired in `(from, to]`.
+fn due(
+    machines: &[Machine],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<(&Machine, &Schedule, DateTime<Utc>)> {
+    machines
+        .iter()
+        .filter(|machine| !machine.pending_approval)
+        .flat_map(|machine| {
+            machine.schedules.iter().filter_map(move |schedule| {
+                let fired = schedule.next_after(from)?;
+                (fired <= to).then_some((machine, schedule, fired))
+            })
+        })
+        .collect()
+}
+
+async fn fire(state: &AppState, machine: &Machine, schedule: &Schedule, fired: DateTime<Utc>) {
+    let cause = Cause::new("scheduler", schedule.describe());
+    info!(
+        "Running {:?} for {} from {}",
+        schedule.action,
+        machine.mac,
+        schedule.describe()
+    );
+    let (status, message) = match schedule.action {
+        ScheduleAction::Wake => proxy_server::execute_wake_machine(state, machine, &cause).await,
+        ScheduleAction::Shutdown => {
+            proxy_server::execute_remote_turn_off(state, &machine.mac, &cause).await
+        }
+        ScheduleAction::KeepAwake => {
+            if let Some(until) = schedule.keep_awake_until(fired) {
+                let remaining = (until - Utc::now()).to_std().unwrap_or_default();
+                state
+                    .turn_off_limiter
+                    .keep_awake(&machine.mac, remaining, &cause);
+                info!("Keeping {} awake until {}", machine.mac, until);
+            }
+            proxy_server::execute_wake_machine(state, machine, &cause).await
+        }
+    };
+    if !status.is_success() {
+        warn!(
+            "Scheduled {:?} of {} failed: {}",
+            schedule.action, machine.mac, message
+        );
+    }
+}
+
+/// Fire the schedules of every machine as they come due. Schedules missed while the server
+/// was down are not caught up on.
+pub fn start_scheduler(state: &AppState) -> tokio::task::JoinHandle<()> {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut last = Utc::now();
+        let mut ticker = tokio::time::interval(TICK);
+        loop {
+            ticker.tick().await;
+            let now = Utc::now();
+            let machines = state.machines.read().await.clone();
+            for (machine, schedule, fired) in due(&machines, last, now) {
+                let (state, machine, schedule) = (state.clone(), machine.clone(), schedule.clone());
+                tokio::spawn(async move { fire(&state, &machine, &schedule, fired).await });
+            }
+            last = now;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::machine;
+
+    fn schedule(action: ScheduleAction, cron: &str, timezone: Option<&str>) -> Schedule {
+        Schedule {
+            action,
+            cron: cron.to_string(),
+            timezone: timezone.map(str::to_string),
+            until: None,
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn weekday_schedules_use_standard_cron_days_and_time_zones() {
+        let wake = schedule(ScheduleAction::Wake, "30 7 * * 1-5", Some("Europe/Berlin"));
+        // Friday 2024-01-05 08:00 in Berlin, the next weekday is Monday
+        let next = wake.next_after(utc("2024-01-05T07:00:00Z")).unwrap();
+        assert_eq!(next, utc("2024-01-08T06:30:00Z"));
+
+        let nightly = schedule(ScheduleAction::Wake, "0 2 * * *", None);
+        assert_eq!(
+            nightly.next_after(utc("2024-01-05T02:00:00Z")).unwrap(),
+            utc("2024-01-06T02:00:00Z")
+        );
+    }
+
+    #[test]
+    fn keep_awake_windows_end_at_the_next_until_time() {
+        let mut keep = schedule(ScheduleAction::KeepAwake, "30 7 * * 1-5", None);
+        assert!(keep.validate().is_err());
+        keep.until = Some("20:00".to_string());
+        keep.validate().unwrap();
+        assert_eq!(
+            keep.keep_awake_until(utc("2024-01-05T07:30:00Z")).unwrap(),
+            utc("2024-01-05T20:00:00Z")
+        );
+        keep.until = Some("06:00".to_string());
+        assert_eq!(
+            keep.keep_awake_until(utc("2024-01-05T07:30:00Z")).unwrap(),
+            utc("2024-01-06T06:00:00Z")
+        );
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(schedule(ScheduleAction::Wake, "not cron", None)
+            .validate()
+            .is_err());
+        assert!(
+            schedule(ScheduleAction::Wake, "0 2 * * *", Some("Mars/Olympus"))
+                .validate()
+                .is_err()
+        );
+        let error =
+            validate_schedules(&[schedule(ScheduleAction::Wake, "61 * * * *", None)]).unwrap_err();
+        assert!(error.code.contains("61 * * * *"));
+    }
+
+    #[test]
+    fn due_schedules_are_found_between_ticks() {
+        let mut m = machine("AA:BB:CC:DD:EE:FF");
+        m.schedules = vec![
+            schedule(ScheduleAction::Shutdown, "0 20 * * *", None),
+            schedule(ScheduleAction::Wake, "0 2 * * *", None),
+        ];
+        let machines = vec![m];
+        let fired = due(
+            &machines,
+            utc("2024-01-05T19:59:50Z"),
+            utc("2024-01-05T20:00:10Z"),
+        );
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].1.action, ScheduleAction::Shutdown);
+        assert!(due(
+            &machines,
+            utc("2024-01-05T20:00:10Z"),
+            utc("2024-01-05T20:00:30Z")
+        )
+        .is_empty());
+    }
+}
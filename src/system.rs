@@ -1,37 +1,215 @@
 use pnet::datalink;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use std::process::Command;
+use sysinfo::System;
+
+use crate::ethtool::{self, WolSettings};
+
+/// Snapshot of the host reported by the client agent's `/status` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemStatus {
+    pub hostname: Option<String>,
+    pub agent_version: String,
+    pub uptime_secs: u64,
+    /// Unix timestamp (seconds) of the last boot
+    pub boot_time: u64,
+    pub load_average: LoadAverage,
+    pub memory: MemoryStatus,
+    pub logged_in_users: Vec<String>,
+    pub mac_addresses: Vec<String>,
+    #[serde(default)]
+    pub wake_on_lan: Vec<WolSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+/// Memory usage in bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryStatus {
+    pub total: u64,
+    pub used: u64,
+    pub available: u64,
+    pub swap_total: u64,
+    pub swap_used: u64,
+}
 
-#[allow(dead_code)]
 pub fn get_local_mac_addresses() -> Vec<String> {
     datalink::interfaces()
         .into_iter()
-        .filter_map(|iface| iface.mac.map(|mac| mac.to_string()))
+        .filter_map(|iface| iface.mac)
+        .filter(|mac| !mac.is_zero())
+        .map(|mac| mac.to_string())
         .collect()
 }
 
-#[allow(dead_code)]
-pub fn shutdown_machine() {
+/// Find the interface this host would use to reach `target`, returning its MAC and IPv4.
+///
+/// Falls back to the first interface that is up, not a loopback and has both a MAC and
+/// an IPv4 address when the route lookup fails.
+pub fn primary_interface(target: SocketAddr) -> Option<(String, Ipv4Addr)> {
+    let interfaces = datalink::interfaces();
+    let with_mac_and_ipv4 = |iface: &datalink::NetworkInterface| {
+        let mac = iface.mac.filter(|mac| !mac.is_zero())?;
+        let ip = iface.ips.iter().find_map(|ip| match ip.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })?;
+        Some((mac.to_string(), ip))
+    };
+
+    // Connecting a UDP socket sends nothing, but lets the kernel pick the outgoing address.
+    let routed_ip = UdpSocket::bind(("0.0.0.0", 0))
+        .and_then(|socket| {
+            socket.connect(target)?;
+            socket.local_addr()
+        })
+        .ok()
+        .map(|addr| addr.ip());
+
+    if let Some(routed_ip) = routed_ip {
+        let routed = interfaces
+            .iter()
+            .find(|iface| iface.ips.iter().any(|ip| ip.ip() == routed_ip))
+            .and_then(|iface| {
+                let mac = iface.mac.filter(|mac| !mac.is_zero())?;
+                match routed_ip {
+                    IpAddr::V4(ip) => Some((mac.to_string(), ip)),
+                    IpAddr::V6(_) => with_mac_and_ipv4(iface),
+                }
+            });
+        if routed.is_some() {
+            return routed;
+        }
+    }
+
+    interfaces
+        .iter()
+        .filter(|iface| iface.is_up() && !iface.is_loopback())
+        .find_map(with_mac_and_ipv4)
+}
+
+/// Shutdown actions `shutdown_machine` may perform on this platform, in order of preference.
+pub fn supported_shutdown_actions() -> Vec<String> {
+    let actions: &[&str] = if cfg!(target_os = "linux") {
+        &["suspend", "poweroff"]
+    } else if cfg!(target_os = "windows") {
+        &["hibernate", "poweroff"]
+    } else {
+        &["poweroff"]
+    };
+    actions.iter().map(|action| action.to_string()).collect()
+}
+
+/// Whether the interface with the given MAC would wake on a magic packet, if known.
+pub fn wol_armed_for(wake_on_lan: &[WolSettings], mac: &str) -> Option<bool> {
+    wake_on_lan
+        .iter()
+        .find(|settings| {
+            settings
+                .mac
+                .as_deref()
+                .is_some_and(|m| m.eq_ignore_ascii_case(mac))
+        })
+        .map(WolSettings::magic_packet_armed)
+}
+
+pub fn collect_status() -> SystemStatus {
+    let mut sys = System::new();
+    sys.refresh_memory();
+    let load = System::load_average();
+
+    SystemStatus {
+        hostname: System::host_name(),
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: System::uptime(),
+        boot_time: System::boot_time(),
+        load_average: LoadAverage {
+            one: load.one,
+            five: load.five,
+            fifteen: load.fifteen,
+        },
+        memory: MemoryStatus {
+            total: sys.total_memory(),
+            used: sys.used_memory(),
+            available: sys.available_memory(),
+            swap_total: sys.total_swap(),
+            swap_used: sys.used_swap(),
+        },
+        logged_in_users: get_logged_in_users(),
+        mac_addresses: get_local_mac_addresses(),
+        wake_on_lan: ethtool::list_wol_settings(),
+    }
+}
+
+/// List the distinct users with an active login session, as reported by `who`.
+fn get_logged_in_users() -> Vec<String> {
+    if cfg!(target_os = "windows") {
+        return Vec::new();
+    }
+
+    match Command::new("who").output() {
+        Ok(output) if output.status.success() => {
+            parse_who_output(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            tracing::debug!("`who` exited with status: {}", output.status);
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::debug!("Failed to execute `who`: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_who_output(output: &str) -> Vec<String> {
+    let mut users: Vec<String> = Vec::new();
+    for user in output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+    {
+        if !users.iter().any(|u| u == user) {
+            users.push(user.to_string());
+        }
+    }
+    users
+}
+
+/// Shut down with one of [`supported_shutdown_actions`], or the platform default when `None`.
+pub fn shutdown_machine_with(action: Option<String>) {
     tracing::warn!("SHUTTING DOWN THE MACHINE IN 5 SECONDS!");
-    std::thread::spawn(|| {
+    std::thread::spawn(move || {
         std::thread::sleep(std::time::Duration::from_secs(5));
-        // Try to execute suspend instead of shutdown for linux systems with systemd
-        // and fall back to shutdown if suspend is not supported.
-        let status = if cfg!(target_os = "linux") {
-            let suspend_command_status = Command::new("systemctl").args(["suspend"]).status();
-            match suspend_command_status {
-                Ok(s) if s.success() => return,
-                _ => Command::new("shutdown").args(["-h", "now"]).status(),
+        let status = match action.as_deref() {
+            Some("suspend") => Command::new("systemctl").args(["suspend"]).status(),
+            Some("hibernate") if cfg!(target_os = "windows") => {
+                Command::new("shutdown").args(["/h"]).status()
+            }
+            Some("hibernate") => Command::new("systemctl").args(["hibernate"]).status(),
+            Some("poweroff") => poweroff_command().status(),
+            // Try to execute suspend instead of shutdown for linux systems with systemd
+            // and fall back to shutdown if suspend is not supported.
+            _ if cfg!(target_os = "linux") => {
+                let suspend_command_status = Command::new("systemctl").args(["suspend"]).status();
+                match suspend_command_status {
+                    Ok(s) if s.success() => return,
+                    _ => Command::new("shutdown").args(["-h", "now"]).status(),
+                }
+            }
+            _ if cfg!(target_os = "windows") => Command::new("shutdown").args(["/h"]).status(),
+            _ => {
+                if !cfg!(target_os = "macos") {
+                    let os_name = std::env::consts::OS;
+                    tracing::warn!("Unsupported OS for hibernate: {}", os_name);
+                }
+                poweroff_command().status()
             }
-        } else if cfg!(target_os = "macos") {
-            Command::new("osascript")
-                .args(["-e", "tell app \"System Events\" to shut down"])
-                .status()
-        } else if cfg!(target_os = "windows") {
-            Command::new("shutdown").args(["/h"]).status()
-        } else {
-            let os_name = std::env::consts::OS;
-            tracing::warn!("Unsupported OS for hibernate: {}", os_name);
-            Command::new("shutdown").args(["-h", "now"]).status()
         };
 
         match status {
@@ -42,6 +220,22 @@ pub fn shutdown_machine() {
     });
 }
 
+fn poweroff_command() -> Command {
+    if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.args(["-e", "tell app \"System Events\" to shut down"]);
+        command
+    } else if cfg!(target_os = "windows") {
+        let mut command = Command::new("shutdown");
+        command.args(["/s", "/t", "0"]);
+        command
+    } else {
+        let mut command = Command::new("shutdown");
+        command.args(["-h", "now"]);
+        command
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -52,4 +246,25 @@ mod tests {
         // Ensure any discovered MAC addresses are non-empty strings.
         assert!(addrs.iter().all(|addr| !addr.is_empty()));
     }
+
+    #[test]
+    fn parse_who_output_deduplicates_users() {
+        let output = "alice    tty1         2024-01-01 09:00\n\
+                      bob      pts/0        2024-01-01 09:05 (10.0.0.2)\n\
+                      alice    pts/1        2024-01-01 09:10 (10.0.0.3)\n";
+        assert_eq!(parse_who_output(output), vec!["alice", "bob"]);
+        assert!(parse_who_output("").is_empty());
+    }
+
+    #[test]
+    fn supported_shutdown_actions_is_not_empty() {
+        assert!(!supported_shutdown_actions().is_empty());
+    }
+
+    #[test]
+    fn collect_status_reports_agent_version() {
+        let status = collect_status();
+        assert_eq!(status.agent_version, env!("CARGO_PKG_VERSION"));
+        assert!(status.memory.total >= status.memory.used);
+    }
 }
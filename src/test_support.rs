@@ -4,3 +4,33 @@ use once_cell::sync::Lazy;
 use std::sync::Mutex;
 
 pub(crate) static ENV_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// A plain machine record for tests that only care about a few fields.
+pub(crate) fn machine(mac: &str) -> crate::web::Machine {
+    crate::web::Machine {
+        id: String::new(),
+        mac: mac.to_string(),
+        ip: std::net::Ipv4Addr::new(127, 0, 0, 1),
+        name: "Test machine".to_string(),
+        description: None,
+        turn_off_port: None,
+        can_be_turned_off: false,
+        inactivity_period: 30,
+        port_forwards: Vec::new(),
+        pending_approval: false,
+        agent: None,
+        wol_armed: None,
+        power_off: Default::default(),
+        power_on: Default::default(),
+        shutdown_fallback: None,
+        agent_tls_fingerprint: None,
+        tags: Vec::new(),
+        schedules: Vec::new(),
+        inactivity_policy: Default::default(),
+        wake_policy: Default::default(),
+        depends_on: Vec::new(),
+        interfaces: Vec::new(),
+        ip_proposal: None,
+        hostname: None,
+    }
+}
@@ -0,0 +1,324 @@
+//! Optional TLS for the proxy web server and the client agent.
+//!
+//! Certificates are read from the PEM files configured in `ServerConfig`. When TLS is
+//! enabled and the files don't exist yet, a self-signed certificate is generated on first
+//! run. Agents usually run with such self-signed certificates, so the proxy talks to them
+//! over https with the certificate's SHA-256 fingerprint pinned on the machine record
+//! instead of relying on a CA.
+
+use anyhow::{Context, Result};
+use axum::Router;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::config::ServerConfig;
+
+/// Certificate and private key files used to serve https.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsFiles {
+    /// TLS settings from the server configuration, `None` when TLS is disabled.
+    pub fn from_config(server: &ServerConfig) -> Option<Self> {
+        if !server.tls_enabled {
+            return None;
+        }
+        Some(Self {
+            cert_path: PathBuf::from(&server.tls_cert_path),
+            key_path: PathBuf::from(&server.tls_key_path),
+        })
+    }
+
+    /// Generate a self-signed certificate unless both files already exist.
+    pub fn ensure_exists(&self) -> Result<()> {
+        if self.cert_path.exists() && self.key_path.exists() {
+            return Ok(());
+        }
+
+        let mut names = vec!["localhost".to_string()];
+        if let Some(hostname) = sysinfo::System::host_name() {
+            names.push(hostname);
+        }
+        let certified = rcgen::generate_simple_self_signed(names)
+            .context("Failed to generate a self-signed certificate")?;
+        write_private(
+            &self.key_path,
+            certified.key_pair.serialize_pem().as_bytes(),
+        )?;
+        std::fs::write(&self.cert_path, certified.cert.pem())
+            .with_context(|| format!("Failed to write certificate {}", self.cert_path.display()))?;
+        info!(
+            "Generated self-signed certificate {} (SHA-256 fingerprint {})",
+            self.cert_path.display(),
+            fingerprint(certified.cert.der())
+        );
+        Ok(())
+    }
+
+    /// SHA-256 fingerprint of the certificate, for pinning on the proxy side.
+    pub fn fingerprint(&self) -> Result<String> {
+        let pem = std::fs::read(&self.cert_path)
+            .with_context(|| format!("Failed to read certificate {}", self.cert_path.display()))?;
+        let der = first_cert_der(&pem)?;
+        Ok(fingerprint(&der))
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write private key {}", path.display()))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    std::fs::write(path, data)
+        .with_context(|| format!("Failed to write private key {}", path.display()))
+}
+
+/// DER bytes of the first certificate in a PEM file.
+fn first_cert_der(pem: &[u8]) -> Result<CertificateDer<'static>> {
+    use rustls::pki_types::pem::PemObject;
+    CertificateDer::pem_slice_iter(pem)
+        .next()
+        .context("No certificate found in PEM data")?
+        .context("Invalid certificate PEM")
+}
+
+/// Uppercase, colon separated SHA-256 digest, the format `openssl x509 -fingerprint` prints.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Normalise a user supplied fingerprint so formatting differences don't matter.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Serve `app` on `addr`, over https when `tls` is set.
+pub async fn serve(addr: SocketAddr, app: Router, tls: Option<TlsFiles>) -> Result<()> {
+    match tls {
+        Some(files) => {
+            files.ensure_exists()?;
+            let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(
+                &files.cert_path,
+                &files.key_path,
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to load TLS certificate {} and key {}",
+                    files.cert_path.display(),
+                    files.key_path.display()
+                )
+            })?;
+            info!(
+                "listening on https://{} (certificate fingerprint {})",
+                addr,
+                files.fingerprint()?
+            );
+            axum_server::bind_rustls(addr, config)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            info!("listening on http://{}", listener.local_addr()?);
+            axum::serve(listener, app).await?;
+        }
+    }
+    Ok(())
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Accepts exactly one certificate, identified by its SHA-256 fingerprint.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if normalize_fingerprint(&fingerprint(end_entity)) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Scheme for agent URLs: https when the agent's certificate is pinned.
+pub fn agent_scheme(fingerprint: Option<&str>) -> &'static str {
+    if fingerprint.is_some() {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+/// HTTP client builder that pins the server certificate when a fingerprint is given.
+pub fn pinned_client_builder(fingerprint: Option<&str>) -> Result<reqwest::ClientBuilder> {
+    let builder = reqwest::Client::builder().no_proxy();
+    let Some(fingerprint) = fingerprint else {
+        return Ok(builder);
+    };
+
+    let provider = provider();
+    let verifier = PinnedCertVerifier {
+        fingerprint: normalize_fingerprint(fingerprint),
+        provider: provider.clone(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(builder.use_preconfigured_tls(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tempfile::tempdir;
+
+    #[test]
+    fn fingerprint_normalisation_ignores_case_and_separators() {
+        assert_eq!(normalize_fingerprint("ab:cd:01"), "ABCD01");
+        assert_eq!(normalize_fingerprint("ABCD01"), "ABCD01");
+    }
+
+    #[test]
+    fn ensure_exists_generates_certificate_once() {
+        let dir = tempdir().unwrap();
+        let files = TlsFiles {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+        };
+        files.ensure_exists().unwrap();
+        let first = files.fingerprint().unwrap();
+        files.ensure_exists().unwrap();
+        assert_eq!(files.fingerprint().unwrap(), first);
+        assert_eq!(first.len(), 32 * 3 - 1);
+    }
+
+    #[tokio::test]
+    async fn pinned_client_accepts_only_the_pinned_certificate() {
+        let dir = tempdir().unwrap();
+        let files = TlsFiles {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+        };
+        files.ensure_exists().unwrap();
+        let pinned = files.fingerprint().unwrap();
+
+        let listener = match std::net::TcpListener::bind("127.0.0.1:0") {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                eprintln!("skipping test because binding TCP sockets is not permitted: {err}");
+                return;
+            }
+            Err(err) => panic!("failed to bind test listener: {err}"),
+        };
+        let addr = listener.local_addr().unwrap();
+        let config =
+            axum_server::tls_rustls::RustlsConfig::from_pem_file(&files.cert_path, &files.key_path)
+                .await
+                .unwrap();
+        let app = Router::new().route("/health", get(|| async { "ok" }));
+        let server = tokio::spawn(
+            axum_server::from_tcp_rustls(listener, config).serve(app.into_make_service()),
+        );
+
+        let url = format!("https://127.0.0.1:{}/health", addr.port());
+        let client = pinned_client_builder(Some(&pinned))
+            .unwrap()
+            .build()
+            .unwrap();
+        let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+        assert_eq!(body, "ok");
+
+        let wrong = "00".repeat(32);
+        let client = pinned_client_builder(Some(&wrong))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(client.get(&url).send().await.is_err());
+
+        server.abort();
+    }
+}
@@ -0,0 +1,160 @@
+//! Wake suppression.
+//!
+//! Forwarded connections wake a machine that is down, which also happens when a backup client
+//! retries or a phone app polls right after the inactivity monitor shut it down. A
+//! [`WakePolicy`] names quiet hours during which connections don't wake the machine and a
+//! cooldown after automatic shutdowns, with an allowlist of clients that may wake it anyway.
+//! Wakes through the API, the web interface and schedules are never suppressed.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::inactivity::TimeWindow;
+use crate::schedule::parse_timezone;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+pub struct WakePolicy {
+    /// Minutes after an automatic shutdown during which connections don't wake the machine
+    #[serde(default)]
+    pub cooldown_minutes: u32,
+    /// IANA time zone of `quiet_hours`, UTC when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Windows during which connections don't wake the machine
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quiet_hours: Vec<TimeWindow>,
+    /// Client addresses or networks (`192.168.1.20`, `10.0.0.0/24`) that may always wake it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_clients: Vec<String>,
+}
+
+impl WakePolicy {
+    pub fn validate(&self) -> Result<()> {
+        parse_timezone(self.timezone.as_deref())?;
+        for window in &self.quiet_hours {
+            window.validate()?;
+        }
+        for client in &self.allowed_clients {
+            client
+                .parse::<IpNetwork>()
+                .map_err(|_| anyhow!("invalid client address or network '{}'", client))?;
+        }
+        Ok(())
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(u64::from(self.cooldown_minutes) * 60)
+    }
+
+    fn allows(&self, client: IpAddr) -> bool {
+        self.allowed_clients.iter().any(|allowed| {
+            allowed
+                .parse::<IpNetwork>()
+                .is_ok_and(|network| network.contains(client))
+        })
+    }
+
+    /// Why a connection from `client` may not wake the machine at `now`, `None` if it may.
+    /// `since_shutdown` is the time since the inactivity monitor last shut the machine down.
+    pub fn suppression(
+        &self,
+        client: IpAddr,
+        now: DateTime<Utc>,
+        since_shutdown: Option<Duration>,
+    ) -> Option<String> {
+        if self.allows(client) {
+            return None;
+        }
+        if let Some(elapsed) = since_shutdown.filter(|elapsed| *elapsed < self.cooldown()) {
+            return Some(format!(
+                "shut down for inactivity {} min ago, cooldown is {} min",
+                elapsed.as_secs() / 60,
+                self.cooldown_minutes
+            ));
+        }
+        let tz = parse_timezone(self.timezone.as_deref()).ok()?;
+        self.quiet_hours
+            .iter()
+            .find(|window| window.contains(tz, now))
+            .map(|window| format!("quiet hours {}-{}", window.start, window.end))
+    }
+}
+
+/// Validator for the `wake_policy` of machine payloads.
+pub fn validate_wake_policy(policy: &WakePolicy) -> Result<(), validator::ValidationError> {
+    policy.validate().map_err(|e| {
+        let mut error = validator::ValidationError::new("invalid_wake_policy");
+        error.code = e.to_string().into();
+        error
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn policy() -> WakePolicy {
+        WakePolicy {
+            cooldown_minutes: 10,
+            timezone: Some("Europe/Berlin".to_string()),
+            quiet_hours: vec![TimeWindow {
+                days: vec![],
+                start: "23:00".to_string(),
+                end: "07:00".to_string(),
+            }],
+            allowed_clients: vec!["192.168.1.20".to_string(), "10.0.0.0/24".to_string()],
+        }
+    }
+
+    #[test]
+    fn quiet_hours_and_cooldown_suppress_other_clients() {
+        let policy = policy();
+        policy.validate().unwrap();
+        let phone: IpAddr = "192.168.1.99".parse().unwrap();
+        let daytime = utc("2024-01-05T11:00:00Z");
+
+        assert_eq!(policy.suppression(phone, daytime, None), None);
+        let reason = policy
+            .suppression(phone, daytime, Some(Duration::from_secs(120)))
+            .unwrap();
+        assert!(reason.contains("cooldown"), "{reason}");
+        assert_eq!(
+            policy.suppression(phone, daytime, Some(Duration::from_secs(11 * 60))),
+            None
+        );
+
+        // 01:00 in Berlin
+        let night = utc("2024-01-05T00:00:00Z");
+        let reason = policy.suppression(phone, night, None).unwrap();
+        assert_eq!(reason, "quiet hours 23:00-07:00");
+
+        for allowed in ["192.168.1.20", "10.0.0.7"] {
+            let allowed: IpAddr = allowed.parse().unwrap();
+            assert_eq!(
+                policy.suppression(allowed, night, Some(Duration::ZERO)),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        let mut policy = policy();
+        policy.allowed_clients.push("not-an-ip".to_string());
+        assert!(validate_wake_policy(&policy)
+            .unwrap_err()
+            .code
+            .contains("not-an-ip"));
+        let mut policy = self::policy();
+        policy.quiet_hours[0].end = "23:00".to_string();
+        assert!(policy.validate().is_err());
+    }
+}
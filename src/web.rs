@@ -31,7 +31,17 @@ where
     Ipv4Addr::from_str(&s).map_err(serde::de::Error::custom)
 }
 
+use crate::auth::Auth;
+use crate::event_log::EventLog;
+use crate::events::EventBus;
 use crate::forward;
+use crate::inactivity::{validate_inactivity_policy, InactivityPolicy};
+use crate::machine_state::StateTracker;
+use crate::metrics::Metrics;
+use crate::power::{validate_power_off, validate_power_on, PowerOffBackend, PowerOnBackend};
+use crate::schedule::{validate_schedules, Schedule};
+use crate::wake_policy::{validate_wake_policy, WakePolicy};
+use crate::wol;
 
 const DEFAULT_DB_PATH: &str = "machines.json";
 
@@ -50,12 +60,19 @@ fn machines_db_path() -> PathBuf {
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Machine {
+    /// Generated once and kept when the MAC changes, assigned on load to older databases
+    #[serde(default)]
+    pub id: String,
+    /// Normalized to `AA:BB:CC:DD:EE:FF`
     pub mac: String,
     #[serde(
         serialize_with = "serialize_ipv4addr",
         deserialize_with = "deserialize_ipv4addr"
     )]
     pub ip: Ipv4Addr,
+    /// DNS name `ip` is resolved from, `ip` then holds the last address it resolved to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
     pub name: String,
     pub description: Option<String>,
     pub turn_off_port: Option<u16>,
@@ -64,6 +81,242 @@ pub struct Machine {
     pub inactivity_period: u32,
 
     pub port_forwards: Vec<PortForward>,
+
+    /// Set for machines announced by a client agent until an admin approves them
+    #[serde(default)]
+    pub pending_approval: bool,
+    /// Details reported by the client agent on its last registration
+    #[serde(default)]
+    pub agent: Option<AgentInfo>,
+    /// Last Wake-on-LAN state reported by the client agent, `None` when unknown
+    #[serde(default)]
+    pub wol_armed: Option<bool>,
+    /// How the machine is shut down, defaults to the client agent on `turn_off_port`
+    #[serde(default)]
+    pub power_off: PowerOffBackend,
+    /// How the machine is woken, defaults to magic packets
+    #[serde(default)]
+    pub power_on: PowerOnBackend,
+    /// Used for retries when the machine is still up after a `power_off` request
+    #[serde(default)]
+    pub shutdown_fallback: Option<PowerOffBackend>,
+    /// SHA-256 fingerprint of the agent's certificate; the agent is reached over https when set
+    #[serde(default)]
+    pub agent_tls_fingerprint: Option<String>,
+    /// Free-form labels, used to grant users access to groups of machines
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Cron-style wake and shutdown times
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    /// Minimum uptime and time windows refining `inactivity_period`
+    #[serde(default)]
+    pub inactivity_policy: InactivityPolicy,
+    /// When forwarded connections may not wake the machine
+    #[serde(default)]
+    pub wake_policy: WakePolicy,
+    /// IDs or MACs of machines that must be up before this one, e.g. a NAS it mounts shares
+    /// from
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Further NICs next to the one with `mac` and `ip`, e.g. Wi-Fi next to the wired one
+    #[serde(default)]
+    pub interfaces: Vec<MachineInterface>,
+    /// New address seen by neighbor discovery, waiting to be accepted through the API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_proposal: Option<IpProposal>,
+}
+
+/// A network interface of a machine with several NICs. An entry with the machine's own
+/// `mac` overrides the defaults of its primary interface.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct MachineInterface {
+    pub mac: String,
+    /// Address of the interface, tried by forwards and probes when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<Ipv4Addr>,
+    /// Whether magic packets are sent to this MAC
+    #[serde(default = "default_wake_on_lan")]
+    pub wake_on_lan: bool,
+    /// Interfaces with a lower priority are tried first, the primary one has 0
+    #[serde(default)]
+    pub priority: u32,
+}
+
+/// An address change of one of a machine's interfaces, see `WAKEZILLA__NETWORK__IP_DRIFT`
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct IpProposal {
+    /// MAC of the interface seen at `ip`
+    pub mac: String,
+    pub ip: Ipv4Addr,
+    /// Unix timestamp in seconds of the scan that saw it
+    pub seen_at: u64,
+}
+
+fn default_wake_on_lan() -> bool {
+    true
+}
+
+impl Machine {
+    /// Whether `key` names this machine, either by ID or by MAC in any notation.
+    pub fn matches(&self, key: &str) -> bool {
+        (!self.id.is_empty() && self.id == key)
+            || wol::normalize_mac(key).is_ok_and(|mac| self.macs().any(|own| own == mac))
+    }
+
+    /// The primary MAC followed by those of the other interfaces.
+    pub fn macs(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.mac.as_str()).chain(self.interfaces.iter().map(|i| i.mac.as_str()))
+    }
+
+    /// Every interface including the primary one, in the order they are tried.
+    pub fn all_interfaces(&self) -> Vec<MachineInterface> {
+        let mut all = self.interfaces.clone();
+        match all.iter_mut().find(|i| i.mac == self.mac) {
+            Some(primary) => primary.ip = primary.ip.or(Some(self.ip)),
+            None => all.insert(
+                0,
+                MachineInterface {
+                    mac: self.mac.clone(),
+                    ip: Some(self.ip),
+                    wake_on_lan: true,
+                    priority: 0,
+                },
+            ),
+        }
+        all.sort_by_key(|i| i.priority);
+        all
+    }
+
+    /// MACs that magic packets are sent to.
+    pub fn wake_macs(&self) -> Vec<String> {
+        self.all_interfaces()
+            .into_iter()
+            .filter(|i| i.wake_on_lan)
+            .map(|i| i.mac)
+            .collect()
+    }
+
+    /// Addresses of the machine in the order forwards and probes try them.
+    pub fn addresses(&self) -> Vec<Ipv4Addr> {
+        let mut addresses: Vec<Ipv4Addr> = Vec::new();
+        for ip in self.all_interfaces().into_iter().filter_map(|i| i.ip) {
+            if !addresses.contains(&ip) {
+                addresses.push(ip);
+            }
+        }
+        addresses
+    }
+
+    /// A copy with the credentials of its power backends masked, for API responses.
+    pub fn redacted(&self) -> Machine {
+        Machine {
+            power_off: self.power_off.redacted(),
+            power_on: self.power_on.redacted(),
+            shutdown_fallback: self
+                .shutdown_fallback
+                .as_ref()
+                .map(PowerOffBackend::redacted),
+            ..self.clone()
+        }
+    }
+}
+
+/// The MAC of `machine` another machine in `machines` already uses, if any.
+pub fn mac_conflict(machines: &[Machine], machine: &Machine) -> Option<String> {
+    machine
+        .macs()
+        .find(|mac| {
+            machines
+                .iter()
+                .any(|other| other.id != machine.id && other.macs().any(|own| own == *mac))
+        })
+        .map(str::to_string)
+}
+
+/// Interfaces with their MACs normalized.
+pub fn normalize_interfaces(interfaces: &[MachineInterface]) -> Vec<MachineInterface> {
+    interfaces
+        .iter()
+        .map(|interface| MachineInterface {
+            mac: wol::normalize_mac(&interface.mac).unwrap_or_else(|_| interface.mac.clone()),
+            ..interface.clone()
+        })
+        .collect()
+}
+
+/// Validator for the `interfaces` of machine payloads.
+fn validate_interfaces(interfaces: &[MachineInterface]) -> Result<(), ValidationError> {
+    let mut seen = Vec::new();
+    for interface in interfaces {
+        let Ok(mac) = wol::normalize_mac(&interface.mac) else {
+            let mut error = ValidationError::new("invalid_interface");
+            error.code = format!("Invalid MAC address '{}'", interface.mac).into();
+            return Err(error);
+        };
+        if seen.contains(&mac) {
+            let mut error = ValidationError::new("duplicate_interface");
+            error.code = format!("{} is listed twice", mac).into();
+            return Err(error);
+        }
+        seen.push(mac);
+    }
+    Ok(())
+}
+
+/// A new random machine ID.
+pub fn new_machine_id() -> String {
+    crate::auth::random_hex(8)
+}
+
+/// Find the machine named by `key`, an ID or a MAC.
+pub fn find_machine<'a>(machines: &'a [Machine], key: &str) -> Option<&'a Machine> {
+    machines.iter().find(|m| m.matches(key))
+}
+
+/// Normalize the MACs among machine references such as `depends_on`, IDs are kept as is.
+pub fn normalize_machine_keys(keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .map(|key| wol::normalize_mac(key).unwrap_or_else(|_| key.clone()))
+        .collect()
+}
+
+/// Give machines from older databases an ID and normalize their MACs. Returns whether
+/// anything changed.
+fn migrate_machines(machines: &mut [Machine]) -> bool {
+    let mut changed = false;
+    for machine in machines.iter_mut() {
+        if machine.id.is_empty() {
+            machine.id = new_machine_id();
+            changed = true;
+        }
+        if let Ok(mac) = wol::normalize_mac(&machine.mac) {
+            if mac != machine.mac {
+                machine.mac = mac;
+                changed = true;
+            }
+        }
+        let depends_on = normalize_machine_keys(&machine.depends_on);
+        if depends_on != machine.depends_on {
+            machine.depends_on = depends_on;
+            changed = true;
+        }
+        let interfaces = normalize_interfaces(&machine.interfaces);
+        if interfaces != machine.interfaces {
+            machine.interfaces = interfaces;
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AgentInfo {
+    pub hostname: String,
+    pub port: u16,
+    pub shutdown_actions: Vec<String>,
+    /// Unix timestamp (seconds) of the last registration
+    pub last_seen: u64,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -75,6 +328,8 @@ pub struct PortForward {
 
 #[derive(Deserialize)]
 pub struct DeleteForm {
+    /// ID or MAC of the machine
+    #[serde(alias = "id")]
     pub mac: String,
 }
 
@@ -86,6 +341,26 @@ fn validate_ip(ip: &str) -> Result<(), ValidationError> {
     }
 }
 
+static HOSTNAME_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?(\.[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)*\.?$")
+        .unwrap()
+});
+
+/// Machines are addressed by an IPv4 address or by a hostname resolved when needed. Names
+/// ending in a numeric label are malformed addresses such as `192.168.1.300`.
+fn validate_host(host: &str) -> Result<(), ValidationError> {
+    let numeric = host
+        .trim_end_matches('.')
+        .rsplit('.')
+        .next()
+        .is_some_and(|label| label.chars().all(|c| c.is_ascii_digit()));
+    if host.parse::<Ipv4Addr>().is_ok() || (HOSTNAME_REGEX.is_match(host) && !numeric) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("Invalid IP address or hostname"))
+    }
+}
+
 static MAC_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^([0-9A-Fa-f]{2}[:-]){5}([0-9A-Fa-f]{2})$").unwrap());
 
@@ -100,7 +375,8 @@ fn validate_mac(mac: &str) -> Result<(), ValidationError> {
 pub struct AddMachineForm {
     #[validate(custom(function = "validate_mac"))]
     pub mac: String,
-    #[validate(custom(function = "validate_ip"))]
+    /// IPv4 address or hostname
+    #[validate(custom(function = "validate_host"))]
     pub ip: String,
     pub name: String,
     pub description: Option<String>,
@@ -109,13 +385,41 @@ pub struct AddMachineForm {
     pub can_be_turned_off: bool,
     pub inactivity_period: Option<u32>,
     pub port_forwards: Option<Vec<PortForward>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_power_off"))]
+    pub power_off: Option<PowerOffBackend>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_power_on"))]
+    pub power_on: Option<PowerOnBackend>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_power_off"))]
+    pub shutdown_fallback: Option<PowerOffBackend>,
+    #[serde(default)]
+    pub agent_tls_fingerprint: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_schedules"))]
+    pub schedules: Option<Vec<Schedule>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_inactivity_policy"))]
+    pub inactivity_policy: Option<InactivityPolicy>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_wake_policy"))]
+    pub wake_policy: Option<WakePolicy>,
+    #[serde(default)]
+    pub depends_on: Option<Vec<String>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_interfaces"))]
+    pub interfaces: Option<Vec<MachineInterface>>,
 }
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct MachinePayload {
     #[validate(custom(function = "validate_mac"))]
     pub mac: String,
-    #[validate(custom(function = "validate_ip"))]
+    /// IPv4 address or hostname
+    #[validate(custom(function = "validate_host"))]
     pub ip: String,
     pub name: String,
     pub description: Option<String>,
@@ -124,6 +428,52 @@ pub struct MachinePayload {
     pub can_be_turned_off: bool,
     pub inactivity_period: Option<u32>,
     pub port_forwards: Option<Vec<PortForward>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_power_off"))]
+    pub power_off: Option<PowerOffBackend>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_power_on"))]
+    pub power_on: Option<PowerOnBackend>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_power_off"))]
+    pub shutdown_fallback: Option<PowerOffBackend>,
+    #[serde(default)]
+    pub agent_tls_fingerprint: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_schedules"))]
+    pub schedules: Option<Vec<Schedule>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_inactivity_policy"))]
+    pub inactivity_policy: Option<InactivityPolicy>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_wake_policy"))]
+    pub wake_policy: Option<WakePolicy>,
+    #[serde(default)]
+    pub depends_on: Option<Vec<String>>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_interfaces"))]
+    pub interfaces: Option<Vec<MachineInterface>>,
+}
+
+/// Announcement sent by `wakezilla client-server --register` to the proxy.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct AgentRegistration {
+    pub hostname: String,
+    #[validate(custom(function = "validate_mac"))]
+    pub mac: String,
+    #[validate(custom(function = "validate_ip"))]
+    pub ip: String,
+    pub port: u16,
+    #[serde(default)]
+    pub shutdown_actions: Vec<String>,
+    /// Whether the registering interface is armed for magic packets, if the agent can tell
+    #[serde(default)]
+    pub wol_armed: Option<bool>,
+    /// Fingerprint of the agent's TLS certificate when it serves https
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
 }
 
 pub fn get_default_inactivity_period() -> u32 {
@@ -140,6 +490,14 @@ pub struct AppState {
     pub proxies: Arc<RwLock<HashMap<String, watch::Sender<bool>>>>,
     pub connection_pool: ConnectionPool,
     pub turn_off_limiter: Arc<forward::TurnOffLimiter>,
+    pub events: EventBus,
+    pub auth: Arc<Auth>,
+    /// Live power state of every machine, kept up to date by a background poller
+    pub machine_states: StateTracker,
+    /// History of past events, served by `GET /api/events/history`
+    pub event_log: Arc<EventLog>,
+    /// Counters and histograms served on `GET /metrics`
+    pub metrics: Arc<Metrics>,
     pub monitor_handle: Arc<std::sync::Mutex<Option<tokio::task::AbortHandle>>>,
 }
 
@@ -158,8 +516,19 @@ pub fn load_machines_from_path<P: AsRef<Path>>(path: P) -> Result<Vec<Machine>>
         )
     })?;
 
-    let machines: Vec<Machine> =
+    let mut machines: Vec<Machine> =
         serde_json::from_str(&data).with_context(|| "Failed to parse machines database")?;
+    if migrate_machines(&mut machines) {
+        info!("Assigned machine IDs and normalized MACs, updating the database");
+        let data =
+            serde_json::to_string_pretty(&machines).context("Failed to serialize machines data")?;
+        crate::auth::write_private(path_ref, data.as_bytes()).with_context(|| {
+            format!(
+                "Failed to write machines database to {}",
+                path_ref.display()
+            )
+        })?;
+    }
 
     info!(
         "Successfully loaded {} machines from database at {:?}",
@@ -169,17 +538,24 @@ pub fn load_machines_from_path<P: AsRef<Path>>(path: P) -> Result<Vec<Machine>>
     Ok(machines)
 }
 
+/// Write the machines database; it holds power backend credentials, so only the owner can read it.
 pub fn save_machines(machines: &[Machine]) -> Result<()> {
-    tracing::debug!("Saving machines {:?}", machines);
     let data =
         serde_json::to_string_pretty(machines).context("Failed to serialize machines data")?;
     let path = machines_db_path();
     info!("Saving machines database to {}", path.display());
-    fs::write(&path, data)
+    crate::auth::write_private(&path, data.as_bytes())
         .with_context(|| format!("Failed to write machines database to {}", path.display()))
 }
 
 pub fn start_proxy_if_configured(machine: &Machine, state: &AppState) {
+    if machine.pending_approval {
+        info!(
+            "Machine {} is pending approval, not starting its forwarders",
+            machine.mac
+        );
+        return;
+    }
     for pf in &machine.port_forwards {
         let remote_addr = SocketAddr::new(machine.ip.into(), pf.target_port);
         let wol_port = 9; // Default WOL port
@@ -188,7 +564,7 @@ pub fn start_proxy_if_configured(machine: &Machine, state: &AppState) {
 
         let (tx, rx) = watch::channel(true);
         // The key for the proxy should probably include the port to be unique
-        let proxy_key = format!("{}-{}-{}", machine.mac, local_port, pf.target_port);
+        let proxy_key = format!("{}-{}-{}", machine.id, local_port, pf.target_port);
 
         let proxies_clone = state.proxies.clone();
         let connection_pool_clone = state.connection_pool.clone();
@@ -220,6 +596,32 @@ pub fn start_proxy_if_configured(machine: &Machine, state: &AppState) {
     }
 }
 
+/// Stop every forwarder started for the machine with the given ID, and its inactivity
+/// monitoring until the forwarders start again at its current address.
+pub async fn stop_proxies(state: &AppState, id: &str) {
+    state.turn_off_limiter.forget_machine(id);
+    let prefix = format!("{}-", id);
+    let mut proxies = state.proxies.write().await;
+    proxies.retain(|key, tx| {
+        if key.starts_with(&prefix) {
+            if tx.send(false).is_ok() {
+                info!("Stopped proxy for key: {}", key);
+            }
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Restart the forwarders of `machine` and the inactivity monitor, e.g. after its address
+/// changed.
+pub async fn restart_proxies(state: &AppState, machine: &Machine) {
+    stop_proxies(state, &machine.id).await;
+    start_proxy_if_configured(machine, state);
+    restart_global_monitor(state);
+}
+
 pub fn start_global_monitor(state: &AppState) {
     let mut handle_guard = state.monitor_handle.lock().unwrap();
     if handle_guard.is_none() {
@@ -282,6 +684,17 @@ mod tests {
         assert!(validate_ip("999.999.999.999").is_err());
     }
 
+    #[test]
+    fn validate_host_accepts_ipv4_addresses_and_hostnames() {
+        assert!(validate_host("192.168.0.1").is_ok());
+        assert!(validate_host("nas").is_ok());
+        assert!(validate_host("desktop.lan.").is_ok());
+        assert!(validate_host("::1").is_err());
+        assert!(validate_host("999.999.999.999").is_err());
+        assert!(validate_host("not an ip").is_err());
+        assert!(validate_host("-nas.lan").is_err());
+    }
+
     #[test]
     fn validate_mac_accepts_common_format() {
         assert!(validate_mac("AA:BB:CC:DD:EE:FF").is_ok());
@@ -318,6 +731,84 @@ mod tests {
         assert_eq!(machines[0].ip, Ipv4Addr::new(192, 168, 1, 10));
     }
 
+    #[test]
+    fn interfaces_are_tried_by_priority_and_woken_when_capable() {
+        let mut machine = crate::test_support::machine("AA:BB:CC:DD:EE:FF");
+        machine.ip = Ipv4Addr::new(192, 168, 1, 10);
+        machine.interfaces = vec![
+            MachineInterface {
+                mac: "AA:BB:CC:DD:EE:02".to_string(),
+                ip: Some(Ipv4Addr::new(192, 168, 1, 12)),
+                wake_on_lan: false,
+                priority: 2,
+            },
+            MachineInterface {
+                mac: "AA:BB:CC:DD:EE:01".to_string(),
+                ip: Some(Ipv4Addr::new(192, 168, 1, 11)),
+                wake_on_lan: true,
+                priority: 1,
+            },
+        ];
+        assert_eq!(
+            machine.addresses(),
+            vec![
+                Ipv4Addr::new(192, 168, 1, 10),
+                Ipv4Addr::new(192, 168, 1, 11),
+                Ipv4Addr::new(192, 168, 1, 12)
+            ]
+        );
+        assert_eq!(
+            machine.wake_macs(),
+            vec!["AA:BB:CC:DD:EE:FF", "AA:BB:CC:DD:EE:01"]
+        );
+        assert!(machine.matches("aa-bb-cc-dd-ee-02"));
+
+        // An entry for the primary MAC overrides its defaults
+        machine.interfaces.push(MachineInterface {
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            ip: None,
+            wake_on_lan: false,
+            priority: 3,
+        });
+        assert_eq!(machine.wake_macs(), vec!["AA:BB:CC:DD:EE:01"]);
+        assert_eq!(machine.addresses()[2], Ipv4Addr::new(192, 168, 1, 10));
+
+        let duplicate = vec![machine.interfaces[0].clone(), machine.interfaces[0].clone()];
+        assert!(validate_interfaces(&duplicate).is_err());
+    }
+
+    #[test]
+    fn loading_an_older_database_assigns_ids_and_normalizes_macs() {
+        let mut file = NamedTempFile::new().expect("failed to create temp file");
+        let json = r#"
+            [
+                {
+                    "mac": "aa-bb-cc-dd-ee-ff",
+                    "ip": "192.168.1.10",
+                    "name": "Test",
+                    "description": null,
+                    "turn_off_port": null,
+                    "can_be_turned_off": false,
+                    "port_forwards": [],
+                    "depends_on": ["aa:bb:cc:dd:ee:01"]
+                }
+            ]
+        "#;
+        use std::io::Write;
+        file.write_all(json.as_bytes())
+            .expect("failed to write json");
+        let machines = load_machines_from_path(file.path()).expect("load should succeed");
+        assert_eq!(machines[0].mac, "AA:BB:CC:DD:EE:FF");
+        assert_eq!(machines[0].depends_on, vec!["AA:BB:CC:DD:EE:01"]);
+        assert_eq!(machines[0].id.len(), 16);
+        assert!(machines[0].matches("aa:bb:cc:dd:ee:ff"));
+        assert!(machines[0].matches(&machines[0].id));
+
+        // The migration is written back, so the ID stays the same
+        let reloaded = load_machines_from_path(file.path()).expect("load should succeed");
+        assert_eq!(reloaded[0].id, machines[0].id);
+    }
+
     #[test]
     fn save_machines_writes_using_configured_path() {
         let _lock = ENV_LOCK.lock().unwrap();
@@ -326,6 +817,7 @@ mod tests {
         let _guard = EnvGuard::set_path("WAKEZILLA__STORAGE__MACHINES_DB_PATH", &file_path);
 
         let machines = vec![Machine {
+            id: String::new(),
             mac: "AA:BB:CC:DD:EE:FF".to_string(),
             ip: Ipv4Addr::new(10, 0, 0, 1),
             name: "Test".to_string(),
@@ -334,6 +826,21 @@ mod tests {
             can_be_turned_off: true,
             inactivity_period: get_default_inactivity_period(),
             port_forwards: vec![],
+            pending_approval: false,
+            agent: None,
+            wol_armed: None,
+            power_off: Default::default(),
+            power_on: Default::default(),
+            shutdown_fallback: None,
+            agent_tls_fingerprint: None,
+            tags: Vec::new(),
+            schedules: Vec::new(),
+            inactivity_policy: Default::default(),
+            wake_policy: Default::default(),
+            depends_on: Vec::new(),
+            interfaces: Vec::new(),
+            ip_proposal: None,
+            hostname: None,
         }];
 
         save_machines(&machines).expect("save should succeed");
@@ -346,5 +853,15 @@ mod tests {
         let data: serde_json::Value = serde_json::from_str(&contents).expect("valid json");
         assert_eq!(data[0]["mac"], "AA:BB:CC:DD:EE:FF");
         assert_eq!(data[0]["ip"], "10.0.0.1");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&resolved_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
     }
 }
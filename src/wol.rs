@@ -114,6 +114,16 @@ pub fn parse_mac(s: &str) -> Result<[u8; 6]> {
     Ok(mac)
 }
 
+/// Canonical form of a MAC address: uppercase and colon separated, e.g. `AA:BB:CC:DD:EE:FF`.
+pub fn normalize_mac(s: &str) -> Result<String> {
+    let mac = parse_mac(s)?;
+    Ok(mac
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
 /// Build WOL magic packet: 6 x 0xFF + 16 repetitions of the MAC.
 fn build_magic_packet(mac: &[u8; 6]) -> [u8; 102] {
     let mut pkt = [0u8; 102];
@@ -168,6 +178,7 @@ mod tests {
                 "parsed MAC did not match for input '{}':",
                 input
             );
+            assert_eq!(normalize_mac(input).unwrap(), "AA:BB:CC:DD:EE:FF");
         }
     }
 
@@ -1,18 +1,25 @@
+use std::sync::{Mutex, MutexGuard};
 use std::time::Duration;
 
-use wakezilla::config::Config;
+use wakezilla::config::{Config, IpDriftMode};
+
+/// Tests changing environment variables run one at a time
+static ENV_LOCK: Mutex<()> = Mutex::new(());
 
 struct EnvGuard {
     keys: Vec<&'static str>,
+    _lock: MutexGuard<'static, ()>,
 }
 
 impl EnvGuard {
     fn set(vars: &[(&'static str, &str)]) -> Self {
+        let lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         for (key, value) in vars {
             std::env::set_var(key, value);
         }
         Self {
             keys: vars.iter().map(|(key, _)| *key).collect(),
+            _lock: lock,
         }
     }
 }
@@ -52,6 +59,30 @@ fn config_from_env_applies_overrides() {
     assert_eq!(cfg.health.check_interval_ms, 5000);
 }
 
+#[test]
+fn config_from_env_sets_the_ip_drift_mode() {
+    let _guard = EnvGuard::set(&[("WAKEZILLA__NETWORK__IP_DRIFT", "propose")]);
+
+    let cfg = Config::from_env().expect("config should load from env");
+
+    assert_eq!(cfg.network.ip_drift, IpDriftMode::Propose);
+}
+
+#[test]
+fn config_from_env_sets_the_shutdown_policy() {
+    let _guard = EnvGuard::set(&[
+        ("WAKEZILLA__SHUTDOWN__ATTEMPTS", "5"),
+        ("WAKEZILLA__SHUTDOWN__BACKOFF_SECS", "10"),
+    ]);
+
+    let cfg = Config::from_env().expect("config should load from env");
+
+    let policy = cfg.shutdown_policy();
+    assert_eq!(policy.attempts, 5);
+    assert_eq!(policy.backoff, Duration::from_secs(10));
+    assert_eq!(policy.verify_timeout, Duration::from_secs(120));
+}
+
 #[test]
 fn helper_methods_return_expected_durations() {
     let cfg = Config::default();
@@ -28,14 +28,9 @@ async fn connection_pool_reuses_and_removes_connections() {
     let acceptor_sockets = sockets.clone();
     let acceptor_count = accept_count.clone();
     let accept_task = tokio::spawn(async move {
-        loop {
-            match listener.accept().await {
-                Ok((socket, _)) => {
-                    acceptor_count.fetch_add(1, Ordering::SeqCst);
-                    acceptor_sockets.lock().await.push(socket);
-                }
-                Err(_) => break,
-            }
+        while let Ok((socket, _)) = listener.accept().await {
+            acceptor_count.fetch_add(1, Ordering::SeqCst);
+            acceptor_sockets.lock().await.push(socket);
         }
     });
 
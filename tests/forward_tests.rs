@@ -40,7 +40,7 @@ async fn turn_off_remote_machine_sends_post_request() {
         }
     });
 
-    forward::turn_off_remote_machine(&addr.ip().to_string(), addr.port())
+    forward::turn_off_remote_machine(&addr.ip().to_string(), addr.port(), None, None)
         .await
         .expect("turn_off_remote_machine should succeed");
 
@@ -8,7 +8,10 @@ use tokio::sync::watch;
 
 use std::sync::Arc;
 use wakezilla::connection_pool::ConnectionPool;
+use wakezilla::events::{EventBus, EventKind};
 use wakezilla::forward::{self, TurnOffLimiter};
+use wakezilla::inactivity::TimeWindow;
+use wakezilla::wake_policy::WakePolicy;
 use wakezilla::web::Machine;
 
 fn find_free_port() -> std::io::Result<u16> {
@@ -53,6 +56,7 @@ async fn proxy_forwards_tcp_traffic_and_can_shutdown() {
     });
 
     let machine = Machine {
+        id: String::new(),
         mac: "AA:BB:CC:DD:EE:FF".to_string(),
         ip: match remote_addr.ip() {
             IpAddr::V4(ip) => ip,
@@ -64,6 +68,21 @@ async fn proxy_forwards_tcp_traffic_and_can_shutdown() {
         can_be_turned_off: false,
         inactivity_period: 60,
         port_forwards: Vec::new(),
+        pending_approval: false,
+        agent: None,
+        wol_armed: None,
+        power_off: Default::default(),
+        power_on: Default::default(),
+        shutdown_fallback: None,
+        agent_tls_fingerprint: None,
+        tags: Vec::new(),
+        schedules: Vec::new(),
+        inactivity_policy: Default::default(),
+        wake_policy: Default::default(),
+        depends_on: Vec::new(),
+        interfaces: Vec::new(),
+        ip_proposal: None,
+        hostname: None,
     };
 
     let (tx, rx) = watch::channel(true);
@@ -123,3 +142,106 @@ async fn proxy_forwards_tcp_traffic_and_can_shutdown() {
 
     remote_task.abort();
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn proxy_drops_connections_during_quiet_hours_instead_of_waking() {
+    let local_port = match find_free_port() {
+        Ok(port) => port,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            eprintln!(
+                "skipping proxy integration test because discovering free ports is not permitted: {}",
+                err
+            );
+            return;
+        }
+        Err(err) => panic!("failed to discover free port: {err}"),
+    };
+    // Nothing listens on the target, so the machine looks down
+    let remote_port = find_free_port().expect("failed to discover free port");
+    let remote_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), remote_port);
+
+    let quiet = |start: &str, end: &str| TimeWindow {
+        days: Vec::new(),
+        start: start.to_string(),
+        end: end.to_string(),
+    };
+    let machine = Machine {
+        id: String::new(),
+        mac: "AA:BB:CC:DD:EE:FF".to_string(),
+        ip: Ipv4Addr::LOCALHOST,
+        name: "quiet".to_string(),
+        description: None,
+        turn_off_port: None,
+        can_be_turned_off: false,
+        inactivity_period: 60,
+        port_forwards: Vec::new(),
+        pending_approval: false,
+        agent: None,
+        wol_armed: None,
+        power_off: Default::default(),
+        power_on: Default::default(),
+        shutdown_fallback: None,
+        agent_tls_fingerprint: None,
+        tags: Vec::new(),
+        schedules: Vec::new(),
+        inactivity_policy: Default::default(),
+        // Quiet all day long
+        wake_policy: WakePolicy {
+            quiet_hours: vec![quiet("00:00", "12:00"), quiet("12:00", "00:00")],
+            ..Default::default()
+        },
+        depends_on: Vec::new(),
+        interfaces: Vec::new(),
+        ip_proposal: None,
+        hostname: None,
+    };
+
+    let events = EventBus::new();
+    let mut received = events.subscribe();
+    let limiter = Arc::new(TurnOffLimiter::with_events(events));
+    let (tx, rx) = watch::channel(true);
+    let proxy_task = tokio::spawn(forward::TurnOffLimiter::proxy(
+        local_port,
+        remote_addr,
+        machine,
+        9,
+        rx,
+        ConnectionPool::new(),
+        limiter,
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client =
+        TcpStream::connect(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), local_port))
+            .await
+            .expect("client failed to connect to proxy");
+    let mut buf = [0u8; 4];
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buf))
+        .await
+        .expect("connection should be dropped without waiting for a wake");
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    let event = loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("no event")
+            .expect("event bus closed");
+        if !matches!(event.kind, EventKind::ForwarderStarted { .. }) {
+            break event;
+        }
+    };
+    match event.kind {
+        EventKind::WakeSuppressed { client, reason } => {
+            assert_eq!(client, "127.0.0.1");
+            assert!(reason.starts_with("quiet hours"), "{reason}");
+        }
+        other => panic!("expected a suppressed wake, got {other:?}"),
+    }
+    assert_eq!(event.cause.actor.as_deref(), Some("client 127.0.0.1"));
+
+    tx.send(false).expect("failed to send shutdown signal");
+    proxy_task
+        .await
+        .expect("proxy task panicked")
+        .expect("proxy task returned error");
+}
@@ -1,5 +1,5 @@
 #![cfg(test)]
-use wakezilla::scanner::{DiscoveredDevice, NetworkInterface};
+use wakezilla::scanner::{parse_neighbor_table, DiscoveredDevice, NetworkInterface};
 
 #[test]
 fn discovered_device_serialize() {
@@ -12,6 +12,49 @@ fn discovered_device_serialize() {
     assert!(json.contains("\"ip\":\"192.168.1.1\""));
 }
 
+#[test]
+fn neighbor_table_entries_are_filtered_to_resolved_hosts_on_the_interface() {
+    let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0
+192.168.1.20     0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.30     0x1         0x6         aa:bb:cc:dd:ee:03     *        eth0
+10.0.0.5         0x1         0x2         aa:bb:cc:dd:ee:04     *        eth0
+192.168.1.40     0x1         0x2         aa:bb:cc:dd:ee:05     *        wlan0
+";
+    let network = "192.168.1.0/24".parse().unwrap();
+    let devices = parse_neighbor_table(table, "eth0", network);
+    let found: Vec<(&str, &str)> = devices
+        .iter()
+        .map(|d| (d.ip.as_str(), d.mac.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("192.168.1.1", "AA:BB:CC:DD:EE:01"),
+            ("192.168.1.30", "AA:BB:CC:DD:EE:03")
+        ]
+    );
+}
+
+#[test]
+fn neighbor_table_drops_macs_listed_at_several_addresses() {
+    // A host that moved keeps its stale entry until it expires
+    let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.10     0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0
+192.168.1.11     0x1         0x2         aa:bb:cc:dd:ee:02     *        eth0
+192.168.1.20     0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0
+";
+    let network = "192.168.1.0/24".parse().unwrap();
+    let devices = parse_neighbor_table(table, "eth0", network);
+    let found: Vec<(&str, &str)> = devices
+        .iter()
+        .map(|d| (d.ip.as_str(), d.mac.as_str()))
+        .collect();
+    assert_eq!(found, vec![("192.168.1.11", "AA:BB:CC:DD:EE:02")]);
+}
+
 #[tokio::test]
 async fn test_scan_network_basic() {
     // This test triggers the network interface detection code
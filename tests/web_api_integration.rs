@@ -7,10 +7,19 @@ use http_body_util::BodyExt;
 use tempfile::TempDir;
 use tokio::sync::RwLock;
 use tower::util::ServiceExt;
+use wakezilla::auth::{Auth, Grants, Role, TokenScope};
+use wakezilla::client_server::ClientOptions;
+use wakezilla::config::IpDriftMode;
 use wakezilla::connection_pool::ConnectionPool;
+use wakezilla::event_log::EventLog;
+use wakezilla::events::{Cause, EventBus, EventKind};
 use wakezilla::forward::TurnOffLimiter;
-use wakezilla::proxy_server::{api_routes, build_router};
-use wakezilla::web::{AppState, Machine};
+use wakezilla::ip_drift;
+use wakezilla::machine_state::StateTracker;
+use wakezilla::metrics::Metrics;
+use wakezilla::proxy_server::{api_routes, build_router, local_agent_routes};
+use wakezilla::scanner::DiscoveredDevice;
+use wakezilla::web::{AppState, Machine, MachineInterface};
 
 struct EnvVarGuard {
     key: &'static str,
@@ -43,6 +52,15 @@ fn setup_state(temp_dir: &TempDir) -> (AppState, EnvVarGuard) {
         proxies,
         connection_pool: ConnectionPool::new(),
         turn_off_limiter: Arc::new(TurnOffLimiter::new()),
+        events: EventBus::new(),
+        auth: Arc::new(Auth::disabled()),
+        machine_states: StateTracker::new(EventBus::new()),
+        event_log: Arc::new(EventLog::open(
+            temp_dir.path().join("events.jsonl"),
+            1024 * 1024,
+            1,
+        )),
+        metrics: Arc::new(Metrics::new()),
         monitor_handle: Arc::new(std::sync::Mutex::new(None)),
     };
 
@@ -51,6 +69,7 @@ fn setup_state(temp_dir: &TempDir) -> (AppState, EnvVarGuard) {
 
 fn sample_machine() -> Machine {
     Machine {
+        id: String::new(),
         mac: "AA:BB:CC:DD:EE:FF".to_string(),
         ip: "127.0.0.1".parse().expect("valid ip"),
         name: "Workstation".to_string(),
@@ -59,6 +78,21 @@ fn sample_machine() -> Machine {
         can_be_turned_off: false,
         inactivity_period: 60,
         port_forwards: Vec::new(),
+        pending_approval: false,
+        agent: None,
+        wol_armed: None,
+        power_off: Default::default(),
+        power_on: Default::default(),
+        shutdown_fallback: None,
+        agent_tls_fingerprint: None,
+        tags: Vec::new(),
+        schedules: Vec::new(),
+        inactivity_policy: Default::default(),
+        wake_policy: Default::default(),
+        depends_on: Vec::new(),
+        interfaces: Vec::new(),
+        ip_proposal: None,
+        hostname: None,
     }
 }
 
@@ -222,7 +256,7 @@ async fn add_machine_with_invalid_data_returns_errors() {
                 .body(Body::from(
                     serde_json::to_vec(&serde_json::json!({
                         "mac": "bad",
-                        "ip": "not-an-ip",
+                        "ip": "not an ip",
                         "name": "",
                         "description": null,
                         "turn_off_port": null,
@@ -253,3 +287,1188 @@ async fn add_machine_with_invalid_data_returns_errors() {
     let machines = state.machines.read().await;
     assert!(machines.is_empty());
 }
+
+#[tokio::test]
+async fn agent_registration_creates_pending_machine_and_tracks_ip() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = build_router(state.clone()).merge(api_routes(state.clone()));
+
+    let register = |ip: &str| {
+        Request::builder()
+            .uri("/api/agents/register")
+            .method("POST")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&serde_json::json!({
+                    "hostname": "lab-01",
+                    "mac": "aa:bb:cc:dd:ee:01",
+                    "ip": ip,
+                    "port": 3001,
+                    "shutdown_actions": ["suspend", "poweroff"],
+                    "tls_fingerprint": format!("{}:01", ip)
+                }))
+                .expect("serialize payload"),
+            ))
+            .expect("failed to build register request")
+    };
+
+    let response = app
+        .clone()
+        .oneshot(register("192.168.1.20"))
+        .await
+        .expect("register handler failed");
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    {
+        let machines = state.machines.read().await;
+        assert_eq!(machines.len(), 1);
+        assert!(machines[0].pending_approval);
+        assert_eq!(machines[0].name, "lab-01");
+        assert_eq!(machines[0].turn_off_port, Some(3001));
+        assert_eq!(
+            machines[0].agent_tls_fingerprint.as_deref(),
+            Some("192.168.1.20:01")
+        );
+    }
+
+    let response = app
+        .clone()
+        .oneshot(register("192.168.1.42"))
+        .await
+        .expect("register handler failed");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    {
+        let machines = state.machines.read().await;
+        assert_eq!(machines.len(), 1);
+        assert_eq!(machines[0].ip.to_string(), "192.168.1.42");
+        let agent = machines[0].agent.as_ref().expect("agent info recorded");
+        assert_eq!(agent.shutdown_actions, vec!["suspend", "poweroff"]);
+        // The first announced certificate stays pinned
+        assert_eq!(
+            machines[0].agent_tls_fingerprint.as_deref(),
+            Some("192.168.1.20:01")
+        );
+    }
+
+    let mac = state.machines.read().await[0].mac.clone();
+    let approve = app
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/machines/{}/approve", mac))
+                .method("POST")
+                .body(Body::empty())
+                .expect("failed to build approve request"),
+        )
+        .await
+        .expect("approve handler failed");
+    assert_eq!(approve.status(), StatusCode::OK);
+    assert!(!state.machines.read().await[0].pending_approval);
+}
+
+#[tokio::test]
+async fn agents_reporting_a_second_nic_refresh_the_existing_machine() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let mut machine = sample_machine();
+    machine.hostname = Some("workstation.lan".to_string());
+    machine.interfaces = vec![MachineInterface {
+        mac: "AA:BB:CC:DD:EE:02".to_string(),
+        ip: None,
+        wake_on_lan: false,
+        priority: 1,
+    }];
+    state.machines.write().await.push(machine);
+    let app = api_routes(state.clone());
+    let register = |ip: &str| {
+        Request::builder()
+            .uri("/api/agents/register")
+            .method("POST")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&serde_json::json!({
+                    "hostname": "workstation",
+                    "mac": "aa:bb:cc:dd:ee:02",
+                    "ip": ip,
+                    "port": 3001,
+                }))
+                .expect("serialize payload"),
+            ))
+            .expect("failed to build register request")
+    };
+
+    let response = app
+        .clone()
+        .oneshot(register("192.168.1.77"))
+        .await
+        .expect("register handler failed");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let machines = state.machines.read().await;
+    assert_eq!(machines.len(), 1);
+    assert!(machines[0].agent.is_some());
+    // The address of a machine configured by hostname comes from the resolver
+    assert_eq!(machines[0].ip.to_string(), "127.0.0.1");
+    drop(machines);
+
+    // Without a hostname, the second NIC's address still isn't the primary one
+    state.machines.write().await[0].hostname = None;
+    let response = app
+        .oneshot(register("192.168.1.78"))
+        .await
+        .expect("register handler failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(state.machines.read().await[0].ip.to_string(), "127.0.0.1");
+}
+
+#[tokio::test]
+async fn api_requires_a_session_with_csrf_token_or_an_api_token() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (mut state, _guard) = setup_state(&temp_dir);
+    let auth = Auth::in_memory(std::time::Duration::from_secs(60));
+    auth.add_user("alice", "correct horse", Some(Role::Admin))
+        .expect("failed to add user");
+    state.auth = Arc::new(auth);
+    let app = build_router(state.clone()).merge(api_routes(state.clone()));
+
+    let request = |method: &str, uri: &str, headers: &[(&str, &str)], body: Body| {
+        let mut builder = Request::builder().uri(uri).method(method);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(body).expect("failed to build request")
+    };
+    let login = |password: &str| {
+        request(
+            "POST",
+            "/api/auth/login",
+            &[("content-type", "application/json")],
+            Body::from(
+                serde_json::to_vec(&serde_json::json!({
+                    "username": "alice",
+                    "password": password,
+                }))
+                .expect("serialize login"),
+            ),
+        )
+    };
+
+    let anonymous = app
+        .clone()
+        .oneshot(request("GET", "/api/machines", &[], Body::empty()))
+        .await
+        .expect("handler failed");
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+
+    let wrong = app
+        .clone()
+        .oneshot(login("wrong password"))
+        .await
+        .expect("login handler failed");
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .clone()
+        .oneshot(login("correct horse"))
+        .await
+        .expect("login handler failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    let set_cookie = response.headers()["set-cookie"]
+        .to_str()
+        .expect("cookie header")
+        .to_string();
+    assert!(set_cookie.contains("HttpOnly"));
+    let cookie = set_cookie
+        .split(';')
+        .next()
+        .expect("cookie pair")
+        .to_string();
+    let json: serde_json::Value = serde_json::from_slice(
+        &response
+            .into_body()
+            .collect()
+            .await
+            .expect("failed to collect body")
+            .to_bytes(),
+    )
+    .expect("valid login json");
+    let csrf = json["csrf_token"].as_str().expect("csrf token").to_string();
+
+    let listed = app
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/api/machines",
+            &[("cookie", &cookie)],
+            Body::empty(),
+        ))
+        .await
+        .expect("handler failed");
+    assert_eq!(listed.status(), StatusCode::OK);
+
+    let approve = "/api/machines/AA:BB:CC:DD:EE:FF/approve";
+    let without_csrf = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            approve,
+            &[("cookie", &cookie)],
+            Body::empty(),
+        ))
+        .await
+        .expect("handler failed");
+    assert_eq!(without_csrf.status(), StatusCode::FORBIDDEN);
+
+    let with_csrf = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            approve,
+            &[("cookie", &cookie), ("x-csrf-token", &csrf)],
+            Body::empty(),
+        ))
+        .await
+        .expect("handler failed");
+    assert_eq!(with_csrf.status(), StatusCode::NOT_FOUND);
+
+    let (_, secret) = state
+        .auth
+        .create_token("alice", "script", TokenScope::Full)
+        .expect("failed to create token");
+    let bearer = format!("Bearer {}", secret);
+    let with_token = app
+        .oneshot(request(
+            "POST",
+            approve,
+            &[("authorization", &bearer)],
+            Body::empty(),
+        ))
+        .await
+        .expect("handler failed");
+    assert_eq!(with_token.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn all_in_one_agent_routes_require_authentication() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (mut state, _guard) = setup_state(&temp_dir);
+    let auth = Auth::in_memory(std::time::Duration::from_secs(60));
+    auth.add_user("guest", "correct horse", Some(Role::Viewer))
+        .expect("failed to add user");
+    state.auth = Arc::new(auth);
+    let app = local_agent_routes(state.clone(), ClientOptions::default());
+
+    let send = |method: &str, uri: &str, token: Option<&str>| {
+        let mut builder = Request::builder().uri(uri).method(method);
+        if let Some(token) = token {
+            builder = builder.header("authorization", token);
+        }
+        app.clone().oneshot(
+            builder
+                .body(Body::empty())
+                .expect("failed to build request"),
+        )
+    };
+
+    // The action is unsupported, so nothing would shut down even if the check failed
+    let turn_off = "/machines/turn-off?action=not-an-action";
+    let anonymous = send("POST", turn_off, None).await.expect("handler failed");
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+    let enable_wol = send("POST", "/wol/eth0/enable", None)
+        .await
+        .expect("handler failed");
+    assert_eq!(enable_wol.status(), StatusCode::UNAUTHORIZED);
+
+    let (_, secret) = state
+        .auth
+        .create_token("guest", "test", TokenScope::Full)
+        .expect("failed to create token");
+    let guest = format!("Bearer {}", secret);
+    let viewer = send("POST", turn_off, Some(&guest))
+        .await
+        .expect("handler failed");
+    assert_eq!(viewer.status(), StatusCode::FORBIDDEN);
+
+    let health = send("GET", "/health", None).await.expect("handler failed");
+    assert_eq!(health.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn roles_and_grants_limit_what_users_can_see_and_do() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (mut state, _guard) = setup_state(&temp_dir);
+    let auth = Auth::in_memory(std::time::Duration::from_secs(60));
+    auth.add_user("kid", "correct horse", Some(Role::Operator))
+        .expect("failed to add user");
+    auth.set_access(
+        "kid",
+        None,
+        Grants {
+            machines: vec!["aa:bb:cc:dd:ee:ff".to_string()],
+            tags: Vec::new(),
+        },
+    )
+    .expect("failed to set access");
+    auth.add_user("guest", "correct horse", Some(Role::Viewer))
+        .expect("failed to add user");
+    state.auth = Arc::new(auth);
+
+    let mut other = sample_machine();
+    other.mac = "11:22:33:44:55:66".to_string();
+    other.name = "Lab server".to_string();
+    *state.machines.write().await = vec![sample_machine(), other];
+    let app = api_routes(state.clone());
+
+    let bearer = |username: &str| {
+        let (_, secret) = state
+            .auth
+            .create_token(username, "test", TokenScope::Full)
+            .expect("failed to create token");
+        format!("Bearer {}", secret)
+    };
+    let kid = bearer("kid");
+    let guest = bearer("guest");
+    let send = |method: &str, uri: &str, token: &str| {
+        let request = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("authorization", token)
+            .header("content-type", "application/json")
+            .body(Body::from("{\"mac\":\"AA:BB:CC:DD:EE:FF\"}"))
+            .expect("failed to build request");
+        app.clone().oneshot(request)
+    };
+
+    let listed = send("GET", "/api/machines", &kid)
+        .await
+        .expect("handler failed");
+    assert_eq!(listed.status(), StatusCode::OK);
+    let machines: Vec<Machine> = serde_json::from_slice(
+        &listed
+            .into_body()
+            .collect()
+            .await
+            .expect("failed to collect body")
+            .to_bytes(),
+    )
+    .expect("valid machines json");
+    assert_eq!(machines.len(), 1);
+    assert_eq!(machines[0].mac, "AA:BB:CC:DD:EE:FF");
+
+    let hidden = send("GET", "/api/machines/11:22:33:44:55:66", &kid)
+        .await
+        .expect("handler failed");
+    assert_eq!(hidden.status(), StatusCode::NOT_FOUND);
+    let wake_hidden = send("POST", "/api/machines/11:22:33:44:55:66/wake", &kid)
+        .await
+        .expect("handler failed");
+    assert_eq!(wake_hidden.status(), StatusCode::NOT_FOUND);
+
+    let scan = send("GET", "/api/scan", &kid)
+        .await
+        .expect("handler failed");
+    assert_eq!(scan.status(), StatusCode::FORBIDDEN);
+    let delete = send("DELETE", "/api/machines/delete", &kid)
+        .await
+        .expect("handler failed");
+    assert_eq!(delete.status(), StatusCode::FORBIDDEN);
+    assert_eq!(state.machines.read().await.len(), 2);
+
+    let viewer_listed = send("GET", "/api/machines", &guest)
+        .await
+        .expect("handler failed");
+    assert_eq!(viewer_listed.status(), StatusCode::OK);
+    let viewer_off = send(
+        "POST",
+        "/api/machines/AA:BB:CC:DD:EE:FF/remote-turn-off",
+        &guest,
+    )
+    .await
+    .expect("handler failed");
+    assert_eq!(viewer_off.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn wakers_and_register_tokens_are_limited_to_their_action() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (mut state, _guard) = setup_state(&temp_dir);
+    let auth = Auth::in_memory(std::time::Duration::from_secs(60));
+    auth.add_user("family", "correct horse", Some(Role::Waker))
+        .expect("failed to add user");
+    auth.add_user("alice", "correct horse", Some(Role::Admin))
+        .expect("failed to add user");
+    assert!(auth
+        .create_token("family", "agent", TokenScope::Register)
+        .is_err());
+    state.auth = Arc::new(auth);
+    *state.machines.write().await = vec![sample_machine()];
+    let app = api_routes(state.clone());
+
+    let bearer = |username: &str, scope: TokenScope| {
+        let (_, secret) = state
+            .auth
+            .create_token(username, "test", scope)
+            .expect("failed to create token");
+        format!("Bearer {}", secret)
+    };
+    let family = bearer("family", TokenScope::Full);
+    let agent = bearer("alice", TokenScope::Register);
+    let send = |method: &str, uri: &str, token: &str| {
+        let request = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("authorization", token)
+            .header("content-type", "application/json")
+            .body(Body::from("{}"))
+            .expect("failed to build request");
+        app.clone().oneshot(request)
+    };
+
+    // Past the role check the unknown group is reported as missing
+    let wake = send("POST", "/api/groups/nothing/wake", &family)
+        .await
+        .expect("handler failed");
+    assert_eq!(wake.status(), StatusCode::NOT_FOUND);
+    let turn_off = send(
+        "POST",
+        "/api/machines/AA:BB:CC:DD:EE:FF/remote-turn-off",
+        &family,
+    )
+    .await
+    .expect("handler failed");
+    assert_eq!(turn_off.status(), StatusCode::FORBIDDEN);
+
+    let listed = send("GET", "/api/machines", &agent)
+        .await
+        .expect("handler failed");
+    assert_eq!(listed.status(), StatusCode::FORBIDDEN);
+    // The empty announcement gets past authentication and fails validation
+    let register = send("POST", "/api/agents/register", &agent)
+        .await
+        .expect("handler failed");
+    assert_eq!(register.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn events_endpoint_streams_machine_events() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/events")
+                .body(Body::empty())
+                .expect("failed to build events request"),
+        )
+        .await
+        .expect("events handler failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    state
+        .events
+        .emit("AA:BB:CC:DD:EE:FF", EventKind::WakeRequested);
+    let mut body = response.into_body();
+    let frame = tokio::time::timeout(std::time::Duration::from_secs(5), body.frame())
+        .await
+        .expect("no event within 5s")
+        .expect("stream ended")
+        .expect("body error");
+    let data =
+        String::from_utf8(frame.into_data().expect("data frame").to_vec()).expect("utf-8 event");
+    assert!(data.starts_with("data: "));
+    assert!(data.contains("\"type\":\"wake_requested\""));
+    assert!(data.contains("\"mac\":\"AA:BB:CC:DD:EE:FF\""));
+}
+
+#[tokio::test]
+async fn history_endpoint_returns_recorded_events_with_their_cause() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let recorder = state.event_log.start(&state.events);
+    let app = api_routes(state.clone());
+
+    let machine = sample_machine();
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/machines")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({
+                        "mac": machine.mac,
+                        "ip": machine.ip.to_string(),
+                        "name": machine.name,
+                        "can_be_turned_off": false,
+                    }))
+                    .unwrap(),
+                ))
+                .expect("failed to build add request"),
+        )
+        .await
+        .expect("add handler failed");
+    assert_eq!(response.status(), StatusCode::CREATED);
+    state.events.emit_caused(
+        "11:22:33:44:55:66",
+        EventKind::WakeRequested,
+        &Cause::new("client 10.0.0.9", "connection to port 8080"),
+    );
+
+    // The log is written by a background task
+    let mut history = serde_json::Value::Null;
+    for _ in 0..50 {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/events/history?mac={}&since=0", machine.mac))
+                    .body(Body::empty())
+                    .expect("failed to build history request"),
+            )
+            .await
+            .expect("history handler failed");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        history = serde_json::from_slice(&body).expect("history should be JSON");
+        if !history.as_array().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let history = history.as_array().expect("history should be a list");
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0]["type"], "machine_added");
+    assert_eq!(history[0]["mac"], machine.mac);
+    assert_eq!(history[0]["actor"], "anonymous");
+    assert!(temp_dir.path().join("events.jsonl").exists());
+    recorder.abort();
+}
+
+#[tokio::test]
+async fn metrics_endpoint_exposes_prometheus_text() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    state.machines.write().await.push(sample_machine());
+    let app = api_routes(state.clone());
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .expect("failed to build metrics request"),
+        )
+        .await
+        .expect("metrics handler failed");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let text = String::from_utf8(body.to_vec()).expect("metrics should be utf-8");
+    assert!(text.contains("# TYPE wakezilla_wake_duration_seconds histogram"));
+    assert!(text.contains(
+        "wakezilla_machine_state{mac=\"AA:BB:CC:DD:EE:FF\",name=\"Workstation\",state=\"unknown\"} 1"
+    ));
+}
+
+#[tokio::test]
+async fn schedules_are_validated_and_persisted() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let add = |schedules: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/machines")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&serde_json::json!({
+                    "mac": "AA:BB:CC:DD:EE:FF",
+                    "ip": "192.168.1.50",
+                    "name": "Build server",
+                    "schedules": schedules,
+                }))
+                .unwrap(),
+            ))
+            .expect("failed to build add request")
+    };
+
+    let response = app
+        .clone()
+        .oneshot(add(serde_json::json!([
+            { "action": "keep_awake", "cron": "30 7 * * 1-5" }
+        ])))
+        .await
+        .expect("add handler failed");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(body["errors"]["schedules"][0]
+        .as_str()
+        .unwrap()
+        .contains("until"));
+
+    let response = app
+        .oneshot(add(serde_json::json!([
+            { "action": "keep_awake", "cron": "30 7 * * 1-5", "timezone": "Europe/Berlin", "until": "20:00" },
+            { "action": "shutdown", "cron": "0 20 * * 1-5", "timezone": "Europe/Berlin" }
+        ])))
+        .await
+        .expect("add handler failed");
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let saved = wakezilla::web::load_machines_from_path(temp_dir.path().join("machines.json"))
+        .expect("machines should be saved");
+    assert_eq!(saved[0].schedules.len(), 2);
+    assert_eq!(saved[0].schedules[1].cron, "0 20 * * 1-5");
+}
+
+#[tokio::test]
+async fn inactivity_policy_is_saved_and_machines_can_be_kept_awake() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/machines")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({
+                        "mac": "AA:BB:CC:DD:EE:FF",
+                        "ip": "192.168.1.50",
+                        "name": "Build server",
+                        "inactivity_policy": {
+                            "min_uptime_minutes": 20,
+                            "timezone": "Europe/Berlin",
+                            "windows": [
+                                { "days": ["mon", "fri"], "start": "08:00", "end": "18:00" }
+                            ]
+                        },
+                    }))
+                    .unwrap(),
+                ))
+                .expect("failed to build add request"),
+        )
+        .await
+        .expect("add handler failed");
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let saved = wakezilla::web::load_machines_from_path(temp_dir.path().join("machines.json"))
+        .expect("machines should be saved");
+    assert_eq!(saved[0].inactivity_policy.min_uptime_minutes, 20);
+    assert_eq!(
+        saved[0].inactivity_policy.windows[0].inactivity_period,
+        None
+    );
+
+    let keep_awake = |minutes: u32| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/machines/AA:BB:CC:DD:EE:FF/keep-awake")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&serde_json::json!({ "minutes": minutes })).unwrap(),
+            ))
+            .expect("failed to build keep-awake request")
+    };
+    let response = app.clone().oneshot(keep_awake(0)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.clone().oneshot(keep_awake(180)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let until = body["keep_awake_until"].as_u64().expect("override end");
+
+    let details = |app: axum::Router| async move {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/machines/AA:BB:CC:DD:EE:FF")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+    assert_eq!(details(app.clone()).await["keep_awake_until"], until);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("DELETE")
+                .uri("/api/machines/AA:BB:CC:DD:EE:FF/keep-awake")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(details(app).await.get("keep_awake_until").is_none());
+}
+
+#[tokio::test]
+async fn dependency_cycles_are_rejected_and_dependencies_cannot_be_deleted() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let request = |method: &str, uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .expect("failed to build request")
+    };
+    let machine = |mac: &str, name: &str, depends_on: &[&str]| {
+        serde_json::json!({
+            "mac": mac,
+            "ip": "192.168.1.50",
+            "name": name,
+            "depends_on": depends_on,
+        })
+    };
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/api/machines",
+            machine("AA:BB:CC:DD:EE:01", "Media", &["AA:BB:CC:DD:EE:02"]),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    for (mac, name, depends_on) in [
+        ("AA:BB:CC:DD:EE:02", "NAS", vec![]),
+        ("AA:BB:CC:DD:EE:01", "Media", vec!["AA:BB:CC:DD:EE:02"]),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(request(
+                "POST",
+                "/api/machines",
+                machine(mac, name, &depends_on),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PUT",
+            "/api/machines/AA:BB:CC:DD:EE:02",
+            machine("AA:BB:CC:DD:EE:02", "NAS", &["AA:BB:CC:DD:EE:01"]),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(body["errors"]["depends_on"][0]
+        .as_str()
+        .unwrap()
+        .contains("dependency cycle"));
+    {
+        // The rejected update left the machines untouched
+        let machines = state.machines.read().await;
+        let names: Vec<&str> = machines.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["NAS", "Media"]);
+        assert!(machines[0].depends_on.is_empty());
+    }
+
+    let response = app
+        .oneshot(request(
+            "DELETE",
+            "/api/machines/delete",
+            serde_json::json!({ "mac": "AA:BB:CC:DD:EE:02" }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(state.machines.read().await.len(), 2);
+}
+
+#[tokio::test]
+async fn group_actions_report_an_outcome_per_machine() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    {
+        let mut machines = state.machines.write().await;
+        for (i, tags) in [vec!["lab"], vec!["lab", "gpu"], vec!["office"]]
+            .into_iter()
+            .enumerate()
+        {
+            let mut machine = sample_machine();
+            machine.mac = format!("AA:BB:CC:DD:EE:0{}", i);
+            machine.name = format!("machine-{}", i);
+            machine.tags = tags.into_iter().map(str::to_string).collect();
+            machines.push(machine);
+        }
+    }
+    let app = api_routes(state.clone());
+    let call = |method: &str, uri: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .expect("failed to build request");
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.expect("handler failed");
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    let (status, groups) = call("GET", "/api/groups").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        groups,
+        serde_json::json!([
+            { "name": "gpu", "machines": 1 },
+            { "name": "lab", "machines": 2 },
+            { "name": "office", "machines": 1 },
+        ])
+    );
+
+    let (status, members) = call("GET", "/api/groups/lab").await;
+    assert_eq!(status, StatusCode::OK);
+    let names: Vec<&str> = members
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["machine-0", "machine-1"]);
+    assert_eq!(members[0]["state"], "unknown");
+
+    // None of them can be turned off, each one reports why
+    let (status, body) = call("POST", "/api/groups/lab/turn-off").await;
+    assert_eq!(status, StatusCode::OK);
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[1]["mac"], "AA:BB:CC:DD:EE:01");
+    assert_eq!(results[1]["success"], false);
+    assert!(!results[1]["message"].as_str().unwrap().is_empty());
+
+    let (status, _) = call("POST", "/api/groups/nothing/wake").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn machines_keep_their_id_when_the_mac_changes_and_macs_stay_aliases() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let request = |method: &str, uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .expect("failed to build request")
+    };
+    let machine = |mac: &str, name: &str, depends_on: &[&str]| {
+        serde_json::json!({
+            "mac": mac,
+            "ip": "192.168.1.50",
+            "name": name,
+            "depends_on": depends_on,
+        })
+    };
+    let get = |uri: String| {
+        Request::builder()
+            .uri(uri)
+            .body(Body::empty())
+            .expect("failed to build request")
+    };
+
+    for (mac, name, depends_on) in [
+        ("aa-bb-cc-dd-ee-10", "NAS", vec![]),
+        ("AA:BB:CC:DD:EE:20", "Media", vec!["aa:bb:cc:dd:ee:10"]),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(request(
+                "POST",
+                "/api/machines",
+                machine(mac, name, &depends_on),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    let id = {
+        let machines = state.machines.read().await;
+        assert_eq!(machines[0].mac, "AA:BB:CC:DD:EE:10");
+        assert_eq!(machines[1].depends_on, vec!["AA:BB:CC:DD:EE:10"]);
+        assert!(!machines[0].id.is_empty());
+        assert_ne!(machines[0].id, machines[1].id);
+        machines[0].id.clone()
+    };
+
+    // The same MAC in another notation is the same machine
+    let response = app
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/api/machines",
+            machine("AA-BB-CC-DD-EE-10", "Duplicate", &[]),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    for uri in [
+        format!("/api/machines/{}", id),
+        "/api/machines/aa-bb-cc-dd-ee-10".to_string(),
+    ] {
+        let response = app.clone().oneshot(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["id"], id.as_str());
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request(
+            "PUT",
+            &format!("/api/machines/{}", id),
+            machine("aa:bb:cc:dd:ee:11", "NAS", &[]),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    {
+        let machines = state.machines.read().await;
+        let nas = machines.iter().find(|m| m.id == id).unwrap();
+        assert_eq!(nas.mac, "AA:BB:CC:DD:EE:11");
+        let media = machines.iter().find(|m| m.name == "Media").unwrap();
+        assert_eq!(media.depends_on, vec!["AA:BB:CC:DD:EE:11"]);
+    }
+    let response = app
+        .clone()
+        .oneshot(get("/api/machines/AA:BB:CC:DD:EE:10".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = app
+        .oneshot(request(
+            "DELETE",
+            "/api/machines/delete",
+            serde_json::json!({ "id": id }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn interfaces_are_saved_and_their_macs_are_unique() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let post = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/machines")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .expect("failed to build request")
+    };
+
+    let response = app
+        .clone()
+        .oneshot(post(serde_json::json!({
+            "mac": "AA:BB:CC:DD:EE:30",
+            "ip": "192.168.1.60",
+            "name": "Laptop",
+            "interfaces": [
+                { "mac": "aa-bb-cc-dd-ee-31", "ip": "192.168.1.61", "wake_on_lan": false, "priority": 1 }
+            ],
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    {
+        let machines = state.machines.read().await;
+        assert_eq!(machines[0].interfaces[0].mac, "AA:BB:CC:DD:EE:31");
+        assert_eq!(machines[0].wake_macs(), vec!["AA:BB:CC:DD:EE:30"]);
+        assert_eq!(machines[0].addresses().len(), 2);
+    }
+
+    // The Wi-Fi MAC of the laptop can't be added as another machine
+    let response = app
+        .clone()
+        .oneshot(post(serde_json::json!({
+            "mac": "AA:BB:CC:DD:EE:31",
+            "ip": "192.168.1.61",
+            "name": "Laptop Wi-Fi",
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = app
+        .oneshot(post(serde_json::json!({
+            "mac": "AA:BB:CC:DD:EE:40",
+            "ip": "192.168.1.70",
+            "name": "Broken",
+            "interfaces": [{ "mac": "not-a-mac" }],
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.machines.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn machines_can_be_added_by_hostname_and_show_the_resolved_ip() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let app = api_routes(state.clone());
+
+    let post = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/machines")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .expect("failed to build request")
+    };
+
+    let response = app
+        .clone()
+        .oneshot(post(serde_json::json!({
+            "mac": "AA:BB:CC:DD:EE:50",
+            "ip": "localhost",
+            "name": "Desktop",
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/machines/AA:BB:CC:DD:EE:50")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&response.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    assert_eq!(json["hostname"], "localhost");
+    assert_eq!(json["ip"], "127.0.0.1");
+
+    // Names that don't resolve can't be added without a last known address
+    let response = app
+        .oneshot(post(serde_json::json!({
+            "mac": "AA:BB:CC:DD:EE:51",
+            "ip": "nas.invalid",
+            "name": "NAS",
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.machines.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn moved_machines_get_a_proposal_that_can_be_accepted() {
+    let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (state, _guard) = setup_state(&temp_dir);
+    let mut machine = sample_machine();
+    machine.id = "workstation".to_string();
+    state.machines.write().await.push(machine);
+    let app = api_routes(state.clone());
+
+    let devices = vec![DiscoveredDevice {
+        ip: "192.168.1.80".to_string(),
+        mac: "aa:bb:cc:dd:ee:ff".to_string(),
+        hostname: None,
+    }];
+    ip_drift::handle_devices(&state, &devices, IpDriftMode::Propose).await;
+    {
+        let machines = state.machines.read().await;
+        assert_eq!(machines[0].ip.to_string(), "127.0.0.1");
+        let proposal = machines[0].ip_proposal.as_ref().expect("a proposal");
+        assert_eq!(proposal.ip.to_string(), "192.168.1.80");
+    }
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/machines/workstation/ip-proposal")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    {
+        let machines = state.machines.read().await;
+        assert_eq!(machines[0].ip.to_string(), "192.168.1.80");
+        assert!(machines[0].ip_proposal.is_none());
+    }
+
+    // Nothing left to accept
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/machines/workstation/ip-proposal")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // In update mode the address follows the scan right away
+    let moved_from = state.machines.read().await[0].clone();
+    state.turn_off_limiter.initialize_machine(&moved_from);
+    let devices = vec![DiscoveredDevice {
+        ip: "192.168.1.81".to_string(),
+        mac: "AA-BB-CC-DD-EE-FF".to_string(),
+        hostname: None,
+    }];
+    ip_drift::handle_devices(&state, &devices, IpDriftMode::Update).await;
+    assert_eq!(
+        state.machines.read().await[0].ip.to_string(),
+        "192.168.1.81"
+    );
+    // The inactivity monitor no longer shuts down whatever now has the old address
+    assert!(!state.turn_off_limiter.is_monitoring(moved_from.ip));
+}
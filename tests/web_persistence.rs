@@ -27,6 +27,7 @@ fn save_and_load_machines_round_trip() {
     );
 
     let machines = vec![Machine {
+        id: String::new(),
         mac: "AA:BB:CC:DD:EE:FF".into(),
         ip: "192.168.1.10".parse().unwrap(),
         name: "Desktop".into(),
@@ -39,6 +40,21 @@ fn save_and_load_machines_round_trip() {
             local_port: 2222,
             target_port: 22,
         }],
+        pending_approval: false,
+        agent: None,
+        wol_armed: None,
+        power_off: Default::default(),
+        power_on: Default::default(),
+        shutdown_fallback: None,
+        agent_tls_fingerprint: None,
+        tags: Vec::new(),
+        schedules: Vec::new(),
+        inactivity_policy: Default::default(),
+        wake_policy: Default::default(),
+        depends_on: Vec::new(),
+        interfaces: Vec::new(),
+        ip_proposal: None,
+        hostname: None,
     }];
 
     web::save_machines(&machines).expect("failed to save machines");